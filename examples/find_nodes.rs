@@ -193,10 +193,17 @@ async fn main() {
                     Discv5Event::Discovered(enr) => info!("Enr discovered {}", enr),
                     Discv5Event::EnrAdded { enr, replaced: _ } => info!("Enr added {}", enr),
                     Discv5Event::NodeInserted { node_id, replaced: _ } => info!("Node inserted {}", node_id),
+                    Discv5Event::NodeUpdated { node_id } => info!("Node updated {}", node_id),
+                    Discv5Event::NodeEvicted { node_id } => info!("Node evicted {}", node_id),
                     Discv5Event::SessionEstablished(enr, _) => info!("Session established {}", enr),
+                    Discv5Event::HandshakeFailed { reason, .. } => info!("Handshake failed {:?}", reason),
+                    Discv5Event::Bootstrapped => info!("Bootstrapped"),
                     Discv5Event::SocketUpdated(addr) => info!("Socket updated {}", addr),
+                    Discv5Event::LocalEnrUpdated { seq } => info!("Local ENR updated to seq {}", seq),
+                    Discv5Event::IncomingPing { from, .. } => info!("PING received from unknown peer {}", from),
                     Discv5Event::TalkRequest(_) => info!("Talk request received"),
                     Discv5Event::FindValue(_) => info!("FindValue request received"),
+                    Discv5Event::ExternalAddrUpdated(addr) => info!("External address updated {}", addr),
                 };
             }
         }
@@ -1,9 +1,33 @@
 use crate::{
-    ipmode::IpMode, kbucket::MAX_NODES_PER_BUCKET, Enr, Executor, PermitBanList, RateLimiter,
-    RateLimiterBuilder,
+    ipmode::IpMode,
+    kbucket::{EvictionPolicy, TableEnr, MAX_NODES_PER_BUCKET},
+    packet::MAX_PACKET_SIZE,
+    Enr, Executor, PermitBanList, RateLimiter, RateLimiterBuilder,
 };
 ///! A set of configuration parameters to tune the discovery protocol.
-use std::time::Duration;
+use enr::NodeId;
+use std::{
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+/// A user-supplied filter run on every inbound datagram, given its source address and raw
+/// bytes. Datagrams for which the filter returns `false` are dropped before being decoded.
+pub type PacketFilter = Arc<dyn Fn(&SocketAddr, &[u8]) -> bool + Send + Sync>;
+
+/// A user-supplied filter run on the ENR of a peer initiating a handshake with us. Handshakes for
+/// which the filter returns `false` are aborted before the session is established.
+pub type HandshakeFilter = Arc<dyn Fn(&Enr) -> bool + Send + Sync>;
+
+/// A user-supplied predicate on the ENRs inserted into the routing table. ENRs for which the
+/// predicate returns `false` are never inserted.
+pub type EnrFilter = Arc<dyn Fn(&Enr) -> bool + Send + Sync>;
+
+/// A user-supplied validator run on the key and value of every inbound STORE request. Values for
+/// which the validator returns `false` are rejected and not stored.
+pub type StoreValidator = Arc<dyn Fn(&NodeId, &[u8]) -> bool + Send + Sync>;
 
 /// Configuration parameters that define the performance of the discovery network.
 #[derive(Clone)]
@@ -14,6 +38,36 @@ pub struct Discv5Config {
     /// The request timeout for each UDP request. Default: 1 seconds.
     pub request_timeout: Duration,
 
+    /// The timeout of PING requests, overriding `request_timeout`. Default: None.
+    pub ping_request_timeout: Option<Duration>,
+
+    /// The timeout of FINDNODE requests, overriding `request_timeout`. A response to FINDNODE
+    /// may span several packets. Default: None.
+    pub find_node_request_timeout: Option<Duration>,
+
+    /// The timeout of TALKREQ requests, overriding `request_timeout`. Default: None.
+    pub talk_request_timeout: Option<Duration>,
+
+    /// The time an inbound TALKREQ waits for the application to respond. A request left
+    /// unanswered is then answered with an empty TALKRESP. Default: 1 second.
+    pub talk_response_timeout: Duration,
+
+    /// The protocol ids inbound TALKREQs are accepted for. Requests of other protocols are not
+    /// dispatched. If set to `None`, all the protocols not in `talk_protocols_denied` are
+    /// accepted. Default: None.
+    pub talk_protocols_allowed: Option<Vec<Vec<u8>>>,
+
+    /// The protocol ids inbound TALKREQs are never dispatched for. Default: empty.
+    pub talk_protocols_denied: Vec<Vec<u8>>,
+
+    /// Whether the inbound TALKREQs of protocols that aren't accepted are dropped silently,
+    /// rather than answered with an empty TALKRESP. Default: false.
+    pub drop_disallowed_talk_requests: bool,
+
+    /// The time a WHOAREYOU challenge we sent waits for the handshake of the node, overriding
+    /// `request_timeout`. The challenge is dropped once elapsed. Default: None.
+    pub challenge_timeout: Option<Duration>,
+
     /// The interval over which votes are remembered when determining our external IP. A lower
     /// interval will respond faster to IP changes. Default is 30 seconds.
     pub vote_duration: Duration,
@@ -29,25 +83,78 @@ pub struct Discv5Config {
     /// The number of retries for each UDP request. Default: 1.
     pub request_retries: u8,
 
+    /// The delay before a request without a response is first sent again, doubled on every
+    /// retransmission and capped to the request timeout. The last attempt waits the full request
+    /// timeout. If set to `None`, every attempt waits the request timeout. Default: None.
+    pub request_retransmit_interval: Option<Duration>,
+
+    /// The maximum duration a graceful shutdown waits for the in-flight queries and requests to
+    /// resolve. See `Discv5::shutdown_gracefully`. Default: 10 seconds.
+    pub shutdown_timeout: Duration,
+
     /// The session timeout for each node. Default: 1 day.
     pub session_timeout: Duration,
 
-    /// The maximum number of established sessions to maintain. Default: 1000.
+    /// The maximum number of established sessions to maintain. When full, the least recently
+    /// used session is evicted along with its keys, the next contact with its peer performs a new
+    /// handshake. Default: 1000.
     pub session_cache_capacity: usize,
 
+    /// The number of messages encrypted with a session's keys after which the next request to
+    /// the node performs a new handshake, establishing fresh keys. Default: 2^31.
+    pub session_rekey_threshold: u32,
+
+    /// The maximum number of requests in flight to a node over a session at once. Further
+    /// requests to the node are queued until a response frees a slot. If set to `None`, every
+    /// request over an established session is sent right away. Default: None.
+    pub max_requests_per_session: Option<usize>,
+
+    /// A file persisting the counter of the message nonces, if any.
+    ///
+    /// The keys of a session are derived from ephemeral keys and never reused, so the nonces of
+    /// different sessions can't collide. Nonces only start with the number of messages sent over
+    /// their session, followed by random bytes. When set, the counter is instead shared by all
+    /// the sessions and persisted to this file, so that the counters of the nonces keep
+    /// increasing across restarts, even if keys were reloaded. The file must not be shared by
+    /// several running nodes. Default: None.
+    pub nonce_persistence_path: Option<PathBuf>,
+
     /// Updates the local ENR IP and port based on PONG responses from peers. Default: true.
     pub enr_update: bool,
 
     /// The maximum number of nodes we return to a find nodes request. The default is 16.
     pub max_nodes_response: usize,
 
+    /// The maximum number of nodes accepted from a single find nodes request, across all the
+    /// packets of its response. Further nodes are discarded and no further packets are awaited.
+    /// The default is 48.
+    pub max_nodes_response_total: usize,
+
+    /// The maximum number of ENRs we return to a find nodes request, the ENRs at the lowest
+    /// requested distances being kept. Unlike `max_nodes_response`, this only bounds our
+    /// responses and counts our own ENR. If set to `None`, responses are only bounded by
+    /// `max_nodes_response`. Default: None.
+    pub max_enrs_per_response: Option<usize>,
+
+    /// The maximum size of the packets the ENRs of a NODES response are packed into. Values
+    /// above the protocol limit of 1280 bytes are capped to it. The default is 1280.
+    pub max_packet_size: usize,
+
     /// The minimum number of peer's who agree on an external IP port before updating the
-    /// local ENR. Default: 10.
+    /// local ENR. Until then, the advertised IP and port are kept. Default: 10.
     pub enr_peer_update_min: usize,
 
     /// The number of peers to request in parallel in a single query. Default: 3.
     pub query_parallelism: usize,
 
+    /// The maximum number of queries that run at once. Queries started beyond this limit are
+    /// queued until a running query completes. Default: None (unlimited).
+    pub max_concurrent_queries: Option<usize>,
+
+    /// The maximum number of untrusted ENRs a single query buffers while traversing the DHT. When
+    /// exceeded, the ENRs farthest from the query target are dropped. Default: 500.
+    pub max_untrusted_enrs: usize,
+
     /// Limits the number of IP addresses from the same
     /// /24 subnet in the kbuckets table. This is to mitigate eclipse attacks. Default: false.
     pub ip_limit: bool,
@@ -56,18 +163,91 @@ pub struct Discv5Config {
     /// than the bucket size (16). By default this is disabled (set to the maximum bucket size, 16).
     pub incoming_bucket_limit: usize,
 
+    /// The duration a node waits in a full bucket's pending slot before it replaces the
+    /// least-recently connected node of the bucket, if that node is still disconnected.
+    /// Default: 60 seconds.
+    pub bucket_pending_timeout: Duration,
+
+    /// The strategy choosing which disconnected node of a full bucket a pending node replaces.
+    /// Default: `EvictionPolicy::LeastRecentlySeen`.
+    pub eviction_policy: EvictionPolicy<NodeId, TableEnr>,
+
     /// A filter used to decide whether to insert nodes into our local routing table. Nodes can be
     /// excluded if they do not pass this filter. The default is to accept all nodes.
     pub table_filter: fn(&Enr) -> bool,
 
+    /// A predicate checked before inserting discovered or connected nodes into the routing table,
+    /// e.g. to keep the table to the nodes of a single network or fork. Unlike the `table_filter`,
+    /// nodes failing the predicate are still returned in query results. Default: None.
+    pub enr_filter: Option<EnrFilter>,
+
+    /// Stores the ENRs of the routing table RLP-encoded, decoding them on access. This trades CPU
+    /// for memory, for nodes holding large tables such as crawlers. Default: false.
+    pub compact_enr_storage: bool,
+
     /// The time between pings to ensure connectivity amongst connected nodes. Default: 300
     /// seconds.
     pub ping_interval: Duration,
 
+    /// The interval at which a `FIND_NODE` query for a random target is run in the background to
+    /// keep the buckets of the routing table populated. Targets are picked in the sparsest
+    /// buckets. If set to `None`, the table is not refreshed. Default: None.
+    pub table_refresh_interval: Option<Duration>,
+
+    /// The interval at which the entries of the routing table contacted least recently are
+    /// pinged to check they are still alive. If set to `None`, entries are only pinged when
+    /// connected, every `ping_interval`. Default: None.
+    pub liveness_check_interval: Option<Duration>,
+
+    /// The number of consecutive failed PINGs after which a node is evicted from the routing
+    /// table, when liveness checks are enabled. Default: 3.
+    pub max_ping_failures: u32,
+
+    /// The ENRs of the nodes contacted on startup to join the network. They are retried until a
+    /// session is established with any node, at which point `Discv5Event::Bootstrapped` is
+    /// emitted. Default: empty.
+    pub boot_nodes: Vec<Enr>,
+
+    /// The delay before retrying to contact the boot nodes, doubled after every failed attempt.
+    /// Default: 1 second.
+    pub bootstrap_backoff: Duration,
+
+    /// The maximum delay between two attempts to contact the boot nodes. Default: 60 seconds.
+    pub bootstrap_max_backoff: Duration,
+
+    /// The ENRs inserted in the routing table on construction, typically exported with
+    /// `Discv5::export_table` before a restart. They start disconnected and are subject to the
+    /// table and ENR filters. Default: empty.
+    pub seed_table: Vec<Enr>,
+
+    /// Pings the connected peers whenever the local ENR is updated through
+    /// `Discv5::enr_insert`, so they learn the new sequence number and request the updated ENR.
+    /// Default: false.
+    pub proactive_enr_updates: bool,
+
+    /// Pings back the peers that PING us without being in the routing table, if their ENR is
+    /// known, and inserts them into the routing table once they answer. Default: false.
+    pub probe_incoming_pings: bool,
+
     /// Configures the type of socket to bind to. This also affects the selection of address to use
     /// to contact an ENR.
     pub ip_mode: IpMode,
 
+    /// The addresses of the IPv4 and IPv6 sockets to listen on. If an address of either family is
+    /// set, a separate socket is bound for each of them instead of the socket given to
+    /// `Discv5::start`, and packets are sent from the socket of their destination's family. To
+    /// contact peers of both families, use `IpMode::Ip6 { enable_mapped_addresses: true }`.
+    /// Default: no addresses.
+    pub listen_addresses: (Option<SocketAddrV4>, Option<SocketAddrV6>),
+
+    /// The size of the OS receive buffer of the bound sockets. If set to `None`, the OS default
+    /// is kept. Default: None.
+    pub recv_buffer_size: Option<usize>,
+
+    /// The size of the OS send buffer of the bound sockets. If set to `None`, the OS default is
+    /// kept. Default: None.
+    pub send_buffer_size: Option<usize>,
+
     /// Reports all discovered ENR's when traversing the DHT to the event stream. Default true.
     pub report_discovered_peers: bool,
 
@@ -96,6 +276,73 @@ pub struct Discv5Config {
     /// will last indefinitely. Default is 1 hour.
     pub ban_duration: Option<Duration>,
 
+    /// The maximum number of requests accepted from a single node, as a number of requests every
+    /// given duration. Bursts of up to that number of requests are allowed. Requests exceeding
+    /// the limit are silently dropped. If set to `None`, requests are not limited per node.
+    /// Default: None.
+    pub per_node_request_rate_limit: Option<(u64, Duration)>,
+
+    /// The maximum number of WHOAREYOU challenges sent in response to unknown packets, as a
+    /// number of challenges every given duration, across all sources. Unknown packets exceeding
+    /// the limit are dropped without a challenge. If set to `None`, challenges are not limited.
+    /// Default: None.
+    pub whoareyou_rate_limit: Option<(u64, Duration)>,
+
+    /// The maximum number of WHOAREYOU challenges sent in response to unknown packets from a
+    /// single IP, as a number of challenges every given duration. Unknown packets exceeding the
+    /// limit are dropped without a challenge. If set to `None`, challenges are not limited per IP.
+    /// Default: None.
+    pub whoareyou_rate_limit_per_ip: Option<(u64, Duration)>,
+
+    /// A filter run on every inbound datagram before it is decoded. Datagrams rejected by the
+    /// filter are silently dropped. This runs regardless of `enable_packet_filter`, ahead of the
+    /// built-in filter. Default: None.
+    pub packet_filter: Option<PacketFilter>,
+
+    /// A filter run on the ENR of every peer initiating a handshake. Handshakes rejected by the
+    /// filter are aborted and no session is established. Unlike the packet filter, this has the
+    /// peer's verified ENR. Default: None.
+    pub incoming_handshake_filter: Option<HandshakeFilter>,
+
+    /// The number of closest peers a value is stored on via `Discv5::put_value`, independent of
+    /// the k-bucket size used for routing. Default: 16.
+    pub value_replication_factor: usize,
+
+    /// The maximum size in bytes of a value accepted from a STORE request or a VALUE response,
+    /// and of a value stored via `Discv5::put_value`. Values stored are further limited to those
+    /// fitting in a single STORE packet, whereas VALUE responses are chunked. Default: 1000.
+    pub max_value_size: usize,
+
+    /// The maximum number of values stored on behalf of other nodes. Once reached, the least
+    /// recently used value is evicted. Default: 1000.
+    pub value_store_capacity: usize,
+
+    /// The duration a value stored on behalf of another node is kept for. Default: 24 hours.
+    pub stored_value_ttl: Duration,
+
+    /// The interval at which values stored via `Discv5::put_value` are stored again on the
+    /// closest peers. This should be shorter than the time peers keep stored values for.
+    /// Default: 1 hour.
+    pub value_republish_interval: Duration,
+
+    /// The duration a value stored via `Discv5::put_value` is republished for. Once elapsed, the
+    /// local node no longer originates the value. Default: 24 hours.
+    pub value_ttl: Duration,
+
+    /// Whether values retrieved via `FIND_VALUE` are cached in the local value store, from which
+    /// `FIND_VALUE` requests of other nodes are then answered. Cached values are subject to the
+    /// `value_store_capacity` and the `stored_value_ttl`. Default: false.
+    pub cache_found_values: bool,
+
+    /// A validator run on the values of inbound STORE and STOREBATCH requests, before they are
+    /// stored. Values rejected by the validator are answered as not accepted. Default: None.
+    pub store_validator: Option<StoreValidator>,
+
+    /// The fraction by which the table refresh and value republish intervals are randomly
+    /// lengthened or shortened, so that nodes started together do not refresh and republish in
+    /// lockstep. Must be within `[0, 1)`. Default: 0.
+    pub timer_jitter: f64,
+
     /// A custom executor which can spawn the discv5 tasks. This must be a tokio runtime, with
     /// timing support. By default, the executor that created the discv5 struct will be used.
     pub executor: Option<Box<dyn Executor + Send + Sync>>,
@@ -116,27 +363,75 @@ impl Default for Discv5Config {
         Self {
             enable_packet_filter: false,
             request_timeout: Duration::from_secs(1),
+            ping_request_timeout: None,
+            challenge_timeout: None,
+            find_node_request_timeout: None,
+            talk_request_timeout: None,
+            talk_response_timeout: Duration::from_secs(1),
+            talk_protocols_allowed: None,
+            talk_protocols_denied: Vec::new(),
+            drop_disallowed_talk_requests: false,
             vote_duration: Duration::from_secs(30),
             query_peer_timeout: Duration::from_secs(2),
             query_timeout: Duration::from_secs(60),
             request_retries: 1,
+            request_retransmit_interval: None,
+            shutdown_timeout: Duration::from_secs(10),
             session_timeout: Duration::from_secs(86400),
             session_cache_capacity: 1000,
+            session_rekey_threshold: 1 << 31,
+            max_requests_per_session: None,
+            nonce_persistence_path: None,
             enr_update: true,
             max_nodes_response: 16,
+            max_nodes_response_total: 48,
+            max_enrs_per_response: None,
+            max_packet_size: MAX_PACKET_SIZE,
             enr_peer_update_min: 10,
             query_parallelism: 3,
+            max_concurrent_queries: None,
+            max_untrusted_enrs: 500,
             ip_limit: false,
             incoming_bucket_limit: MAX_NODES_PER_BUCKET,
+            bucket_pending_timeout: Duration::from_secs(60),
+            eviction_policy: EvictionPolicy::default(),
             table_filter: |_| true,
+            enr_filter: None,
+            compact_enr_storage: false,
             ping_interval: Duration::from_secs(300),
+            table_refresh_interval: None,
+            liveness_check_interval: None,
+            max_ping_failures: 3,
+            boot_nodes: Vec::new(),
+            seed_table: Vec::new(),
+            bootstrap_backoff: Duration::from_secs(1),
+            bootstrap_max_backoff: Duration::from_secs(60),
+            proactive_enr_updates: false,
+            probe_incoming_pings: false,
             report_discovered_peers: true,
             filter_rate_limiter,
             filter_max_nodes_per_ip: Some(10),
             filter_max_bans_per_ip: Some(5),
             permit_ban_list: PermitBanList::default(),
             ban_duration: Some(Duration::from_secs(3600)), // 1 hour
+            per_node_request_rate_limit: None,
+            whoareyou_rate_limit: None,
+            whoareyou_rate_limit_per_ip: None,
+            packet_filter: None,
+            incoming_handshake_filter: None,
+            value_replication_factor: 16,
+            max_value_size: 1000,
+            value_store_capacity: 1000,
+            stored_value_ttl: Duration::from_secs(24 * 60 * 60),
+            value_republish_interval: Duration::from_secs(60 * 60),
+            value_ttl: Duration::from_secs(24 * 60 * 60),
+            cache_found_values: false,
+            store_validator: None,
+            timer_jitter: 0.0,
             ip_mode: IpMode::default(),
+            listen_addresses: (None, None),
+            recv_buffer_size: None,
+            send_buffer_size: None,
             executor: None,
         }
     }
@@ -165,6 +460,57 @@ impl Discv5ConfigBuilder {
         self
     }
 
+    /// The timeout of PING requests, overriding `request_timeout`.
+    pub fn ping_request_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.ping_request_timeout = Some(timeout);
+        self
+    }
+
+    /// The timeout of FINDNODE requests, overriding `request_timeout`.
+    pub fn find_node_request_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.find_node_request_timeout = Some(timeout);
+        self
+    }
+
+    /// The timeout of TALKREQ requests, overriding `request_timeout`.
+    pub fn talk_request_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.talk_request_timeout = Some(timeout);
+        self
+    }
+
+    /// The time an inbound TALKREQ waits for the application to respond, before it is answered
+    /// with an empty TALKRESP.
+    pub fn talk_response_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.talk_response_timeout = timeout;
+        self
+    }
+
+    /// Only dispatches the inbound TALKREQs of these protocol ids.
+    pub fn talk_protocols_allowed(&mut self, protocols: Vec<Vec<u8>>) -> &mut Self {
+        self.config.talk_protocols_allowed = Some(protocols);
+        self
+    }
+
+    /// Never dispatches the inbound TALKREQs of these protocol ids.
+    pub fn talk_protocols_denied(&mut self, protocols: Vec<Vec<u8>>) -> &mut Self {
+        self.config.talk_protocols_denied = protocols;
+        self
+    }
+
+    /// Silently drops the inbound TALKREQs of protocols that aren't accepted, rather than
+    /// answering them with an empty TALKRESP.
+    pub fn drop_disallowed_talk_requests(&mut self) -> &mut Self {
+        self.config.drop_disallowed_talk_requests = true;
+        self
+    }
+
+    /// The time a WHOAREYOU challenge we sent waits for the handshake of the node, overriding
+    /// `request_timeout`.
+    pub fn challenge_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.challenge_timeout = Some(timeout);
+        self
+    }
+
     /// The interval over which votes are remembered when determining our external IP. A lower
     /// interval will respond faster to IP changes. Default is 30 seconds.
     pub fn vote_duration(&mut self, vote_duration: Duration) -> &mut Self {
@@ -186,24 +532,63 @@ impl Discv5ConfigBuilder {
         self
     }
 
+    /// The maximum duration a graceful shutdown waits for the in-flight queries and requests to
+    /// resolve.
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
     /// The number of retries for each UDP request.
     pub fn request_retries(&mut self, retries: u8) -> &mut Self {
         self.config.request_retries = retries;
         self
     }
 
+    /// The delay before a request without a response is first sent again, doubled on every
+    /// retransmission up to `request_retries` attempts.
+    pub fn request_retransmit_interval(&mut self, interval: Duration) -> &mut Self {
+        self.config.request_retransmit_interval = Some(interval);
+        self
+    }
+
     /// The session timeout for each node.
     pub fn session_timeout(&mut self, timeout: Duration) -> &mut Self {
         self.config.session_timeout = timeout;
         self
     }
 
-    /// The maximum number of established sessions to maintain.
+    /// The maximum number of established sessions to maintain. When full, the least recently
+    /// used session is evicted.
     pub fn session_cache_capacity(&mut self, capacity: usize) -> &mut Self {
         self.config.session_cache_capacity = capacity;
         self
     }
 
+    /// The number of messages encrypted with a session's keys after which the next request to
+    /// the node performs a new handshake, establishing fresh keys.
+    pub fn session_rekey_threshold(&mut self, threshold: u32) -> &mut Self {
+        self.config.session_rekey_threshold = threshold;
+        self
+    }
+
+    /// The maximum number of requests in flight to a node over a session at once. Requests over
+    /// the limit are queued until a slot frees. The limit must be positive.
+    pub fn max_requests_per_session(&mut self, max: usize) -> &mut Self {
+        if max == 0 {
+            panic!("The maximum requests per session must be positive");
+        }
+        self.config.max_requests_per_session = Some(max);
+        self
+    }
+
+    /// A file persisting the counter of the message nonces, so that the counters keep increasing
+    /// across restarts. The file must not be shared by several running nodes.
+    pub fn nonce_persistence_path(&mut self, path: PathBuf) -> &mut Self {
+        self.config.nonce_persistence_path = Some(path);
+        self
+    }
+
     /// Disables the auto-update of the local ENR IP and port based on PONG responses from peers.
     pub fn disable_enr_update(&mut self) -> &mut Self {
         self.config.enr_update = false;
@@ -216,8 +601,28 @@ impl Discv5ConfigBuilder {
         self
     }
 
+    /// The maximum number of nodes accepted from a single find nodes request.
+    pub fn max_nodes_response_total(&mut self, max: usize) -> &mut Self {
+        self.config.max_nodes_response_total = max;
+        self
+    }
+
+    /// The maximum number of ENRs we return to a find nodes request, to bound the bandwidth and
+    /// packets spent on responses.
+    pub fn max_enrs_per_response(&mut self, max: usize) -> &mut Self {
+        self.config.max_enrs_per_response = Some(max);
+        self
+    }
+
+    /// The maximum size of the packets the ENRs of a NODES response are packed into, capped to
+    /// the protocol limit of 1280 bytes.
+    pub fn max_packet_size(&mut self, size: usize) -> &mut Self {
+        self.config.max_packet_size = size;
+        self
+    }
+
     /// The minimum number of peer's who agree on an external IP port before updating the
-    /// local ENR.
+    /// local ENR. Until then, the advertised IP and port are kept.
     pub fn enr_peer_update_min(&mut self, min: usize) -> &mut Self {
         if min < 2 {
             panic!("Setting enr_peer_update_min to a value less than 2 will cause issues with discovery with peers behind NAT");
@@ -232,6 +637,23 @@ impl Discv5ConfigBuilder {
         self
     }
 
+    /// The maximum number of queries that run at once. Queries started beyond this limit are
+    /// queued until a running query completes. The limit must be at least 1.
+    pub fn max_concurrent_queries(&mut self, max: usize) -> &mut Self {
+        if max == 0 {
+            panic!("The maximum number of concurrent queries must be at least 1");
+        }
+        self.config.max_concurrent_queries = Some(max);
+        self
+    }
+
+    /// The maximum number of untrusted ENRs a single query buffers while traversing the DHT. When
+    /// exceeded, the ENRs farthest from the query target are dropped.
+    pub fn max_untrusted_enrs(&mut self, max: usize) -> &mut Self {
+        self.config.max_untrusted_enrs = max;
+        self
+    }
+
     /// Limits the number of IP addresses from the same
     /// /24 subnet in the kbuckets table. This is to mitigate eclipse attacks.
     pub fn ip_limit(&mut self) -> &mut Self {
@@ -246,6 +668,20 @@ impl Discv5ConfigBuilder {
         self
     }
 
+    /// The duration a node waits in a full bucket's pending slot before it replaces the
+    /// least-recently connected node of the bucket, if that node is still disconnected.
+    pub fn bucket_pending_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.bucket_pending_timeout = timeout;
+        self
+    }
+
+    /// Sets the strategy choosing which disconnected node of a full bucket a pending node
+    /// replaces. Pinned nodes are never evicted.
+    pub fn eviction_policy(&mut self, policy: EvictionPolicy<NodeId, TableEnr>) -> &mut Self {
+        self.config.eviction_policy = policy;
+        self
+    }
+
     /// A filter used to decide whether to insert nodes into our local routing table. Nodes can be
     /// excluded if they do not pass this filter.
     pub fn table_filter(&mut self, filter: fn(&Enr) -> bool) -> &mut Self {
@@ -253,12 +689,82 @@ impl Discv5ConfigBuilder {
         self
     }
 
+    /// Sets a predicate checked before inserting nodes into the routing table. Nodes failing the
+    /// predicate are never inserted, but are still returned in query results.
+    pub fn enr_filter(&mut self, filter: EnrFilter) -> &mut Self {
+        self.config.enr_filter = Some(filter);
+        self
+    }
+
+    /// Stores the ENRs of the routing table RLP-encoded, decoding them on access, to reduce the
+    /// memory of large tables.
+    pub fn compact_enr_storage(&mut self, enabled: bool) -> &mut Self {
+        self.config.compact_enr_storage = enabled;
+        self
+    }
+
     /// The time between pings to ensure connectivity amongst connected nodes.
     pub fn ping_interval(&mut self, interval: Duration) -> &mut Self {
         self.config.ping_interval = interval;
         self
     }
 
+    /// Runs a `FIND_NODE` query for a random target every `interval`, to keep the buckets of the
+    /// routing table populated.
+    pub fn table_refresh_interval(&mut self, interval: Duration) -> &mut Self {
+        self.config.table_refresh_interval = Some(interval);
+        self
+    }
+
+    /// Pings the entries of the routing table contacted least recently at the given interval,
+    /// evicting the nodes failing `max_ping_failures` consecutive PINGs.
+    pub fn liveness_check_interval(&mut self, interval: Duration) -> &mut Self {
+        self.config.liveness_check_interval = Some(interval);
+        self
+    }
+
+    /// The number of consecutive failed PINGs after which a node is evicted from the routing
+    /// table, when liveness checks are enabled.
+    pub fn max_ping_failures(&mut self, failures: u32) -> &mut Self {
+        self.config.max_ping_failures = failures;
+        self
+    }
+
+    /// The ENRs of the nodes contacted on startup, retried until a session is established.
+    pub fn boot_nodes(&mut self, boot_nodes: Vec<Enr>) -> &mut Self {
+        self.config.boot_nodes = boot_nodes;
+        self
+    }
+
+    /// The delay before the first retry to contact the boot nodes, doubled after every failed
+    /// attempt up to `max_backoff`.
+    pub fn bootstrap_backoff(&mut self, backoff: Duration, max_backoff: Duration) -> &mut Self {
+        self.config.bootstrap_backoff = backoff;
+        self.config.bootstrap_max_backoff = max_backoff;
+        self
+    }
+
+    /// The ENRs inserted disconnected in the routing table on construction, to restore a table
+    /// exported with `Discv5::export_table`.
+    pub fn seed_table(&mut self, enrs: Vec<Enr>) -> &mut Self {
+        self.config.seed_table = enrs;
+        self
+    }
+
+    /// Pings the connected peers whenever the local ENR is updated through
+    /// `Discv5::enr_insert`, so they learn the new sequence number and request the updated ENR.
+    pub fn proactive_enr_updates(&mut self, enabled: bool) -> &mut Self {
+        self.config.proactive_enr_updates = enabled;
+        self
+    }
+
+    /// Pings back the peers that PING us without being in the routing table, if their ENR is
+    /// known, and inserts them into the routing table once they answer.
+    pub fn probe_incoming_pings(&mut self, enabled: bool) -> &mut Self {
+        self.config.probe_incoming_pings = enabled;
+        self
+    }
+
     /// Disables reporting of discovered peers through the event stream.
     pub fn disable_report_discovered_peers(&mut self) -> &mut Self {
         self.config.report_discovered_peers = false;
@@ -300,6 +806,102 @@ impl Discv5ConfigBuilder {
         self
     }
 
+    /// Limits the requests accepted from a single node to `n` requests every `time_period`.
+    /// Requests exceeding the limit are silently dropped.
+    pub fn per_node_request_rate_limit(&mut self, n: u64, time_period: Duration) -> &mut Self {
+        self.config.per_node_request_rate_limit = Some((n, time_period));
+        self
+    }
+
+    /// Limits the WHOAREYOU challenges sent in response to unknown packets to `n` every
+    /// `time_period`, across all sources. Unknown packets exceeding the limit are dropped.
+    pub fn whoareyou_rate_limit(&mut self, n: u64, time_period: Duration) -> &mut Self {
+        self.config.whoareyou_rate_limit = Some((n, time_period));
+        self
+    }
+
+    /// Limits the WHOAREYOU challenges sent in response to unknown packets from a single IP to
+    /// `n` every `time_period`. Unknown packets exceeding the limit are dropped.
+    pub fn whoareyou_rate_limit_per_ip(&mut self, n: u64, time_period: Duration) -> &mut Self {
+        self.config.whoareyou_rate_limit_per_ip = Some((n, time_period));
+        self
+    }
+
+    /// Sets a filter run on every inbound datagram, given its source address and raw bytes.
+    /// Datagrams for which the filter returns `false` are dropped before being decoded.
+    pub fn packet_filter(&mut self, filter: PacketFilter) -> &mut Self {
+        self.config.packet_filter = Some(filter);
+        self
+    }
+
+    /// Sets a filter run on the ENR of every peer initiating a handshake, once the ENR is learned.
+    /// Handshakes for which the filter returns `false` are aborted.
+    pub fn incoming_handshake_filter(&mut self, filter: HandshakeFilter) -> &mut Self {
+        self.config.incoming_handshake_filter = Some(filter);
+        self
+    }
+
+    /// The number of closest peers a value is stored on via `Discv5::put_value`.
+    pub fn value_replication_factor(&mut self, factor: usize) -> &mut Self {
+        self.config.value_replication_factor = factor;
+        self
+    }
+
+    /// The maximum size in bytes of a value accepted from a STORE request or a VALUE response.
+    pub fn max_value_size(&mut self, size: usize) -> &mut Self {
+        self.config.max_value_size = size;
+        self
+    }
+
+    /// The maximum number of values stored on behalf of other nodes.
+    pub fn value_store_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.config.value_store_capacity = capacity;
+        self
+    }
+
+    /// The duration a value stored on behalf of another node is kept for.
+    pub fn stored_value_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.config.stored_value_ttl = ttl;
+        self
+    }
+
+    /// The interval at which values stored via `Discv5::put_value` are stored again on the
+    /// closest peers.
+    pub fn value_republish_interval(&mut self, interval: Duration) -> &mut Self {
+        self.config.value_republish_interval = interval;
+        self
+    }
+
+    /// The duration a value stored via `Discv5::put_value` is republished for.
+    pub fn value_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.config.value_ttl = ttl;
+        self
+    }
+
+    /// Whether values retrieved via `FIND_VALUE` are cached in the local value store, to answer
+    /// the `FIND_VALUE` requests of other nodes.
+    pub fn cache_found_values(&mut self, cache: bool) -> &mut Self {
+        self.config.cache_found_values = cache;
+        self
+    }
+
+    /// Sets a validator run on the key and value of every inbound STORE request. Values for which
+    /// the validator returns `false` are rejected.
+    pub fn store_validator(&mut self, validator: StoreValidator) -> &mut Self {
+        self.config.store_validator = Some(validator);
+        self
+    }
+
+    /// Randomly lengthens or shortens each table refresh and value republish interval by up to
+    /// this fraction of the interval. The jitter must be within `[0, 1)`.
+    pub fn timer_jitter(&mut self, jitter: f64) -> &mut Self {
+        if !(0.0..1.0).contains(&jitter) {
+            panic!("The timer jitter must be within [0, 1)");
+        }
+        self.config.timer_jitter = jitter;
+        self
+    }
+
     /// A custom executor which can spawn the discv5 tasks. This must be a tokio runtime, with
     /// timing support.
     pub fn executor(&mut self, executor: Box<dyn Executor + Send + Sync>) -> &mut Self {
@@ -314,6 +916,32 @@ impl Discv5ConfigBuilder {
         self
     }
 
+    /// Sets the addresses of separate IPv4 and IPv6 sockets to listen on. These are bound instead
+    /// of the socket given to `Discv5::start`, and packets are sent from the socket of their
+    /// destination's family.
+    pub fn listen_addresses(
+        &mut self,
+        v4: Option<SocketAddrV4>,
+        v6: Option<SocketAddrV6>,
+    ) -> &mut Self {
+        self.config.listen_addresses = (v4, v6);
+        self
+    }
+
+    /// The size of the OS receive buffer of the bound sockets. The OS may clamp the size to its
+    /// own limit.
+    pub fn recv_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.config.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// The size of the OS send buffer of the bound sockets. The OS may clamp the size to its own
+    /// limit.
+    pub fn send_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.config.send_buffer_size = Some(size);
+        self
+    }
+
     pub fn build(&mut self) -> Discv5Config {
         // If an executor is not provided, assume a current tokio runtime is running.
         if self.config.executor.is_none() {
@@ -331,22 +959,85 @@ impl std::fmt::Debug for Discv5Config {
         f.debug_struct("Discv5Config")
             .field("filter_enabled", &self.enable_packet_filter)
             .field("request_timeout", &self.request_timeout)
+            .field("ping_request_timeout", &self.ping_request_timeout)
+            .field("find_node_request_timeout", &self.find_node_request_timeout)
+            .field("talk_request_timeout", &self.talk_request_timeout)
+            .field("talk_response_timeout", &self.talk_response_timeout)
+            .field("talk_protocols_allowed", &self.talk_protocols_allowed)
+            .field("talk_protocols_denied", &self.talk_protocols_denied)
+            .field(
+                "drop_disallowed_talk_requests",
+                &self.drop_disallowed_talk_requests,
+            )
+            .field("challenge_timeout", &self.challenge_timeout)
             .field("vote_duration", &self.vote_duration)
             .field("query_timeout", &self.query_timeout)
             .field("query_peer_timeout", &self.query_peer_timeout)
             .field("request_retries", &self.request_retries)
+            .field(
+                "request_retransmit_interval",
+                &self.request_retransmit_interval,
+            )
+            .field("shutdown_timeout", &self.shutdown_timeout)
             .field("session_timeout", &self.session_timeout)
             .field("session_cache_capacity", &self.session_cache_capacity)
+            .field("session_rekey_threshold", &self.session_rekey_threshold)
+            .field("max_requests_per_session", &self.max_requests_per_session)
+            .field("nonce_persistence_path", &self.nonce_persistence_path)
             .field("enr_update", &self.enr_update)
+            .field("max_nodes_response_total", &self.max_nodes_response_total)
+            .field("max_enrs_per_response", &self.max_enrs_per_response)
+            .field("max_packet_size", &self.max_packet_size)
             .field("query_parallelism", &self.query_parallelism)
+            .field("max_concurrent_queries", &self.max_concurrent_queries)
+            .field("max_untrusted_enrs", &self.max_untrusted_enrs)
             .field("report_discovered_peers", &self.report_discovered_peers)
             .field("ip_limit", &self.ip_limit)
             .field("filter_max_nodes_per_ip", &self.filter_max_nodes_per_ip)
             .field("filter_max_bans_per_ip", &self.filter_max_bans_per_ip)
             .field("ip_limit", &self.ip_limit)
             .field("incoming_bucket_limit", &self.incoming_bucket_limit)
+            .field("bucket_pending_timeout", &self.bucket_pending_timeout)
+            .field("eviction_policy", &self.eviction_policy)
+            .field("enr_filter", &self.enr_filter.is_some())
+            .field("compact_enr_storage", &self.compact_enr_storage)
             .field("ping_interval", &self.ping_interval)
+            .field("table_refresh_interval", &self.table_refresh_interval)
+            .field("liveness_check_interval", &self.liveness_check_interval)
+            .field("max_ping_failures", &self.max_ping_failures)
+            .field("boot_nodes", &self.boot_nodes)
+            .field("seed_table", &self.seed_table.len())
+            .field("bootstrap_backoff", &self.bootstrap_backoff)
+            .field("bootstrap_max_backoff", &self.bootstrap_max_backoff)
+            .field("proactive_enr_updates", &self.proactive_enr_updates)
+            .field("probe_incoming_pings", &self.probe_incoming_pings)
             .field("ban_duration", &self.ban_duration)
+            .field(
+                "per_node_request_rate_limit",
+                &self.per_node_request_rate_limit,
+            )
+            .field("whoareyou_rate_limit", &self.whoareyou_rate_limit)
+            .field(
+                "whoareyou_rate_limit_per_ip",
+                &self.whoareyou_rate_limit_per_ip,
+            )
+            .field("packet_filter", &self.packet_filter.is_some())
+            .field(
+                "incoming_handshake_filter",
+                &self.incoming_handshake_filter.is_some(),
+            )
+            .field("value_replication_factor", &self.value_replication_factor)
+            .field("max_value_size", &self.max_value_size)
+            .field("value_store_capacity", &self.value_store_capacity)
+            .field("stored_value_ttl", &self.stored_value_ttl)
+            .field("value_republish_interval", &self.value_republish_interval)
+            .field("value_ttl", &self.value_ttl)
+            .field("cache_found_values", &self.cache_found_values)
+            .field("store_validator", &self.store_validator.is_some())
+            .field("timer_jitter", &self.timer_jitter)
+            .field("listen_addresses", &self.listen_addresses)
+            .field("recv_buffer_size", &self.recv_buffer_size)
+            .field("send_buffer_size", &self.send_buffer_size)
             .finish()
     }
 }
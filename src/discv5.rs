@@ -14,26 +14,32 @@
 
 use crate::{
     error::{Discv5Error, QueryError, RequestError, FindValueError},
+    handler::HandshakeFailureReason,
     kbucket::{
         self, ConnectionDirection, ConnectionState, FailureReason, InsertResult, KBucketsTable,
-        NodeStatus, UpdateResult,
+        NodeStatus, TableEnr, UpdateResult, MAX_NODES_PER_BUCKET,
     },
     node_info::NodeContact,
-    service::{QueryKind, Service, ServiceRequest, TalkRequest},
-    Discv5Config, Enr,
+    service::{
+        store_batches, store_entry_size, PingResponse, PreferredPeers, QueryCompletion, QueryKind, QueryStatus,
+        Service, ServiceRequest, SharedResponses, TalkProtocols, TalkRequest,
+        MAX_STORE_BATCH_SIZE,
+    },
+    signed_value::SignedValue,
+    Discv5Config, Enr, IpMode,
 };
-use enr::{CombinedKey, EnrError, EnrKey, NodeId};
+use enr::{CombinedKey, CombinedPublicKey, EnrError, EnrKey, NodeId};
 use parking_lot::RwLock;
 use std::{
+    collections::{HashMap, HashSet},
     future::Future,
-    net::SocketAddr,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
     sync::Arc,
     time::{Duration, Instant},
 };
-use futures::channel::mpsc::UnboundedSender;
-use futures::StreamExt;
+use futures::{future, Stream, StreamExt};
 use tokio::sync::{mpsc, oneshot};
-use tokio_stream::wrappers::{ReceiverStream, UnboundedReceiverStream};
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use tracing::{debug, warn};
 
 #[cfg(feature = "libp2p")]
@@ -65,13 +71,33 @@ pub enum Discv5Event {
         node_id: NodeId,
         replaced: Option<NodeId>,
     },
+    /// The ENR or the connection status of a node in the routing table has changed.
+    NodeUpdated { node_id: NodeId },
+    /// A node has been removed from the routing table without being replaced. Nodes replaced by
+    /// a pending node are reported as `replaced` by `NodeInserted`.
+    NodeEvicted { node_id: NodeId },
     /// A new session has been established with a node.
     SessionEstablished(Enr, SocketAddr),
+    /// A handshake with a node failed. The node is given if it is known.
+    HandshakeFailed {
+        node_id: Option<NodeId>,
+        reason: HandshakeFailureReason,
+    },
+    /// A first session has been established since startup, after contacting the configured boot
+    /// nodes.
+    Bootstrapped,
     /// Our local ENR IP address has been updated.
     SocketUpdated(SocketAddr),
+    /// The external address predicted from the addresses peers observe us from has changed.
+    ExternalAddrUpdated(SocketAddr),
+    /// The local ENR was signed again under a new sequence number.
+    LocalEnrUpdated { seq: u64 },
+    /// A node outside the routing table has sent us a PING. Its ENR is given if it is known.
+    IncomingPing { enr: Option<Enr>, from: SocketAddr },
     /// A node has initiated a talk request.
     TalkRequest(TalkRequest),
-    /// A node has initiated a FIND_VALUE request.
+    /// A node has initiated a FIND_VALUE request for a key that is not held in the local value
+    /// store.
     FindValue(FindValueRequest),
 }
 
@@ -84,11 +110,17 @@ pub struct Discv5 {
     /// The exit channel to shutdown the underlying service.
     service_exit: Option<oneshot::Sender<()>>,
     /// The routing table of the discv5 service.
-    kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+    kbuckets: Arc<RwLock<KBucketsTable<NodeId, TableEnr>>>,
     /// The local ENR of the server.
     local_enr: Arc<RwLock<Enr>>,
     /// The key associated with the local ENR, required for updating the local ENR.
     enr_key: Arc<RwLock<CombinedKey>>,
+    /// The external address predicted from the addresses peers observe us from.
+    external_address_prediction: Arc<RwLock<(Option<SocketAddrV4>, Option<SocketAddrV6>)>>,
+    /// The handlers of the registered TALK protocols.
+    talk_protocols: TalkProtocols,
+    /// The peers queries contact first.
+    preferred_peers: PreferredPeers,
 }
 
 impl Discv5 {
@@ -112,8 +144,8 @@ impl Discv5 {
         // may expose this functionality to the users if there is demand for it.
         let (table_filter, bucket_filter) = if config.ip_limit {
             (
-                Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<Enr>>),
-                Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<Enr>>),
+                Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<TableEnr>>),
+                Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<TableEnr>>),
             )
         } else {
             (None, None)
@@ -123,26 +155,41 @@ impl Discv5 {
         let enr_key = Arc::new(RwLock::new(enr_key));
         let kbuckets = Arc::new(RwLock::new(KBucketsTable::new(
             local_enr.read().node_id().into(),
-            Duration::from_secs(60),
+            config.bucket_pending_timeout,
             config.incoming_bucket_limit,
             table_filter,
             bucket_filter,
+            config.eviction_policy.clone(),
         )));
 
         // Update the PermitBan list based on initial configuration
         *PERMIT_BAN_LIST.write() = config.permit_ban_list.clone();
 
-        Ok(Discv5 {
+        let seed_table = std::mem::take(&mut config.seed_table);
+        let discv5 = Discv5 {
             config,
             service_channel: None,
             service_exit: None,
             kbuckets,
             local_enr,
             enr_key,
-        })
+            external_address_prediction: Arc::new(RwLock::new((None, None))),
+            talk_protocols: Default::default(),
+            preferred_peers: Default::default(),
+        };
+
+        // seeded nodes start disconnected, until the service verifies them
+        for enr in seed_table {
+            if let Err(e) = discv5.add_enr(enr) {
+                debug!("Seeded ENR not added to the routing table: {}", e);
+            }
+        }
+
+        Ok(discv5)
     }
 
-    /// Starts the required tasks and begins listening on a given UDP SocketAddr.
+    /// Starts the required tasks and begins listening on a given UDP SocketAddr. If the config sets
+    /// `listen_addresses`, the sockets are bound on those addresses instead.
     pub async fn start(&mut self, listen_socket: SocketAddr) -> Result<(), Discv5Error> {
         if self.service_channel.is_some() {
             warn!("Service is already started");
@@ -154,6 +201,9 @@ impl Discv5 {
             self.local_enr.clone(),
             self.enr_key.clone(),
             self.kbuckets.clone(),
+            self.external_address_prediction.clone(),
+            self.talk_protocols.clone(),
+            self.preferred_peers.clone(),
             self.config.clone(),
             listen_socket,
         )
@@ -175,6 +225,32 @@ impl Discv5 {
         }
     }
 
+    /// Shuts the service down gracefully. New requests are no longer accepted, and the service
+    /// waits for the in-flight queries and requests to resolve, for up to the configured
+    /// `shutdown_timeout`, before closing the socket. The returned future resolves once the
+    /// service has shut down.
+    pub fn shutdown_gracefully(mut self) -> impl Future<Output = ()> + 'static {
+        let channel = self.service_channel.take();
+        // the service exits as soon as the exit channel is dropped
+        let exit = self.service_exit.take();
+
+        async move {
+            if let Some(channel) = channel {
+                let (callback_send, callback_recv) = oneshot::channel();
+                if channel
+                    .send(ServiceRequest::Shutdown(callback_send))
+                    .await
+                    .is_ok()
+                {
+                    let _ = callback_recv.await;
+                }
+            }
+            if let Some(exit) = exit {
+                let _ = exit.send(());
+            }
+        }
+    }
+
     /// Adds a known ENR of a peer participating in Service to the
     /// routing table.
     ///
@@ -183,6 +259,24 @@ impl Discv5 {
     /// operations involving one of these peers, without having to dial
     /// them upfront.
     pub fn add_enr(&self, enr: Enr) -> Result<(), &'static str> {
+        self.add_enr_with_status(
+            enr,
+            ConnectionState::Disconnected,
+            ConnectionDirection::Incoming,
+        )
+    }
+
+    /// Adds a known ENR to the routing table like [`Discv5::add_enr`], with the given initial
+    /// connection `state` and `direction`.
+    ///
+    /// This allows marking trusted peers as connected upfront, so that they are preferred
+    /// over disconnected peers in the routing table.
+    pub fn add_enr_with_status(
+        &self,
+        enr: Enr,
+        state: ConnectionState,
+        direction: ConnectionDirection,
+    ) -> Result<(), &'static str> {
         // only add ENR's that have a valid udp socket.
         if self.config.ip_mode.get_contactable_addr(&enr).is_none() {
             warn!("ENR attempted to be added without an UDP socket compatible with configured IpMode has been ignored.");
@@ -194,22 +288,27 @@ impl Discv5 {
             return Err("ENR banned by table filter");
         }
 
+        if let Some(filter) = &self.config.enr_filter {
+            if !filter(&enr) {
+                warn!("ENR attempted to be added which is rejected by the configured ENR filter.");
+                return Err("ENR rejected by the ENR filter");
+            }
+        }
+
         let key = kbucket::Key::from(enr.node_id());
 
         match self.kbuckets.write().insert_or_update(
             &key,
-            enr,
-            NodeStatus {
-                state: ConnectionState::Disconnected,
-                direction: ConnectionDirection::Incoming,
-            },
+            TableEnr::new(enr, self.config.compact_enr_storage),
+            NodeStatus { state, direction },
         ) {
             InsertResult::Inserted
             | InsertResult::Pending { .. }
             | InsertResult::StatusUpdated { .. }
             | InsertResult::ValueUpdated
             | InsertResult::Updated { .. }
-            | InsertResult::UpdatedPending => Ok(()),
+            | InsertResult::UpdatedPending
+            | InsertResult::NotModified => Ok(()),
             InsertResult::Failed(FailureReason::BucketFull) => Err("Table full"),
             InsertResult::Failed(FailureReason::BucketFilter) => Err("Failed bucket filter"),
             InsertResult::Failed(FailureReason::TableFilter) => Err("Failed table filter"),
@@ -218,6 +317,13 @@ impl Discv5 {
         }
     }
 
+    /// Sets the peers queries contact first, such as known fast nodes, replacing the previous
+    /// ones. A preferred peer is only contacted first if it is among the closest known peers to
+    /// the target of a query, those the query starts from.
+    pub fn set_preferred_peers(&self, peers: Vec<NodeId>) {
+        *self.preferred_peers.write() = peers.into_iter().collect();
+    }
+
     /// Removes a `node_id` from the routing table.
     ///
     /// This allows applications, for whatever reason, to remove nodes from the local routing
@@ -227,6 +333,20 @@ impl Discv5 {
         self.kbuckets.write().remove(key)
     }
 
+    /// Pins a node, which is then never evicted from the routing table to make room for other
+    /// nodes, nor for failing liveness checks. A pinned node that goes unreachable is still marked
+    /// as disconnected. Nodes not in the table yet are pinned once added. Explicitly removing a
+    /// pinned node still removes it. Returns whether the node wasn't pinned already.
+    pub fn pin_node(&self, node_id: &NodeId) -> bool {
+        self.kbuckets.write().pin(kbucket::Key::from(*node_id))
+    }
+
+    /// Unpins a node, which can then be evicted from the routing table again. Returns whether the
+    /// node was pinned.
+    pub fn unpin_node(&self, node_id: &NodeId) -> bool {
+        self.kbuckets.write().unpin(&kbucket::Key::from(*node_id))
+    }
+
     /// Returns a vector of closest nodes by the given distances.
     pub fn nodes_by_distance(&self, mut distances: Vec<u64>) -> Vec<Enr> {
         let mut nodes_to_send = Vec::new();
@@ -244,7 +364,7 @@ impl Discv5 {
             for node in kbuckets
                 .nodes_by_distances(distances.as_slice(), self.config.max_nodes_response)
                 .into_iter()
-                .map(|entry| entry.node.value.clone())
+                .map(|entry| entry.node.value.enr())
             {
                 nodes_to_send.push(node);
             }
@@ -252,6 +372,18 @@ impl Discv5 {
         nodes_to_send
     }
 
+    /// Returns the `count` known ENRs closest to `key` in the routing table, closest first. These
+    /// are the candidates for storing the value of the key, found without any network I/O.
+    pub fn candidate_storage_nodes(&self, key: NodeId, count: usize) -> Vec<Enr> {
+        let target = kbucket::Key::from(key);
+        self.kbuckets
+            .write()
+            .closest_values(&target)
+            .take(count)
+            .map(|closest| closest.value.enr())
+            .collect()
+    }
+
     /// Mark a node in the routing table as `Disconnnected`.
     ///
     /// A `Disconnected` node will be present in the routing table and will be only
@@ -267,6 +399,67 @@ impl Discv5 {
         )
     }
 
+    /// Drops the session established with a node and marks it as `Disconnected` in the routing
+    /// table, like [`Discv5::disconnect_node`].
+    ///
+    /// Further requests to the node perform a new handshake.
+    pub fn disconnect_peer(
+        &self,
+        node_id: NodeId,
+    ) -> impl Future<Output = Result<(), Discv5Error>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel?;
+            channel
+                .send(ServiceRequest::Disconnect(node_id))
+                .await
+                .map_err(|_| Discv5Error::ServiceChannelClosed)
+        }
+    }
+
+    /// Establishes fresh keys for the session with a node through a new handshake.
+    ///
+    /// The node is pinged straight away if it is in the routing table, otherwise the keys are
+    /// renewed with the next request to it. Requests in flight over the current keys are not
+    /// affected.
+    pub fn rekey_session(
+        &self,
+        node_id: NodeId,
+    ) -> impl Future<Output = Result<(), Discv5Error>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel?;
+            channel
+                .send(ServiceRequest::Rekey(node_id))
+                .await
+                .map_err(|_| Discv5Error::ServiceChannelClosed)
+        }
+    }
+
+    /// Returns whether a session is established with a node, in which case requests to the node
+    /// don't trigger a handshake. This is `false` if the service isn't started.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn is_connected(&self, node_id: NodeId) -> impl Future<Output = bool> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = match channel {
+                Ok(channel) => channel,
+                Err(_) => return false,
+            };
+            let (callback_send, callback_recv) = oneshot::channel();
+            let event = ServiceRequest::IsConnected(node_id, callback_send);
+            if channel.send(event).await.is_err() {
+                return false;
+            }
+            callback_recv.await.unwrap_or(false)
+        }
+    }
+
     /// Returns the number of connected peers that exist in the routing table.
     pub fn connected_peers(&self) -> usize {
         self.kbuckets
@@ -291,8 +484,30 @@ impl Discv5 {
         self.local_enr.read().clone()
     }
 
+    /// Returns the external address of the local node predicted from the addresses the
+    /// majority of peers observe it from, if enough peers agree.
+    ///
+    /// The IPv4 prediction is returned if there is one. Predictions are only made if
+    /// `enr_update` is enabled (see [`Discv5Config`]).
+    pub fn external_address_prediction(&self) -> Option<SocketAddr> {
+        let (ip4, ip6) = *self.external_address_prediction.read();
+        ip4.map(SocketAddr::V4).or_else(|| ip6.map(SocketAddr::V6))
+    }
+
+    /// Returns the external IPv4 address of the local node predicted from the IPv4 addresses
+    /// peers observe it from, if enough peers agree.
+    pub fn external_address_prediction_v4(&self) -> Option<SocketAddrV4> {
+        self.external_address_prediction.read().0
+    }
+
+    /// Returns the external IPv6 address of the local node predicted from the IPv6 addresses
+    /// peers observe it from, if enough peers agree.
+    pub fn external_address_prediction_v6(&self) -> Option<SocketAddrV6> {
+        self.external_address_prediction.read().1
+    }
+
     /// Returns the routing table of the discv5 service
-    pub fn kbuckets(&self) -> KBucketsTable<NodeId, Enr> {
+    pub fn kbuckets(&self) -> KBucketsTable<NodeId, TableEnr> {
         self.kbuckets.read().clone()
     }
 
@@ -301,11 +516,29 @@ impl Discv5 {
         // check if we know this node id in our routing table
         let key = kbucket::Key::from(*node_id);
         if let kbucket::Entry::Present(entry, _) = self.kbuckets.write().entry(&key) {
-            return Some(entry.value().clone());
+            return Some(entry.value().enr());
         }
         None
     }
 
+    /// Returns the index of the bucket a node id falls in, the log2 distance of the node id to the
+    /// local node, whether or not the node is in the routing table. The local node id is at 0.
+    pub fn bucket_index_of(&self, node_id: &NodeId) -> u64 {
+        let local_key = kbucket::Key::from(self.local_enr.read().node_id());
+        local_key
+            .log2_distance(&kbucket::Key::from(*node_id))
+            .unwrap_or(0)
+    }
+
+    /// Returns whether a node is in the routing table.
+    pub fn contains_node(&self, node_id: &NodeId) -> bool {
+        let key = kbucket::Key::from(*node_id);
+        matches!(
+            self.kbuckets.write().entry(&key),
+            kbucket::Entry::Present(..)
+        )
+    }
+
     /// Bans a node from the server. This will remove the node from the routing table if it exists
     /// and block all incoming packets from the node until the timeout specified. Setting the
     /// timeout to `None` creates a permanent ban.
@@ -354,8 +587,21 @@ impl Discv5 {
         PERMIT_BAN_LIST.write().permit_ips.remove(ip);
     }
 
-    /// Updates the local ENR TCP/UDP socket.
+    /// Updates the local ENR TCP/UDP socket, which bumps its sequence number and signs it again.
+    /// Returns whether the socket changed.
+    ///
+    /// The update is reported as `Discv5Event::LocalEnrUpdated`. If `proactive_enr_updates` is
+    /// enabled, the connected peers are pinged with the new sequence number.
     pub fn update_local_enr_socket(&self, socket_addr: SocketAddr, is_tcp: bool) -> bool {
+        let updated = self.set_local_enr_socket(socket_addr, is_tcp);
+        if updated {
+            self.report_local_enr_update();
+        }
+        updated
+    }
+
+    /// Sets the local ENR TCP/UDP socket if it changed.
+    fn set_local_enr_socket(&self, socket_addr: SocketAddr, is_tcp: bool) -> bool {
         let mut local_enr = self.local_enr.write();
         let update_socket: Option<SocketAddr> = match socket_addr {
             SocketAddr::V4(socket_addr) => {
@@ -389,11 +635,34 @@ impl Discv5 {
     }
 
     /// Allows application layer to insert an arbitrary field into the local ENR.
+    ///
+    /// The update is reported as `Discv5Event::LocalEnrUpdated`. If `proactive_enr_updates` is
+    /// enabled, the connected peers are pinged with the new sequence number.
     pub fn enr_insert(&self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>, EnrError> {
-        self.local_enr
+        let previous = self
+            .local_enr
             .write()
             .insert(key, value, &self.enr_key.read())
-            .map(|v| v.map(|v| v.to_vec()))
+            .map(|v| v.map(|v| v.to_vec()))?;
+
+        self.report_local_enr_update();
+        Ok(previous)
+    }
+
+    /// Returns the sequence number of the local ENR.
+    pub fn local_enr_seq(&self) -> u64 {
+        self.local_enr.read().seq()
+    }
+
+    /// Reports an update of the local ENR to the service, which emits the event and lets the
+    /// connected peers know if configured.
+    fn report_local_enr_update(&self) {
+        let seq = self.local_enr.read().seq();
+        if let Some(channel) = self.service_channel.as_ref() {
+            if let Err(e) = channel.try_send(ServiceRequest::LocalEnrUpdated(seq)) {
+                warn!("Failed to report the local ENR update: {}", e);
+            }
+        }
     }
 
     /// Returns an iterator over all ENR node IDs of nodes currently contained in the routing table.
@@ -410,10 +679,16 @@ impl Discv5 {
         self.kbuckets
             .write()
             .iter()
-            .map(|entry| entry.node.value.clone())
+            .map(|entry| entry.node.value.enr())
             .collect()
     }
 
+    /// Returns the ENRs of the routing table, to be restored on the next start through
+    /// `Discv5ConfigBuilder::seed_table`.
+    pub fn export_table(&self) -> Vec<Enr> {
+        self.table_entries_enr()
+    }
+
     /// Returns an iterator over all the entries in the routing table.
     pub fn table_entries(&self) -> Vec<(NodeId, Enr, NodeStatus)> {
         self.kbuckets
@@ -422,13 +697,48 @@ impl Discv5 {
             .map(|entry| {
                 (
                     *entry.node.key.preimage(),
-                    entry.node.value.clone(),
+                    entry.node.value.enr(),
                     entry.status,
                 )
             })
             .collect()
     }
 
+    /// Returns all the entries in the routing table along with the time each node was inserted or
+    /// last contacted.
+    ///
+    /// Unlike `table_entries` this doesn't apply pending nodes and only requires a read lock on
+    /// the routing table.
+    pub fn table_entries_detailed(&self) -> Vec<(NodeId, Enr, NodeStatus, Instant)> {
+        self.kbuckets
+            .read()
+            .buckets_iter()
+            .flat_map(|bucket| bucket.iter())
+            .map(|node| {
+                (
+                    *node.key.preimage(),
+                    node.value.enr(),
+                    node.status,
+                    node.last_seen,
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the ENRs held in the routing table bucket at the given log2 distance from the local
+    /// node. The distance must be in the range 1..=256, an empty list is returned otherwise.
+    pub fn table_entries_by_distance(&self, distance: u64) -> Vec<Enr> {
+        if !(1..=256).contains(&distance) {
+            return Vec::new();
+        }
+        self.kbuckets
+            .write()
+            .nodes_by_distances(&[distance], MAX_NODES_PER_BUCKET)
+            .into_iter()
+            .map(|entry| entry.node.value.enr())
+            .collect()
+    }
+
     /// Requests the ENR of a node corresponding to multiaddr or multi-addr string.
     ///
     /// Only `ed25519` and `secp256k1` key types are currently supported.
@@ -469,24 +779,23 @@ impl Discv5 {
         }
     }
 
-    /// Request a TALK message from a node, identified via the ENR.
-    pub fn talk_req(
+    /// Requests the ENR of a node known only by its public key and socket address, such as a
+    /// node learned out-of-band, establishing a session with it.
+    ///
+    /// The request fails if the node returns the ENR of another node.
+    pub fn request_enr_from_address(
         &self,
-        enr: Enr,
-        protocol: Vec<u8>,
-        request: Vec<u8>,
-    ) -> impl Future<Output = Result<Vec<u8>, RequestError>> + 'static {
-        // convert the ENR to a node_contact.
-
+        public_key: CombinedPublicKey,
+        socket_addr: SocketAddr,
+    ) -> impl Future<Output = Result<Enr, RequestError>> + 'static {
         let (callback_send, callback_recv) = oneshot::channel();
         let channel = self.clone_channel();
-        let ip_mode = self.config.ip_mode;
 
         async move {
-            let node_contact = NodeContact::try_from_enr(enr, ip_mode)?;
+            let node_contact = NodeContact::from_public_key(public_key, socket_addr);
             let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
 
-            let event = ServiceRequest::Talk(node_contact, protocol, request, callback_send);
+            let event = ServiceRequest::FindEnr(node_contact, callback_send);
 
             // send the request
             channel
@@ -500,131 +809,1015 @@ impl Discv5 {
         }
     }
 
-    /// Runs an iterative `FIND_NODE` request.
-    ///
-    /// This will return peers containing contactable nodes of the DHT closest to the
-    /// requested `NodeId`.
+    /// Requests the current ENR of a node, identified via a known ENR, with a FINDNODE request of
+    /// distance 0.
     ///
-    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
-    /// underlying sending channel is cloned.
-    pub fn find_node(
+    /// If the node returns an ENR older than the given one, the given ENR is returned.
+    pub fn request_node_enr(
         &self,
-        target_node: NodeId,
-    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        enr: Enr,
+    ) -> impl Future<Output = Result<Enr, RequestError>> + 'static {
+        let (callback_send, callback_recv) = oneshot::channel();
         let channel = self.clone_channel();
+        let ip_mode = self.config.ip_mode;
 
         async move {
-            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
-            let (callback_send, callback_recv) = oneshot::channel();
+            let node_contact = NodeContact::try_from_enr(enr, ip_mode)?;
+            let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
 
-            let query_kind = QueryKind::FindNode { target_node };
+            let event = ServiceRequest::FindEnr(node_contact, callback_send);
 
-            let event = ServiceRequest::StartQuery(query_kind, callback_send);
+            // send the request
             channel
                 .send(event)
                 .await
-                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
-
+                .map_err(|_| RequestError::ChannelFailed("Service channel closed".into()))?;
+            // await the response
             callback_recv
                 .await
-                .map_err(|e| QueryError::ChannelFailed(e.to_string()))
+                .map_err(|e| RequestError::ChannelFailed(e.to_string()))?
         }
     }
 
-    /// Runs an iterative `FIND_VALUE` request.
-    ///
-    /// This will return peers containing contactable nodes of the DHT closest to the
-    /// requested `NodeId`.
+    /// Requests the current ENR of a node of the routing table, identified via its node id. See
+    /// [`Discv5::request_node_enr`].
+    pub fn request_node_enr_by_id(
+        &self,
+        node_id: &NodeId,
+    ) -> impl Future<Output = Result<Enr, RequestError>> + 'static {
+        let request = self.find_enr(node_id).map(|enr| self.request_node_enr(enr));
+
+        async move {
+            request
+                .ok_or(RequestError::InvalidEnr("The node is not in the routing table"))?
+                .await
+        }
+    }
+
+    /// Sends a PING request to a node, identified via the ENR.
     ///
-    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
-    /// underlying sending channel is cloned.
-    pub fn find_value(
+    /// The response contains the sequence number of the node's ENR and the address the node
+    /// observed the request from, which is useful to diagnose the local node's reachability
+    /// behind a NAT.
+    pub fn ping(
         &self,
-        target_value: NodeId,
-    ) -> impl Future<Output = Result<Vec<u8>, FindValueError>> + 'static {
+        enr: Enr,
+    ) -> impl Future<Output = Result<PingResponse, RequestError>> + 'static {
+        let (callback_send, callback_recv) = oneshot::channel();
         let channel = self.clone_channel();
+        let ip_mode = self.config.ip_mode;
 
         async move {
-            let channel = channel.map_err(|_| FindValueError::RequestError(RequestError::ServiceNotStarted))?;
-            let (callback_send, callback_recv) = mpsc::unbounded_channel();
+            let node_contact = NodeContact::try_from_enr(enr, ip_mode)?;
+            let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
 
-            let event = ServiceRequest::FindValue(target_value, callback_send);
+            let event = ServiceRequest::Ping(node_contact, callback_send);
+
+            // send the request
             channel
                 .send(event)
                 .await
-                .map_err(|_| FindValueError::RequestError(RequestError::ChannelFailed("Service channel closed".into())))?;
-
-            UnboundedReceiverStream::new(callback_recv).next()
+                .map_err(|_| RequestError::ChannelFailed("Service channel closed".into()))?;
+            // await the response
+            callback_recv
                 .await
-                .or(Some(Err(FindValueError::RequestError(RequestError::ChannelFailed("Unknown reason".to_string())))))
-                .unwrap()
+                .map_err(|e| RequestError::ChannelFailed(e.to_string()))?
         }
     }
 
-    /// Starts a `FIND_NODE` request.
-    ///
-    /// This will return less than or equal to `num_nodes` ENRs which satisfy the
-    /// `predicate`.
-    ///
-    /// The predicate is a boxed function that takes an ENR reference and returns a boolean
-    /// indicating if the record is applicable to the query or not.
-    ///
-    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
-    /// underlying sending channel is cloned.
-    ///
-    /// ### Example
-    /// ```ignore
-    ///  let predicate = Box::new(|enr: &Enr| enr.ip().is_some());
-    ///  let target = NodeId::random();
-    ///  let result = discv5.find_node_predicate(target, predicate, 5).await;
-    ///  ```
-    pub fn find_node_predicate(
+    /// Request a TALK message from a node, identified via the ENR.
+    pub fn talk_req(
         &self,
-        target_node: NodeId,
-        predicate: Box<dyn Fn(&Enr) -> bool + Send>,
-        target_peer_no: usize,
-    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        enr: Enr,
+        protocol: Vec<u8>,
+        request: Vec<u8>,
+    ) -> impl Future<Output = Result<Vec<u8>, RequestError>> + 'static {
+        // convert the ENR to a node_contact.
+
+        let (callback_send, callback_recv) = oneshot::channel();
         let channel = self.clone_channel();
+        let ip_mode = self.config.ip_mode;
 
         async move {
-            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
-            let (callback_send, callback_recv) = oneshot::channel();
+            let node_contact = NodeContact::try_from_enr(enr, ip_mode)?;
+            let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
 
-            let query_kind = QueryKind::Predicate {
-                target_node,
-                predicate,
-                target_peer_no,
-            };
+            let event = ServiceRequest::Talk(node_contact, protocol, request, callback_send);
 
-            let event = ServiceRequest::StartQuery(query_kind, callback_send);
+            // send the request
             channel
                 .send(event)
                 .await
-                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
-
+                .map_err(|_| RequestError::ChannelFailed("Service channel closed".into()))?;
+            // await the response
             callback_recv
                 .await
-                .map_err(|e| QueryError::ChannelFailed(e.to_string()))
+                .map_err(|e| RequestError::ChannelFailed(e.to_string()))?
         }
     }
 
-    /// Creates an event stream channel which can be polled to receive Discv5 events.
-    pub fn event_stream(
+    /// Registers a handler for inbound TALK requests with the given protocol id, replacing and
+    /// returning the handler previously registered for it.
+    ///
+    /// Requests of registered protocols are passed to their handler instead of the event stream.
+    /// Requests the handler can't accept, or doesn't respond to, are answered with an empty TALK
+    /// response, as are requests of unregistered protocols when no event stream is listening.
+    pub fn register_talk_protocol(
         &self,
-    ) -> impl Future<Output = Result<mpsc::Receiver<Discv5Event>, Discv5Error>> + 'static {
+        protocol: Vec<u8>,
+        handler: mpsc::Sender<TalkRequest>,
+    ) -> Option<mpsc::Sender<TalkRequest>> {
+        self.talk_protocols.write().insert(protocol, handler)
+    }
+
+    /// Asks `relay` to prompt `target` to contact the local node, establishing a session with a
+    /// node we can't reach directly, for example one behind a NAT.
+    ///
+    /// Returns whether the relay forwarded the request. The relay only forwards requests to
+    /// nodes it is connected to. Once the target contacts the local node, the session is
+    /// reported via [`Discv5Event::SessionEstablished`].
+    pub fn establish_session_via_relay(
+        &self,
+        target: Enr,
+        relay: Enr,
+    ) -> impl Future<Output = Result<bool, RequestError>> + 'static {
+        let (callback_send, callback_recv) = oneshot::channel();
         let channel = self.clone_channel();
+        let ip_mode = self.config.ip_mode;
 
         async move {
-            let channel = channel?;
+            let relay_contact = NodeContact::try_from_enr(relay, ip_mode)?;
+            let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
 
-            let (callback_send, callback_recv) = oneshot::channel();
+            let event = ServiceRequest::RelayInit(relay_contact, target.node_id(), callback_send);
 
-            let event = ServiceRequest::RequestEventStream(callback_send);
+            // send the request
             channel
                 .send(event)
                 .await
-                .map_err(|_| Discv5Error::ServiceChannelClosed)?;
-
+                .map_err(|_| RequestError::ChannelFailed("Service channel closed".into()))?;
+            // await the response
+            callback_recv
+                .await
+                .map_err(|e| RequestError::ChannelFailed(e.to_string()))?
+        }
+    }
+
+    /// Runs an iterative `FIND_NODE` request.
+    ///
+    /// This will return peers containing contactable nodes of the DHT closest to the
+    /// requested `NodeId`.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_node(
+        &self,
+        target_node: NodeId,
+    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        let query = self.find_node_with_distances(target_node);
+
+        async move { Ok(without_distances(query.await?)) }
+    }
+
+    /// Runs an iterative `FIND_NODE` request for the nodes closest to an arbitrary 256-bit key,
+    /// such as a content hash, rather than to a node id.
+    ///
+    /// Keys share the XOR metric of node ids, this is the lookup `FIND_VALUE` runs to locate the
+    /// holders of the value under a key.
+    pub fn find_node_by_key(
+        &self,
+        key: [u8; 32],
+    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        self.find_node(NodeId::new(&key))
+    }
+
+    /// Runs an iterative `FIND_NODE` request like `find_node`, returning the signed RLP encodings
+    /// of the ENRs found exactly as they were received from the peers.
+    ///
+    /// ENRs decode from encodings other than the one they re-encode to, the bytes received are
+    /// therefore kept rather than re-encoded. Nodes found only in the local routing table, which
+    /// no peer returned during the request, have no received encoding and are omitted.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_node_raw(
+        &self,
+        target_node: NodeId,
+    ) -> impl Future<Output = Result<Vec<Vec<u8>>, QueryError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let event = ServiceRequest::FindNodeRaw(target_node, callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
+
+            callback_recv
+                .await
+                .map_err(|e| QueryError::ChannelFailed(e.to_string()))
+        }
+    }
+
+    /// Runs an iterative `FIND_NODE` request for a random target in the bucket at the log2
+    /// `distance` from the local node, in the range 1-256, to fill the bucket with the nodes
+    /// found.
+    pub fn refresh_bucket(
+        &self,
+        distance: u64,
+    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        let query = (1..=256).contains(&distance).then(|| {
+            let target = random_node_id_at_distance(&self.local_enr.read().node_id(), distance);
+            self.find_node(target)
+        });
+
+        async move {
+            match query {
+                Some(query) => query.await,
+                None => Err(QueryError::InvalidDistances(format!(
+                    "Invalid bucket distance: {}, expected 1-256",
+                    distance
+                ))),
+            }
+        }
+    }
+
+    /// Runs an iterative `FIND_NODE` request like `find_node`, then pings each node found. Nodes
+    /// reporting a newer ENR sequence number than the one found are asked for their updated ENR,
+    /// which is returned instead. Nodes that fail to respond are returned as found. This trades
+    /// latency for freshness of the returned ENRs.
+    pub fn find_node_fresh(
+        &self,
+        target_node: NodeId,
+    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        let query = self.find_node(target_node);
+        let channel = self.clone_channel();
+        let ip_mode = self.config.ip_mode;
+
+        async move {
+            let found = query.await?;
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            let refreshed = found
+                .into_iter()
+                .map(|enr| refresh_enr(channel.clone(), ip_mode, enr));
+            Ok(future::join_all(refreshed).await)
+        }
+    }
+
+    /// Runs an iterative `FIND_NODE` request, yielding each node as it is discovered rather
+    /// than once the request has finished.
+    ///
+    /// Every node returned by a queried peer is yielded once, as soon as its response has been
+    /// processed, whether or not it ends up among the closest nodes found. The stream ends once
+    /// the request has finished. If the request cannot be started, only the error is yielded.
+    pub fn find_node_stream(
+        &self,
+        target_node: NodeId,
+    ) -> impl Stream<Item = Result<Enr, QueryError>> + 'static {
+        let channel = self.clone_channel();
+        let (callback_send, callback_recv) = mpsc::unbounded_channel();
+
+        let request = async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+
+            let event = ServiceRequest::FindNodeStream(target_node, callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))
+        };
+
+        // If the request could not be sent, the callback is dropped and only the error is
+        // yielded.
+        futures::stream::once(request)
+            .filter_map(|result| future::ready(result.err().map(Err)))
+            .chain(UnboundedReceiverStream::new(callback_recv).map(Ok))
+    }
+
+    /// Runs an iterative `FIND_NODE` request, returning each node found along with its log2
+    /// distance to `target_node`. A node which is the target itself has a distance of 0.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_node_with_distances(
+        &self,
+        target_node: NodeId,
+    ) -> impl Future<Output = Result<Vec<(Enr, u64)>, QueryError>> + 'static {
+        let query = self.find_node_query(target_node, None, None);
+
+        async move { Ok(query.await?.0) }
+    }
+
+    /// Runs an iterative `FIND_NODE` request like [`Discv5::find_node`], also returning how the
+    /// request ended.
+    ///
+    /// If the request timed out or ran out of peers to contact, the peers returned may not be
+    /// the closest to `target_node`, and callers may want to retry.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_node_with_completion(
+        &self,
+        target_node: NodeId,
+    ) -> impl Future<Output = Result<(Vec<Enr>, QueryCompletion), QueryError>> + 'static {
+        let query = self.find_node_query(target_node, None, None);
+
+        async move {
+            let (found, completion) = query.await?;
+            Ok((without_distances(found), completion))
+        }
+    }
+
+    /// Runs an iterative `FIND_NODE` request which times out after `timeout` rather than the
+    /// configured query timeout. The peers found before the timeout are returned.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_node_with_timeout(
+        &self,
+        target_node: NodeId,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        let query = self.find_node_query(target_node, Some(timeout), None);
+
+        async move { Ok(without_distances(query.await?.0)) }
+    }
+
+    /// Looks up the closest nodes to each of several targets, returning the nodes found by
+    /// target.
+    ///
+    /// The targets are looked up one after the other, in the order of their ids so that nearby
+    /// targets follow each other. Peers common to the lookups of nearby targets are asked for the
+    /// same distances, their NODES responses are only requested once and reused by the
+    /// following lookups.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_nodes_multi(
+        &self,
+        mut targets: Vec<NodeId>,
+    ) -> impl Future<Output = Result<HashMap<NodeId, Vec<Enr>>, QueryError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            targets.sort_unstable_by_key(|target| target.raw());
+            targets.dedup();
+
+            let shared_responses = SharedResponses::default();
+            let mut found = HashMap::new();
+            for target_node in targets {
+                let (callback_send, callback_recv) = oneshot::channel();
+                let query_kind = QueryKind::FindNodeShared {
+                    target_node,
+                    shared_responses: shared_responses.clone(),
+                };
+                let event = ServiceRequest::StartQuery(query_kind, callback_send);
+                channel
+                    .send(event)
+                    .await
+                    .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
+                let (nodes, _) = callback_recv
+                    .await
+                    .map_err(|e| QueryError::ChannelFailed(e.to_string()))?;
+                found.insert(target_node, without_distances(nodes));
+            }
+            Ok(found)
+        }
+    }
+
+    /// Internal helper function to start a `FIND_NODE` query, optionally overriding the
+    /// configured query timeout and the number of closest peers returned.
+    fn find_node_query(
+        &self,
+        target_node: NodeId,
+        timeout: Option<Duration>,
+        num_results: Option<usize>,
+    ) -> impl Future<Output = Result<(Vec<(Enr, u64)>, QueryCompletion), QueryError>> + 'static
+    {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let query_kind = QueryKind::FindNode {
+                target_node,
+                timeout,
+                num_results,
+            };
+
+            let event = ServiceRequest::StartQuery(query_kind, callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
+
+            callback_recv
+                .await
+                .map_err(|e| QueryError::ChannelFailed(e.to_string()))
+        }
+    }
+
+    /// Runs an iterative `FIND_NODE` request which asks each peer for exactly the given
+    /// `distances`, rather than the distances surrounding `target_node`.
+    ///
+    /// The distances are sent verbatim, they must number no more than 127, each no larger than
+    /// 256. Note that peers running this implementation reject `FIND_NODE` requests for more than
+    /// 10 distances.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_node_at(
+        &self,
+        target_node: NodeId,
+        distances: Vec<u64>,
+    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            if distances.len() > 127 {
+                return Err(QueryError::InvalidDistances(format!(
+                    "Too many distances requested: {}, maximum 127",
+                    distances.len()
+                )));
+            }
+            if let Some(distance) = distances.iter().find(|distance| **distance > 256) {
+                return Err(QueryError::InvalidDistances(format!(
+                    "Invalid distance requested: {}, maximum 256",
+                    distance
+                )));
+            }
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let query_kind = QueryKind::FindNodeAt {
+                target_node,
+                distances,
+            };
+
+            let event = ServiceRequest::StartQuery(query_kind, callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
+
+            callback_recv
+                .await
+                .map(|(found, _)| without_distances(found))
+                .map_err(|e| QueryError::ChannelFailed(e.to_string()))
+        }
+    }
+
+    /// Runs an iterative `FIND_VALUE` request.
+    ///
+    /// Peers which do not hold the value respond with the closest nodes they know of to the key,
+    /// which are queried in turn. This resolves to the first value retrieved, or the first
+    /// error encountered.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_value(
+        &self,
+        target_value: NodeId,
+    ) -> impl Future<Output = Result<Vec<u8>, FindValueError>> + 'static {
+        first_value(self.find_value_query(target_value, None, None, 1))
+    }
+
+    /// Runs an iterative `FIND_VALUE` request like [`Discv5::find_value`], which contacts up to
+    /// `parallelism` peers simultaneously rather than the configured `query_parallelism`.
+    ///
+    /// A higher parallelism lets the lookup converge in fewer round trips at the cost of
+    /// sending more requests.
+    pub fn find_value_with_parallelism(
+        &self,
+        target_value: NodeId,
+        parallelism: usize,
+    ) -> impl Future<Output = Result<Vec<u8>, FindValueError>> + 'static {
+        first_value(self.find_value_query(target_value, None, Some(parallelism), 1))
+    }
+
+    /// Runs an iterative `FIND_VALUE` request which only resolves to a value once at least
+    /// `quorum` distinct peers returned identical data for `key`.
+    ///
+    /// Errors of individual peers are ignored. If the lookup completes without the quorum being
+    /// reached, `FindValueError::QuorumFailed` is returned. A `quorum` of 0 is treated as 1.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_value_quorum(
+        &self,
+        key: NodeId,
+        quorum: usize,
+    ) -> impl Future<Output = Result<Vec<u8>, FindValueError>> + 'static {
+        let values = self.find_value_query(key, None, None, quorum.max(1));
+
+        async move {
+            futures::pin_mut!(values);
+            // the terminal error of the lookup is yielded last
+            let mut last_error = None;
+            while let Some(result) = values.next().await {
+                match result {
+                    Ok(value) => return Ok(value),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            Err(last_error.unwrap_or_else(|| {
+                FindValueError::RequestError(RequestError::ChannelFailed(
+                    "Unknown reason".to_string(),
+                ))
+            }))
+        }
+    }
+
+    /// Runs an iterative `FIND_VALUE` request for a value stored with
+    /// [`Discv5::put_signed_value`], which resolves to the first value carrying a valid
+    /// signature of `originator`.
+    ///
+    /// Values that are not signed by `originator`, or whose signature does not match the key
+    /// and value, are discarded. If the lookup completes without a valid value being found,
+    /// `FindValueError::InvalidSignature` is returned if any discarded value was retrieved.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn find_signed_value(
+        &self,
+        key: NodeId,
+        originator: NodeId,
+    ) -> impl Future<Output = Result<Vec<u8>, FindValueError>> + 'static {
+        let values = self.find_value_query(key, None, None, 1);
+
+        async move {
+            futures::pin_mut!(values);
+            let mut last_error = None;
+            while let Some(result) = values.next().await {
+                match result {
+                    Ok(data) => match SignedValue::decode(&data) {
+                        Ok(signed) if signed.verify(&key, &originator) => return Ok(signed.value),
+                        Ok(signed) => {
+                            debug!(
+                                "Discarding value for {} with an invalid signature of {}",
+                                key,
+                                signed.signer()
+                            );
+                            last_error = Some(FindValueError::InvalidSignature);
+                        }
+                        Err(e) => {
+                            debug!("Discarding value for {} without a signature: {:?}", key, e);
+                            last_error = Some(FindValueError::InvalidSignature);
+                        }
+                    },
+                    // keep reporting tampered values over the lookup failing
+                    Err(e) if last_error != Some(FindValueError::InvalidSignature) => {
+                        last_error = Some(e)
+                    }
+                    Err(_) => {}
+                }
+            }
+            Err(last_error.unwrap_or_else(|| {
+                FindValueError::RequestError(RequestError::ChannelFailed(
+                    "Unknown reason".to_string(),
+                ))
+            }))
+        }
+    }
+
+    /// Runs an iterative `FIND_VALUE` request, returning the first value retrieved for `key`.
+    ///
+    /// Failed requests to individual peers are yielded as `FindValueError::RequestError` as
+    /// they occur. Once a peer returns the value, it is yielded, the requests still in flight
+    /// are cancelled and the stream ends. If the lookup completes without any peer returning
+    /// the value, a final `FindValueError::NotFound` is yielded. If it times out instead, the
+    /// final item is a `FindValueError::RequestErrorWithEnrs` containing the closest peers
+    /// found.
+    pub fn get_value(
+        &self,
+        key: NodeId,
+    ) -> impl Stream<Item = Result<Vec<u8>, FindValueError>> + 'static {
+        self.find_value_query(key, None, None, 1)
+    }
+
+    /// Runs an iterative `FIND_VALUE` request like [`Discv5::get_value`], which times out after
+    /// `timeout` rather than the configured query timeout.
+    pub fn get_value_with_timeout(
+        &self,
+        key: NodeId,
+        timeout: Duration,
+    ) -> impl Stream<Item = Result<Vec<u8>, FindValueError>> + 'static {
+        self.find_value_query(key, Some(timeout), None, 1)
+    }
+
+    /// Internal helper function to start a `FIND_VALUE` query, optionally overriding the
+    /// configured query timeout and parallelism. The first value returned by `quorum` peers is
+    /// yielded and ends the stream.
+    fn find_value_query(
+        &self,
+        key: NodeId,
+        timeout: Option<Duration>,
+        parallelism: Option<usize>,
+        quorum: usize,
+    ) -> impl Stream<Item = Result<Vec<u8>, FindValueError>> + 'static {
+        let channel = self.clone_channel();
+        let (callback_send, callback_recv) = mpsc::unbounded_channel();
+
+        let request = async move {
+            let channel = channel
+                .map_err(|_| FindValueError::RequestError(RequestError::ServiceNotStarted))?;
+
+            let event = ServiceRequest::FindValue {
+                key,
+                timeout,
+                parallelism,
+                quorum,
+                callback: callback_send,
+            };
+            channel.send(event).await.map_err(|_| {
+                FindValueError::RequestError(RequestError::ChannelFailed(
+                    "Service channel closed".into(),
+                ))
+            })
+        };
+
+        // If the request could not be sent, the callback is dropped and only the error is
+        // yielded.
+        futures::stream::once(request)
+            .filter_map(|result| future::ready(result.err().map(Err)))
+            .chain(UnboundedReceiverStream::new(callback_recv))
+    }
+
+    /// Stores a value in the DHT.
+    ///
+    /// This runs an iterative `FIND_NODE` request for `key` and sends a `STORE` request to the
+    /// `value_replication_factor` closest contactable peers found (see [`Discv5Config`]).
+    /// Peers that know of nodes closer to `key` return them, and the value is stored on those
+    /// too. Returns the number of peers that accepted the value.
+    ///
+    /// Values larger than `max_value_size`, or too large to fit in a single `STORE` packet, are
+    /// rejected with `FindValueError::ValueTooLarge`.
+    ///
+    /// The value is stored again every `value_republish_interval` until `value_ttl` has elapsed.
+    /// Calling this again for the same key replaces the value and restarts its ttl.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn put_value(
+        &self,
+        key: NodeId,
+        value: Vec<u8>,
+    ) -> impl Future<Output = Result<usize, FindValueError>> + 'static {
+        let channel = self.clone_channel();
+        let replication_factor = self.config.value_replication_factor;
+        // query at least a bucket's worth of peers to find the closest ones reliably
+        let closest_peers = self.find_node_query(
+            key,
+            None,
+            Some(replication_factor.max(MAX_NODES_PER_BUCKET)),
+        );
+        let ip_mode = self.config.ip_mode;
+        let max_value_size = self.config.max_value_size;
+        let local_id = self.local_enr().node_id();
+
+        async move {
+            check_store_value(&key, &value, max_value_size)?;
+
+            let channel = channel
+                .map_err(|_| FindValueError::RequestError(RequestError::ServiceNotStarted))?;
+
+            // register the value to be republished
+            let event = ServiceRequest::Publish(key, value.clone());
+            channel.send(event).await.map_err(|_| {
+                FindValueError::RequestError(RequestError::ChannelFailed(
+                    "Service channel closed".into(),
+                ))
+            })?;
+
+            let closest_peers = closest_peers.await.map_err(|e| {
+                FindValueError::RequestError(RequestError::ChannelFailed(e.to_string()))
+            })?;
+
+            let contactable = |enr| match NodeContact::try_from_enr(enr, ip_mode) {
+                Ok(contact) => Some(contact),
+                Err(e) => {
+                    debug!("Not storing value on non-contactable peer {}", e.enr);
+                    None
+                }
+            };
+            let mut node_contacts: Vec<NodeContact> = without_distances(closest_peers.0)
+                .into_iter()
+                .filter_map(contactable)
+                .take(replication_factor)
+                .collect();
+            let mut stored_on: HashSet<NodeId> =
+                node_contacts.iter().map(|contact| contact.node_id()).collect();
+
+            // the value is stored on the closest peers, then once on the closer nodes they return
+            let mut accepted = 0;
+            for _ in 0..2 {
+                let mut callbacks = Vec::new();
+                for node_contact in node_contacts {
+                    let (callback_send, callback_recv) = oneshot::channel();
+
+                    let event =
+                        ServiceRequest::Store(node_contact, key, value.clone(), callback_send);
+                    channel.send(event).await.map_err(|_| {
+                        FindValueError::RequestError(RequestError::ChannelFailed(
+                            "Service channel closed".into(),
+                        ))
+                    })?;
+                    callbacks.push(callback_recv);
+                }
+
+                // await the responses
+                let mut closer = Vec::new();
+                for response in future::join_all(callbacks).await {
+                    if let Ok(Ok((stored, nodes))) = response {
+                        if stored {
+                            accepted += 1;
+                        }
+                        closer.extend(nodes);
+                    }
+                }
+                node_contacts = closer
+                    .into_iter()
+                    .filter(|enr| enr.node_id() != local_id && stored_on.insert(enr.node_id()))
+                    .filter_map(contactable)
+                    .collect();
+            }
+            Ok(accepted)
+        }
+    }
+
+    /// Stores a value signed by the local node in the DHT, like [`Discv5::put_value`].
+    ///
+    /// The value is stored along with a signature of `key` and `value` and the local public
+    /// key, allowing nodes retrieving it with [`Discv5::find_signed_value`] to detect tampering.
+    /// The signature and public key count towards `max_value_size`.
+    pub fn put_signed_value(
+        &self,
+        key: NodeId,
+        value: Vec<u8>,
+    ) -> impl Future<Output = Result<usize, FindValueError>> + 'static {
+        let put = SignedValue::new(&key, value, &self.enr_key.read())
+            .map(|signed| self.put_value(key, signed.encode()));
+
+        async move {
+            match put {
+                Ok(put) => put.await,
+                Err(e) => {
+                    warn!("Failed to sign value for {}: {}", key, e);
+                    Err(FindValueError::InvalidSignature)
+                }
+            }
+        }
+    }
+
+    /// Stores several values in the DHT, like [`Discv5::put_value`], batching the values destined
+    /// for the same peer into `STOREBATCH` requests.
+    ///
+    /// The values for a peer are split into as many requests as needed for each request to fit
+    /// in a single packet. If any value is too large to be stored, as for [`Discv5::put_value`],
+    /// nothing is stored and `FindValueError::ValueTooLarge` is returned. Returns the number of
+    /// peers that accepted each value, in the order of `entries`.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn put_values(
+        &self,
+        entries: Vec<(NodeId, Vec<u8>)>,
+    ) -> impl Future<Output = Result<Vec<usize>, FindValueError>> + 'static {
+        let channel = self.clone_channel();
+        let replication_factor = self.config.value_replication_factor;
+        // query at least a bucket's worth of peers to find the closest ones reliably
+        let closest_peers = future::join_all(entries.iter().map(|(key, _)| {
+            self.find_node_query(
+                *key,
+                None,
+                Some(replication_factor.max(MAX_NODES_PER_BUCKET)),
+            )
+        }));
+        let ip_mode = self.config.ip_mode;
+        let max_value_size = self.config.max_value_size;
+
+        async move {
+            for (key, value) in entries.iter() {
+                check_store_value(key, value, max_value_size)?;
+            }
+
+            let channel = channel
+                .map_err(|_| FindValueError::RequestError(RequestError::ServiceNotStarted))?;
+
+            // register the values to be republished
+            for (key, value) in entries.iter() {
+                let event = ServiceRequest::Publish(*key, value.clone());
+                channel.send(event).await.map_err(|_| {
+                    FindValueError::RequestError(RequestError::ChannelFailed(
+                        "Service channel closed".into(),
+                    ))
+                })?;
+            }
+
+            // group the entries by the peers to store them on
+            let mut peers: HashMap<NodeId, (NodeContact, Vec<usize>)> = HashMap::new();
+            for (index, found) in closest_peers.await.into_iter().enumerate() {
+                let found = found.map_err(|e| {
+                    FindValueError::RequestError(RequestError::ChannelFailed(e.to_string()))
+                })?;
+                let node_contacts = without_distances(found.0)
+                    .into_iter()
+                    .filter_map(|enr| match NodeContact::try_from_enr(enr, ip_mode) {
+                        Ok(contact) => Some(contact),
+                        Err(e) => {
+                            debug!("Not storing value on non-contactable peer {}", e.enr);
+                            None
+                        }
+                    })
+                    .take(replication_factor);
+                for node_contact in node_contacts {
+                    peers
+                        .entry(node_contact.node_id())
+                        .or_insert_with(|| (node_contact, Vec::new()))
+                        .1
+                        .push(index);
+                }
+            }
+
+            let mut callbacks = Vec::new();
+            for (_, (node_contact, indices)) in peers {
+                let peer_entries = indices.iter().map(|i| entries[*i].clone()).collect();
+                let mut indices = indices.into_iter();
+                for batch in store_batches(peer_entries) {
+                    let batch_indices: Vec<usize> = indices.by_ref().take(batch.len()).collect();
+                    let (callback_send, callback_recv) = oneshot::channel();
+
+                    let event =
+                        ServiceRequest::StoreBatch(node_contact.clone(), batch, callback_send);
+                    channel.send(event).await.map_err(|_| {
+                        FindValueError::RequestError(RequestError::ChannelFailed(
+                            "Service channel closed".into(),
+                        ))
+                    })?;
+                    callbacks.push(async move { (batch_indices, callback_recv.await) });
+                }
+            }
+
+            // await the responses
+            let mut accepted = vec![0; entries.len()];
+            for (indices, response) in future::join_all(callbacks).await {
+                if let Ok(Ok(response)) = response {
+                    for (index, _) in indices.into_iter().zip(response).filter(|(_, a)| *a) {
+                        accepted[index] += 1;
+                    }
+                }
+            }
+            Ok(accepted)
+        }
+    }
+
+    /// Returns the keys of the values currently held in the local value store on behalf of
+    /// other nodes.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn stored_keys(
+        &self,
+    ) -> impl Future<Output = Result<Vec<NodeId>, RequestError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let event = ServiceRequest::StoredKeys(callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| RequestError::ChannelFailed("Service channel closed".into()))?;
+            callback_recv
+                .await
+                .map_err(|e| RequestError::ChannelFailed(e.to_string()))
+        }
+    }
+
+    /// Returns the value currently held under `key` in the local value store, if any.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn stored_value(
+        &self,
+        key: NodeId,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, RequestError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let event = ServiceRequest::StoredValue(key, callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| RequestError::ChannelFailed("Service channel closed".into()))?;
+            callback_recv
+                .await
+                .map_err(|e| RequestError::ChannelFailed(e.to_string()))
+        }
+    }
+
+    /// Returns a snapshot of the in-flight queries: their target, type, elapsed time and the
+    /// number of peers contacted and responses received so far. This helps diagnosing lookups
+    /// that don't converge.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    pub fn active_queries(
+        &self,
+    ) -> impl Future<Output = Result<Vec<QueryStatus>, RequestError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let event = ServiceRequest::ActiveQueries(callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| RequestError::ChannelFailed("Service channel closed".into()))?;
+            callback_recv
+                .await
+                .map_err(|e| RequestError::ChannelFailed(e.to_string()))
+        }
+    }
+
+    /// Starts a `FIND_NODE` request.
+    ///
+    /// This will return less than or equal to `num_nodes` ENRs which satisfy the
+    /// `predicate`.
+    ///
+    /// The predicate is a boxed function that takes an ENR reference and returns a boolean
+    /// indicating if the record is applicable to the query or not. Nodes not satisfying the
+    /// predicate are still queried to traverse the DHT, but are not returned.
+    ///
+    /// Note: The async syntax is forgone here in order to create `'static` futures, where the
+    /// underlying sending channel is cloned.
+    ///
+    /// ### Example
+    /// ```ignore
+    ///  let predicate = Box::new(|enr: &Enr| enr.ip().is_some());
+    ///  let target = NodeId::random();
+    ///  let result = discv5.find_node_predicate(target, predicate, 5).await;
+    ///  ```
+    pub fn find_node_predicate(
+        &self,
+        target_node: NodeId,
+        predicate: Box<dyn Fn(&Enr) -> bool + Send>,
+        target_peer_no: usize,
+    ) -> impl Future<Output = Result<Vec<Enr>, QueryError>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let query_kind = QueryKind::Predicate {
+                target_node,
+                predicate,
+                target_peer_no,
+            };
+
+            let event = ServiceRequest::StartQuery(query_kind, callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
+
+            callback_recv
+                .await
+                .map(|(found, _)| without_distances(found))
+                .map_err(|e| QueryError::ChannelFailed(e.to_string()))
+        }
+    }
+
+    /// Creates an event stream channel which can be polled to receive Discv5 events.
+    pub fn event_stream(
+        &self,
+    ) -> impl Future<Output = Result<mpsc::Receiver<Discv5Event>, Discv5Error>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel?;
+
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let event = ServiceRequest::RequestEventStream(callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| Discv5Error::ServiceChannelClosed)?;
+
             callback_recv
                 .await
                 .map_err(|_| Discv5Error::ServiceChannelClosed)
@@ -641,6 +1834,87 @@ impl Discv5 {
     }
 }
 
+/// Generates a random node id at the log2 `distance` from `local_id`, in the range 1-256.
+pub(crate) fn random_node_id_at_distance(local_id: &NodeId, distance: u64) -> NodeId {
+    // the distance fixes the most significant bit differing from the local id, the lower bits
+    // are random
+    let leading_zeros = (256 - distance) as usize;
+    let (byte, bit) = (leading_zeros / 8, leading_zeros % 8);
+    let mut raw: [u8; 32] = rand::random();
+    raw[..byte].iter_mut().for_each(|b| *b = 0);
+    raw[byte] = (raw[byte] & (0x7f >> bit)) | (0x80 >> bit);
+    for (b, local) in raw.iter_mut().zip(local_id.raw().iter()) {
+        *b ^= local;
+    }
+    NodeId::new(&raw)
+}
+
+/// Checks that `value` is at most `max_value_size` and that its entry fits in a single `STORE` or
+/// `STOREBATCH` packet.
+fn check_store_value(
+    key: &NodeId,
+    value: &[u8],
+    max_value_size: usize,
+) -> Result<(), FindValueError> {
+    let overhead = store_entry_size(key, value) - value.len();
+    let max = max_value_size.min(MAX_STORE_BATCH_SIZE.saturating_sub(overhead));
+    if value.len() > max {
+        return Err(FindValueError::ValueTooLarge {
+            size: value.len(),
+            max,
+        });
+    }
+    Ok(())
+}
+
+/// Strips the distances from the result of a `FIND_NODE` query.
+fn without_distances(found: Vec<(Enr, u64)>) -> Vec<Enr> {
+    found.into_iter().map(|(enr, _)| enr).collect()
+}
+
+/// Pings the node of `enr`, and requests its ENR if it reports a newer sequence number. Resolves
+/// to the freshest ENR obtained for the node.
+async fn refresh_enr(channel: mpsc::Sender<ServiceRequest>, ip_mode: IpMode, enr: Enr) -> Enr {
+    let node_contact = match NodeContact::try_from_enr(enr.clone(), ip_mode) {
+        Ok(node_contact) => node_contact,
+        Err(_) => return enr,
+    };
+
+    let (callback_send, callback_recv) = oneshot::channel();
+    let event = ServiceRequest::Ping(node_contact.clone(), callback_send);
+    if channel.send(event).await.is_err() {
+        return enr;
+    }
+    match callback_recv.await {
+        Ok(Ok(pong)) if pong.enr_seq > enr.seq() => {}
+        _ => return enr,
+    }
+
+    let (callback_send, callback_recv) = oneshot::channel();
+    let event = ServiceRequest::FindEnr(node_contact, callback_send);
+    if channel.send(event).await.is_err() {
+        return enr;
+    }
+    match callback_recv.await {
+        Ok(Ok(updated)) if updated.node_id() == enr.node_id() && updated.seq() > enr.seq() => {
+            updated
+        }
+        _ => enr,
+    }
+}
+
+/// Resolves to the first item yielded by a `FIND_VALUE` query.
+async fn first_value(
+    values: impl Stream<Item = Result<Vec<u8>, FindValueError>>,
+) -> Result<Vec<u8>, FindValueError> {
+    futures::pin_mut!(values);
+    values.next().await.unwrap_or_else(|| {
+        Err(FindValueError::RequestError(RequestError::ChannelFailed(
+            "Unknown reason".to_string(),
+        )))
+    })
+}
+
 impl Drop for Discv5 {
     fn drop(&mut self) {
         self.shutdown();
@@ -2,8 +2,13 @@
 
 use crate::{kbucket, Discv5, *};
 use enr::{k256, CombinedKey, Enr, EnrBuilder, EnrKey, NodeId};
+use futures::StreamExt;
 use rand_core::{RngCore, SeedableRng};
-use std::{collections::HashMap, net::Ipv4Addr};
+use std::{
+    collections::{HashMap, HashSet},
+    net::Ipv4Addr,
+    time::Duration,
+};
 
 fn init() {
     let _ = tracing_subscriber::fmt()
@@ -421,6 +426,38 @@ async fn test_findnode_query() {
     assert_eq!(found_nodes.len(), expected_node_ids.len());
 }
 
+/// Stream a query over a linear topology. Every node is yielded once and the stream ends with
+/// the query.
+#[tokio::test]
+async fn test_findnode_stream() {
+    init();
+    let total_nodes = 8;
+    // Same topology as `test_findnode_query`.
+    let mut keypairs = generate_deterministic_keypair(total_nodes + 1, 5);
+    let target_node_id = NodeId::from(keypairs.remove(0).public());
+    let mut nodes = build_nodes_from_keypairs(keypairs, 13300).await;
+    let node_enrs: Vec<Enr<CombinedKey>> = nodes.iter().map(|n| n.local_enr()).collect();
+
+    for (node, previous_node_enr) in nodes.iter_mut().skip(1).zip(node_enrs.clone()) {
+        node.add_enr(previous_node_enr).unwrap();
+    }
+
+    let found_nodes: Vec<NodeId> = nodes
+        .last()
+        .unwrap()
+        .find_node_stream(target_node_id)
+        .map(|enr| enr.unwrap().node_id())
+        .collect()
+        .await;
+
+    let unique: std::collections::HashSet<NodeId> = found_nodes.iter().copied().collect();
+    assert_eq!(unique.len(), found_nodes.len());
+    // the known peer seeds the query and need not be discovered
+    for enr in node_enrs.iter().take(total_nodes - 2) {
+        assert!(unique.contains(&enr.node_id()));
+    }
+}
+
 /// Run a query where the target is one of the nodes. We expect to result to return the target.
 #[tokio::test]
 async fn test_findnode_query_with_target() {
@@ -510,6 +547,7 @@ async fn test_predicate_search() {
     }
 
     // Predicate function for filtering enrs
+    let expected_attnet_value = required_attnet_value.clone();
     let predicate = move |enr: &Enr<CombinedKey>| {
         if let Some(v) = enr.get("attnets") {
             v == required_attnet_value.as_slice()
@@ -535,6 +573,10 @@ async fn test_predicate_search() {
     );
     println!("Nodes expected to pass predicate search {}", num_nodes);
     assert!(found_nodes.len() == num_nodes);
+    // the non-matching bootstrap node is traversed but not returned
+    for enr in found_nodes {
+        assert_eq!(enr.get("attnets"), Some(expected_attnet_value.as_slice()));
+    }
 }
 
 // The kbuckets table can have maximum 10 nodes in the same /24 subnet across all buckets
@@ -574,6 +616,45 @@ async fn test_table_limits() {
     assert_eq!(discv5.kbuckets.read().iter_ref().count(), table_limit);
 }
 
+/// Nodes added with an explicit status are inserted with that status.
+#[tokio::test]
+async fn test_add_enr_with_status() {
+    let mut keypairs = generate_deterministic_keypair(3, 9487);
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let enr_key: CombinedKey = keypairs.remove(0);
+    let enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(13200)
+        .build(&enr_key)
+        .unwrap();
+    let discv5: Discv5 = Discv5::new(enr, enr_key, Discv5Config::default()).unwrap();
+
+    let statuses = [
+        (ConnectionState::Connected, ConnectionDirection::Outgoing),
+        (ConnectionState::Disconnected, ConnectionDirection::Incoming),
+    ];
+    for (i, (state, direction)) in statuses.iter().enumerate() {
+        let enr_key: CombinedKey = keypairs.remove(0);
+        let enr = EnrBuilder::new("v4")
+            .ip4(ip)
+            .udp4(13201 + i as u16)
+            .build(&enr_key)
+            .unwrap();
+        discv5
+            .add_enr_with_status(enr.clone(), *state, *direction)
+            .unwrap();
+
+        let key = kbucket::Key::from(enr.node_id());
+        match discv5.kbuckets.write().entry(&key) {
+            kbucket::Entry::Present(_, status) => {
+                assert_eq!(status.state, *state);
+                assert_eq!(status.direction, *direction);
+            }
+            _ => panic!("Node not inserted"),
+        }
+    }
+}
+
 // Each bucket can have maximum 2 nodes in the same /24 subnet
 #[tokio::test]
 async fn test_bucket_limits() {
@@ -624,3 +705,1866 @@ async fn test_bucket_limits() {
     // Number of entries should be equal to `bucket_limit`.
     assert_eq!(discv5.kbuckets.read().iter_ref().count(), bucket_limit);
 }
+
+/// The entries of a single bucket are read by their log2 distance.
+#[tokio::test]
+async fn test_table_entries_by_distance() {
+    let enr_key = CombinedKey::generate_secp256k1();
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(13930)
+        .build(&enr_key)
+        .unwrap();
+    let local_key: kbucket::Key<NodeId> = enr.node_id().into();
+    let discv5 = Discv5::new(enr, enr_key, Discv5Config::default()).unwrap();
+
+    // random nodes fall in the furthest buckets
+    let mut expected: HashMap<u64, Vec<NodeId>> = HashMap::new();
+    for i in 0..20 {
+        let enr = EnrBuilder::new("v4")
+            .ip4(ip)
+            .udp4(13931 + i)
+            .build(&CombinedKey::generate_secp256k1())
+            .unwrap();
+        let distance = local_key.log2_distance(&enr.node_id().into()).unwrap();
+        if discv5.add_enr(enr.clone()).is_ok() {
+            expected.entry(distance).or_default().push(enr.node_id());
+        }
+    }
+
+    for distance in 1..=256 {
+        let entries: HashSet<NodeId> = discv5
+            .table_entries_by_distance(distance)
+            .iter()
+            .map(|enr| enr.node_id())
+            .collect();
+        let expected: HashSet<NodeId> = expected
+            .remove(&distance)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        assert_eq!(entries, expected);
+    }
+    assert!(discv5.table_entries_by_distance(0).is_empty());
+    assert!(discv5.table_entries_by_distance(257).is_empty());
+}
+
+/// The detailed table entries report the connection status of each node and the time it was
+/// last contacted.
+#[tokio::test]
+async fn test_table_entries_detailed() {
+    init();
+    let mut nodes = build_nodes(2, 13980).await;
+    let peer = nodes.remove(1);
+    let node = nodes.remove(0);
+
+    let before_insert = std::time::Instant::now();
+    node.add_enr(peer.local_enr()).unwrap();
+    let entries = node.table_entries_detailed();
+    assert_eq!(entries.len(), 1);
+    let (node_id, enr, status, inserted_at) = entries[0].clone();
+    assert_eq!(node_id, peer.local_enr().node_id());
+    assert_eq!(enr, peer.local_enr());
+    assert_eq!(status.state, kbucket::ConnectionState::Disconnected);
+    assert_eq!(status.direction, kbucket::ConnectionDirection::Incoming);
+    assert!(inserted_at >= before_insert);
+
+    // a successful request marks the node as connected
+    node.ping(peer.local_enr()).await.unwrap();
+    let entries = node.table_entries_detailed();
+    assert_eq!(entries.len(), 1);
+    let (_, _, status, contacted_at) = entries[0].clone();
+    assert_eq!(status.state, kbucket::ConnectionState::Connected);
+    assert!(contacted_at > inserted_at);
+}
+
+/// A pending node replaces a disconnected node of a full bucket once the configured timeout
+/// has elapsed.
+#[tokio::test]
+async fn test_bucket_pending_timeout() {
+    let enr_key = CombinedKey::generate_secp256k1();
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(13970)
+        .build(&enr_key)
+        .unwrap();
+    let local_key: kbucket::Key<NodeId> = enr.node_id().into();
+    let config = Discv5ConfigBuilder::new()
+        .bucket_pending_timeout(std::time::Duration::from_millis(200))
+        .build();
+    let discv5 = Discv5::new(enr, enr_key, config).unwrap();
+
+    // enough nodes to fill the furthest bucket, and one more
+    let mut enrs = Vec::new();
+    while enrs.len() < kbucket::MAX_NODES_PER_BUCKET + 1 {
+        let enr = EnrBuilder::new("v4")
+            .ip4(ip)
+            .udp4(13971)
+            .build(&CombinedKey::generate_secp256k1())
+            .unwrap();
+        if local_key.log2_distance(&enr.node_id().into()) == Some(256) {
+            enrs.push(enr);
+        }
+    }
+    let pending = enrs.pop().unwrap();
+    for enr in enrs.iter() {
+        discv5.add_enr(enr.clone()).unwrap();
+    }
+    // the bucket is full, the connected node waits for a disconnected one to be replaced
+    discv5
+        .add_enr_with_status(
+            pending.clone(),
+            ConnectionState::Connected,
+            ConnectionDirection::Outgoing,
+        )
+        .unwrap();
+    let in_bucket = |node_id: NodeId| {
+        discv5
+            .table_entries_by_distance(256)
+            .iter()
+            .any(|enr| enr.node_id() == node_id)
+    };
+
+    assert!(!in_bucket(pending.node_id()));
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    // the least-recently connected node is replaced
+    assert!(in_bucket(pending.node_id()));
+    assert!(!in_bucket(enrs[0].node_id()));
+}
+
+/// A node stores a value at a bootstrap node, which a second node then retrieves via FIND_VALUE.
+#[tokio::test]
+async fn test_store_and_find_value() {
+    init();
+    let keypairs = generate_deterministic_keypair(3, 1652);
+    let mut nodes = build_nodes_from_keypairs(keypairs, 12000).await;
+    let bootstrap_node = nodes.remove(0);
+    let storing_node = nodes.remove(0);
+    let retrieving_node = nodes.remove(0);
+
+    for node in [&storing_node, &retrieving_node] {
+        node.add_enr(bootstrap_node.local_enr()).unwrap();
+        bootstrap_node.add_enr(node.local_enr()).unwrap();
+    }
+
+    let key = NodeId::random();
+    let value = b"some value".to_vec();
+
+    let accepted = storing_node.put_value(key, value.clone()).await.unwrap();
+    assert!(accepted >= 1);
+
+    let found = retrieving_node.find_value(key).await.unwrap();
+    assert_eq!(found, value);
+}
+
+/// Run a query and check the distance reported for each found node.
+#[tokio::test]
+async fn test_findnode_query_with_distances() {
+    init();
+    let total_nodes = 5;
+    let mut keypairs = generate_deterministic_keypair(total_nodes + 1, 5);
+    let target_node_id = NodeId::from(keypairs.remove(0).public());
+    let mut nodes = build_nodes_from_keypairs(keypairs, 12100).await;
+    let node_enrs: Vec<Enr<CombinedKey>> = nodes.iter().map(|n| n.local_enr()).collect();
+
+    // link the nodes together
+    for (node, previous_node_enr) in nodes.iter_mut().skip(1).zip(node_enrs) {
+        node.add_enr(previous_node_enr).unwrap();
+    }
+
+    let found_nodes = nodes
+        .last_mut()
+        .unwrap()
+        .find_node_with_distances(target_node_id)
+        .await
+        .unwrap();
+
+    assert!(!found_nodes.is_empty());
+    for (enr, distance) in found_nodes {
+        assert_eq!(Some(distance), get_distance(target_node_id, enr.node_id()));
+    }
+}
+
+/// A FIND_VALUE lookup for a key no node stores ends with a single `NotFound` error.
+#[tokio::test]
+async fn test_find_value_not_found() {
+    init();
+    let keypairs = generate_deterministic_keypair(3, 1652);
+    let nodes = build_nodes_from_keypairs(keypairs, 12200).await;
+    let bootstrap_node = &nodes[0];
+
+    for node in nodes.iter().skip(1) {
+        node.add_enr(bootstrap_node.local_enr()).unwrap();
+        bootstrap_node.add_enr(node.local_enr()).unwrap();
+    }
+
+    let results: Vec<_> = nodes[1].get_value(NodeId::random()).collect().await;
+    assert_eq!(results, vec![Err(FindValueError::NotFound)]);
+}
+
+/// A FIND_VALUE lookup with a short timeout against an unresponsive peer times out early.
+#[tokio::test]
+async fn test_find_value_timeout_override() {
+    init();
+    let mut nodes = build_nodes(1, 12300).await;
+    let node = nodes.remove(0);
+
+    // a peer which is never started
+    let offline_key = CombinedKey::generate_secp256k1();
+    let offline_enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(12301)
+        .build(&offline_key)
+        .unwrap();
+    node.add_enr(offline_enr).unwrap();
+
+    let started = std::time::Instant::now();
+    let results: Vec<_> = node
+        .get_value_with_timeout(NodeId::random(), std::time::Duration::from_millis(500))
+        .collect()
+        .await;
+
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+    assert!(matches!(
+        results.first(),
+        Some(Err(FindValueError::RequestErrorWithEnrs((
+            RequestError::Timeout,
+            _
+        ))))
+    ));
+}
+
+/// A FIND_NODE query reports whether it timed out or ran out of peers to contact.
+#[tokio::test]
+async fn test_find_node_completion() {
+    init();
+    let mut nodes = build_nodes(1, 13400).await;
+    let peer = nodes.remove(0);
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(13401)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+    let config = Discv5ConfigBuilder::new()
+        .query_timeout(std::time::Duration::from_millis(500))
+        .build();
+    let mut node = Discv5::new(enr, enr_key, config).unwrap();
+    node.start(socket_addr.into()).await.unwrap();
+    node.add_enr(peer.local_enr()).unwrap();
+
+    // the only peer responds, but there are less peers than requested
+    let (found, completion) = node
+        .find_node_with_completion(NodeId::random())
+        .await
+        .unwrap();
+    assert_eq!(found, vec![peer.local_enr()]);
+    assert_eq!(completion, QueryCompletion::Exhausted);
+
+    // a peer which is never started keeps the query from finishing before its timeout
+    let offline_key = CombinedKey::generate_secp256k1();
+    let offline_enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(13402)
+        .build(&offline_key)
+        .unwrap();
+    node.add_enr(offline_enr).unwrap();
+
+    let (found, completion) = node
+        .find_node_with_completion(NodeId::random())
+        .await
+        .unwrap();
+    assert_eq!(found, vec![peer.local_enr()]);
+    assert_eq!(completion, QueryCompletion::TimedOut);
+}
+
+/// A FIND_VALUE lookup reaches a value held two hops away by following the NODES responses of
+/// peers which do not hold it.
+#[tokio::test]
+async fn test_find_value_two_hops() {
+    init();
+    let keypairs = generate_deterministic_keypair(3, 1652);
+    let mut nodes = build_nodes_from_keypairs(keypairs, 12400).await;
+    let querying_node = nodes.remove(0);
+    let middle_node = nodes.remove(0);
+    let holding_node = nodes.remove(0);
+
+    // Keying the value by the holder's id ensures the middle node returns the holder in
+    // response to the FIND_VALUE request.
+    let key = holding_node.local_enr().node_id();
+    let value = b"some value".to_vec();
+
+    middle_node.add_enr(holding_node.local_enr()).unwrap();
+    let accepted = middle_node.put_value(key, value.clone()).await.unwrap();
+    assert_eq!(accepted, 1);
+
+    // The querying node only knows of the middle node, which does not hold the value
+    querying_node.add_enr(middle_node.local_enr()).unwrap();
+    assert!(querying_node
+        .kbuckets
+        .read()
+        .iter_ref()
+        .all(|entry| *entry.node.key.preimage() != key));
+
+    let found = querying_node.find_value(key).await.unwrap();
+    assert_eq!(found, value);
+}
+
+/// Values stored at a node can be inspected through its local value store.
+#[tokio::test]
+async fn test_stored_keys() {
+    init();
+    let keypairs = generate_deterministic_keypair(2, 1652);
+    let mut nodes = build_nodes_from_keypairs(keypairs, 12500).await;
+    let storage_node = nodes.remove(0);
+    let storing_node = nodes.remove(0);
+    storing_node.add_enr(storage_node.local_enr()).unwrap();
+
+    let values: Vec<(NodeId, Vec<u8>)> =
+        (0..3u8).map(|i| (NodeId::random(), vec![i; 10])).collect();
+    for (key, value) in values.iter() {
+        let accepted = storing_node.put_value(*key, value.clone()).await.unwrap();
+        assert_eq!(accepted, 1);
+    }
+
+    let stored_keys = storage_node.stored_keys().await.unwrap();
+    assert_eq!(stored_keys.len(), values.len());
+    for (key, value) in values {
+        assert!(stored_keys.contains(&key));
+        assert_eq!(storage_node.stored_value(key).await.unwrap(), Some(value));
+    }
+    assert_eq!(
+        storage_node.stored_value(NodeId::random()).await.unwrap(),
+        None
+    );
+    assert!(storing_node.stored_keys().await.unwrap().is_empty());
+}
+
+/// `put_value` stores a value on exactly `value_replication_factor` of the closest peers.
+#[tokio::test]
+async fn test_put_value_replication_factor() {
+    init();
+    let keypairs = generate_deterministic_keypair(5, 1652);
+    let mut nodes = build_nodes_from_keypairs(keypairs, 12600).await;
+    let mut storing_node = nodes.remove(0);
+    for node in nodes.iter() {
+        storing_node.add_enr(node.local_enr()).unwrap();
+    }
+
+    for replication_factor in [1, 3] {
+        storing_node.config.value_replication_factor = replication_factor;
+        let key = NodeId::random();
+
+        let accepted = storing_node.put_value(key, vec![1, 2, 3]).await.unwrap();
+        assert_eq!(accepted, replication_factor);
+
+        let mut holders = 0;
+        for node in nodes.iter() {
+            if node.stored_value(key).await.unwrap().is_some() {
+                holders += 1;
+            }
+        }
+        assert_eq!(holders, replication_factor);
+    }
+}
+
+/// A value returned by several holders is only yielded once by the FIND_VALUE stream.
+#[tokio::test]
+async fn test_find_value_deduplicates_holders() {
+    init();
+    let keypairs = generate_deterministic_keypair(5, 1652);
+    let mut nodes = build_nodes_from_keypairs(keypairs, 12700).await;
+    let storing_node = nodes.remove(0);
+    let querying_node = nodes.remove(0);
+    for holder in nodes.iter() {
+        storing_node.add_enr(holder.local_enr()).unwrap();
+    }
+
+    let key = NodeId::random();
+    let value = b"some value".to_vec();
+    let accepted = storing_node.put_value(key, value.clone()).await.unwrap();
+    assert_eq!(accepted, 3);
+
+    for holder in nodes.iter() {
+        querying_node.add_enr(holder.local_enr()).unwrap();
+    }
+    let results: Vec<_> = querying_node.get_value(key).collect().await;
+    assert_eq!(results, vec![Ok(value)]);
+}
+
+/// Stores a value directly on `holder`, without a lookup.
+async fn store_at(node: &Discv5, holder: &Discv5, key: NodeId, value: Vec<u8>) {
+    let contact =
+        crate::node_info::NodeContact::try_from_enr(holder.local_enr(), IpMode::default()).unwrap();
+    let (callback_send, callback_recv) = tokio::sync::oneshot::channel();
+    let request = crate::service::ServiceRequest::Store(contact, key, value, callback_send);
+    node.clone_channel().unwrap().send(request).await.unwrap();
+    assert_eq!(
+        callback_recv.await.unwrap().map(|(accepted, _)| accepted),
+        Ok(true)
+    );
+}
+
+/// A quorum lookup resolves once enough holders returned identical values.
+#[tokio::test]
+async fn test_find_value_quorum() {
+    init();
+    let keypairs = generate_deterministic_keypair(4, 1652);
+    let mut nodes = build_nodes_from_keypairs(keypairs, 12800).await;
+    let querying_node = nodes.remove(0);
+
+    let key = NodeId::random();
+    let value = b"some value".to_vec();
+    for holder in nodes.iter() {
+        store_at(&querying_node, holder, key, value.clone()).await;
+    }
+
+    let found = querying_node.find_value_quorum(key, 3).await.unwrap();
+    assert_eq!(found, value);
+}
+
+/// A quorum lookup fails if a holder returns a different value.
+#[tokio::test]
+async fn test_find_value_quorum_failed() {
+    init();
+    let keypairs = generate_deterministic_keypair(4, 1652);
+    let mut nodes = build_nodes_from_keypairs(keypairs, 12900).await;
+    let querying_node = nodes.remove(0);
+
+    let key = NodeId::random();
+    store_at(&querying_node, &nodes[0], key, b"some value".to_vec()).await;
+    store_at(&querying_node, &nodes[1], key, b"some value".to_vec()).await;
+    store_at(&querying_node, &nodes[2], key, b"another value".to_vec()).await;
+
+    let result = querying_node.find_value_quorum(key, 3).await;
+    assert_eq!(result, Err(FindValueError::QuorumFailed));
+}
+
+/// A signed value is returned if its signature matches the originator.
+#[tokio::test]
+async fn test_find_signed_value() {
+    init();
+    let keypairs = generate_deterministic_keypair(3, 1652);
+    let mut nodes = build_nodes_from_keypairs(keypairs, 13000).await;
+    let querying_node = nodes.remove(0);
+    let originator = nodes.remove(0);
+    let holder = nodes.remove(0);
+
+    let key = NodeId::random();
+    let value = b"some value".to_vec();
+    let signed =
+        crate::signed_value::SignedValue::new(&key, value.clone(), &originator.enr_key.read())
+            .unwrap();
+    store_at(&querying_node, &holder, key, signed.encode()).await;
+
+    let found = querying_node
+        .find_signed_value(key, originator.local_enr().node_id())
+        .await
+        .unwrap();
+    assert_eq!(found, value);
+
+    // the value is not accepted as signed by another node
+    let result = querying_node
+        .find_signed_value(key, holder.local_enr().node_id())
+        .await;
+    assert_eq!(result, Err(FindValueError::InvalidSignature));
+}
+
+/// A signed value modified by its holder is rejected.
+#[tokio::test]
+async fn test_find_signed_value_tampered() {
+    init();
+    let keypairs = generate_deterministic_keypair(3, 1652);
+    let mut nodes = build_nodes_from_keypairs(keypairs, 13100).await;
+    let querying_node = nodes.remove(0);
+    let originator = nodes.remove(0);
+    let holder = nodes.remove(0);
+
+    let key = NodeId::random();
+    let mut signed = crate::signed_value::SignedValue::new(
+        &key,
+        b"some value".to_vec(),
+        &originator.enr_key.read(),
+    )
+    .unwrap();
+    signed.value = b"another value".to_vec();
+    store_at(&querying_node, &holder, key, signed.encode()).await;
+
+    let result = querying_node
+        .find_signed_value(key, originator.local_enr().node_id())
+        .await;
+    assert_eq!(result, Err(FindValueError::InvalidSignature));
+}
+
+/// A PING reports the address the peer observed the request from.
+#[tokio::test]
+async fn test_ping() {
+    init();
+    let mut nodes = build_nodes(2, 13500).await;
+    let peer = nodes.remove(1);
+    let node = nodes.remove(0);
+
+    let response = node.ping(peer.local_enr()).await.unwrap();
+    assert_eq!(response.enr_seq, peer.local_enr().seq());
+    assert_eq!(
+        Some(response.observed_socket()),
+        node.local_enr().udp4_socket().map(Into::into)
+    );
+}
+
+#[tokio::test]
+async fn test_establish_session_via_relay() {
+    init();
+    let mut nodes = build_nodes(3, 13600).await;
+    let relay = nodes.remove(2);
+    let target = nodes.remove(1);
+    let initiator = nodes.remove(0);
+
+    // the initiator and the target only know of the relay
+    target.ping(relay.local_enr()).await.unwrap();
+    initiator.ping(relay.local_enr()).await.unwrap();
+    assert!(initiator.find_enr(&target.local_enr().node_id()).is_none());
+
+    let mut events = initiator.event_stream().await.unwrap();
+    let forwarded = initiator
+        .establish_session_via_relay(target.local_enr(), relay.local_enr())
+        .await
+        .unwrap();
+    assert!(forwarded);
+
+    // the target contacts the initiator
+    let established = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while let Some(event) = events.recv().await {
+            if let Discv5Event::SessionEstablished(enr, _) = event {
+                return enr;
+            }
+        }
+        panic!("Event stream closed");
+    })
+    .await
+    .expect("Session wasn't established in time");
+    assert_eq!(established.node_id(), target.local_enr().node_id());
+
+    // the relay only forwards requests to nodes it is connected to
+    let unknown = build_nodes(1, 13603).await.remove(0);
+    let forwarded = initiator
+        .establish_session_via_relay(unknown.local_enr(), relay.local_enr())
+        .await
+        .unwrap();
+    assert!(!forwarded);
+}
+
+#[tokio::test]
+async fn test_disconnect_peer() {
+    init();
+    let mut nodes = build_nodes(2, 13700).await;
+    let peer = nodes.remove(1);
+    let node = nodes.remove(0);
+    let peer_id = peer.local_enr().node_id();
+
+    node.ping(peer.local_enr()).await.unwrap();
+    assert_eq!(node.connected_peers(), 1);
+
+    node.disconnect_peer(peer_id).await.unwrap();
+    // requests to the service are processed in order, the peer is disconnected once the event
+    // stream is returned
+    let mut events = node.event_stream().await.unwrap();
+    assert_eq!(node.connected_peers(), 0);
+    assert!(node.find_enr(&peer_id).is_some());
+
+    // a new request performs a new handshake
+    node.ping(peer.local_enr()).await.unwrap();
+    let established = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while let Some(event) = events.recv().await {
+            if let Discv5Event::SessionEstablished(enr, _) = event {
+                return enr;
+            }
+        }
+        panic!("Event stream closed");
+    })
+    .await
+    .expect("Session wasn't established again");
+    assert_eq!(established.node_id(), peer_id);
+    assert_eq!(node.connected_peers(), 1);
+}
+
+#[tokio::test]
+async fn test_query_metrics() {
+    init();
+    let mut nodes = build_nodes(2, 13800).await;
+    let peer = nodes.remove(1);
+    let node = nodes.remove(0);
+    node.add_enr(peer.local_enr()).unwrap();
+
+    // the metrics are shared by all nodes of the process, other tests may increase them too
+    let before = node.metrics();
+
+    // the only peer doesn't hold enough peers to complete the query
+    node.find_node(NodeId::random()).await.unwrap();
+    let after = node.metrics();
+    assert!(after.find_node_queries.started > before.find_node_queries.started);
+    assert!(after.find_node_queries.exhausted > before.find_node_queries.exhausted);
+
+    let key = NodeId::random();
+    assert!(matches!(
+        node.find_value(key).await,
+        Err(FindValueError::NotFound)
+    ));
+    assert_eq!(node.put_value(key, vec![1, 2, 3]).await.unwrap(), 1);
+    assert_eq!(node.find_value(key).await.unwrap(), vec![1, 2, 3]);
+    let after = node.metrics();
+    assert!(after.find_value_queries.started >= before.find_value_queries.started + 2);
+    assert!(after.find_value_queries.exhausted > before.find_value_queries.exhausted);
+    assert!(after.find_value_queries.completed > before.find_value_queries.completed);
+
+    // each finished query is counted in the duration histogram
+    let finished = |metrics: &metrics::Metrics| metrics.query_durations.iter().sum::<usize>();
+    assert!(finished(&after) >= finished(&before) + 4);
+}
+
+#[tokio::test]
+async fn test_packet_filter() {
+    init();
+    let mut peers = build_nodes(2, 13901).await;
+    let allowed = peers.remove(1);
+    let blocked = peers.remove(0);
+    let blocked_addr = blocked.local_enr().udp4_socket().unwrap().into();
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(13900)
+        .build(&enr_key)
+        .unwrap();
+    let config = Discv5ConfigBuilder::new()
+        .packet_filter(std::sync::Arc::new(move |src, _| *src != blocked_addr))
+        .build();
+    let socket_addr = enr.udp4_socket().unwrap();
+    let mut node = Discv5::new(enr, enr_key, config).unwrap();
+    node.start(socket_addr.into()).await.unwrap();
+
+    // packets from the blocked peer never reach the handler
+    assert!(blocked.ping(node.local_enr()).await.is_err());
+    assert!(node.find_enr(&blocked.local_enr().node_id()).is_none());
+    assert_eq!(blocked.connected_peers(), 0);
+
+    allowed.ping(node.local_enr()).await.unwrap();
+    assert_eq!(node.connected_peers(), 1);
+}
+
+#[tokio::test]
+async fn test_dual_stack_listen_addresses() {
+    init();
+    let ip4: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let ip6 = std::net::Ipv6Addr::LOCALHOST;
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4(ip4)
+        .udp4(13910)
+        .ip6(ip6)
+        .udp6(13910)
+        .build(&enr_key)
+        .unwrap();
+    let config = Discv5ConfigBuilder::new()
+        .ip_mode(IpMode::Ip6 {
+            enable_mapped_addresses: true,
+        })
+        .listen_addresses(enr.udp4_socket(), enr.udp6_socket())
+        .build();
+    let mut node = Discv5::new(enr, enr_key, config).unwrap();
+    // the listen addresses of the config are bound instead
+    node.start("0.0.0.0:0".parse().unwrap()).await.unwrap();
+
+    let peer4 = build_nodes(1, 13911).await.remove(0);
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip6(ip6)
+        .udp6(13912)
+        .build(&enr_key)
+        .unwrap();
+    let config = Discv5ConfigBuilder::new()
+        .ip_mode(IpMode::Ip6 {
+            enable_mapped_addresses: false,
+        })
+        .build();
+    let socket_addr = enr.udp6_socket().unwrap();
+    let mut peer6 = Discv5::new(enr, enr_key, config).unwrap();
+    peer6.start(socket_addr.into()).await.unwrap();
+
+    // each peer reaches the node on the socket of its family, and the responses are sent back
+    // from the socket the requests arrived on
+    peer4.ping(node.local_enr()).await.unwrap();
+    peer6.ping(node.local_enr()).await.unwrap();
+    assert_eq!(node.connected_peers(), 2);
+
+    // requests to the peers are sent from the socket of their family
+    let pong = node.ping(peer4.local_enr()).await.unwrap();
+    assert_eq!(pong.ip, std::net::IpAddr::V4(ip4));
+    assert_eq!(pong.port, 13910);
+    let pong = node.ping(peer6.local_enr()).await.unwrap();
+    assert_eq!(pong.port, 13910);
+}
+
+#[tokio::test]
+async fn test_shutdown_gracefully() {
+    init();
+    let mut nodes = build_nodes(2, 13920).await;
+    let peer = nodes.remove(1);
+    let node = nodes.remove(0);
+    node.add_enr(peer.local_enr()).unwrap();
+
+    // the query is sent to the service ahead of the shutdown request
+    let query = tokio::spawn(node.find_node(NodeId::random()));
+    tokio::task::yield_now().await;
+
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        node.shutdown_gracefully(),
+    )
+    .await
+    .expect("Shutdown didn't complete in time");
+
+    // the pending query resolved before the service shut down
+    let found = query.await.unwrap().unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].node_id(), peer.local_enr().node_id());
+}
+
+#[tokio::test]
+async fn test_find_node_fresh() {
+    init();
+    let mut nodes = build_nodes(2, 13960).await;
+    let mut peer = nodes.remove(1);
+    let node = nodes.remove(0);
+    node.add_enr(peer.local_enr()).unwrap();
+    let old_seq = peer.local_enr().seq();
+
+    // the peer updates its ENR after the node learnt it
+    assert!(update_enr(&mut peer, "foo", b"bar"));
+    let new_seq = peer.local_enr().seq();
+    assert!(new_seq > old_seq);
+
+    // the routing table still holds the outdated ENR
+    let found = node.find_node(NodeId::random()).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].seq(), old_seq);
+
+    let found = node.find_node_fresh(NodeId::random()).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].node_id(), peer.local_enr().node_id());
+    assert_eq!(found[0].seq(), new_seq);
+    assert_eq!(found[0].get("foo"), Some(b"bar".as_ref()));
+}
+
+/// With proactive ENR updates enabled, the connected peers are pinged with the new sequence
+/// number after the local ENR is updated, and request the updated ENR.
+#[tokio::test]
+async fn test_proactive_enr_updates() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(13990)
+        .build(&enr_key)
+        .unwrap();
+    let config = Discv5ConfigBuilder::new()
+        .proactive_enr_updates(true)
+        .build();
+    let socket_addr = enr.udp4_socket().unwrap();
+    let mut node = Discv5::new(enr, enr_key, config).unwrap();
+    node.start(socket_addr.into()).await.unwrap();
+    let peer = build_nodes(1, 13991).await.remove(0);
+
+    // establish a session with the peer, which knows the current ENR of the node
+    peer.add_enr(node.local_enr()).unwrap();
+    node.add_enr(peer.local_enr()).unwrap();
+    node.ping(peer.local_enr()).await.unwrap();
+    let old_seq = node.local_enr().seq();
+
+    node.enr_insert("foo", b"bar").unwrap();
+    let new_seq = node.local_enr().seq();
+    assert!(new_seq > old_seq);
+
+    let node_id = node.local_enr().node_id();
+    let known_seq = || {
+        peer.table_entries_enr()
+            .into_iter()
+            .find(|enr| enr.node_id() == node_id)
+            .map(|enr| enr.seq())
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(2), async {
+        while known_seq() != Some(new_seq) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("The peer didn't learn the updated ENR");
+}
+
+/// Dropping the session with a node fails the requests in flight to it straight away, rather
+/// than letting them time out.
+#[tokio::test]
+async fn test_session_expired_request() {
+    init();
+    let mut nodes = build_nodes(2, 14000).await;
+    let peer = nodes.remove(1);
+    let node = nodes.remove(0);
+    let peer_id = peer.local_enr().node_id();
+    node.add_enr(peer.local_enr()).unwrap();
+    node.ping(peer.local_enr()).await.unwrap();
+
+    // the peer holds on to the TALK request without responding
+    let mut peer_events = peer.event_stream().await.unwrap();
+    let talk = tokio::spawn(node.talk_req(peer.local_enr(), b"proto".to_vec(), b"req".to_vec()));
+    let _request = loop {
+        match peer_events.recv().await.unwrap() {
+            Discv5Event::TalkRequest(request) => break request,
+            _ => continue,
+        }
+    };
+
+    let start = std::time::Instant::now();
+    node.disconnect_peer(peer_id).await.unwrap();
+    let result = talk.await.unwrap();
+    assert_eq!(result, Err(RequestError::SessionExpired));
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+}
+
+/// TALK requests of a registered protocol are passed to its handler, while requests of other
+/// protocols receive an empty response.
+#[tokio::test]
+async fn test_register_talk_protocol() {
+    init();
+    let mut nodes = build_nodes(2, 14010).await;
+    let peer = nodes.remove(1);
+    let node = nodes.remove(0);
+
+    let (handler, mut requests) = tokio::sync::mpsc::channel::<TalkRequest>(10);
+    assert!(peer
+        .register_talk_protocol(b"echo".to_vec(), handler)
+        .is_none());
+    let node_id = node.local_enr().node_id();
+    tokio::spawn(async move {
+        while let Some(request) = requests.recv().await {
+            assert_eq!(request.node_id(), &node_id);
+            assert_eq!(request.protocol(), b"echo");
+            let response = request.body().to_vec();
+            request.respond(response).unwrap();
+        }
+    });
+
+    let response = node
+        .talk_req(peer.local_enr(), b"echo".to_vec(), b"hello".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(response, b"hello".to_vec());
+
+    let response = node
+        .talk_req(peer.local_enr(), b"other".to_vec(), b"hello".to_vec())
+        .await
+        .unwrap();
+    assert!(response.is_empty());
+}
+
+/// A TALK request the peer doesn't respond to fails with a timeout, honouring the configured
+/// request timeout.
+#[tokio::test]
+async fn test_talk_req_timeout() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(14020)
+        .build(&enr_key)
+        .unwrap();
+    let config = Discv5ConfigBuilder::new()
+        .request_timeout(std::time::Duration::from_millis(200))
+        .build();
+    let socket_addr = enr.udp4_socket().unwrap();
+    let mut node = Discv5::new(enr, enr_key, config).unwrap();
+    node.start(socket_addr.into()).await.unwrap();
+    let peer = build_nodes(1, 14021).await.remove(0);
+
+    // the peer holds on to the TALK requests without responding
+    let (handler, mut requests) = tokio::sync::mpsc::channel::<TalkRequest>(10);
+    peer.register_talk_protocol(b"silent".to_vec(), handler);
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Some(request) = requests.recv().await {
+            held.push(request);
+        }
+    });
+
+    let start = std::time::Instant::now();
+    let result = node
+        .talk_req(peer.local_enr(), b"silent".to_vec(), b"hello".to_vec())
+        .await;
+    assert_eq!(result, Err(RequestError::Timeout));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= std::time::Duration::from_millis(200));
+    assert!(elapsed < std::time::Duration::from_secs(1));
+}
+
+/// Builds a started node on `port` listening on localhost with the given configuration.
+async fn build_node_with_config(port: u16, config: Discv5Config) -> Discv5 {
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(port)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+    let mut discv5 = Discv5::new(enr, enr_key, config).unwrap();
+    discv5.start(socket_addr.into()).await.unwrap();
+    discv5
+}
+
+/// `put_values` reports which values a peer accepted when it rejects some entries of a batch.
+#[tokio::test]
+async fn test_put_values_partial_acceptance() {
+    init();
+    let node = build_nodes(1, 14030).await.remove(0);
+    let config = Discv5ConfigBuilder::new().max_value_size(10).build();
+    let peer = build_node_with_config(14031, config).await;
+    node.add_enr(peer.local_enr()).unwrap();
+
+    let entries = vec![
+        (NodeId::random(), vec![1; 4]),
+        (NodeId::random(), vec![2; 100]),
+        (NodeId::random(), vec![3; 10]),
+    ];
+    let accepted = node.put_values(entries.clone()).await.unwrap();
+    assert_eq!(accepted, vec![1, 0, 1]);
+
+    let stored: HashSet<NodeId> = peer.stored_keys().await.unwrap().into_iter().collect();
+    let expected: HashSet<NodeId> = [entries[0].0, entries[2].0].iter().copied().collect();
+    assert_eq!(stored, expected);
+}
+
+/// Values too large to share a packet are split over several requests to the same peer.
+#[tokio::test]
+async fn test_put_values_batches() {
+    init();
+    let mut nodes = build_nodes(3, 14040).await;
+    let node = nodes.remove(0);
+    for peer in nodes.iter() {
+        node.add_enr(peer.local_enr()).unwrap();
+    }
+
+    let entries: Vec<(NodeId, Vec<u8>)> =
+        (0..5).map(|i| (NodeId::random(), vec![i; 500])).collect();
+    let accepted = node.put_values(entries.clone()).await.unwrap();
+    assert_eq!(accepted, vec![2; 5]);
+
+    for peer in nodes.iter() {
+        for (key, value) in entries.iter() {
+            assert_eq!(peer.stored_value(*key).await.unwrap().as_ref(), Some(value));
+        }
+    }
+
+    let too_large = vec![(NodeId::random(), vec![0; 1001])];
+    assert_eq!(
+        node.put_values(too_large).await,
+        Err(FindValueError::ValueTooLarge {
+            size: 1001,
+            max: 1000
+        })
+    );
+}
+
+/// Values within `max_value_size` but too large for a single STORE packet are rejected up front.
+#[tokio::test]
+async fn test_put_values_exceeding_packet() {
+    init();
+    let config = Discv5ConfigBuilder::new().max_value_size(4000).build();
+    let node = build_node_with_config(14164, config.clone()).await;
+    let peer = build_node_with_config(14165, config).await;
+    node.add_enr(peer.local_enr()).unwrap();
+
+    let key = NodeId::random();
+    let too_large = vec![(NodeId::random(), vec![0; 10]), (key, vec![0; 2000])];
+    let result = node.put_values(too_large).await;
+    assert!(matches!(
+        result,
+        Err(FindValueError::ValueTooLarge { size: 2000, max }) if max < crate::service::MAX_STORE_BATCH_SIZE
+    ));
+    assert!(matches!(
+        node.put_value(key, vec![0; 2000]).await,
+        Err(FindValueError::ValueTooLarge { size: 2000, .. })
+    ));
+    assert!(peer.stored_keys().await.unwrap().is_empty());
+
+    // the largest value reported fits in a packet
+    let max = match result {
+        Err(FindValueError::ValueTooLarge { max, .. }) => max,
+        _ => unreachable!(),
+    };
+    let accepted = node.put_values(vec![(key, vec![0; max])]).await.unwrap();
+    assert_eq!(accepted, vec![1]);
+    assert_eq!(peer.stored_value(key).await.unwrap(), Some(vec![0; max]));
+}
+
+/// Rekeying a session performs a new handshake with the node straight away, after which the
+/// session keeps working.
+#[tokio::test]
+async fn test_rekey_session() {
+    init();
+    let mut nodes = build_nodes(2, 14050).await;
+    let peer = nodes.remove(1);
+    let node = nodes.remove(0);
+    let peer_id = peer.local_enr().node_id();
+    node.add_enr(peer.local_enr()).unwrap();
+    node.ping(peer.local_enr()).await.unwrap();
+
+    let mut events = node.event_stream().await.unwrap();
+    node.rekey_session(peer_id).await.unwrap();
+    tokio::time::timeout(std::time::Duration::from_secs(1), async {
+        loop {
+            if let Some(Discv5Event::SessionEstablished(enr, _)) = events.recv().await {
+                if enr.node_id() == peer_id {
+                    return;
+                }
+            }
+        }
+    })
+    .await
+    .expect("No new handshake was performed");
+
+    node.ping(peer.local_enr()).await.unwrap();
+}
+
+/// The current ENR of a node can be requested by its node id, and an outdated ENR returned by
+/// the node is ignored in favour of the known one.
+#[tokio::test]
+async fn test_request_node_enr_by_id() {
+    init();
+    let peer_key = CombinedKey::generate_secp256k1();
+    let mut peer_key_bytes = peer_key.encode();
+    let peer_key_copy = CombinedKey::secp256k1_from_bytes(&mut peer_key_bytes).unwrap();
+    let mut peer = build_nodes_from_keypairs(vec![peer_key], 14061)
+        .await
+        .remove(0);
+    let node = build_nodes(1, 14060).await.remove(0);
+    let peer_id = peer.local_enr().node_id();
+
+    // the node only knows an old ENR of the peer
+    let old_enr = peer.local_enr();
+    node.add_enr(old_enr.clone()).unwrap();
+    assert!(update_enr(&mut peer, "test", b"value"));
+    let enr = node.request_node_enr_by_id(&peer_id).await.unwrap();
+    assert!(enr.seq() > old_enr.seq());
+    assert_eq!(enr, peer.local_enr());
+
+    // a known ENR more recent than the node's own one is kept
+    let mut newer_enr = peer.local_enr();
+    newer_enr.set_seq(enr.seq() + 5, &peer_key_copy).unwrap();
+    node.add_enr(newer_enr.clone()).unwrap();
+    let enr = node.request_node_enr_by_id(&peer_id).await.unwrap();
+    assert_eq!(enr.seq(), newer_enr.seq());
+
+    // unknown nodes can't be contacted by their node id
+    assert!(node
+        .request_node_enr_by_id(&NodeId::random())
+        .await
+        .is_err());
+}
+
+/// Nodes failing the ENR filter are returned by queries but never inserted into the routing
+/// table.
+#[tokio::test]
+async fn test_enr_filter() {
+    init();
+    let config = Discv5ConfigBuilder::new()
+        .enr_filter(std::sync::Arc::new(|enr: &crate::Enr| {
+            enr.get("eth2").is_some_and(|fork| fork == b"fork-a")
+        }))
+        .build();
+    let mut node = build_node_with_config(14070, config).await;
+    let mut peers = build_nodes(2, 14071).await;
+    assert!(update_enr(&mut node, "eth2", b"fork-a"));
+    assert!(update_enr(&mut peers[0], "eth2", b"fork-a"));
+    assert!(update_enr(&mut peers[1], "eth2", b"fork-b"));
+    let other_fork = peers[1].local_enr();
+
+    // the ENR of another fork can't be added manually
+    assert!(node.add_enr(other_fork.clone()).is_err());
+
+    // the node discovers the other fork through its bootstrap peer
+    node.add_enr(peers[0].local_enr()).unwrap();
+    peers[0].add_enr(other_fork.clone()).unwrap();
+    let found = node.find_node(other_fork.node_id()).await.unwrap();
+    assert!(found
+        .iter()
+        .any(|enr| enr.node_id() == other_fork.node_id()));
+
+    let table: Vec<NodeId> = node.table_entries_id();
+    assert!(table.contains(&peers[0].local_enr().node_id()));
+    assert!(!table.contains(&other_fork.node_id()));
+}
+
+/// The boot nodes are retried until one becomes reachable, after which `Bootstrapped` is emitted.
+#[tokio::test]
+async fn test_bootstrap_retry() {
+    init();
+    let boot_key = CombinedKey::generate_secp256k1();
+    let boot_enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(14081)
+        .build(&boot_key)
+        .unwrap();
+    let mut boot_node = Discv5::new(boot_enr.clone(), boot_key, Default::default()).unwrap();
+
+    let config = Discv5ConfigBuilder::new()
+        .boot_nodes(vec![boot_enr.clone()])
+        .bootstrap_backoff(
+            std::time::Duration::from_millis(50),
+            std::time::Duration::from_millis(200),
+        )
+        .request_timeout(std::time::Duration::from_millis(50))
+        .build();
+    let node = build_node_with_config(14080, config).await;
+    let mut events = node.event_stream().await.unwrap();
+
+    // the boot node only comes up after the first attempts failed
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    boot_node
+        .start(boot_enr.udp4_socket().unwrap().into())
+        .await
+        .unwrap();
+
+    tokio::time::timeout(std::time::Duration::from_secs(2), async {
+        loop {
+            if let Some(Discv5Event::Bootstrapped) = events.recv().await {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("The node never bootstrapped");
+    assert!(node.table_entries_id().contains(&boot_enr.node_id()));
+}
+
+#[test]
+fn test_random_node_id_at_distance() {
+    let local_id = NodeId::random();
+    for distance in 1..=256 {
+        let node_id = super::random_node_id_at_distance(&local_id, distance);
+        assert_eq!(get_distance(local_id, node_id), Some(distance));
+    }
+}
+
+/// Refreshing an empty bucket finds the nodes of the bucket and inserts them.
+#[tokio::test]
+async fn test_refresh_bucket() {
+    init();
+    let node = build_nodes(1, 14090).await.remove(0);
+    let local_id = node.local_enr().node_id();
+    let key_at = |distance| loop {
+        let key = CombinedKey::generate_secp256k1();
+        if get_distance(local_id, NodeId::from(key.public())) == Some(distance) {
+            return key;
+        }
+    };
+    // the bootstrap peer lies in the bucket at distance 256, the node to find at distance 255
+    let peers = build_nodes_from_keypairs(vec![key_at(256), key_at(255)], 14091).await;
+    let target_id = peers[1].local_enr().node_id();
+    node.add_enr(peers[0].local_enr()).unwrap();
+    peers[0].add_enr(peers[1].local_enr()).unwrap();
+    assert!(!node.table_entries_id().contains(&target_id));
+
+    let found = node.refresh_bucket(255).await.unwrap();
+    assert!(found.iter().any(|enr| enr.node_id() == target_id));
+    let in_bucket: Vec<NodeId> = node
+        .table_entries_id()
+        .into_iter()
+        .filter(|node_id| get_distance(local_id, *node_id) == Some(255))
+        .collect();
+    assert_eq!(in_bucket, vec![target_id]);
+
+    assert!(node.refresh_bucket(0).await.is_err());
+    assert!(node.refresh_bucket(257).await.is_err());
+}
+
+/// Compact ENR storage keeps the table usable, returning the ENRs that were inserted.
+#[tokio::test]
+async fn test_compact_enr_storage() {
+    init();
+    let config = Discv5ConfigBuilder::new().compact_enr_storage(true).build();
+    let node = build_node_with_config(14100, config).await;
+    let peers = build_nodes(2, 14101).await;
+    for peer in &peers {
+        node.add_enr(peer.local_enr()).unwrap();
+    }
+
+    assert!(node
+        .kbuckets()
+        .iter_ref()
+        .all(|entry| entry.node.value.is_compact()));
+    for peer in &peers {
+        let enr = peer.local_enr();
+        assert_eq!(node.find_enr(&enr.node_id()), Some(enr.clone()));
+        assert!(node.table_entries_enr().contains(&enr));
+    }
+}
+
+/// An exported routing table is restored disconnected into a fresh node, without the entries
+/// rejected by its ENR filter.
+#[tokio::test]
+async fn test_export_seed_table() {
+    init();
+    let node = build_node_with_config(14103, Default::default()).await;
+    let mut peers = build_nodes(3, 14104).await;
+    assert!(update_enr(&mut peers[2], "eth2", b"fork-b"));
+    for peer in &peers {
+        node.add_enr_with_status(
+            peer.local_enr(),
+            ConnectionState::Connected,
+            ConnectionDirection::Outgoing,
+        )
+        .unwrap();
+    }
+    let exported = node.export_table();
+    assert_eq!(exported.len(), 3);
+
+    let config = Discv5ConfigBuilder::new()
+        .seed_table(exported)
+        .enr_filter(std::sync::Arc::new(|enr: &crate::Enr| {
+            enr.get("eth2").is_none()
+        }))
+        .build();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(14107)
+        .build(&enr_key)
+        .unwrap();
+    let restored = Discv5::new(enr, enr_key, config).unwrap();
+
+    let entries = restored.table_entries();
+    assert_eq!(entries.len(), 2);
+    for (node_id, enr, status) in entries {
+        assert_ne!(node_id, peers[2].local_enr().node_id());
+        assert!(peers.iter().any(|peer| peer.local_enr() == enr));
+        assert_eq!(status.state, ConnectionState::Disconnected);
+    }
+}
+
+/// A node failing to respond to the liveness checks is evicted from the routing table once it
+/// reaches the failure threshold, while responsive nodes are kept.
+#[tokio::test]
+async fn test_liveness_eviction() {
+    init();
+    let config = Discv5ConfigBuilder::new()
+        .liveness_check_interval(std::time::Duration::from_millis(100))
+        .max_ping_failures(2)
+        .request_timeout(std::time::Duration::from_millis(50))
+        .build();
+    let node = build_node_with_config(14108, config).await;
+    let peer = build_nodes(1, 14109).await.pop().unwrap();
+    let dead_key = CombinedKey::generate_secp256k1();
+    let dead_enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(14110)
+        .build(&dead_key)
+        .unwrap();
+    node.add_enr(peer.local_enr()).unwrap();
+    node.add_enr(dead_enr.clone()).unwrap();
+    let mut events = node.event_stream().await.unwrap();
+
+    let evicted = tokio::time::timeout(std::time::Duration::from_secs(3), async {
+        loop {
+            if let Some(Discv5Event::NodeEvicted { node_id }) = events.recv().await {
+                return node_id;
+            }
+        }
+    })
+    .await
+    .expect("The unresponsive node must be evicted");
+    assert_eq!(evicted, dead_enr.node_id());
+    assert_eq!(node.table_entries_id(), vec![peer.local_enr().node_id()]);
+}
+
+/// A node known only by its public key and address is contacted to learn its ENR.
+#[tokio::test]
+async fn test_request_enr_from_address() {
+    init();
+    let config = Discv5ConfigBuilder::new()
+        .request_timeout(std::time::Duration::from_millis(100))
+        .build();
+    let node = build_node_with_config(14111, config).await;
+    let peer = build_nodes(1, 14112).await.remove(0);
+    let peer_enr = peer.local_enr();
+    let peer_addr: std::net::SocketAddr = peer_enr.udp4_socket().unwrap().into();
+
+    let enr = node
+        .request_enr_from_address(peer_enr.public_key(), peer_addr)
+        .await
+        .unwrap();
+    assert_eq!(enr, peer_enr);
+
+    // the handshake fails with the key of another node
+    let other_key = CombinedKey::generate_secp256k1();
+    assert!(node
+        .request_enr_from_address(other_key.public(), peer_addr)
+        .await
+        .is_err());
+}
+
+/// A multi-target lookup returns the nodes found for each target.
+#[tokio::test]
+async fn test_find_nodes_multi() {
+    init();
+    let nodes = build_nodes(4, 14113).await;
+    let targets: Vec<NodeId> = nodes[2..]
+        .iter()
+        .map(|node| node.local_enr().node_id())
+        .collect();
+    nodes[0].add_enr(nodes[1].local_enr()).unwrap();
+    for node in &nodes[2..] {
+        nodes[1].add_enr(node.local_enr()).unwrap();
+    }
+
+    let found = nodes[0].find_nodes_multi(targets.clone()).await.unwrap();
+    assert_eq!(found.len(), 2);
+    for target in targets {
+        assert!(found[&target].iter().any(|enr| enr.node_id() == target));
+    }
+}
+
+/// The snapshot of the in-flight queries reflects their target and progress.
+#[tokio::test]
+async fn test_active_queries() {
+    init();
+    let config = Discv5ConfigBuilder::new()
+        .request_timeout(Duration::from_secs(5))
+        .build();
+    let node = build_node_with_config(14117, config).await;
+    let peer = build_nodes(1, 14118).await.remove(0);
+    // a peer that never responds keeps the query in flight
+    let silent_key = CombinedKey::generate_secp256k1();
+    let silent_peer = EnrBuilder::new("v4")
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(14119)
+        .build(&silent_key)
+        .unwrap();
+    node.add_enr(peer.local_enr()).unwrap();
+    node.add_enr(silent_peer).unwrap();
+    assert!(node.active_queries().await.unwrap().is_empty());
+
+    let target = NodeId::random();
+    let query = tokio::spawn(node.find_node(target));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let active = node.active_queries().await.unwrap();
+    assert_eq!(active.len(), 1);
+    assert_eq!(active[0].target, target);
+    assert_eq!(active[0].query_type, QueryType::FindNode(target));
+    assert!(active[0].elapsed >= Duration::from_millis(500));
+    assert_eq!(active[0].peers_contacted, 2);
+    assert_eq!(active[0].responses, 1);
+    query.abort();
+}
+
+/// A peer accepting a STORE returns the nodes it knows of closer to the key, which the value is
+/// also stored on.
+#[tokio::test]
+async fn test_put_value_closer_nodes() {
+    init();
+    // the closer node shares the first bits of its node id with the responder, so that the
+    // responder doesn't return it in the NODES response for keys far from both
+    let responder_key = CombinedKey::generate_secp256k1();
+    let responder_id = NodeId::from(responder_key.public());
+    let closer_key = loop {
+        let key = CombinedKey::generate_secp256k1();
+        let distance = get_distance(responder_id, NodeId::from(key.public())).unwrap();
+        if distance <= 250 {
+            break key;
+        }
+    };
+    let nodes = build_nodes_from_keypairs(
+        vec![CombinedKey::generate_secp256k1(), responder_key, closer_key],
+        14120,
+    )
+    .await;
+    let (originator, responder, closer) = (&nodes[0], &nodes[1], &nodes[2]);
+    originator.add_enr(responder.local_enr()).unwrap();
+    responder.add_enr(closer.local_enr()).unwrap();
+
+    // the key is closer to the closer node than to the responder, at the farthest distance
+    let mut raw_key = closer.local_enr().node_id().raw();
+    raw_key[0] ^= 0x80;
+    let key = NodeId::new(&raw_key);
+
+    let value = b"some value".to_vec();
+    let accepted = originator.put_value(key, value.clone()).await.unwrap();
+    assert_eq!(accepted, 2);
+    assert_eq!(
+        responder.stored_value(key).await.unwrap(),
+        Some(value.clone())
+    );
+    assert_eq!(closer.stored_value(key).await.unwrap(), Some(value));
+}
+
+/// A node caching the values it found serves them to other nodes.
+#[tokio::test]
+async fn test_cache_found_values() {
+    init();
+    let config = Discv5ConfigBuilder::new().cache_found_values(true).build();
+    let finder = build_node_with_config(14123, config).await;
+    let mut holder = build_nodes(1, 14124).await.remove(0);
+    let third_party = build_nodes(1, 14125).await.remove(0);
+    finder.add_enr(holder.local_enr()).unwrap();
+    third_party.add_enr(finder.local_enr()).unwrap();
+
+    let key = NodeId::random();
+    let value = b"some value".to_vec();
+    store_at(&finder, &holder, key, value.clone()).await;
+    assert_eq!(finder.find_value(key).await.unwrap(), value);
+    assert_eq!(finder.stored_value(key).await.unwrap(), Some(value.clone()));
+
+    // the value is served by the finder without the holder
+    holder.shutdown();
+    assert_eq!(third_party.find_value(key).await.unwrap(), value);
+}
+
+/// A session is reported once a request was exchanged with a node.
+#[tokio::test]
+async fn test_is_connected() {
+    init();
+    let nodes = build_nodes(2, 14126).await;
+    let peer_id = nodes[1].local_enr().node_id();
+    assert!(!nodes[0].is_connected(peer_id).await);
+
+    nodes[0].ping(nodes[1].local_enr()).await.unwrap();
+    assert!(nodes[0].is_connected(peer_id).await);
+    assert!(nodes[1].is_connected(nodes[0].local_enr().node_id()).await);
+    assert!(!nodes[0].is_connected(NodeId::random()).await);
+}
+
+#[tokio::test]
+async fn test_find_node_exact_known_id() {
+    init();
+    let nodes = build_nodes(3, 14128).await;
+    let target = nodes[2].local_enr();
+    for node in &nodes[..2] {
+        for peer in &nodes {
+            if peer.local_enr().node_id() != node.local_enr().node_id() {
+                let _ = node.add_enr(peer.local_enr());
+            }
+        }
+    }
+
+    // the lookup only requests the target's own ENR instead of iterating towards it
+    let found = nodes[0].find_node(target.node_id()).await.unwrap();
+    assert_eq!(found, vec![target]);
+}
+
+/// A lookup of a known node's exact id falls back to an iterative lookup if the node doesn't
+/// respond.
+#[tokio::test]
+async fn test_find_node_exact_known_id_unresponsive() {
+    init();
+    let config = Discv5ConfigBuilder::new()
+        .request_timeout(Duration::from_millis(100))
+        .request_retries(0)
+        .build();
+    let node = build_node_with_config(14161, config).await;
+    let peer = build_nodes(1, 14162).await.remove(0);
+    // nothing listens on the target's port
+    let target_key = CombinedKey::generate_secp256k1();
+    let target = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(14163)
+        .build(&target_key)
+        .unwrap();
+    node.add_enr(target.clone()).unwrap();
+    node.add_enr(peer.local_enr()).unwrap();
+    peer.add_enr(target.clone()).unwrap();
+
+    // the peer is asked once the target failed to respond
+    let found = node.find_node(target.node_id()).await.unwrap();
+    assert_eq!(found, vec![peer.local_enr()]);
+}
+
+#[tokio::test]
+async fn test_store_validator() {
+    init();
+    let validator: StoreValidator = std::sync::Arc::new(|_key, value| value.starts_with(b"header"));
+    let config = Discv5ConfigBuilder::new()
+        .store_validator(validator)
+        .build();
+    let holder = build_node_with_config(14131, config).await;
+    let node = build_nodes(1, 14132).await.remove(0);
+    node.add_enr(holder.local_enr()).unwrap();
+
+    let valid_key = NodeId::random();
+    store_at(&node, &holder, valid_key, b"header: 1".to_vec()).await;
+
+    // malformed values are rejected and never stored
+    let invalid_key = NodeId::random();
+    let accepted = node
+        .put_value(invalid_key, b"garbage".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(accepted, 0);
+    assert_eq!(holder.stored_keys().await.unwrap(), vec![valid_key]);
+}
+
+/// Once the value is found, the requests still in flight are dropped and the lookup ends
+/// without waiting for them.
+#[tokio::test]
+async fn test_find_value_early_termination() {
+    init();
+    let config = Discv5ConfigBuilder::new()
+        .request_timeout(Duration::from_secs(5))
+        .build();
+    let node = build_node_with_config(14133, config).await;
+    let holder = build_nodes(1, 14134).await.remove(0);
+
+    let key = NodeId::random();
+    let value = b"some value".to_vec();
+    store_at(&node, &holder, key, value.clone()).await;
+    node.add_enr(holder.local_enr()).unwrap();
+
+    // a peer that never responds, contacted alongside the holder
+    let silent_key = CombinedKey::generate_secp256k1();
+    let silent_peer = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(14135)
+        .build(&silent_key)
+        .unwrap();
+    node.add_enr(silent_peer).unwrap();
+
+    let values = node.find_value_query(key, None, Some(2), 1);
+    futures::pin_mut!(values);
+    assert_eq!(values.next().await, Some(Ok(value)));
+    let end = tokio::time::timeout(Duration::from_secs(1), values.next()).await;
+    assert_eq!(end, Ok(None));
+}
+
+#[tokio::test]
+async fn test_find_node_by_key() {
+    init();
+    let nodes = build_nodes(5, 14136).await;
+    for peer in &nodes[1..] {
+        nodes[0].add_enr(peer.local_enr()).unwrap();
+    }
+
+    // a content key, which is not the id of any node
+    let key = NodeId::random().raw();
+    let found = nodes[0].find_node_by_key(key).await.unwrap();
+    assert_eq!(found.len(), nodes.len() - 1);
+
+    // the nodes are ordered by their distance to the key
+    let target: kbucket::Key<NodeId> = NodeId::new(&key).into();
+    let distances: Vec<_> = found
+        .iter()
+        .map(|enr| target.distance(&kbucket::Key::from(enr.node_id())))
+        .collect();
+    let mut sorted = distances.clone();
+    sorted.sort();
+    assert_eq!(distances, sorted);
+}
+
+#[tokio::test]
+async fn test_update_local_enr_socket() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(14141)
+        .build(&enr_key)
+        .unwrap();
+    let config = Discv5ConfigBuilder::new()
+        .proactive_enr_updates(true)
+        .build();
+    let socket_addr = enr.udp4_socket().unwrap();
+    let mut node = Discv5::new(enr, enr_key, config).unwrap();
+    node.start(socket_addr.into()).await.unwrap();
+    let peer = build_nodes(1, 14142).await.remove(0);
+    peer.add_enr(node.local_enr()).unwrap();
+    node.add_enr(peer.local_enr()).unwrap();
+    node.ping(peer.local_enr()).await.unwrap();
+
+    let old_seq = node.local_enr().seq();
+    let new_socket: std::net::SocketAddrV4 = "127.0.0.2:14150".parse().unwrap();
+    assert!(node.update_local_enr_socket(new_socket.into(), false));
+    let enr = node.local_enr();
+    assert_eq!(enr.udp4_socket(), Some(new_socket));
+    assert_eq!(enr.seq(), old_seq + 1);
+    assert!(enr.verify());
+
+    // setting the same socket again leaves the ENR untouched
+    assert!(!node.update_local_enr_socket(new_socket.into(), false));
+    assert_eq!(node.local_enr().seq(), old_seq + 1);
+
+    // the connected peer is notified and learns the updated ENR
+    let node_id = enr.node_id();
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while peer.find_enr(&node_id).map(|enr| enr.udp4_socket()) != Some(Some(new_socket)) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("The peer didn't learn the updated ENR");
+}
+
+#[tokio::test]
+async fn test_bucket_index_of() {
+    init();
+    let nodes = build_nodes(2, 14143).await;
+    let local_id = nodes[0].local_enr().node_id();
+    let peer_id = nodes[1].local_enr().node_id();
+    nodes[0].add_enr(nodes[1].local_enr()).unwrap();
+
+    // a present node
+    assert!(nodes[0].contains_node(&peer_id));
+    assert_eq!(
+        nodes[0].bucket_index_of(&peer_id),
+        get_distance(local_id, peer_id).unwrap()
+    );
+
+    // an absent node
+    let absent_id = NodeId::random();
+    assert!(!nodes[0].contains_node(&absent_id));
+    assert_eq!(
+        nodes[0].bucket_index_of(&absent_id),
+        get_distance(local_id, absent_id).unwrap()
+    );
+    assert_eq!(nodes[0].bucket_index_of(&local_id), 0);
+}
+
+/// TALK requests of unregistered protocols surface as events and are answered through the
+/// request, or with an empty response once the response timeout elapses.
+#[tokio::test]
+async fn test_talk_request_event() {
+    init();
+    let config = Discv5ConfigBuilder::new()
+        .talk_response_timeout(Duration::from_millis(200))
+        .build();
+    let peer = build_node_with_config(14145, config).await;
+    let node = build_nodes(1, 14146).await.remove(0);
+    let mut peer_events = peer.event_stream().await.unwrap();
+
+    let talk = tokio::spawn(node.talk_req(peer.local_enr(), b"dyn".to_vec(), b"ping".to_vec()));
+    let request = loop {
+        match peer_events.recv().await.unwrap() {
+            Discv5Event::TalkRequest(request) => break request,
+            _ => continue,
+        }
+    };
+    assert_eq!(request.node_id(), &node.local_enr().node_id());
+    assert_eq!(request.protocol(), b"dyn");
+    assert_eq!(request.body(), b"ping");
+    request.respond(b"pong".to_vec()).unwrap();
+    assert_eq!(talk.await.unwrap().unwrap(), b"pong".to_vec());
+
+    // the peer holds on to the request past the response timeout
+    let talk = tokio::spawn(node.talk_req(peer.local_enr(), b"dyn".to_vec(), b"ping".to_vec()));
+    let request = loop {
+        match peer_events.recv().await.unwrap() {
+            Discv5Event::TalkRequest(request) => break request,
+            _ => continue,
+        }
+    };
+    assert!(talk.await.unwrap().unwrap().is_empty());
+    assert_eq!(
+        request.respond(b"pong".to_vec()),
+        Err(ResponseError::TimedOut)
+    );
+}
+
+/// Only the TALK requests of allowed protocols are dispatched, the others receive an empty
+/// response.
+#[tokio::test]
+async fn test_talk_protocols_allowed() {
+    init();
+    let config = Discv5ConfigBuilder::new()
+        .talk_protocols_allowed(vec![b"allowed".to_vec()])
+        .build();
+    let peer = build_node_with_config(14147, config).await;
+    let node = build_nodes(1, 14148).await.remove(0);
+
+    let (handler, mut requests) = tokio::sync::mpsc::channel::<TalkRequest>(10);
+    peer.register_talk_protocol(b"allowed".to_vec(), handler.clone());
+    peer.register_talk_protocol(b"blocked".to_vec(), handler);
+    let (dispatched_send, mut dispatched) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(request) = requests.recv().await {
+            let _ = dispatched_send.send(request.protocol().to_vec());
+            let response = request.body().to_vec();
+            request.respond(response).unwrap();
+        }
+    });
+
+    let response = node
+        .talk_req(peer.local_enr(), b"blocked".to_vec(), b"hello".to_vec())
+        .await
+        .unwrap();
+    assert!(response.is_empty());
+    let response = node
+        .talk_req(peer.local_enr(), b"allowed".to_vec(), b"hello".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(response, b"hello".to_vec());
+
+    // only the allowed protocol reached the handler
+    assert_eq!(dispatched.recv().await.unwrap(), b"allowed".to_vec());
+    assert!(dispatched.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_candidate_storage_nodes() {
+    init();
+    let node = build_nodes(1, 14149).await.remove(0);
+    let enrs: Vec<Enr<CombinedKey>> = (0..20)
+        .map(|i| {
+            let key = CombinedKey::generate_secp256k1();
+            EnrBuilder::new("v4")
+                .ip4("127.0.0.1".parse().unwrap())
+                .udp4(20000 + i)
+                .build(&key)
+                .unwrap()
+        })
+        .collect();
+    for enr in enrs.iter() {
+        let _ = node.add_enr(enr.clone());
+    }
+
+    let key = NodeId::random();
+    let target = kbucket::Key::from(key);
+    let mut expected = node.table_entries_id();
+    assert!(expected.len() > 5);
+    expected.sort_by_key(|node_id| target.distance(&kbucket::Key::from(*node_id)));
+    expected.truncate(5);
+
+    let candidates: Vec<NodeId> = node
+        .candidate_storage_nodes(key, 5)
+        .into_iter()
+        .map(|enr| enr.node_id())
+        .collect();
+    assert_eq!(candidates, expected);
+}
+
+/// Returns the sequence number of the next local ENR update reported to the event stream, if
+/// reported within a short timeout.
+async fn next_local_enr_update(
+    events: &mut tokio::sync::mpsc::Receiver<Discv5Event>,
+) -> Option<u64> {
+    tokio::time::timeout(Duration::from_millis(200), async {
+        loop {
+            if let Discv5Event::LocalEnrUpdated { seq } = events.recv().await? {
+                return Some(seq);
+            }
+        }
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Every update of the local ENR is reported once, with the new sequence number.
+#[tokio::test]
+async fn test_local_enr_updated_event() {
+    init();
+    let node = build_nodes(1, 14151).await.remove(0);
+    let mut events = node.event_stream().await.unwrap();
+    let seq = node.local_enr_seq();
+    node.enr_insert("eth2", b"fork").unwrap();
+    assert_eq!(node.local_enr_seq(), seq + 1);
+    assert_eq!(next_local_enr_update(&mut events).await, Some(seq + 1));
+
+    let new_socket: std::net::SocketAddrV4 = "127.0.0.2:14152".parse().unwrap();
+    assert!(node.update_local_enr_socket(new_socket.into(), false));
+    assert_eq!(next_local_enr_update(&mut events).await, Some(seq + 2));
+
+    // an unchanged socket is not signed again
+    assert!(!node.update_local_enr_socket(new_socket.into(), false));
+    assert_eq!(next_local_enr_update(&mut events).await, None);
+    assert_eq!(node.local_enr_seq(), seq + 2);
+}
+
+#[tokio::test]
+async fn test_find_node_raw() {
+    init();
+    let nodes = build_nodes(4, 14153).await;
+    // a custom field is kept in the raw encoding
+    nodes[1].enr_insert("custom", b"value").unwrap();
+    for peer in &nodes[1..] {
+        nodes[0].add_enr(peer.local_enr()).unwrap();
+    }
+    // the other peers return the target, which is then received during the request
+    for peer in &nodes[2..] {
+        peer.add_enr(nodes[1].local_enr()).unwrap();
+    }
+
+    let target = nodes[1].local_enr();
+    let found = nodes[0].find_node_raw(target.node_id()).await.unwrap();
+    let mut found_target = false;
+    for raw in found {
+        let enr: Enr<CombinedKey> = rlp::decode(&raw).unwrap();
+        assert!(nodes[1..].iter().any(|peer| peer.local_enr() == enr));
+        found_target |= enr == target;
+    }
+    assert!(found_target);
+}
+
+/// A PING from a node outside the routing table is reported.
+#[tokio::test]
+async fn test_incoming_ping_event() {
+    init();
+    // the node never inserts its peers, so the pinger stays outside the routing table
+    let config = Discv5ConfigBuilder::new()
+        .enr_filter(std::sync::Arc::new(|_: &crate::Enr| false))
+        .build();
+    let node = build_node_with_config(14157, config).await;
+    let pinger = build_nodes(1, 14158).await.remove(0);
+    let mut events = node.event_stream().await.unwrap();
+
+    pinger.ping(node.local_enr()).await.unwrap();
+    let incoming_ping = tokio::time::timeout(Duration::from_millis(200), async {
+        loop {
+            if let Discv5Event::IncomingPing { enr, from } = events.recv().await.unwrap() {
+                return (enr, from);
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(incoming_ping, (None, "127.0.0.1:14158".parse().unwrap()));
+    assert!(node.table_entries_id().is_empty());
+}
+
+/// A value too large for a single packet is sent in chunks and reassembled by the querying node.
+#[tokio::test]
+async fn test_find_value_chunked() {
+    init();
+    let config = Discv5ConfigBuilder::new().max_value_size(4000).build();
+    let node = build_node_with_config(14159, config).await;
+    let holder = build_nodes(1, 14160).await.remove(0);
+    node.add_enr(holder.local_enr()).unwrap();
+
+    let value: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    assert!(value.len() > 2 * crate::service::MAX_VALUE_CHUNK_SIZE);
+    let mut events = holder.event_stream().await.unwrap();
+    let response = value.clone();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if let Discv5Event::FindValue(request) = event {
+                request.respond(Some(response.clone())).unwrap();
+            }
+        }
+    });
+
+    let found = node.find_value(NodeId::random()).await.unwrap();
+    assert_eq!(found, value);
+}
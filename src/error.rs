@@ -33,6 +33,8 @@ pub enum Discv5Error {
     EncryptionFail(String),
     /// Failed to decrypt a message.
     DecryptionFailed(String),
+    /// The message reused the nonce of a message already received over the same session.
+    ReplayedNonce,
     /// The custom error has occurred.
     Custom(&'static str),
     /// A generic dynamic error occurred.
@@ -75,6 +77,8 @@ pub enum PacketError {
 pub enum ResponseError {
     /// The channel used to send the response has already been closed.
     ChannelClosed,
+    /// The request was already answered with an empty response, as it timed out.
+    TimedOut,
 }
 
 impl fmt::Display for ResponseError {
@@ -83,6 +87,9 @@ impl fmt::Display for ResponseError {
             ResponseError::ChannelClosed => {
                 write!(f, "response channel has already been closed")
             }
+            ResponseError::TimedOut => {
+                write!(f, "request was answered with an empty response after timing out")
+            }
         }
     }
 }
@@ -92,6 +99,17 @@ pub enum FindValueError  {
     /// The request timed out.
     RequestError(RequestError),
     RequestErrorWithEnrs((RequestError, Vec<Enr>)),
+    /// The lookup completed without any peer returning the value.
+    NotFound,
+    /// A value exceeded the maximum value size.
+    ValueTooLarge { size: usize, max: usize },
+    /// The lookup completed without the required number of peers returning identical values.
+    QuorumFailed,
+    /// A value's signature is invalid, was not made by the expected originator or could not
+    /// be created.
+    InvalidSignature,
+    /// A value was rejected by the configured store validator.
+    ValueRejected,
 }
 
 impl std::error::Error for ResponseError {}
@@ -100,6 +118,9 @@ impl std::error::Error for ResponseError {}
 pub enum RequestError {
     /// The request timed out.
     Timeout,
+    /// The session the request was sent over no longer exists. A new request performs a new
+    /// handshake.
+    SessionExpired,
     /// The discovery service has not been started.
     ServiceNotStarted,
     /// The request was sent to ourselves.
@@ -132,6 +153,8 @@ pub enum QueryError {
     EncryptionFailed(String),
     /// The multiaddr provided was invalid.
     InvalidMultiaddr(String),
+    /// The requested distances were invalid.
+    InvalidDistances(String),
 }
 
 impl fmt::Display for Discv5Error {
@@ -2,34 +2,110 @@ use super::*;
 use delay_map::HashMapDelay;
 use more_asserts::debug_unreachable;
 
+/// The timeouts of the requests, by type of request.
+#[derive(Clone, Copy)]
+pub(crate) struct RequestTimeouts {
+    /// The timeout of the requests without a specific timeout.
+    default: Duration,
+    /// The timeout of PING requests, if different from the default.
+    ping: Option<Duration>,
+    /// The timeout of FINDNODE requests, if different from the default.
+    find_node: Option<Duration>,
+    /// The timeout of TALKREQ requests, if different from the default.
+    talk: Option<Duration>,
+    /// The delay before the first retransmission of a request, if requests are retransmitted
+    /// with an exponential backoff.
+    retransmit_interval: Option<Duration>,
+    /// The number of attempts of each request.
+    retries: u8,
+}
+
+impl RequestTimeouts {
+    pub(crate) fn new(config: &Discv5Config) -> Self {
+        RequestTimeouts {
+            default: config.request_timeout,
+            ping: config.ping_request_timeout,
+            find_node: config.find_node_request_timeout,
+            talk: config.talk_request_timeout,
+            retransmit_interval: config.request_retransmit_interval,
+            retries: config.request_retries,
+        }
+    }
+
+    /// The time to wait for a response to the current attempt of a request.
+    pub(crate) fn timeout(&self, request_call: &RequestCall) -> Duration {
+        let timeout = match request_call.request.body {
+            RequestBody::Ping { .. } => self.ping,
+            RequestBody::FindNode { .. } => self.find_node,
+            RequestBody::Talk { .. } => self.talk,
+            _ => None,
+        }
+        .unwrap_or(self.default);
+        match self.retransmit_interval {
+            // the last attempt waits the full timeout
+            Some(interval) if request_call.retries < self.retries => {
+                let backoff = 2u32.saturating_pow(u32::from(request_call.retries - 1));
+                std::cmp::min(interval.saturating_mul(backoff), timeout)
+            }
+            _ => timeout,
+        }
+    }
+}
+
 pub(crate) struct ActiveRequests {
-    /// A list of raw messages we are awaiting a response from the remote.
-    active_requests_mapping: HashMapDelay<NodeAddress, RequestCall>,
+    /// The timeouts of the requests.
+    timeouts: RequestTimeouts,
+    /// The raw messages we are awaiting a response from the remote, by node address. Several
+    /// requests may be in flight to a node over an established session.
+    active_requests_mapping: HashMap<NodeAddress, Vec<RequestCall>>,
     // WHOAREYOU messages do not include the source node id. We therefore maintain another
     // mapping of active_requests via message_nonce. This allows us to match WHOAREYOU
     // requests with active requests sent.
-    /// A mapping of all pending active raw requests message nonces to their NodeAddress.
-    active_requests_nonce_mapping: HashMap<MessageNonce, NodeAddress>,
+    /// A mapping of all pending active raw requests message nonces to their NodeAddress, expiring
+    /// after the timeout of the current attempt of the request.
+    active_requests_nonce_mapping: HashMapDelay<MessageNonce, NodeAddress>,
 }
 
 impl ActiveRequests {
-    pub(crate) fn new(request_timeout: Duration) -> Self {
+    pub(crate) fn new(timeouts: RequestTimeouts) -> Self {
         ActiveRequests {
-            active_requests_mapping: HashMapDelay::new(request_timeout),
-            active_requests_nonce_mapping: HashMap::new(),
+            timeouts,
+            active_requests_mapping: HashMap::new(),
+            active_requests_nonce_mapping: HashMapDelay::new(timeouts.default),
         }
     }
 
+    /// Inserts a request call, expiring after the timeout of its current attempt.
     pub(crate) fn insert(&mut self, node_address: NodeAddress, request_call: RequestCall) {
         let nonce = *request_call.packet.message_nonce();
-        self.active_requests_mapping
-            .insert(node_address.clone(), request_call);
+        let timeout = self.timeouts.timeout(&request_call);
         self.active_requests_nonce_mapping
-            .insert(nonce, node_address);
+            .insert_at(nonce, node_address.clone(), timeout);
+        self.active_requests_mapping
+            .entry(node_address)
+            .or_default()
+            .push(request_call);
+    }
+
+    /// Returns whether any request is awaiting a response from a node.
+    pub(crate) fn contains(&self, node_address: &NodeAddress) -> bool {
+        self.active_requests_mapping.contains_key(node_address)
+    }
+
+    /// Returns the number of requests awaiting a response from a node.
+    pub(crate) fn in_flight(&self, node_address: &NodeAddress) -> usize {
+        self.active_requests_mapping
+            .get(node_address)
+            .map_or(0, |requests| requests.len())
     }
 
-    pub(crate) fn get(&self, node_address: &NodeAddress) -> Option<&RequestCall> {
-        self.active_requests_mapping.get(node_address)
+    /// Returns whether a request to a node is establishing a session with it.
+    pub(crate) fn awaiting_session(&self, node_address: &NodeAddress) -> bool {
+        self.active_requests_mapping
+            .get(node_address)
+            .into_iter()
+            .flatten()
+            .any(|request| request.awaiting_session())
     }
 
     pub(crate) fn remove_by_nonce(
@@ -37,7 +113,9 @@ impl ActiveRequests {
         nonce: &MessageNonce,
     ) -> Option<(NodeAddress, RequestCall)> {
         match self.active_requests_nonce_mapping.remove(nonce) {
-            Some(node_address) => match self.active_requests_mapping.remove(&node_address) {
+            Some(node_address) => match self.remove_from_mapping(&node_address, |request| {
+                request.packet.message_nonce() == nonce
+            }) {
                 Some(request_call) => Some((node_address, request_call)),
                 None => {
                     debug_unreachable!("A matching request call doesn't exist");
@@ -49,26 +127,56 @@ impl ActiveRequests {
         }
     }
 
-    pub(crate) fn remove(&mut self, node_address: &NodeAddress) -> Option<RequestCall> {
-        match self.active_requests_mapping.remove(node_address) {
-            Some(request_call) => {
-                // Remove the associated nonce mapping.
-                match self
-                    .active_requests_nonce_mapping
-                    .remove(request_call.packet.message_nonce())
-                {
-                    Some(_) => Some(request_call),
-                    None => {
-                        debug_unreachable!("A matching nonce mapping doesn't exist");
-                        error!("A matching nonce mapping doesn't exist");
-                        None
-                    }
-                }
+    /// Removes the request to a node with the given id.
+    pub(crate) fn remove_request(
+        &mut self,
+        node_address: &NodeAddress,
+        id: &RequestId,
+    ) -> Option<RequestCall> {
+        let request_call = self.remove_from_mapping(node_address, |request| request.id() == id)?;
+        // Remove the associated nonce mapping.
+        match self
+            .active_requests_nonce_mapping
+            .remove(request_call.packet.message_nonce())
+        {
+            Some(_) => Some(request_call),
+            None => {
+                debug_unreachable!("A matching nonce mapping doesn't exist");
+                error!("A matching nonce mapping doesn't exist");
+                None
             }
-            None => None,
         }
     }
 
+    /// Removes all the requests to a node.
+    pub(crate) fn remove_requests(&mut self, node_address: &NodeAddress) -> Vec<RequestCall> {
+        let requests = self
+            .active_requests_mapping
+            .remove(node_address)
+            .unwrap_or_default();
+        for request in requests.iter() {
+            self.active_requests_nonce_mapping
+                .remove(request.packet.message_nonce());
+        }
+        requests
+    }
+
+    /// Removes the first request to a node matching the predicate from the requests by node
+    /// address, leaving the nonce mapping untouched.
+    fn remove_from_mapping(
+        &mut self,
+        node_address: &NodeAddress,
+        predicate: impl Fn(&RequestCall) -> bool,
+    ) -> Option<RequestCall> {
+        let requests = self.active_requests_mapping.get_mut(node_address)?;
+        let position = requests.iter().position(predicate)?;
+        let request_call = requests.remove(position);
+        if requests.is_empty() {
+            self.active_requests_mapping.remove(node_address);
+        }
+        Some(request_call)
+    }
+
     /// Checks that `active_requests_mapping` and `active_requests_nonce_mapping` are in sync.
     // this function is only available in tests
     #[cfg(test)]
@@ -76,17 +184,28 @@ impl ActiveRequests {
     // function.
     #[track_caller]
     pub(crate) fn check_invariant(&self) {
-        // First check that for every `MessageNonce` there is an associated `NodeAddress`.
+        // First check that for every `MessageNonce` there is an associated request.
         for (nonce, address) in self.active_requests_nonce_mapping.iter() {
-            if !self.active_requests_mapping.contains_key(address) {
-                panic!("Nonce {:?} maps to address {}, which does not exist in `active_requests_mapping`", nonce, address);
+            let exists = self
+                .active_requests_mapping
+                .get(address)
+                .into_iter()
+                .flatten()
+                .any(|request| request.packet.message_nonce() == nonce);
+            if !exists {
+                panic!("Nonce {:?} maps to address {}, which does not hold a matching request in `active_requests_mapping`", nonce, address);
             }
         }
 
-        for (address, request) in self.active_requests_mapping.iter() {
-            let nonce = request.packet.message_nonce();
-            if !self.active_requests_nonce_mapping.contains_key(nonce) {
-                panic!("Address {} maps to request with nonce {:?}, which does not exist in `active_requests_nonce_mapping`", address, nonce);
+        for (address, requests) in self.active_requests_mapping.iter() {
+            if requests.is_empty() {
+                panic!("Address {} maps to no requests", address);
+            }
+            for request in requests {
+                let nonce = request.packet.message_nonce();
+                if !self.active_requests_nonce_mapping.contains_key(nonce) {
+                    panic!("Address {} maps to request with nonce {:?}, which does not exist in `active_requests_nonce_mapping`", address, nonce);
+                }
             }
         }
     }
@@ -95,12 +214,18 @@ impl ActiveRequests {
 impl Stream for ActiveRequests {
     type Item = Result<(NodeAddress, RequestCall), String>;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match self.active_requests_mapping.poll_next_unpin(cx) {
-            Poll::Ready(Some(Ok((node_address, request_call)))) => {
-                // Remove the associated nonce mapping.
-                self.active_requests_nonce_mapping
-                    .remove(request_call.packet.message_nonce());
-                Poll::Ready(Some(Ok((node_address, request_call))))
+        match self.active_requests_nonce_mapping.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok((nonce, node_address)))) => {
+                // Remove the associated request.
+                match self.remove_from_mapping(&node_address, |request| {
+                    request.packet.message_nonce() == &nonce
+                }) {
+                    Some(request_call) => Poll::Ready(Some(Ok((node_address, request_call)))),
+                    None => {
+                        debug_unreachable!("A matching request call doesn't exist");
+                        Poll::Ready(Some(Err("A matching request call doesn't exist".into())))
+                    }
+                }
             }
             Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(err))),
             Poll::Ready(None) => Poll::Ready(None),
@@ -27,13 +27,13 @@
 //! Messages from a node on the network come by [`Socket`] and get the form of a [`HandlerOut`]
 //! and can be forwarded to the application layer via the send channel.
 use crate::{
-    config::Discv5Config,
+    config::{Discv5Config, HandshakeFilter},
     discv5::PERMIT_BAN_LIST,
     error::{Discv5Error, RequestError},
     packet::{ChallengeData, IdNonce, MessageNonce, Packet, PacketKind},
     rpc::{Message, Request, RequestBody, RequestId, Response, ResponseBody},
     socket,
-    socket::{FilterConfig, Socket},
+    socket::{FilterConfig, Limiter, Quota, Socket},
     Enr,
 };
 use delay_map::HashMapDelay;
@@ -44,7 +44,7 @@ use std::{
     collections::HashMap,
     convert::TryFrom,
     default::Default,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
     sync::{atomic::Ordering, Arc},
     task::{Context, Poll},
@@ -55,15 +55,17 @@ use tracing::{debug, error, trace, warn};
 
 mod active_requests;
 mod crypto;
+mod nonce_counter;
 mod session;
 mod tests;
 
 pub use crate::node_info::{NodeAddress, NodeContact};
 
-use crate::metrics::METRICS;
+use crate::metrics::{GaugeShare, METRICS};
 
 use crate::lru_time_cache::LruTimeCache;
-use active_requests::ActiveRequests;
+use active_requests::{ActiveRequests, RequestTimeouts};
+use nonce_counter::PersistedNonceCounter;
 use session::Session;
 
 // The time interval to check banned peer timeouts and unban peers when the timeout has elapsed (in
@@ -71,7 +73,7 @@ use session::Session;
 const BANNED_NODES_CHECK: u64 = 300; // Check every 5 minutes.
 
 /// Messages sent from the application layer to `Handler`.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
 pub enum HandlerIn {
     /// A Request to send to a `NodeContact` has been received from the application layer. A
@@ -100,6 +102,17 @@ pub enum HandlerIn {
     /// The `WhoAreYouRef` is sent out in the `HandlerOut::WhoAreYou` event and should
     /// be returned here to submit the application's response.
     WhoAreYou(WhoAreYouRef, Option<Enr>),
+
+    /// Drops the sessions established with a node. Further requests to the node establish a new
+    /// session.
+    Disconnect(NodeId),
+
+    /// Establishes fresh keys for the sessions with a node through a new handshake, performed
+    /// with the next request to the node. The current keys remain usable until then.
+    Rekey(NodeId),
+
+    /// Returns whether an established session exists with a node.
+    IsConnected(NodeId, oneshot::Sender<bool>),
 }
 
 /// Messages sent between a node on the network and `Handler`.
@@ -126,6 +139,24 @@ pub enum HandlerOut {
     ///
     /// This returns the request ID and an error indicating why the request failed.
     RequestFailed(RequestId, RequestError),
+
+    /// A handshake with a node failed.
+    HandshakeFailed(Option<NodeId>, HandshakeFailureReason),
+}
+
+/// Why a handshake failed.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum HandshakeFailureReason {
+    /// The node didn't complete the handshake in time.
+    Timeout,
+    /// The node signed the challenge with an invalid signature.
+    InvalidSignature,
+    /// The ENR of the node is invalid or doesn't match the address it contacted us from.
+    InvalidEnr,
+    /// The incoming handshake filter rejected the node.
+    Rejected,
+    /// The node sent an unexpected or malformed handshake packet.
+    InvalidPacket,
 }
 
 /// How we connected to the node.
@@ -163,12 +194,15 @@ pub(crate) struct RequestCall {
     handshake_sent: bool,
     /// The number of times this request has been re-sent.
     retries: u8,
-    /// If we receive a Nodes Response with a total greater than 1. This keeps track of the
-    /// remaining responses expected.
+    /// If we receive a Nodes Response or a value chunk with a total greater than 1. This keeps
+    /// track of the remaining responses expected.
     remaining_responses: Option<u64>,
     /// Signifies if we are initiating the session with a random packet. This is only used to
     /// determine the connection direction of the session.
     initiating_session: bool,
+    /// Signifies if we sent a random packet, prompting a handshake which establishes a new
+    /// session or renews the keys of the current one.
+    random_packet: bool,
 }
 
 impl RequestCall {
@@ -177,6 +211,7 @@ impl RequestCall {
         packet: Packet,
         request: Request,
         initiating_session: bool,
+        random_packet: bool,
     ) -> Self {
         RequestCall {
             contact,
@@ -186,18 +221,31 @@ impl RequestCall {
             retries: 1,
             remaining_responses: None,
             initiating_session,
+            random_packet,
         }
     }
 
     fn id(&self) -> &RequestId {
         &self.request.id
     }
+
+    /// Whether the request awaits the handshake of a session, without which further requests to
+    /// the node can not be sent.
+    fn awaiting_session(&self) -> bool {
+        self.random_packet || self.handshake_sent
+    }
 }
 
 /// Process to handle handshakes and sessions established from raw RPC communications between nodes.
 pub struct Handler {
     /// Configuration for the discv5 service.
     request_retries: u8,
+    /// The number of messages encrypted with a session's keys before they are renewed.
+    session_rekey_threshold: u32,
+    /// The maximum number of requests in flight to a node over a session at once, if any.
+    max_requests_per_session: Option<usize>,
+    /// The counter of the message nonces persisted across restarts, if configured.
+    nonce_counter: Option<PersistedNonceCounter>,
     /// The local node id to save unnecessary read locks on the ENR. The NodeID should not change
     /// during the operation of the server.
     node_id: NodeId,
@@ -215,12 +263,26 @@ pub struct Handler {
     active_challenges: HashMapDelay<NodeAddress, Challenge>,
     /// Established sessions with peers.
     sessions: LruTimeCache<NodeAddress, Session>,
+    /// The sessions this handler counts in the active sessions metric.
+    sessions_gauge: GaugeShare,
+    /// The handshakes this handler counts in the pending handshakes metric.
+    handshakes_gauge: GaugeShare,
     /// The channel to receive messages from the application layer.
     service_recv: mpsc::UnboundedReceiver<HandlerIn>,
     /// The channel to send messages to the application layer.
     service_send: mpsc::Sender<HandlerOut>,
-    /// The listening socket to filter out any attempted requests to self.
-    listen_socket: SocketAddr,
+    /// Limits the requests accepted from each node, if configured.
+    request_rate_limiter: Option<Limiter<NodeId>>,
+    /// Limits the WHOAREYOU challenges sent in response to unknown packets, if configured.
+    whoareyou_rate_limiter: Option<Limiter<()>>,
+    /// Limits the WHOAREYOU challenges sent to each IP, if configured.
+    whoareyou_ip_rate_limiter: Option<Limiter<IpAddr>>,
+    /// Approves the handshakes initiated by peers, based on their ENR, if configured.
+    incoming_handshake_filter: Option<HandshakeFilter>,
+    /// The time the rate limiters measure time from.
+    request_rate_limiter_start: Instant,
+    /// The listening sockets to filter out any attempted requests to self.
+    listen_sockets: Vec<SocketAddr>,
     /// The discovery v5 UDP socket tasks.
     socket: Socket,
     /// Exit channel to shutdown the handler.
@@ -253,6 +315,13 @@ impl Handler {
         // The local node id
         let node_id = enr.read().node_id();
 
+        // resume the persisted nonce counter, if any
+        let nonce_counter = config
+            .nonce_persistence_path
+            .clone()
+            .map(PersistedNonceCounter::load)
+            .transpose()?;
+
         // enable the packet filter if required
 
         let filter_config = FilterConfig {
@@ -269,17 +338,44 @@ impl Handler {
             local_node_id: node_id,
             expected_responses: filter_expected_responses.clone(),
             ban_duration: config.ban_duration,
+            packet_filter: config.packet_filter.clone(),
         };
 
-        // Attempt to bind to the socket before spinning up the send/recv tasks.
-        let socket = socket::Socket::new_socket(&socket_config.socket_addr, config.ip_mode).await?;
+        let request_rate_limiter = config
+            .per_node_request_rate_limit
+            .map(|(n, time_period)| Limiter::from_quota(Quota::n_every(n, time_period)))
+            .transpose()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let whoareyou_rate_limiter = config
+            .whoareyou_rate_limit
+            .map(|(n, time_period)| Limiter::from_quota(Quota::n_every(n, time_period)))
+            .transpose()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let whoareyou_ip_rate_limiter = config
+            .whoareyou_rate_limit_per_ip
+            .map(|(n, time_period)| Limiter::from_quota(Quota::n_every(n, time_period)))
+            .transpose()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+        // Attempt to bind to the sockets before spinning up the send/recv tasks.
+        let sockets = socket::Socket::bind_sockets(
+            &socket_config.socket_addr,
+            config.listen_addresses,
+            config.ip_mode,
+            socket::BufferSizes {
+                recv: config.recv_buffer_size,
+                send: config.send_buffer_size,
+            },
+        )
+        .await?;
+        let listen_sockets = sockets.local_addrs();
 
         config
             .executor
             .clone()
             .expect("Executor must be present")
             .spawn(Box::pin(async move {
-                let socket = match socket::Socket::new(socket, socket_config) {
+                let socket = match socket::Socket::new(sockets, socket_config) {
                     Ok(v) => v,
                     Err(e) => {
                         error!("Could not bind UDP socket. {}", e);
@@ -289,20 +385,32 @@ impl Handler {
 
                 let mut handler = Handler {
                     request_retries: config.request_retries,
+                    session_rekey_threshold: config.session_rekey_threshold,
+                    max_requests_per_session: config.max_requests_per_session,
+                    nonce_counter,
                     node_id,
                     enr,
                     key,
-                    active_requests: ActiveRequests::new(config.request_timeout),
+                    active_requests: ActiveRequests::new(RequestTimeouts::new(&config)),
                     pending_requests: HashMap::new(),
                     filter_expected_responses,
                     sessions: LruTimeCache::new(
                         config.session_timeout,
                         Some(config.session_cache_capacity),
                     ),
-                    active_challenges: HashMapDelay::new(config.request_timeout),
+                    active_challenges: HashMapDelay::new(
+                        config.challenge_timeout.unwrap_or(config.request_timeout),
+                    ),
+                    sessions_gauge: GaugeShare::default(),
+                    handshakes_gauge: GaugeShare::default(),
                     service_recv,
                     service_send,
-                    listen_socket,
+                    request_rate_limiter,
+                    whoareyou_rate_limiter,
+                    whoareyou_ip_rate_limiter,
+                    incoming_handshake_filter: config.incoming_handshake_filter.clone(),
+                    request_rate_limiter_start: Instant::now(),
+                    listen_sockets,
                     socket,
                     exit,
                 };
@@ -332,6 +440,11 @@ impl Handler {
                         }
                         HandlerIn::Response(dst, response) => self.send_response(dst, *response).await,
                         HandlerIn::WhoAreYou(wru_ref, enr) => self.send_challenge(wru_ref, enr).await,
+                        HandlerIn::Disconnect(node_id) => self.disconnect(&node_id).await,
+                        HandlerIn::Rekey(node_id) => self.rekey(&node_id),
+                        HandlerIn::IsConnected(node_id, callback) => {
+                            let _ = callback.send(self.is_connected(&node_id));
+                        }
                     }
                 }
                 Some(inbound_packet) = self.socket.recv.recv() => {
@@ -343,10 +456,20 @@ impl Handler {
                 Some(Ok((node_address, _challenge))) = self.active_challenges.next() => {
                     // A challenge has expired. There could be pending requests awaiting this
                     // challenge. We process them here
+                    METRICS.expired_challenges.fetch_add(1, Ordering::Relaxed);
+                    self.update_session_metrics();
+                    self.handshake_failed(Some(node_address.node_id), HandshakeFailureReason::Timeout)
+                        .await;
                     self.send_next_request(node_address).await;
                 }
-                _ = banned_nodes_check.tick() => self.unban_nodes_check(), // Unban nodes that are past the timeout
+                _ = banned_nodes_check.tick() => {
+                    self.unban_nodes_check(); // Unban nodes that are past the timeout
+                    self.prune_rate_limiters();
+                }
                 _ = &mut self.exit => {
+                    // the sessions of this handler are no longer active
+                    self.sessions_gauge.set(&METRICS.active_sessions, 0);
+                    self.handshakes_gauge.set(&METRICS.pending_handshakes, 0);
                     return;
                 }
             }
@@ -436,9 +559,21 @@ impl Handler {
             trace!("Request timed out with {}", node_address);
             // Remove the request from the awaiting packet_filter
             self.remove_expected_response(node_address.socket_addr);
-            // The request has timed out. We keep any established session for future use.
-            self.fail_request(request_call, RequestError::Timeout, false)
-                .await;
+            // The request has timed out. We keep any established session for future use, unless
+            // the session the request was sent over has expired in the meantime.
+            let error = if !request_call.initiating_session
+                && self.sessions.peek(&node_address).is_none()
+            {
+                RequestError::SessionExpired
+            } else {
+                RequestError::Timeout
+            };
+            // the node never answered the handshake we sent
+            if request_call.handshake_sent {
+                self.handshake_failed(Some(node_address.node_id), HandshakeFailureReason::Timeout)
+                    .await;
+            }
+            self.fail_request(request_call, error, false).await;
         } else {
             // increment the request retry count and restart the timeout
             trace!(
@@ -446,6 +581,24 @@ impl Handler {
                 request_call.request,
                 node_address
             );
+            // A message sent over a session is encrypted again under a fresh nonce, as the
+            // remote drops replayed nonces if it received the original message. Random and
+            // handshake packets are resent as is, to match the WHOAREYOU of the remote.
+            if !request_call.initiating_session && !request_call.handshake_sent {
+                let rekey_threshold = self.session_rekey_threshold;
+                if let Some(session) = self.sessions.get_mut(&node_address) {
+                    if !session.needs_rekey(rekey_threshold) {
+                        match session.encrypt_message(
+                            self.node_id,
+                            &request_call.request.clone().encode(),
+                            self.nonce_counter.as_mut(),
+                        ) {
+                            Ok(packet) => request_call.packet = packet,
+                            Err(e) => warn!("Could not encrypt the resent message: {:?}", e),
+                        }
+                    }
+                }
+            }
             self.send(node_address.clone(), request_call.packet.clone())
                 .await;
             request_call.retries += 1;
@@ -461,15 +614,15 @@ impl Handler {
     ) -> Result<(), RequestError> {
         let node_address = contact.node_address();
 
-        if node_address.socket_addr == self.listen_socket {
+        if self.listen_sockets.contains(&node_address.socket_addr) {
             debug!("Filtered request to self");
             return Err(RequestError::SelfRequest);
         }
 
-        // If there is already an active request or an active challenge (WHOAREYOU sent) for this node, add to pending requests
-        if self.active_requests.get(&node_address).is_some()
-            || self.active_challenges.get(&node_address).is_some()
-        {
+        // If a session with this node is being established, or the requests in flight to it are
+        // at the limit, add to pending requests. Requests over an established session are
+        // otherwise sent right away, alongside those already in flight.
+        if self.queues_requests(&node_address) {
             trace!("Request queued for node: {}", node_address);
             self.pending_requests
                 .entry(node_address)
@@ -478,13 +631,27 @@ impl Handler {
             return Ok(());
         }
 
-        let (packet, initiating_session) = {
+        let (packet, initiating_session, random_packet) = {
+            let rekey_threshold = self.session_rekey_threshold;
             if let Some(session) = self.sessions.get_mut(&node_address) {
-                // Encrypt the message and send
-                let packet = session
-                    .encrypt_message(self.node_id, &request.clone().encode())
-                    .map_err(|e| RequestError::EncryptionFailed(format!("{:?}", e)))?;
-                (packet, false)
+                if session.needs_rekey(rekey_threshold) {
+                    // Send a random packet, prompting a new handshake. The session is kept to
+                    // decrypt the responses still in flight, until the fresh keys replace it.
+                    debug!("Renewing the session keys with: {}", node_address);
+                    let packet =
+                        Packet::new_random(&self.node_id).map_err(RequestError::EntropyFailure)?;
+                    (packet, false, true)
+                } else {
+                    // Encrypt the message and send
+                    let packet = session
+                        .encrypt_message(
+                            self.node_id,
+                            &request.clone().encode(),
+                            self.nonce_counter.as_mut(),
+                        )
+                        .map_err(|e| RequestError::EncryptionFailed(format!("{:?}", e)))?;
+                    (packet, false, false)
+                }
             } else {
                 // No session exists, start a new handshake
                 trace!(
@@ -494,11 +661,17 @@ impl Handler {
                 let packet =
                     Packet::new_random(&self.node_id).map_err(RequestError::EntropyFailure)?;
                 // We are initiating a new session
-                (packet, true)
+                (packet, true, true)
             }
         };
 
-        let call = RequestCall::new(contact, packet.clone(), request, initiating_session);
+        let call = RequestCall::new(
+            contact,
+            packet.clone(),
+            request,
+            initiating_session,
+            random_packet,
+        );
         // let the filter know we are expecting a response
         self.add_expected_response(node_address.socket_addr);
         self.send(node_address.clone(), packet).await;
@@ -512,7 +685,11 @@ impl Handler {
         // Check for an established session
         if let Some(session) = self.sessions.get_mut(&node_address) {
             // Encrypt the message and send
-            let packet = match session.encrypt_message(self.node_id, &response.encode()) {
+            let packet = match session.encrypt_message(
+                self.node_id,
+                &response.encode(),
+                self.nonce_counter.as_mut(),
+            ) {
                 Ok(packet) => packet,
                 Err(e) => {
                     warn!("Could not encrypt response: {:?}", e);
@@ -567,6 +744,7 @@ impl Handler {
                 remote_enr,
             },
         );
+        self.update_session_metrics();
     }
 
     /* Packet Handling */
@@ -620,6 +798,11 @@ impl Handler {
                 "Authentication response already sent. Dropping session. Node: {}",
                 request_call.contact
             );
+            self.handshake_failed(
+                Some(request_call.contact.node_id()),
+                HandshakeFailureReason::InvalidPacket,
+            )
+            .await;
             self.fail_request(request_call, RequestError::InvalidRemotePacket, true)
                 .await;
             return;
@@ -646,6 +829,11 @@ impl Handler {
             Ok(v) => v,
             Err(e) => {
                 error!("Could not generate a session. Error: {:?}", e);
+                self.handshake_failed(
+                    Some(request_call.contact.node_id()),
+                    HandshakeFailureReason::InvalidPacket,
+                )
+                .await;
                 self.fail_request(request_call, RequestError::InvalidRemotePacket, true)
                     .await;
                 return;
@@ -743,6 +931,15 @@ impl Handler {
             }
     }
 
+    /// Whether the handshake initiated by the peer of `enr` is approved by the incoming handshake
+    /// filter, if configured.
+    fn allows_handshake(&self, enr: &Enr) -> bool {
+        match &self.incoming_handshake_filter {
+            Some(filter) => filter(enr),
+            None => true,
+        }
+    }
+
     /// Handle a message that contains an authentication header.
     #[allow(clippy::too_many_arguments)]
     async fn handle_auth_message(
@@ -764,6 +961,7 @@ impl Handler {
         );
 
         if let Some(challenge) = self.active_challenges.remove(&node_address) {
+            self.update_session_metrics();
             match Session::establish_from_challenge(
                 self.key.clone(),
                 &self.node_id,
@@ -776,7 +974,16 @@ impl Handler {
                 Ok((session, enr)) => {
                     // Receiving an AuthResponse must give us an up-to-date view of the node ENR.
                     // Verify the ENR is valid
-                    if self.verify_enr(&enr, &node_address) {
+                    if !self.allows_handshake(&enr) {
+                        debug!("Handshake rejected by the filter. Node: {}", node_address);
+                        self.handshake_failed(
+                            Some(node_address.node_id),
+                            HandshakeFailureReason::Rejected,
+                        )
+                        .await;
+                        self.fail_session(&node_address, RequestError::InvalidRemoteEnr, true)
+                            .await;
+                    } else if self.verify_enr(&enr, &node_address) {
                         // Session is valid
                         // Notify the application
                         // The session established here are from WHOAREYOU packets that we sent.
@@ -811,6 +1018,11 @@ impl Handler {
                             enr.udp6_socket(),
                             node_address
                         );
+                        self.handshake_failed(
+                            Some(node_address.node_id),
+                            HandshakeFailureReason::InvalidEnr,
+                        )
+                        .await;
                         self.fail_session(&node_address, RequestError::InvalidRemoteEnr, true)
                             .await;
                     }
@@ -820,14 +1032,25 @@ impl Handler {
                         "Authentication header contained invalid signature. Ignoring packet from: {}",
                         node_address
                     );
+                    self.handshake_failed(
+                        Some(node_address.node_id),
+                        HandshakeFailureReason::InvalidSignature,
+                    )
+                    .await;
                     // insert back the challenge
                     self.active_challenges.insert(node_address, challenge);
+                    self.update_session_metrics();
                 }
                 Err(e) => {
                     warn!(
                         "Invalid Authentication header. Dropping session. Error: {:?}",
                         e
                     );
+                    self.handshake_failed(
+                        Some(node_address.node_id),
+                        HandshakeFailureReason::InvalidPacket,
+                    )
+                    .await;
                     self.fail_session(&node_address, RequestError::InvalidRemotePacket, true)
                         .await;
                 }
@@ -840,34 +1063,51 @@ impl Handler {
         }
     }
 
+    /// Sends the requests awaiting the session with a node, until one of them has to establish
+    /// the session anew.
     async fn send_next_request(&mut self, node_address: NodeAddress) {
-        // ensure we are not over writing any existing requests
-        if self.active_requests.get(&node_address).is_none() {
-            if let std::collections::hash_map::Entry::Occupied(mut entry) =
-                self.pending_requests.entry(node_address)
-            {
-                // If it exists, there must be a request here
-                let (contact, request) = entry.get_mut().remove(0);
-                if entry.get().is_empty() {
-                    entry.remove();
-                }
-                let id = request.id.clone();
-                trace!("Sending next awaiting message. Node: {}", contact);
-                if let Err(request_error) = self.send_request(contact, request).await {
-                    warn!("Failed to send next awaiting request {}", request_error);
-                    // Inform the service that the request failed
-                    if let Err(e) = self
-                        .service_send
-                        .send(HandlerOut::RequestFailed(id, request_error))
-                        .await
-                    {
-                        warn!("Failed to inform that request failed {}", e);
+        // stop once a request has to wait for the handshake of the session
+        while !self.queues_requests(&node_address) {
+            let (contact, request) = match self.pending_requests.entry(node_address.clone()) {
+                std::collections::hash_map::Entry::Occupied(mut entry) => {
+                    // If it exists, there must be a request here
+                    let next = entry.get_mut().remove(0);
+                    if entry.get().is_empty() {
+                        entry.remove();
                     }
+                    next
+                }
+                std::collections::hash_map::Entry::Vacant(_) => return,
+            };
+            let id = request.id.clone();
+            trace!("Sending next awaiting message. Node: {}", contact);
+            if let Err(request_error) = self.send_request(contact, request).await {
+                warn!("Failed to send next awaiting request {}", request_error);
+                // Inform the service that the request failed
+                if let Err(e) = self
+                    .service_send
+                    .send(HandlerOut::RequestFailed(id, request_error))
+                    .await
+                {
+                    warn!("Failed to inform that request failed {}", e);
                 }
             }
         }
     }
 
+    /// Whether requests to a node are queued, rather than sent, as a session with the node is
+    /// being established or the requests in flight to the node are at the limit.
+    fn queues_requests(&self, node_address: &NodeAddress) -> bool {
+        self.active_challenges.get(node_address).is_some()
+            || self.active_requests.awaiting_session(node_address)
+            || (self.sessions.peek(node_address).is_none()
+                && self.active_requests.contains(node_address))
+            || matches!(
+                self.max_requests_per_session,
+                Some(max) if self.active_requests.in_flight(node_address) >= max
+            )
+    }
+
     /// Handle a standard message that does not contain an authentication header.
     #[allow(clippy::single_match)]
     async fn handle_message(
@@ -889,6 +1129,13 @@ impl Handler {
                         return;
                     }
                 },
+                Err(Discv5Error::ReplayedNonce) => {
+                    // The packet is a replay of a message already received, or the peer reused a
+                    // nonce. Drop it, keeping the session.
+                    debug!("Dropping packet with a reused nonce from {}", node_address);
+                    METRICS.replayed_packets.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
                 Err(e) => {
                     // We have a session, but the message could not be decrypted. It is likely the node
                     // sending this message has dropped their session. In this case, this message is a
@@ -903,7 +1150,14 @@ impl Handler {
                         .await;
                     // If we haven't already sent a WhoAreYou,
                     // spawn a WHOAREYOU event to check for highest known ENR
-                    if self.active_challenges.get(&node_address).is_none() {
+                    if self.active_challenges.get(&node_address).is_some() {
+                        trace!("WHOAREYOU packet already sent: {}", node_address);
+                    } else if !self.allows_whoareyou(&node_address.socket_addr) {
+                        debug!(
+                            "WHOAREYOU rate limit reached, dropping packet from {}",
+                            node_address
+                        );
+                    } else {
                         let whoareyou_ref = WhoAreYouRef(node_address, message_nonce);
                         if let Err(e) = self
                             .service_send
@@ -912,8 +1166,6 @@ impl Handler {
                         {
                             warn!("Failed to send WhoAreYou to the service {}", e)
                         }
-                    } else {
-                        trace!("WHOAREYOU packet already sent: {}", node_address);
                     }
                     return;
                 }
@@ -924,6 +1176,14 @@ impl Handler {
             // Remove any associated request from pending_request
             match message {
                 Message::Request(request) => {
+                    // silently drop requests over the limit, to avoid amplifying floods
+                    if !self.allows_request(&node_address.node_id) {
+                        debug!("Dropping rate limited request from {}", node_address);
+                        METRICS
+                            .rate_limited_requests
+                            .fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
                     // report the request to the application
                     if let Err(e) = self
                         .service_send
@@ -966,6 +1226,11 @@ impl Handler {
                                 _ => {}
                             }
                             debug!("Session failed invalid ENR response");
+                            self.handshake_failed(
+                                Some(node_address.node_id),
+                                HandshakeFailureReason::InvalidEnr,
+                            )
+                            .await;
                             self.fail_session(&node_address, RequestError::InvalidRemoteEnr, true)
                                 .await;
                             return;
@@ -978,6 +1243,13 @@ impl Handler {
         } else {
             // no session exists
             trace!("Received a message without a session. {}", node_address);
+            if !self.allows_whoareyou(&node_address.socket_addr) {
+                debug!(
+                    "WHOAREYOU rate limit reached, dropping packet from {}",
+                    node_address
+                );
+                return;
+            }
             trace!("Requesting a WHOAREYOU packet to be sent.");
             // spawn a WHOAREYOU event to check for highest known ENR
             let whoareyou_ref = WhoAreYouRef(node_address, message_nonce);
@@ -995,25 +1267,22 @@ impl Handler {
     }
 
     /// Handles a response to a request. Re-inserts the request call if the response is a multiple
-    /// Nodes response.
+    /// Nodes response or a chunk of a value.
     async fn handle_response(&mut self, node_address: NodeAddress, response: Response) {
-        // Find a matching request, if any
-        if let Some(mut request_call) = self.active_requests.remove(&node_address) {
-            if request_call.id() != &response.id {
-                trace!(
-                    "Received an RPC Response to an unknown request. Likely late response. {}",
-                    node_address
-                );
-                // add the request back and reset the timer
-                self.active_requests.insert(node_address, request_call);
-                return;
-            }
-
-            // The response matches a request
-
-            // Check to see if this is a Nodes response, in which case we may require to wait for
-            // extra responses
-            if let ResponseBody::Nodes { total, .. } = response.body {
+        // Find a matching request, if any. Several requests may be in flight to the node, the
+        // response is matched to its request by id.
+        if let Some(mut request_call) = self
+            .active_requests
+            .remove_request(&node_address, &response.id)
+        {
+            // Check to see if this is a Nodes response or a value chunk, in which case we may
+            // require to wait for extra responses
+            let total = match response.body {
+                ResponseBody::Nodes { total, .. } => Some(total),
+                ResponseBody::ValueChunk { total, .. } => Some(total.into()),
+                _ => None,
+            };
+            if let Some(total) = total {
                 if total > 1 {
                     // This is a multi-response Nodes response
                     if let Some(remaining_responses) = request_call.remaining_responses.as_mut() {
@@ -1066,9 +1335,13 @@ impl Handler {
             }
             self.send_next_request(node_address).await;
         } else {
-            // This is likely a late response and we have already failed the request. These get
-            // dropped here.
-            trace!("Late response from node: {}", node_address);
+            // This is likely a late response and we have already failed the request, or a
+            // response spoofing the id of a request. These get dropped here.
+            debug!(
+                "Dropping response with unmatched id {} from node: {}",
+                response.id, node_address
+            );
+            METRICS.mismatched_responses.fetch_add(1, Ordering::Relaxed);
         }
     }
 
@@ -1080,17 +1353,60 @@ impl Handler {
         self.active_requests.insert(node_address, request_call);
     }
 
+    /// Returns whether a request from `node_id` is within the per node request rate limit.
+    fn allows_request(&mut self, node_id: &NodeId) -> bool {
+        match self.request_rate_limiter.as_mut() {
+            Some(limiter) => limiter
+                .allows(self.request_rate_limiter_start.elapsed(), node_id, 1)
+                .is_ok(),
+            None => true,
+        }
+    }
+
+    /// Returns whether a WHOAREYOU may be sent in response to an unknown packet from
+    /// `socket_addr`, within the global and per IP challenge rate limits.
+    fn allows_whoareyou(&mut self, socket_addr: &SocketAddr) -> bool {
+        let elapsed = self.request_rate_limiter_start.elapsed();
+        // check the per IP limit first, so a single flooding IP doesn't use up the global limit
+        if let Some(limiter) = self.whoareyou_ip_rate_limiter.as_mut() {
+            if limiter.allows(elapsed, &socket_addr.ip(), 1).is_err() {
+                return false;
+            }
+        }
+        match self.whoareyou_rate_limiter.as_mut() {
+            Some(limiter) => limiter.allows(elapsed, &(), 1).is_ok(),
+            None => true,
+        }
+    }
+
+    /// Removes the nodes and IPs whose allowance has been fully replenished.
+    fn prune_rate_limiters(&mut self) {
+        let elapsed = self.request_rate_limiter_start.elapsed();
+        if let Some(limiter) = self.request_rate_limiter.as_mut() {
+            limiter.prune(elapsed);
+        }
+        if let Some(limiter) = self.whoareyou_ip_rate_limiter.as_mut() {
+            limiter.prune(elapsed);
+        }
+    }
+
     fn new_session(&mut self, node_address: NodeAddress, session: Session) {
         if let Some(current_session) = self.sessions.get_mut(&node_address) {
             current_session.update(session);
         } else {
             self.sessions.insert(node_address, session);
-            METRICS
-                .active_sessions
-                .store(self.sessions.len(), Ordering::Relaxed);
+            self.update_session_metrics();
         }
     }
 
+    /// Updates the session and handshake metrics with the current state of this handler.
+    fn update_session_metrics(&mut self) {
+        self.sessions_gauge
+            .set(&METRICS.active_sessions, self.sessions.len());
+        self.handshakes_gauge
+            .set(&METRICS.pending_handshakes, self.active_challenges.len());
+    }
+
     /// A request has failed.
     async fn fail_request(
         &mut self,
@@ -1114,6 +1430,18 @@ impl Handler {
             .await;
     }
 
+    /// Records a failed handshake and informs the service of it.
+    async fn handshake_failed(&mut self, node_id: Option<NodeId>, reason: HandshakeFailureReason) {
+        METRICS.handshake_failures.add(reason);
+        if let Err(e) = self
+            .service_send
+            .send(HandlerOut::HandshakeFailed(node_id, reason))
+            .await
+        {
+            warn!("Failed to inform of the failed handshake {}", e)
+        }
+    }
+
     /// Removes a session and updates associated metrics and fields.
     async fn fail_session(
         &mut self,
@@ -1123,9 +1451,7 @@ impl Handler {
     ) {
         if remove_session {
             self.sessions.remove(node_address);
-            METRICS
-                .active_sessions
-                .store(self.sessions.len(), Ordering::Relaxed);
+            self.update_session_metrics();
         }
         if let Some(to_remove) = self.pending_requests.remove(node_address) {
             for request in to_remove {
@@ -1140,6 +1466,55 @@ impl Handler {
         }
     }
 
+    /// Whether an established session exists with a node. Sessions awaiting the ENR of the node
+    /// are not established yet.
+    fn is_connected(&mut self, node_id: &NodeId) -> bool {
+        let node_addresses: Vec<NodeAddress> = self
+            .sessions
+            .iter_mut()
+            .filter(|(node_address, _)| &node_address.node_id == node_id)
+            .map(|(node_address, _)| node_address.clone())
+            .collect();
+        // expired sessions are not returned
+        node_addresses.iter().any(|node_address| {
+            matches!(self.sessions.peek(node_address), Some(session) if session.awaiting_enr.is_none())
+        })
+    }
+
+    /// Drops all the sessions established with a node. The requests in flight over these sessions
+    /// fail with `RequestError::SessionExpired`.
+    async fn disconnect(&mut self, node_id: &NodeId) {
+        let mut dropped = Vec::new();
+        self.sessions.retain(|node_address, _| {
+            if &node_address.node_id == node_id {
+                dropped.push(node_address.clone());
+                false
+            } else {
+                true
+            }
+        });
+        self.update_session_metrics();
+
+        for node_address in dropped {
+            for request_call in self.active_requests.remove_requests(&node_address) {
+                self.remove_expected_response(node_address.socket_addr);
+                self.fail_request(request_call, RequestError::SessionExpired, false)
+                    .await;
+            }
+        }
+    }
+
+    /// Requests fresh keys for all the sessions established with a node.
+    fn rekey(&mut self, node_id: &NodeId) {
+        for (_, session) in self
+            .sessions
+            .iter_mut()
+            .filter(|(node_address, _)| &node_address.node_id == node_id)
+        {
+            session.request_rekey();
+        }
+    }
+
     /// Sends a packet to the send handler to be encoded and sent.
     async fn send(&mut self, node_address: NodeAddress, packet: Packet) {
         let outbound_packet = socket::OutboundPacket {
@@ -0,0 +1,110 @@
+//! A counter of the message nonces persisted to a file, so that the nonces of the messages sent
+//! keep increasing across restarts.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+/// The number of counters reserved by each write to the file. Counters are handed out from the
+/// reserved range without touching the file.
+const RESERVATION: u32 = 1 << 16;
+
+/// A message nonce counter persisted to a file.
+///
+/// The file holds the end of the range of counters reserved so far. All the counters handed out
+/// are below it, so a counter loaded from the file resumes past every counter handed out before.
+pub(crate) struct PersistedNonceCounter {
+    /// The file the end of the reserved range is persisted to.
+    path: PathBuf,
+    /// The next counter to hand out.
+    next: u32,
+    /// The end of the reserved range.
+    reserved: u32,
+}
+
+impl PersistedNonceCounter {
+    /// Loads the counter from `path`, resuming past the counters reserved before. A missing file
+    /// starts the counter afresh.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let reserved = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .trim()
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(PersistedNonceCounter {
+            path,
+            next: reserved,
+            reserved,
+        })
+    }
+
+    /// Returns the next counter, reserving a new range once the current one is exhausted. Fails
+    /// if the new range cannot be persisted, as its counters could be handed out again after a
+    /// restart. The reservation is then retried by the next call.
+    ///
+    /// The counter wraps once all values were handed out. The nonces also hold random bytes,
+    /// which keep them unique.
+    pub fn next(&mut self) -> io::Result<u32> {
+        if self.next == self.reserved {
+            let reserved = self.reserved.wrapping_add(RESERVATION);
+            self.persist(reserved)?;
+            self.reserved = reserved;
+        }
+        let counter = self.next;
+        self.next = self.next.wrapping_add(1);
+        Ok(counter)
+    }
+
+    /// Replaces the end of the reserved range held by the file. The value is written to a
+    /// temporary file, synced to disk and renamed over the file, so that the file holds either
+    /// the previous or the new value if writing is interrupted.
+    fn persist(&self, reserved: u32) -> io::Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(reserved.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_resumes_after_restart() {
+        let path = std::env::temp_dir().join(format!("discv5-nonces-{}", rand::random::<u64>()));
+
+        let mut counter = PersistedNonceCounter::load(path.clone()).unwrap();
+        let sent: Vec<u32> = (0..10).map(|_| counter.next().unwrap()).collect();
+        assert!(sent.windows(2).all(|pair| pair[0] < pair[1]));
+        drop(counter);
+
+        // the restarted counter resumes past all the counters handed out before
+        let mut restarted = PersistedNonceCounter::load(path.clone()).unwrap();
+        let last = *sent.last().unwrap();
+        assert!(restarted.next().unwrap() > last);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_unpersisted_counter_fails() {
+        let path = std::env::temp_dir()
+            .join(format!("discv5-missing-{}", rand::random::<u64>()))
+            .join("nonces");
+
+        // the directory of the file doesn't exist, no range can be reserved
+        let mut counter = PersistedNonceCounter::load(path).unwrap();
+        assert!(counter.next().is_err());
+        assert!(counter.next().is_err());
+    }
+}
@@ -4,8 +4,13 @@ use crate::{
     packet::{ChallengeData, Packet, PacketHeader, PacketKind, MESSAGE_NONCE_LENGTH},
 };
 use enr::{CombinedKey, NodeId};
+use std::collections::VecDeque;
 use zeroize::Zeroize;
 
+/// The number of the most recent message nonces received over a session that are remembered to
+/// detect replayed packets.
+const NONCE_WINDOW: usize = 256;
+
 #[derive(Zeroize, PartialEq)]
 pub(crate) struct Keys {
     /// The encryption key.
@@ -34,6 +39,10 @@ pub(crate) struct Session {
     /// Number of messages sent. Used to ensure the nonce used in message encryption is always
     /// unique.
     counter: u32,
+    /// Whether fresh keys were requested for this session, regardless of its message counter.
+    rekey: bool,
+    /// The nonces of the last `NONCE_WINDOW` messages decrypted over this session.
+    seen_nonces: VecDeque<MessageNonce>,
 }
 
 impl Session {
@@ -43,6 +52,8 @@ impl Session {
             old_keys: None,
             awaiting_enr: None,
             counter: 0,
+            rekey: false,
+            seen_nonces: VecDeque::with_capacity(NONCE_WINDOW),
         }
     }
 
@@ -51,22 +62,44 @@ impl Session {
         // Optimistically assume the new keys are canonical.
         self.old_keys = Some(std::mem::replace(&mut self.keys, new_session.keys));
         self.awaiting_enr = new_session.awaiting_enr;
+        // the nonces of the new keys start afresh
+        self.counter = 0;
+        self.rekey = false;
+    }
+
+    /// Requests fresh keys to be established with the next request over this session.
+    pub fn request_rekey(&mut self) {
+        self.rekey = true;
+    }
+
+    /// Whether the next request should perform a new handshake to establish fresh keys, either
+    /// because it was requested or `threshold` messages were encrypted with the current keys.
+    pub fn needs_rekey(&self, threshold: u32) -> bool {
+        self.rekey || self.counter >= threshold
     }
 
     /// Uses the current `Session` to encrypt a message. Encrypt packets with the current session
     /// key if we are awaiting a response from AuthMessage.
+    ///
+    /// The nonce starts with the counter taken from `nonce_counter` if set, rather than the
+    /// number of messages sent over this session. Fails if the counter cannot be persisted.
     pub(crate) fn encrypt_message(
         &mut self,
         src_id: NodeId,
         message: &[u8],
+        nonce_counter: Option<&mut PersistedNonceCounter>,
     ) -> Result<Packet, Discv5Error> {
         self.counter += 1;
+        let counter = match nonce_counter {
+            Some(nonce_counter) => nonce_counter.next()?,
+            None => self.counter,
+        };
 
         // If the message nonce length is ever set below 4 bytes this will explode. The packet
         // size constants shouldn't be modified.
         let random_nonce: [u8; MESSAGE_NONCE_LENGTH - 4] = rand::random();
         let mut message_nonce: MessageNonce = [0u8; crate::packet::MESSAGE_NONCE_LENGTH];
-        message_nonce[..4].copy_from_slice(&self.counter.to_be_bytes());
+        message_nonce[..4].copy_from_slice(&counter.to_be_bytes());
         message_nonce[4..].copy_from_slice(&random_nonce);
 
         // the authenticated data is the IV concatenated with the packet header
@@ -97,18 +130,25 @@ impl Session {
     /// Decrypts an encrypted message. If a Session is already established, the original decryption
     /// keys are tried first, upon failure, the new keys are attempted. If the new keys succeed,
     /// the session keys are updated along with the Session state.
+    ///
+    /// Messages reusing the nonce of one of the last decrypted messages are rejected with
+    /// `Discv5Error::ReplayedNonce`.
     pub(crate) fn decrypt_message(
         &mut self,
         message_nonce: MessageNonce,
         message: &[u8],
         aad: &[u8],
     ) -> Result<Vec<u8>, Discv5Error> {
+        if self.seen_nonces.contains(&message_nonce) {
+            return Err(Discv5Error::ReplayedNonce);
+        }
         // First try with the canonical keys.
         let result_canon =
             crypto::decrypt_message(&self.keys.decryption_key, message_nonce, message, aad);
 
         // If decryption is fine, nothing more to do.
         if result_canon.is_ok() {
+            self.remember_nonce(message_nonce);
             return result_canon;
         }
 
@@ -119,12 +159,22 @@ impl Session {
             if result.is_ok() {
                 // rotate the keys
                 self.old_keys = Some(std::mem::replace(&mut self.keys, old_keys));
+                self.remember_nonce(message_nonce);
             }
             return result;
         }
         result_canon
     }
 
+    /// Remembers the nonce of a decrypted message. Only authentic messages are remembered, so
+    /// forged packets can't evict the nonces of the window.
+    fn remember_nonce(&mut self, message_nonce: MessageNonce) {
+        if self.seen_nonces.len() == NONCE_WINDOW {
+            self.seen_nonces.pop_front();
+        }
+        self.seen_nonces.push_back(message_nonce);
+    }
+
     /* Session Helper Functions */
 
     /// Generates session keys from an authentication header. If the IP of the ENR does not match the
@@ -263,3 +313,53 @@ impl Session {
         Ok((packet, session))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the sessions of two peers sharing the same keys.
+    fn session_pair() -> (Session, Session) {
+        let (key_a, key_b): ([u8; 16], [u8; 16]) = (rand::random(), rand::random());
+        let sender = Session::new(Keys {
+            encryption_key: key_a,
+            decryption_key: key_b,
+        });
+        let receiver = Session::new(Keys {
+            encryption_key: key_b,
+            decryption_key: key_a,
+        });
+        (sender, receiver)
+    }
+
+    #[test]
+    fn test_replayed_nonce_is_rejected() {
+        let (mut sender, mut receiver) = session_pair();
+        let src_id = NodeId::random();
+
+        let decrypt = |receiver: &mut Session, packet: &Packet| {
+            let mut authenticated_data = packet.iv.to_be_bytes().to_vec();
+            authenticated_data.extend_from_slice(&packet.header.encode());
+            receiver
+                .decrypt_message(
+                    packet.header.message_nonce,
+                    &packet.message,
+                    &authenticated_data,
+                )
+                .map_err(Box::new)
+        };
+
+        let packet = sender.encrypt_message(src_id, b"ping", None).unwrap();
+        assert_eq!(decrypt(&mut receiver, &packet).unwrap(), b"ping");
+
+        // the captured packet is rejected when replayed
+        assert!(matches!(
+            decrypt(&mut receiver, &packet).map_err(|e| *e),
+            Err(Discv5Error::ReplayedNonce)
+        ));
+
+        // while a new message with a fresh nonce is still accepted
+        let packet = sender.encrypt_message(src_id, b"pong", None).unwrap();
+        assert_eq!(decrypt(&mut receiver, &packet).unwrap(), b"pong");
+    }
+}
@@ -5,7 +5,7 @@ use crate::{
     Discv5ConfigBuilder,
 };
 
-use active_requests::ActiveRequests;
+use active_requests::{ActiveRequests, RequestTimeouts};
 use enr::EnrBuilder;
 use std::time::Duration;
 use tokio::time::sleep;
@@ -220,7 +220,8 @@ async fn multiple_messages() {
 #[tokio::test]
 async fn test_active_requests_insert() {
     const EXPIRY: Duration = Duration::from_secs(5);
-    let mut active_requests = ActiveRequests::new(EXPIRY);
+    let config = Discv5ConfigBuilder::new().request_timeout(EXPIRY).build();
+    let mut active_requests = ActiveRequests::new(RequestTimeouts::new(&config));
 
     // Create the test values needed
     let port = 5000;
@@ -244,12 +245,1268 @@ async fn test_active_requests_insert() {
         body: RequestBody::Ping { enr_seq: 1 },
     };
     let initiating_session = true;
-    let request_call = RequestCall::new(contact, packet, request, initiating_session);
+    let request_call = RequestCall::new(contact.clone(), packet, request, initiating_session, true);
 
     // insert the pair and verify the mapping remains in sync
     let nonce = *request_call.packet.message_nonce();
-    active_requests.insert(node_address, request_call);
+    active_requests.insert(node_address.clone(), request_call);
     active_requests.check_invariant();
+
+    // a second request to the same node is tracked alongside the first
+    let second_call = RequestCall::new(
+        contact,
+        Packet::new_random(&node_id).unwrap(),
+        Request {
+            id: RequestId(vec![2]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        },
+        false,
+        false,
+    );
+    active_requests.insert(node_address.clone(), second_call);
+    active_requests.check_invariant();
+
     active_requests.remove_by_nonce(&nonce);
     active_requests.check_invariant();
+    assert!(active_requests.contains(&node_address));
+    let removed = active_requests
+        .remove_request(&node_address, &RequestId(vec![2]))
+        .unwrap();
+    assert_eq!(removed.id(), &RequestId(vec![2]));
+    active_requests.check_invariant();
+    assert!(!active_requests.contains(&node_address));
+}
+
+#[tokio::test(start_paused = true)]
+// Tests that each type of request expires after its configured timeout
+async fn test_active_requests_timeouts() {
+    let config = Discv5ConfigBuilder::new()
+        .request_timeout(Duration::from_secs(4))
+        .ping_request_timeout(Duration::from_secs(1))
+        .find_node_request_timeout(Duration::from_secs(3))
+        .talk_request_timeout(Duration::from_secs(2))
+        .build();
+    let mut active_requests = ActiveRequests::new(RequestTimeouts::new(&config));
+
+    let bodies = vec![
+        RequestBody::FindNode {
+            distances: vec![256],
+        },
+        RequestBody::Store {
+            key: NodeId::random(),
+            value: vec![1],
+        },
+        RequestBody::Ping { enr_seq: 1 },
+        RequestBody::Talk {
+            protocol: vec![1],
+            request: vec![1],
+        },
+    ];
+    for (port, body) in (5018..).zip(bodies) {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = EnrBuilder::new("v4")
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(port)
+            .build(&key)
+            .unwrap();
+        let packet = Packet::new_random(&enr.node_id()).unwrap();
+        let contact: NodeContact = enr.into();
+        let request = Request {
+            id: RequestId(vec![1]),
+            body,
+        };
+        let node_address = contact.node_address();
+        active_requests.insert(
+            node_address,
+            RequestCall::new(contact, packet, request, true, true),
+        );
+    }
+
+    let start = tokio::time::Instant::now();
+    let mut expired = Vec::new();
+    while let Some(Ok((_, request_call))) = active_requests.next().await {
+        expired.push((request_call.request.msg_type(), start.elapsed().as_secs()));
+    }
+    assert_eq!(expired, vec![(1, 1), (5, 2), (3, 3), (13, 4)]);
+}
+
+#[tokio::test]
+// Tests that requests exceeding the per node rate limit are dropped
+async fn per_node_request_rate_limit() {
+    init();
+    let flooder_port = 5004;
+    let other_port = 5005;
+    let receiver_port = 5006;
+    let ip = "127.0.0.1".parse().unwrap();
+    let flooder_key = CombinedKey::generate_secp256k1();
+    let other_key = CombinedKey::generate_secp256k1();
+    let receiver_key = CombinedKey::generate_secp256k1();
+
+    let config = Discv5ConfigBuilder::new().build();
+    let receiver_config = Discv5ConfigBuilder::new()
+        .per_node_request_rate_limit(2, Duration::from_secs(60))
+        .build();
+    let flooder_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(flooder_port)
+        .build(&flooder_key)
+        .unwrap();
+    let other_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(other_port)
+        .build(&other_key)
+        .unwrap();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&receiver_key)
+        .unwrap();
+
+    let (_exit_flooder, flooder_send, mut flooder_recv) = Handler::spawn(
+        arc_rw!(flooder_enr.clone()),
+        arc_rw!(flooder_key),
+        flooder_enr.udp4_socket().unwrap().into(),
+        config.clone(),
+    )
+    .await
+    .unwrap();
+
+    let (_exit_other, other_send, _other_recv) = Handler::spawn(
+        arc_rw!(other_enr.clone()),
+        arc_rw!(other_key),
+        other_enr.udp4_socket().unwrap().into(),
+        config,
+    )
+    .await
+    .unwrap();
+
+    let (_exit_recv, recv_send, mut receiver_handler) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(receiver_key),
+        receiver_enr.udp4_socket().unwrap().into(),
+        receiver_config,
+    )
+    .await
+    .unwrap();
+
+    let request = |id: u8| {
+        Box::new(Request {
+            id: RequestId(vec![id]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        })
+    };
+
+    let _ = flooder_send.send(HandlerIn::Request(receiver_enr.clone().into(), request(1)));
+    let _ = other_send.send(HandlerIn::Request(receiver_enr.clone().into(), request(1)));
+
+    let messages_to_flood = 5u8;
+    let flood_receiver_enr = receiver_enr.clone();
+    let flooder = async move {
+        loop {
+            if let Some(HandlerOut::Established(_, _, _)) = flooder_recv.recv().await {
+                // once the session is established, flood the remaining requests
+                for id in 2..=messages_to_flood {
+                    let _ = flooder_send.send(HandlerIn::Request(
+                        flood_receiver_enr.clone().into(),
+                        request(id),
+                    ));
+                }
+            }
+        }
+    };
+
+    let mut received: HashMap<NodeId, usize> = HashMap::new();
+    let receiver = async {
+        loop {
+            match receiver_handler.recv().await {
+                Some(HandlerOut::WhoAreYou(wru_ref)) => {
+                    let enr = if wru_ref.0.node_id == flooder_enr.node_id() {
+                        flooder_enr.clone()
+                    } else {
+                        other_enr.clone()
+                    };
+                    let _ = recv_send.send(HandlerIn::WhoAreYou(wru_ref, Some(enr)));
+                }
+                Some(HandlerOut::Request(addr, request)) => {
+                    *received.entry(addr.node_id).or_default() += 1;
+                    let response = Response {
+                        id: request.id,
+                        body: ResponseBody::Pong {
+                            enr_seq: 1,
+                            ip: ip.into(),
+                            port: addr.socket_addr.port(),
+                        },
+                    };
+                    let _ = recv_send.send(HandlerIn::Response(addr, Box::new(response)));
+                }
+                _ => continue,
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = flooder => {}
+        _ = receiver => {}
+        _ = sleep(Duration::from_millis(300)) => {}
+    }
+
+    assert_eq!(received.get(&flooder_enr.node_id()), Some(&2));
+    assert_eq!(received.get(&other_enr.node_id()), Some(&1));
+    assert!(METRICS.rate_limited_requests.load(Ordering::Relaxed) >= 1);
+}
+
+#[tokio::test]
+// Tests that established sessions are counted in the metrics
+async fn session_metrics() {
+    init();
+
+    let sender_port = 5007;
+    let receiver_port = 5008;
+    let ip = "127.0.0.1".parse().unwrap();
+
+    let key1 = CombinedKey::generate_secp256k1();
+    let key2 = CombinedKey::generate_secp256k1();
+
+    let config = Discv5ConfigBuilder::new().build();
+
+    let sender_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(sender_port)
+        .build(&key1)
+        .unwrap();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&key2)
+        .unwrap();
+
+    let (_exit_send, sender_send, mut sender_recv) = Handler::spawn(
+        arc_rw!(sender_enr.clone()),
+        arc_rw!(key1),
+        sender_enr.udp4_socket().unwrap().into(),
+        config.clone(),
+    )
+    .await
+    .unwrap();
+
+    let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(key2),
+        receiver_enr.udp4_socket().unwrap().into(),
+        config,
+    )
+    .await
+    .unwrap();
+
+    let _ = sender_send.send(HandlerIn::Request(
+        receiver_enr.clone().into(),
+        Box::new(Request {
+            id: RequestId(vec![1]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        }),
+    ));
+
+    let receiver = async move {
+        loop {
+            if let Some(message) = receiver_recv.recv().await {
+                match message {
+                    HandlerOut::WhoAreYou(wru_ref) => {
+                        let _ =
+                            recv_send.send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                    }
+                    HandlerOut::Request(node_address, request) => {
+                        let response = Response {
+                            id: request.id,
+                            body: ResponseBody::Pong {
+                                enr_seq: 1,
+                                ip: node_address.socket_addr.ip(),
+                                port: node_address.socket_addr.port(),
+                            },
+                        };
+                        let _ =
+                            recv_send.send(HandlerIn::Response(node_address, Box::new(response)));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    };
+
+    let sender = async move {
+        loop {
+            if let Some(HandlerOut::Response(..)) = sender_recv.recv().await {
+                return;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = receiver => {}
+        _ = sender => {}
+        _ = sleep(Duration::from_millis(500)) => {
+            panic!("Test timed out");
+        }
+    }
+
+    // the metrics sum the sessions of all the handlers of the process, both handlers of this test
+    // count their session once the response is received
+    let metrics = crate::metrics::Metrics::from(&METRICS);
+    assert!(metrics.active_sessions >= 2);
+}
+
+#[tokio::test]
+// Tests that unknown packets exceeding the WHOAREYOU rate limits are dropped without a challenge
+async fn whoareyou_rate_limit() {
+    init();
+    let receiver_port = 5009;
+    let ip = "127.0.0.1".parse().unwrap();
+    let receiver_key = CombinedKey::generate_secp256k1();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&receiver_key)
+        .unwrap();
+    let receiver_config = Discv5ConfigBuilder::new()
+        .whoareyou_rate_limit(5, Duration::from_secs(60))
+        .whoareyou_rate_limit_per_ip(3, Duration::from_secs(60))
+        .build();
+
+    let (_exit_recv, _recv_send, mut receiver_handler) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(receiver_key),
+        receiver_enr.udp4_socket().unwrap().into(),
+        receiver_config,
+    )
+    .await
+    .unwrap();
+
+    // flood random packets from many node ids, spread over a few loopback IPs
+    let receiver_addr = receiver_enr.udp4_socket().unwrap();
+    for src_ip in ["127.0.0.1", "127.0.0.2", "127.0.0.3"] {
+        let socket = tokio::net::UdpSocket::bind((src_ip, 0)).await.unwrap();
+        for _ in 0..10 {
+            let packet = Packet::new_random(&NodeId::random()).unwrap();
+            socket
+                .send_to(&packet.encode(&receiver_enr.node_id()), receiver_addr)
+                .await
+                .unwrap();
+        }
+    }
+
+    let mut challenges: HashMap<IpAddr, usize> = HashMap::new();
+    let receiver = async {
+        loop {
+            if let Some(HandlerOut::WhoAreYou(wru_ref)) = receiver_handler.recv().await {
+                *challenges.entry(wru_ref.0.socket_addr.ip()).or_default() += 1;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = receiver => {}
+        _ = sleep(Duration::from_millis(300)) => {}
+    }
+
+    // each IP is limited to 3 challenges, and all sources together to 5
+    assert_eq!(challenges.values().sum::<usize>(), 5);
+    assert!(challenges.values().all(|count| *count <= 3));
+}
+
+#[tokio::test]
+// Tests that the session keys are renewed once the rekey threshold is reached, without losing
+// any request
+async fn session_rekey_threshold() {
+    init();
+    let sender_port = 5010;
+    let receiver_port = 5011;
+    let ip = "127.0.0.1".parse().unwrap();
+    let key1 = CombinedKey::generate_secp256k1();
+    let key2 = CombinedKey::generate_secp256k1();
+
+    let config = Discv5ConfigBuilder::new()
+        .session_rekey_threshold(3)
+        .build();
+    let sender_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(sender_port)
+        .build(&key1)
+        .unwrap();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&key2)
+        .unwrap();
+
+    let (_exit_send, sender_handler, mut sender_handler_recv) = Handler::spawn(
+        arc_rw!(sender_enr.clone()),
+        arc_rw!(key1),
+        sender_enr.udp4_socket().unwrap().into(),
+        config.clone(),
+    )
+    .await
+    .unwrap();
+
+    let (_exit_recv, recv_send, mut receiver_handler) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(key2),
+        receiver_enr.udp4_socket().unwrap().into(),
+        config,
+    )
+    .await
+    .unwrap();
+
+    // answer every request with a PONG
+    tokio::spawn(async move {
+        while let Some(message) = receiver_handler.recv().await {
+            match message {
+                HandlerOut::WhoAreYou(wru_ref) => {
+                    let _ = recv_send.send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                }
+                HandlerOut::Request(addr, request) => {
+                    let response = Response {
+                        id: request.id,
+                        body: ResponseBody::Pong {
+                            enr_seq: 1,
+                            ip: ip.into(),
+                            port: sender_port,
+                        },
+                    };
+                    let _ = recv_send.send(HandlerIn::Response(addr, Box::new(response)));
+                }
+                _ => {}
+            }
+        }
+    });
+
+    // the first request establishes the session and the next three use up its keys
+    let mut handshakes = 0;
+    let sender = async {
+        for id in 0..6u8 {
+            let request = Box::new(Request {
+                id: RequestId(vec![id]),
+                body: RequestBody::Ping { enr_seq: 1 },
+            });
+            let _ = sender_handler.send(HandlerIn::Request(receiver_enr.clone().into(), request));
+            loop {
+                match sender_handler_recv.recv().await {
+                    Some(HandlerOut::Established(..)) => handshakes += 1,
+                    Some(HandlerOut::Response(_, response)) => {
+                        assert_eq!(response.id, RequestId(vec![id]));
+                        break;
+                    }
+                    Some(HandlerOut::RequestFailed(id, e)) => {
+                        panic!("Request {} failed: {:?}", id, e)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = sender => {}
+        _ = sleep(Duration::from_secs(2)) => {
+            panic!("Test timed out");
+        }
+    }
+    assert_eq!(handshakes, 2);
+}
+
+#[tokio::test]
+// Tests that sessions exceeding the session cache capacity evict the least recently used one,
+// and that contacting the evicted peer again performs a new handshake
+async fn session_cache_capacity_eviction() {
+    init();
+    let sender_port = 5012;
+    let ip = "127.0.0.1".parse().unwrap();
+    let sender_key = CombinedKey::generate_secp256k1();
+    let sender_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(sender_port)
+        .build(&sender_key)
+        .unwrap();
+    let sender_config = Discv5ConfigBuilder::new().session_cache_capacity(1).build();
+
+    let (_exit_send, sender_handler, mut sender_handler_recv) = Handler::spawn(
+        arc_rw!(sender_enr.clone()),
+        arc_rw!(sender_key),
+        sender_enr.udp4_socket().unwrap().into(),
+        sender_config,
+    )
+    .await
+    .unwrap();
+
+    // two receivers answering every request with a PONG
+    let mut receiver_enrs = Vec::new();
+    let mut receiver_exits = Vec::new();
+    for receiver_port in [5013, 5014] {
+        let receiver_key = CombinedKey::generate_secp256k1();
+        let receiver_enr = EnrBuilder::new("v4")
+            .ip4(ip)
+            .udp4(receiver_port)
+            .build(&receiver_key)
+            .unwrap();
+        let (exit, recv_send, mut receiver_handler) = Handler::spawn(
+            arc_rw!(receiver_enr.clone()),
+            arc_rw!(receiver_key),
+            receiver_enr.udp4_socket().unwrap().into(),
+            Discv5ConfigBuilder::new().build(),
+        )
+        .await
+        .unwrap();
+        let sender_enr = sender_enr.clone();
+        tokio::spawn(async move {
+            while let Some(message) = receiver_handler.recv().await {
+                match message {
+                    HandlerOut::WhoAreYou(wru_ref) => {
+                        let _ =
+                            recv_send.send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                    }
+                    HandlerOut::Request(addr, request) => {
+                        let response = Response {
+                            id: request.id,
+                            body: ResponseBody::Pong {
+                                enr_seq: 1,
+                                ip: ip.into(),
+                                port: sender_port,
+                            },
+                        };
+                        let _ = recv_send.send(HandlerIn::Response(addr, Box::new(response)));
+                    }
+                    _ => {}
+                }
+            }
+        });
+        receiver_enrs.push(receiver_enr);
+        receiver_exits.push(exit);
+    }
+
+    // contacting the second receiver evicts the session of the first one
+    let mut handshakes = Vec::new();
+    let sender = async {
+        for (id, receiver_enr) in [&receiver_enrs[0], &receiver_enrs[1], &receiver_enrs[0]]
+            .iter()
+            .enumerate()
+        {
+            let request = Box::new(Request {
+                id: RequestId(vec![id as u8]),
+                body: RequestBody::Ping { enr_seq: 1 },
+            });
+            let _ =
+                sender_handler.send(HandlerIn::Request((*receiver_enr).clone().into(), request));
+            loop {
+                match sender_handler_recv.recv().await {
+                    Some(HandlerOut::Established(enr, ..)) => handshakes.push(enr.node_id()),
+                    Some(HandlerOut::Response(..)) => break,
+                    Some(HandlerOut::RequestFailed(id, e)) => {
+                        panic!("Request {} failed: {:?}", id, e)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = sender => {}
+        _ = sleep(Duration::from_secs(2)) => {
+            panic!("Test timed out");
+        }
+    }
+    let first = receiver_enrs[0].node_id();
+    let second = receiver_enrs[1].node_id();
+    assert_eq!(handshakes, vec![first, second, first]);
+}
+
+#[tokio::test]
+// Tests that handshakes initiated by peers rejected by the incoming handshake filter are aborted
+async fn incoming_handshake_filter() {
+    init();
+    let receiver_port = 5015;
+    let ip = "127.0.0.1".parse().unwrap();
+    let receiver_key = CombinedKey::generate_secp256k1();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&receiver_key)
+        .unwrap();
+
+    let mut sender_enrs = Vec::new();
+    let mut sender_handlers = Vec::new();
+    for sender_port in [5016, 5017] {
+        let sender_key = CombinedKey::generate_secp256k1();
+        let sender_enr = EnrBuilder::new("v4")
+            .ip4(ip)
+            .udp4(sender_port)
+            .build(&sender_key)
+            .unwrap();
+        sender_handlers.push(
+            Handler::spawn(
+                arc_rw!(sender_enr.clone()),
+                arc_rw!(sender_key),
+                sender_enr.udp4_socket().unwrap().into(),
+                Discv5ConfigBuilder::new().build(),
+            )
+            .await
+            .unwrap(),
+        );
+        sender_enrs.push(sender_enr);
+    }
+
+    // only the first sender is allowed
+    let allowed = sender_enrs[0].node_id();
+    let receiver_config = Discv5ConfigBuilder::new()
+        .incoming_handshake_filter(Arc::new(move |enr: &Enr| enr.node_id() == allowed))
+        .build();
+    let (_exit_recv, recv_send, mut receiver_handler) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(receiver_key),
+        receiver_enr.udp4_socket().unwrap().into(),
+        receiver_config,
+    )
+    .await
+    .unwrap();
+
+    for (id, (_, sender_handler, _)) in sender_handlers.iter().enumerate() {
+        let request = Box::new(Request {
+            id: RequestId(vec![id as u8]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        });
+        let _ = sender_handler.send(HandlerIn::Request(receiver_enr.clone().into(), request));
+    }
+
+    let mut established = Vec::new();
+    let receiver = async {
+        while let Some(message) = receiver_handler.recv().await {
+            match message {
+                HandlerOut::WhoAreYou(wru_ref) => {
+                    let enr = sender_enrs
+                        .iter()
+                        .find(|enr| enr.node_id() == wru_ref.0.node_id)
+                        .cloned();
+                    let _ = recv_send.send(HandlerIn::WhoAreYou(wru_ref, enr));
+                }
+                HandlerOut::Established(enr, ..) => established.push(enr.node_id()),
+                _ => {}
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = receiver => {}
+        _ = sleep(Duration::from_millis(500)) => {}
+    }
+
+    // only the session with the allowed sender is established
+    assert_eq!(established, vec![allowed]);
+}
+
+#[tokio::test]
+// Tests that lost requests are retransmitted before the request timeout, and that messages resent
+// over a session aren't dropped as replays
+async fn request_retransmission() {
+    init();
+    let sender_port = 5022;
+    let receiver_port = 5023;
+    let ip = "127.0.0.1".parse().unwrap();
+    let sender_key = CombinedKey::generate_secp256k1();
+    let receiver_key = CombinedKey::generate_secp256k1();
+
+    let sender_config = Discv5ConfigBuilder::new()
+        .request_timeout(Duration::from_secs(5))
+        .request_retries(3)
+        .request_retransmit_interval(Duration::from_millis(100))
+        .build();
+    let sender_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(sender_port)
+        .build(&sender_key)
+        .unwrap();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&receiver_key)
+        .unwrap();
+
+    let (_exit_send, sender_send, mut sender_recv) = Handler::spawn(
+        arc_rw!(sender_enr.clone()),
+        arc_rw!(sender_key),
+        sender_enr.udp4_socket().unwrap().into(),
+        sender_config,
+    )
+    .await
+    .unwrap();
+
+    let ping = |id: u8| {
+        Box::new(Request {
+            id: RequestId(vec![id]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        })
+    };
+
+    // the first packet is lost, the receiver isn't listening yet
+    let _ = sender_send.send(HandlerIn::Request(receiver_enr.clone().into(), ping(1)));
+    sleep(Duration::from_millis(30)).await;
+    let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(receiver_key),
+        receiver_enr.udp4_socket().unwrap().into(),
+        Discv5ConfigBuilder::new().build(),
+    )
+    .await
+    .unwrap();
+
+    let exchange = async move {
+        let mut second_requests = 0;
+        loop {
+            tokio::select! {
+                Some(message) = receiver_recv.recv() => match message {
+                    HandlerOut::WhoAreYou(wru_ref) => {
+                        let _ =
+                            recv_send.send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                    }
+                    HandlerOut::Request(addr, request) if request.id == RequestId(vec![1]) => {
+                        let response = Response {
+                            id: request.id,
+                            body: ResponseBody::Pong {
+                                enr_seq: 1,
+                                ip: ip.into(),
+                                port: sender_port,
+                            },
+                        };
+                        let _ = recv_send.send(HandlerIn::Response(addr, Box::new(response)));
+                    }
+                    HandlerOut::Request(_, request) => {
+                        // the response to the second request is lost, it is received again
+                        assert_eq!(request, ping(2));
+                        second_requests += 1;
+                        if second_requests == 2 {
+                            return;
+                        }
+                    }
+                    _ => {}
+                },
+                Some(message) = sender_recv.recv() => {
+                    if let HandlerOut::Response(_, response) = message {
+                        assert_eq!(response.id, RequestId(vec![1]));
+                        let _ = sender_send
+                            .send(HandlerIn::Request(receiver_enr.clone().into(), ping(2)));
+                    }
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = exchange => {}
+        _ = sleep(Duration::from_secs(2)) => {
+            panic!("Test timed out");
+        }
+    }
+}
+
+#[tokio::test]
+// Tests that failed handshakes are reported along with their failure reason
+async fn handshake_failures() {
+    init();
+    let receiver_port = 5024;
+    let ip = "127.0.0.1".parse().unwrap();
+    let receiver_key = CombinedKey::generate_secp256k1();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&receiver_key)
+        .unwrap();
+
+    // the first sender is rejected by the filter, the second declares another port in its ENR
+    let mut sender_enrs = Vec::new();
+    let mut sender_handlers = Vec::new();
+    for (sender_port, enr_port) in [(5025, 5025), (5026, 5027)] {
+        let sender_key = CombinedKey::generate_secp256k1();
+        let sender_enr = EnrBuilder::new("v4")
+            .ip4(ip)
+            .udp4(enr_port)
+            .build(&sender_key)
+            .unwrap();
+        sender_handlers.push(
+            Handler::spawn(
+                arc_rw!(sender_enr.clone()),
+                arc_rw!(sender_key),
+                SocketAddr::new(ip.into(), sender_port),
+                Discv5ConfigBuilder::new().build(),
+            )
+            .await
+            .unwrap(),
+        );
+        sender_enrs.push(sender_enr);
+    }
+
+    let rejected = sender_enrs[0].node_id();
+    let receiver_config = Discv5ConfigBuilder::new()
+        .incoming_handshake_filter(Arc::new(move |enr: &Enr| enr.node_id() != rejected))
+        .build();
+    let (_exit_recv, recv_send, mut receiver_handler) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(receiver_key),
+        receiver_enr.udp4_socket().unwrap().into(),
+        receiver_config,
+    )
+    .await
+    .unwrap();
+
+    let failures_before = crate::metrics::Metrics::from(&METRICS).handshake_failures;
+    for (id, (_, sender_handler, _)) in sender_handlers.iter().enumerate() {
+        let request = Box::new(Request {
+            id: RequestId(vec![id as u8]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        });
+        let _ = sender_handler.send(HandlerIn::Request(receiver_enr.clone().into(), request));
+    }
+
+    let mut failures = Vec::new();
+    let receiver = async {
+        while let Some(message) = receiver_handler.recv().await {
+            match message {
+                HandlerOut::WhoAreYou(wru_ref) => {
+                    let enr = sender_enrs
+                        .iter()
+                        .find(|enr| enr.node_id() == wru_ref.0.node_id)
+                        .cloned();
+                    let _ = recv_send.send(HandlerIn::WhoAreYou(wru_ref, enr));
+                }
+                HandlerOut::HandshakeFailed(node_id, reason) => failures.push((node_id, reason)),
+                _ => {}
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = receiver => {}
+        _ = sleep(Duration::from_millis(500)) => {}
+    }
+
+    failures.sort_by_key(|(_, reason)| *reason as u8);
+    assert_eq!(
+        failures,
+        vec![
+            (
+                Some(sender_enrs[1].node_id()),
+                HandshakeFailureReason::InvalidEnr
+            ),
+            (Some(rejected), HandshakeFailureReason::Rejected),
+        ]
+    );
+    let failures_after = crate::metrics::Metrics::from(&METRICS).handshake_failures;
+    assert!(failures_after.invalid_enr > failures_before.invalid_enr);
+    assert!(failures_after.rejected > failures_before.rejected);
+}
+
+#[tokio::test]
+// Tests that a WHOAREYOU challenge without a handshake in response is dropped after the timeout
+async fn challenge_timeout() {
+    init();
+    let receiver_port = 5028;
+    let ip = "127.0.0.1".parse().unwrap();
+    let receiver_key = CombinedKey::generate_secp256k1();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&receiver_key)
+        .unwrap();
+    let challenge_timeout = Duration::from_millis(200);
+    let receiver_config = Discv5ConfigBuilder::new()
+        .challenge_timeout(challenge_timeout)
+        .build();
+
+    let (_exit_recv, recv_send, mut receiver_handler) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(receiver_key),
+        receiver_enr.udp4_socket().unwrap().into(),
+        receiver_config,
+    )
+    .await
+    .unwrap();
+
+    // a node that never answers the challenge
+    let src_id = NodeId::random();
+    let socket = tokio::net::UdpSocket::bind((ip, 0)).await.unwrap();
+    let packet = Packet::new_random(&src_id).unwrap();
+    socket
+        .send_to(
+            &packet.encode(&receiver_enr.node_id()),
+            receiver_enr.udp4_socket().unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let expired_before = METRICS.expired_challenges.load(Ordering::Relaxed);
+    let receiver = async {
+        let mut challenged = None;
+        loop {
+            match receiver_handler.recv().await {
+                Some(HandlerOut::WhoAreYou(wru_ref)) => {
+                    challenged = Some(Instant::now());
+                    let _ = recv_send.send(HandlerIn::WhoAreYou(wru_ref, None));
+                }
+                Some(HandlerOut::HandshakeFailed(node_id, reason)) => {
+                    return (challenged, node_id, reason)
+                }
+                _ => {}
+            }
+        }
+    };
+
+    tokio::select! {
+        (challenged, node_id, reason) = receiver => {
+            assert!(challenged.unwrap().elapsed() >= challenge_timeout);
+            assert_eq!(node_id, Some(src_id));
+            assert_eq!(reason, HandshakeFailureReason::Timeout);
+        }
+        _ = sleep(Duration::from_secs(2)) => {
+            panic!("The challenge must expire");
+        }
+    }
+    assert!(METRICS.expired_challenges.load(Ordering::Relaxed) > expired_before);
+}
+
+#[tokio::test]
+// Tests that several requests are in flight to a node over one session at once, and that their
+// responses are matched to the requests by id
+async fn pipelined_requests() {
+    init();
+    let sender_port = 5029;
+    let receiver_port = 5030;
+    let ip = "127.0.0.1".parse().unwrap();
+    let sender_key = CombinedKey::generate_secp256k1();
+    let receiver_key = CombinedKey::generate_secp256k1();
+
+    let config = Discv5ConfigBuilder::new().build();
+    let sender_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(sender_port)
+        .build(&sender_key)
+        .unwrap();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&receiver_key)
+        .unwrap();
+
+    let (_exit_send, sender_send, mut sender_recv) = Handler::spawn(
+        arc_rw!(sender_enr.clone()),
+        arc_rw!(sender_key),
+        sender_enr.udp4_socket().unwrap().into(),
+        config.clone(),
+    )
+    .await
+    .unwrap();
+
+    let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(receiver_key),
+        receiver_enr.udp4_socket().unwrap().into(),
+        config,
+    )
+    .await
+    .unwrap();
+
+    let ping = |id: u8| {
+        Box::new(Request {
+            id: RequestId(vec![id]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        })
+    };
+    // the pong to a request carries the id of the request as its sequence number
+    let pong = |id: &RequestId| {
+        Box::new(Response {
+            id: id.clone(),
+            body: ResponseBody::Pong {
+                enr_seq: u64::from(id.0[0]),
+                ip: ip.into(),
+                port: sender_port,
+            },
+        })
+    };
+
+    // the first request establishes the session
+    let _ = sender_send.send(HandlerIn::Request(receiver_enr.clone().into(), ping(1)));
+
+    let pipelined: Vec<u8> = (2..=5).collect();
+    let exchange = async move {
+        let mut received = Vec::new();
+        let mut responded = Vec::new();
+        loop {
+            tokio::select! {
+                Some(message) = receiver_recv.recv() => match message {
+                    HandlerOut::WhoAreYou(wru_ref) => {
+                        let _ =
+                            recv_send.send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                    }
+                    HandlerOut::Request(addr, request) if request.id == RequestId(vec![1]) => {
+                        let _ = recv_send.send(HandlerIn::Response(addr, pong(&request.id)));
+                    }
+                    HandlerOut::Request(addr, request) => {
+                        // the pipelined requests all arrive before any of them is answered
+                        received.push((addr, request.id));
+                        if received.len() == pipelined.len() {
+                            // answer them in reverse order
+                            for (addr, id) in received.drain(..).rev() {
+                                let _ = recv_send.send(HandlerIn::Response(addr, pong(&id)));
+                            }
+                        }
+                    }
+                    _ => {}
+                },
+                Some(message) = sender_recv.recv() => {
+                    if let HandlerOut::Response(_, response) = message {
+                        if response.id == RequestId(vec![1]) {
+                            for id in pipelined.iter() {
+                                let _ = sender_send
+                                    .send(HandlerIn::Request(receiver_enr.clone().into(), ping(*id)));
+                            }
+                            continue;
+                        }
+                        match response.body {
+                            ResponseBody::Pong { enr_seq, .. } => {
+                                assert_eq!(response.id, RequestId(vec![enr_seq as u8]));
+                            }
+                            _ => panic!("Unexpected response"),
+                        }
+                        responded.push(response.id.0[0]);
+                        if responded.len() == pipelined.len() {
+                            responded.sort_unstable();
+                            assert_eq!(responded, pipelined);
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = exchange => {}
+        _ = sleep(Duration::from_secs(1)) => {
+            panic!("Test timed out");
+        }
+    }
+}
+
+#[tokio::test]
+// Tests that the requests over the per session limit are queued until responses free a slot
+async fn max_requests_per_session() {
+    init();
+    let sender_port = 5031;
+    let receiver_port = 5032;
+    let max_requests = 2;
+    let ip = "127.0.0.1".parse().unwrap();
+    let sender_key = CombinedKey::generate_secp256k1();
+    let receiver_key = CombinedKey::generate_secp256k1();
+
+    let sender_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(sender_port)
+        .build(&sender_key)
+        .unwrap();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&receiver_key)
+        .unwrap();
+
+    let (_exit_send, sender_send, mut sender_recv) = Handler::spawn(
+        arc_rw!(sender_enr.clone()),
+        arc_rw!(sender_key),
+        sender_enr.udp4_socket().unwrap().into(),
+        Discv5ConfigBuilder::new()
+            .max_requests_per_session(max_requests)
+            .build(),
+    )
+    .await
+    .unwrap();
+
+    let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(receiver_key),
+        receiver_enr.udp4_socket().unwrap().into(),
+        Discv5ConfigBuilder::new().build(),
+    )
+    .await
+    .unwrap();
+
+    let ping = |id: u8| {
+        Box::new(Request {
+            id: RequestId(vec![id]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        })
+    };
+    let pong = |id: RequestId| {
+        Box::new(Response {
+            id,
+            body: ResponseBody::Pong {
+                enr_seq: 1,
+                ip: ip.into(),
+                port: sender_port,
+            },
+        })
+    };
+
+    // the first request establishes the session
+    let _ = sender_send.send(HandlerIn::Request(receiver_enr.clone().into(), ping(1)));
+
+    let queued: Vec<u8> = (2..=6).collect();
+    let exchange = async move {
+        let mut in_flight = Vec::new();
+        let mut responded = Vec::new();
+        loop {
+            tokio::select! {
+                Some(message) = receiver_recv.recv() => match message {
+                    HandlerOut::WhoAreYou(wru_ref) => {
+                        let _ =
+                            recv_send.send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                    }
+                    HandlerOut::Request(addr, request) if request.id == RequestId(vec![1]) => {
+                        let _ = recv_send.send(HandlerIn::Response(addr, pong(request.id)));
+                    }
+                    HandlerOut::Request(addr, request) => {
+                        in_flight.push((addr, request.id));
+                        if in_flight.len() == max_requests {
+                            // no further request arrives while the limit is reached
+                            sleep(Duration::from_millis(50)).await;
+                            while let Ok(message) = receiver_recv.try_recv() {
+                                assert!(!matches!(message, HandlerOut::Request(..)));
+                            }
+                            let (addr, id) = in_flight.remove(0);
+                            let _ = recv_send.send(HandlerIn::Response(addr, pong(id)));
+                        }
+                    }
+                    _ => {}
+                },
+                Some(message) = sender_recv.recv() => {
+                    if let HandlerOut::Response(_, response) = message {
+                        if response.id == RequestId(vec![1]) {
+                            for id in queued.iter() {
+                                let _ = sender_send
+                                    .send(HandlerIn::Request(receiver_enr.clone().into(), ping(*id)));
+                            }
+                            continue;
+                        }
+                        responded.push(response.id.0[0]);
+                        // the last request is answered once the others freed their slots
+                        if responded.len() == queued.len() - 1 {
+                            let (addr, id) = in_flight.remove(0);
+                            let _ = recv_send.send(HandlerIn::Response(addr, pong(id)));
+                        }
+                        if responded.len() == queued.len() {
+                            assert_eq!(responded, queued);
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = exchange => {}
+        _ = sleep(Duration::from_secs(2)) => {
+            panic!("Test timed out");
+        }
+    }
+}
+
+#[tokio::test]
+// Tests that a response whose id matches no request of the session is dropped, leaving the
+// request awaiting its response
+async fn mismatched_response_dropped() {
+    init();
+    let sender_port = 5033;
+    let receiver_port = 5034;
+    let ip = "127.0.0.1".parse().unwrap();
+    let sender_key = CombinedKey::generate_secp256k1();
+    let receiver_key = CombinedKey::generate_secp256k1();
+
+    let config = Discv5ConfigBuilder::new().build();
+    let sender_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(sender_port)
+        .build(&sender_key)
+        .unwrap();
+    let receiver_enr = EnrBuilder::new("v4")
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&receiver_key)
+        .unwrap();
+
+    let (_exit_send, sender_send, mut sender_recv) = Handler::spawn(
+        arc_rw!(sender_enr.clone()),
+        arc_rw!(sender_key),
+        sender_enr.udp4_socket().unwrap().into(),
+        config.clone(),
+    )
+    .await
+    .unwrap();
+
+    let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn(
+        arc_rw!(receiver_enr.clone()),
+        arc_rw!(receiver_key),
+        receiver_enr.udp4_socket().unwrap().into(),
+        config,
+    )
+    .await
+    .unwrap();
+
+    let pong = |id: RequestId| {
+        Box::new(Response {
+            id,
+            body: ResponseBody::Pong {
+                enr_seq: 1,
+                ip: ip.into(),
+                port: sender_port,
+            },
+        })
+    };
+
+    let request_id = RequestId(vec![1]);
+    let _ = sender_send.send(HandlerIn::Request(
+        receiver_enr.into(),
+        Box::new(Request {
+            id: request_id.clone(),
+            body: RequestBody::Ping { enr_seq: 1 },
+        }),
+    ));
+
+    let mismatched_before = METRICS.mismatched_responses.load(Ordering::Relaxed);
+    let exchange = async move {
+        loop {
+            tokio::select! {
+                Some(message) = receiver_recv.recv() => match message {
+                    HandlerOut::WhoAreYou(wru_ref) => {
+                        let _ =
+                            recv_send.send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                    }
+                    HandlerOut::Request(addr, request) => {
+                        // a response with a bogus id precedes the genuine response
+                        let _ = recv_send
+                            .send(HandlerIn::Response(addr.clone(), pong(RequestId(vec![9]))));
+                        let _ = recv_send.send(HandlerIn::Response(addr, pong(request.id)));
+                    }
+                    _ => {}
+                },
+                Some(message) = sender_recv.recv() => {
+                    if let HandlerOut::Response(_, response) = message {
+                        return response.id;
+                    }
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        id = exchange => assert_eq!(id, request_id),
+        _ = sleep(Duration::from_secs(1)) => {
+            panic!("Test timed out");
+        }
+    }
+    assert!(METRICS.mismatched_responses.load(Ordering::Relaxed) > mismatched_before);
 }
@@ -71,6 +71,7 @@ mod bucket;
 mod entry;
 mod filter;
 mod key;
+mod table_enr;
 
 pub use entry::*;
 
@@ -78,14 +79,15 @@ pub use crate::handler::ConnectionDirection;
 use arrayvec::{self, ArrayVec};
 use bucket::KBucket;
 pub use bucket::{
-    ConnectionState, FailureReason, InsertResult as BucketInsertResult, UpdateResult,
-    MAX_NODES_PER_BUCKET,
+    ConnectionState, EvictionPolicy, EvictionScore, FailureReason,
+    InsertResult as BucketInsertResult, UpdateResult, MAX_NODES_PER_BUCKET,
 };
 pub use filter::{Filter, IpBucketFilter, IpTableFilter};
 use std::{
     collections::VecDeque,
     time::{Duration, Instant},
 };
+pub use table_enr::TableEnr;
 
 /// Maximum number of k-buckets.
 const NUM_BUCKETS: usize = 256;
@@ -165,11 +167,10 @@ pub enum InsertResult<TNodeId> {
     Inserted,
     /// The node was inserted into a pending state.
     Pending {
-        /// The key of the least-recently connected entry that is currently considered
-        /// disconnected and whose corresponding peer should be checked for connectivity
-        /// in order to prevent it from being evicted. If connectivity to the peer is
-        /// re-established, the corresponding entry should be updated with
-        /// [`bucket::ConnectionState::Connected`].
+        /// The key of the unpinned, disconnected entry chosen for eviction by the bucket's
+        /// eviction policy, whose peer should be checked for connectivity in order to prevent it
+        /// from being evicted. If connectivity to the peer is re-established, the corresponding
+        /// entry should be updated with [`bucket::ConnectionState::Connected`].
         disconnected: Key<TNodeId>,
     },
     /// The node existed and the status was updated.
@@ -186,6 +187,8 @@ pub enum InsertResult<TNodeId> {
     },
     /// The pending slot was updated.
     UpdatedPending,
+    /// The node existed and neither its status nor its value changed.
+    NotModified,
     /// The record failed to be inserted. This can happen to not passing table/bucket filters or
     /// the bucket was full.
     Failed(FailureReason),
@@ -230,12 +233,15 @@ where
     ///
     /// A filter can be applied that limits entries into a bucket based on the buckets contents.
     /// Entries that fail the filter, will not be inserted.
+    ///
+    /// The `eviction_policy` chooses the node a pending entry replaces in a full bucket.
     pub fn new(
         local_key: Key<TNodeId>,
         pending_timeout: Duration,
         max_incoming_per_bucket: usize,
         table_filter: Option<Box<dyn Filter<TVal>>>,
         bucket_filter: Option<Box<dyn Filter<TVal>>>,
+        eviction_policy: EvictionPolicy<TNodeId, TVal>,
     ) -> Self {
         KBucketsTable {
             local_key,
@@ -245,6 +251,7 @@ where
                         pending_timeout,
                         max_incoming_per_bucket,
                         bucket_filter.clone(),
+                        eviction_policy.clone(),
                     )
                 })
                 .collect(),
@@ -395,6 +402,8 @@ where
                     key: key.clone(),
                     value,
                     status,
+                    last_seen: Instant::now(),
+                    failures: 0,
                 };
                 match bucket.insert(node) {
                     bucket::InsertResult::NodeExists => unreachable!("Node must exist"),
@@ -448,9 +457,7 @@ where
                         }
                     }
                     (UpdateResult::NotModified, UpdateResult::NotModified) => {
-                        InsertResult::Updated {
-                            promoted_to_connected: false,
-                        }
+                        InsertResult::NotModified
                     }
                     (UpdateResult::UpdatedPending, _) | (_, UpdateResult::UpdatedPending) => {
                         InsertResult::UpdatedPending
@@ -468,6 +475,41 @@ where
         }
     }
 
+    /// Pins a node, which is then never evicted from its bucket by a pending node. Nodes that
+    /// aren't in the table yet are pinned once inserted. Returns whether the node wasn't pinned
+    /// already, the local key can't be pinned.
+    pub fn pin(&mut self, key: Key<TNodeId>) -> bool {
+        match BucketIndex::new(&self.local_key.distance(&key)) {
+            Some(i) => self.buckets[i.get()].pin(key),
+            None => false,
+        }
+    }
+
+    /// Unpins a node. Returns whether the node was pinned.
+    pub fn unpin(&mut self, key: &Key<TNodeId>) -> bool {
+        match BucketIndex::new(&self.local_key.distance(key)) {
+            Some(i) => self.buckets[i.get()].unpin(key),
+            None => false,
+        }
+    }
+
+    /// Returns whether a node is pinned.
+    pub fn is_pinned(&self, key: &Key<TNodeId>) -> bool {
+        match BucketIndex::new(&self.local_key.distance(key)) {
+            Some(i) => self.buckets[i.get()].is_pinned(key),
+            None => false,
+        }
+    }
+
+    /// Records a request a node failed to answer, counted by the `MostFailures` eviction policy
+    /// until the node is marked as connected again. Returns whether the node is in the table.
+    pub fn record_failure(&mut self, key: &Key<TNodeId>) -> bool {
+        match BucketIndex::new(&self.local_key.distance(key)) {
+            Some(i) => self.buckets[i.get()].record_failure(key),
+            None => false,
+        }
+    }
+
     /// Removes a node from the routing table. Returns `true` of the node existed.
     pub fn remove(&mut self, key: &Key<TNodeId>) -> bool {
         let index = BucketIndex::new(&self.local_key.distance(key));
@@ -895,6 +937,7 @@ mod tests {
             MAX_NODES_PER_BUCKET,
             None,
             None,
+            EvictionPolicy::default(),
         );
         if let Entry::Absent(entry) = table.entry(&other_id) {
             match entry.insert((), connected_state()) {
@@ -919,6 +962,7 @@ mod tests {
             MAX_NODES_PER_BUCKET,
             None,
             None,
+            EvictionPolicy::default(),
         );
         match table.entry(&local_key) {
             Entry::SelfEntry => (),
@@ -935,6 +979,7 @@ mod tests {
             MAX_NODES_PER_BUCKET,
             None,
             None,
+            EvictionPolicy::default(),
         );
         let mut count = 0;
         loop {
@@ -976,8 +1021,9 @@ mod tests {
             MAX_NODES_PER_BUCKET,
             None,
             None,
+            EvictionPolicy::default(),
         );
-        let expected_applied;
+        let mut expected_applied;
         let full_bucket_index;
         loop {
             let key = Key::from(NodeId::random());
@@ -993,6 +1039,8 @@ mod tests {
                                             key: disconnected,
                                             value: (),
                                             status: disconnected_state(),
+                                            last_seen: Instant::now(),
+                                            failures: 0,
                                         }),
                                     };
                                     full_bucket_index = BucketIndex::new(&key.distance(&local_key));
@@ -1013,6 +1061,8 @@ mod tests {
 
         // Expire the timeout for the pending entry on the full bucket.`
         let full_bucket = &mut table.buckets[full_bucket_index.unwrap().get()];
+        let evicted = expected_applied.evicted.as_mut().unwrap();
+        evicted.last_seen = full_bucket.get(&evicted.key).unwrap().last_seen;
         let elapsed = Instant::now() - Duration::from_secs(1);
         full_bucket.pending_mut().unwrap().set_ready_at(elapsed);
 
@@ -31,6 +31,7 @@
 #![allow(dead_code)]
 
 use super::*;
+use std::sync::Arc;
 use tracing::{debug, error};
 
 /// Maximum number of nodes in a bucket, i.e. the (fixed) `k` parameter.
@@ -110,6 +111,58 @@ pub struct Node<TNodeId, TVal: Eq> {
     pub value: TVal,
     /// The status of the node.
     pub status: NodeStatus,
+    /// The time the node was inserted, or last marked as connected.
+    pub last_seen: Instant,
+    /// The number of requests the node failed to answer since it was last marked as connected.
+    pub failures: u32,
+}
+
+/// A user-supplied score of a node for the `Custom` eviction policy.
+pub type EvictionScore<TNodeId, TVal> = Arc<dyn Fn(&Node<TNodeId, TVal>) -> u64 + Send + Sync>;
+
+/// The strategy choosing which node to evict from a full bucket for a pending node. Only
+/// disconnected nodes that aren't pinned are evicted.
+#[derive(Default)]
+pub enum EvictionPolicy<TNodeId, TVal: Eq> {
+    /// Evicts the node that was least recently seen connected.
+    #[default]
+    LeastRecentlySeen,
+    /// Evicts the node that failed the most requests, the least recently seen of them on a tie.
+    MostFailures,
+    /// Evicts the node with the highest score given by the closure, the least recently seen of
+    /// them on a tie.
+    Custom(EvictionScore<TNodeId, TVal>),
+}
+
+impl<TNodeId, TVal: Eq> EvictionPolicy<TNodeId, TVal> {
+    /// The score of a node, the node with the highest score being evicted.
+    fn score(&self, node: &Node<TNodeId, TVal>) -> u64 {
+        match self {
+            EvictionPolicy::LeastRecentlySeen => 0,
+            EvictionPolicy::MostFailures => node.failures.into(),
+            EvictionPolicy::Custom(score) => score(node),
+        }
+    }
+}
+
+impl<TNodeId, TVal: Eq> Clone for EvictionPolicy<TNodeId, TVal> {
+    fn clone(&self) -> Self {
+        match self {
+            EvictionPolicy::LeastRecentlySeen => EvictionPolicy::LeastRecentlySeen,
+            EvictionPolicy::MostFailures => EvictionPolicy::MostFailures,
+            EvictionPolicy::Custom(score) => EvictionPolicy::Custom(score.clone()),
+        }
+    }
+}
+
+impl<TNodeId, TVal: Eq> std::fmt::Debug for EvictionPolicy<TNodeId, TVal> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvictionPolicy::LeastRecentlySeen => write!(f, "LeastRecentlySeen"),
+            EvictionPolicy::MostFailures => write!(f, "MostFailures"),
+            EvictionPolicy::Custom(_) => write!(f, "Custom"),
+        }
+    }
 }
 
 /// The position of a node in a `KBucket`, i.e. a non-negative integer
@@ -155,6 +208,12 @@ pub struct KBucket<TNodeId, TVal: Eq> {
     /// The maximum number of incoming connections allowed per bucket. Setting this to
     /// MAX_NODES_PER_BUCKET means there is no restriction on incoming nodes.
     max_incoming: usize,
+
+    /// The keys of the pinned nodes of the bucket, which are never evicted by a pending node.
+    pinned: Vec<Key<TNodeId>>,
+
+    /// The strategy choosing which node a pending node replaces.
+    eviction_policy: EvictionPolicy<TNodeId, TVal>,
 }
 
 /// The result of inserting an entry into a bucket.
@@ -245,6 +304,7 @@ where
         pending_timeout: Duration,
         max_incoming: usize,
         filter: Option<Box<dyn Filter<TVal>>>,
+        eviction_policy: EvictionPolicy<TNodeId, TVal>,
     ) -> Self {
         KBucket {
             nodes: ArrayVec::new(),
@@ -253,6 +313,58 @@ where
             pending_timeout,
             filter,
             max_incoming,
+            pinned: Vec::new(),
+            eviction_policy,
+        }
+    }
+
+    /// Pins a node, which is then never evicted by a pending node. Returns whether the node
+    /// wasn't pinned already.
+    pub fn pin(&mut self, key: Key<TNodeId>) -> bool {
+        if self.is_pinned(&key) {
+            return false;
+        }
+        self.pinned.push(key);
+        true
+    }
+
+    /// Unpins a node. Returns whether the node was pinned.
+    pub fn unpin(&mut self, key: &Key<TNodeId>) -> bool {
+        let len = self.pinned.len();
+        self.pinned.retain(|pinned| pinned != key);
+        self.pinned.len() != len
+    }
+
+    /// Returns whether a node is pinned.
+    pub fn is_pinned(&self, key: &Key<TNodeId>) -> bool {
+        self.pinned.contains(key)
+    }
+
+    /// Returns the position of the node a pending node replaces: the disconnected node that isn't
+    /// pinned chosen by the eviction policy.
+    fn eviction_candidate(&self) -> Option<Position> {
+        let disconnected = self.first_connected_pos.unwrap_or(self.nodes.len());
+        self.nodes[..disconnected]
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| !self.is_pinned(&node.key))
+            .min_by_key(|(_, node)| {
+                (
+                    std::cmp::Reverse(self.eviction_policy.score(node)),
+                    node.last_seen,
+                )
+            })
+            .map(|(pos, _)| Position(pos))
+    }
+
+    /// Records a request the node failed to answer. Returns whether the node is in the bucket.
+    pub fn record_failure(&mut self, key: &Key<TNodeId>) -> bool {
+        match self.get_mut(key) {
+            Some(node) => {
+                node.failures = node.failures.saturating_add(1);
+                true
+            }
+            None => false,
         }
     }
 
@@ -278,7 +390,7 @@ where
     }
 
     /// Inserts the pending node into the bucket, if its timeout has elapsed,
-    /// replacing the least-recently connected node.
+    /// replacing the least-recently connected node that isn't pinned.
     ///
     /// If a pending node has been inserted, its key is returned together with
     /// the node that was replaced. `None` indicates that the nodes in the
@@ -290,10 +402,12 @@ where
                 if self.nodes.is_full() {
                     // Apply bucket filters
 
-                    if self.nodes[0].status.is_connected() {
-                        // The bucket is full with connected nodes. Drop the pending node.
-                        return None;
-                    }
+                    let evict = match self.eviction_candidate() {
+                        Some(Position(evict)) => evict,
+                        // The bucket is full with connected or pinned nodes. Drop the pending
+                        // node.
+                        None => return None,
+                    };
                     // Check the custom filter
                     if let Some(filter) = self.filter.as_ref() {
                         if !filter.filter(
@@ -320,7 +434,7 @@ where
                     // A connected pending node goes at the end of the list for
                     // the connected peers, removing the least-recently connected.
                     if pending.status().is_connected() {
-                        let evicted = Some(self.nodes.remove(0));
+                        let evicted = Some(self.nodes.remove(evict));
                         self.first_connected_pos = self
                             .first_connected_pos
                             .map_or_else(|| Some(self.nodes.len()), |p| p.checked_sub(1));
@@ -331,14 +445,14 @@ where
                     // for the disconnected peers.
                     else if let Some(p) = self.first_connected_pos {
                         if let Some(insert_pos) = p.checked_sub(1) {
-                            let evicted = Some(self.nodes.remove(0));
+                            let evicted = Some(self.nodes.remove(evict));
                             self.nodes.insert(insert_pos, pending.node);
                             return Some(AppliedPending { inserted, evicted });
                         }
                     } else {
                         // All nodes are disconnected. Insert the new node as the most
                         // recently disconnected, removing the least-recently disconnected.
-                        let evicted = Some(self.nodes.remove(0));
+                        let evicted = Some(self.nodes.remove(evict));
                         self.nodes.push(pending.node);
                         return Some(AppliedPending { inserted, evicted });
                     }
@@ -394,6 +508,7 @@ where
         // nodes (i.e. most-recently disconnected or most-recently connected,
         // respectively).
         if let Some(pos) = self.position(key) {
+            let evictable = self.eviction_candidate() == Some(pos);
             // Remove the node from its current position.
             let mut node = self.nodes.remove(pos.0);
             let old_status = node.status;
@@ -401,6 +516,10 @@ where
             if let Some(direction) = direction {
                 node.status.direction = direction;
             }
+            if matches!(state, ConnectionState::Connected) {
+                node.last_seen = Instant::now();
+                node.failures = 0;
+            }
 
             // Flag indicating if this update modified the entry.
             let not_modified = old_status == node.status;
@@ -422,9 +541,9 @@ where
                         self.first_connected_pos.and_then(|p| p.checked_sub(1))
                 }
             }
-            // If the least-recently connected node re-establishes its
+            // If the node the pending node would replace re-establishes its
             // connected status, drop the pending node.
-            if pos == Position(0) && is_connected {
+            if evictable && is_connected {
                 self.pending = None
             }
             // Reinsert the node with the desired status.
@@ -518,9 +637,9 @@ where
     /// The status of the node to insert determines the result as follows:
     ///
     ///   * [`ConnectionState::Connected`] for both directions: If the bucket is full and either all nodes are connected
-    ///     or there is already a pending node, insertion fails with [`InsertResult::Full`].
-    ///     If the bucket is full but at least one node is disconnected and there is no pending
-    ///     node, the new node is inserted as pending, yielding [`InsertResult::Pending`].
+    ///     or pinned, or there is already a pending node, insertion fails with [`InsertResult::Full`].
+    ///     If the bucket is full but at least one node is disconnected and not pinned, and there is
+    ///     no pending node, the new node is inserted as pending, yielding [`InsertResult::Pending`].
     ///     Otherwise the bucket has free slots and the new node is added to the end of the
     ///     bucket as the most-recently connected node.
     ///
@@ -562,16 +681,16 @@ where
                     }
                 }
                 if self.nodes.is_full() {
-                    if self.first_connected_pos == Some(0) || self.pending.is_some() {
-                        return InsertResult::Full;
-                    } else {
-                        self.pending = Some(PendingNode {
-                            node,
-                            replace: Instant::now() + self.pending_timeout,
-                        });
-                        return InsertResult::Pending {
-                            disconnected: self.nodes[0].key.clone(),
-                        };
+                    match self.eviction_candidate() {
+                        Some(Position(evict)) if self.pending.is_none() => {
+                            let disconnected = self.nodes[evict].key.clone();
+                            self.pending = Some(PendingNode {
+                                node,
+                                replace: Instant::now() + self.pending_timeout,
+                            });
+                            return InsertResult::Pending { disconnected };
+                        }
+                        _ => return InsertResult::Full,
                     }
                 }
 
@@ -795,7 +914,12 @@ pub mod tests {
     {
         fn arbitrary<G: Gen>(g: &mut G) -> KBucket<NodeId, V> {
             let timeout = Duration::from_secs(g.gen_range(1, g.size() as u64));
-            let mut bucket = KBucket::<NodeId, V>::new(timeout, MAX_NODES_PER_BUCKET, None);
+            let mut bucket = KBucket::<NodeId, V>::new(
+                timeout,
+                MAX_NODES_PER_BUCKET,
+                None,
+                EvictionPolicy::default(),
+            );
             let num_nodes = g.gen_range(1, MAX_NODES_PER_BUCKET + 1);
             for _ in 0..num_nodes {
                 loop {
@@ -821,6 +945,8 @@ pub mod tests {
                 key,
                 value: V::arbitrary(g),
                 status: NodeStatus::arbitrary(g),
+                last_seen: Instant::now(),
+                failures: 0,
             }
         }
     }
@@ -864,6 +990,8 @@ pub mod tests {
                 key,
                 value: (),
                 status,
+                last_seen: Instant::now(),
+                failures: 0,
             };
             assert_eq!(InsertResult::Inserted, bucket.insert(node));
             assert_eq!(bucket.num_entries(), num_entries_start + i + 1);
@@ -980,8 +1108,12 @@ pub mod tests {
     #[test]
     fn ordering() {
         fn prop(status: Vec<NodeStatus>) -> bool {
-            let mut bucket =
-                KBucket::<NodeId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET, None);
+            let mut bucket = KBucket::<NodeId, ()>::new(
+                Duration::from_secs(1),
+                MAX_NODES_PER_BUCKET,
+                None,
+                EvictionPolicy::default(),
+            );
 
             // The expected lists of connected and disconnected nodes.
             let mut connected = VecDeque::new();
@@ -994,6 +1126,8 @@ pub mod tests {
                     key: key.clone(),
                     value: (),
                     status,
+                    last_seen: Instant::now(),
+                    failures: 0,
                 };
                 let full = bucket.num_entries() == MAX_NODES_PER_BUCKET;
                 if let InsertResult::Inserted = bucket.insert(node) {
@@ -1031,8 +1165,12 @@ pub mod tests {
 
     #[test]
     fn full_bucket() {
-        let mut bucket =
-            KBucket::<NodeId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET, None);
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            None,
+            EvictionPolicy::default(),
+        );
 
         let disconnected_status = NodeStatus {
             state: ConnectionState::Disconnected,
@@ -1047,6 +1185,8 @@ pub mod tests {
             key,
             value: (),
             status: disconnected_status,
+            last_seen: Instant::now(),
+            failures: 0,
         };
         match bucket.insert(node) {
             InsertResult::Full => {}
@@ -1066,6 +1206,8 @@ pub mod tests {
                 key: key.clone(),
                 value: (),
                 status: connected_state(),
+                last_seen: Instant::now(),
+                failures: 0,
             };
             match bucket.insert(node.clone()) {
                 InsertResult::Pending { disconnected } => {
@@ -1113,6 +1255,8 @@ pub mod tests {
             key,
             value: (),
             status: connected_state(),
+            last_seen: Instant::now(),
+            failures: 0,
         };
         match bucket.insert(node) {
             InsertResult::Full => {}
@@ -1122,8 +1266,12 @@ pub mod tests {
 
     #[test]
     fn full_bucket_discard_pending() {
-        let mut bucket =
-            KBucket::<NodeId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET, None);
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            None,
+            EvictionPolicy::default(),
+        );
         fill_bucket(&mut bucket, disconnected_state());
         let first = bucket.iter().next().unwrap();
         let first_disconnected = first.clone();
@@ -1134,6 +1282,8 @@ pub mod tests {
             key: key.clone(),
             value: (),
             status: connected_state(),
+            last_seen: Instant::now(),
+            failures: 0,
         };
         if let InsertResult::Pending { disconnected } = bucket.insert(node) {
             assert_eq!(&disconnected, &first_disconnected.key);
@@ -1162,12 +1312,139 @@ pub mod tests {
         assert_eq!(MAX_NODES_PER_BUCKET - 1, bucket.num_disconnected());
     }
 
+    #[test]
+    fn full_bucket_pinned_node() {
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(0),
+            MAX_NODES_PER_BUCKET,
+            None,
+            EvictionPolicy::default(),
+        );
+        fill_bucket(&mut bucket, disconnected_state());
+        let keys: Vec<Key<NodeId>> = bucket.iter().map(|node| node.key.clone()).collect();
+
+        // Pin the least-recently connected node.
+        assert!(bucket.pin(keys[0].clone()));
+        assert!(!bucket.pin(keys[0].clone()));
+
+        // A pending node replaces the first node that isn't pinned.
+        let node = Node {
+            key: Key::from(NodeId::random()),
+            value: (),
+            status: connected_state(),
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        match bucket.insert(node) {
+            InsertResult::Pending { disconnected } => assert_eq!(disconnected, keys[1]),
+            _ => panic!("The node should be pending"),
+        }
+        let applied = bucket.apply_pending().unwrap();
+        assert_eq!(applied.evicted.unwrap().key, keys[1]);
+        assert!(bucket.position(&keys[0]).is_some());
+        assert!(bucket.position(&keys[1]).is_none());
+
+        // Once all the disconnected nodes are pinned, the bucket is full.
+        for key in keys.iter().skip(2) {
+            assert!(bucket.pin(key.clone()));
+        }
+        let node = Node {
+            key: Key::from(NodeId::random()),
+            value: (),
+            status: connected_state(),
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        assert_eq!(InsertResult::Full, bucket.insert(node));
+
+        // An unpinned node can be evicted again.
+        assert!(bucket.unpin(&keys[0]));
+        assert!(!bucket.unpin(&keys[0]));
+        let node = Node {
+            key: Key::from(NodeId::random()),
+            value: (),
+            status: connected_state(),
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        match bucket.insert(node) {
+            InsertResult::Pending { disconnected } => assert_eq!(disconnected, keys[0]),
+            _ => panic!("The node should be pending"),
+        }
+    }
+
+    #[test]
+    fn full_bucket_eviction_policy() {
+        fn evicted(policy: EvictionPolicy<NodeId, ()>, failed: &[usize]) -> (usize, usize) {
+            let mut bucket = KBucket::<NodeId, ()>::new(
+                Duration::from_secs(0),
+                MAX_NODES_PER_BUCKET,
+                None,
+                policy,
+            );
+            fill_bucket(&mut bucket, disconnected_state());
+            let keys: Vec<Key<NodeId>> = bucket.iter().map(|node| node.key.clone()).collect();
+            for i in failed {
+                assert!(bucket.record_failure(&keys[*i]));
+            }
+
+            let node = Node {
+                key: Key::from(NodeId::random()),
+                value: (),
+                status: connected_state(),
+                last_seen: Instant::now(),
+                failures: 0,
+            };
+            let disconnected = match bucket.insert(node) {
+                InsertResult::Pending { disconnected } => disconnected,
+                _ => panic!("The node should be pending"),
+            };
+            let evicted = bucket.apply_pending().unwrap().evicted.unwrap().key;
+            let position = |key| keys.iter().position(|k| k == &key).unwrap();
+            (position(disconnected), position(evicted))
+        }
+
+        // The least-recently seen node is evicted, regardless of its failures.
+        assert_eq!(
+            evicted(EvictionPolicy::LeastRecentlySeen, &[5, 3, 3]),
+            (0, 0)
+        );
+        // The node that failed the most requests is evicted.
+        assert_eq!(evicted(EvictionPolicy::MostFailures, &[5, 3, 3]), (3, 3));
+        // Without failures, the least-recently seen node is evicted.
+        assert_eq!(evicted(EvictionPolicy::MostFailures, &[]), (0, 0));
+        // The node with the highest custom score is evicted.
+        fn score(node: &Node<NodeId, ()>) -> u64 {
+            let mut prefix = [0; 8];
+            prefix.copy_from_slice(&node.key.preimage().raw()[..8]);
+            u64::from_be_bytes(prefix)
+        }
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(0),
+            MAX_NODES_PER_BUCKET,
+            None,
+            EvictionPolicy::Custom(Arc::new(score)),
+        );
+        fill_bucket(&mut bucket, disconnected_state());
+        let highest = bucket
+            .iter()
+            .max_by_key(|node| score(node))
+            .unwrap()
+            .key
+            .clone();
+        assert_eq!(bucket.eviction_candidate(), bucket.position(&highest));
+    }
+
     /// No duplicate nodes can be inserted via the apply_pending function.
     #[test]
     fn full_bucket_applied_no_duplicates() {
         // First fill the bucket with connected nodes.
-        let mut bucket =
-            KBucket::<NodeId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET, None);
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            None,
+            EvictionPolicy::default(),
+        );
         fill_bucket(&mut bucket, connected_state());
 
         let first = bucket.iter().next().unwrap().clone();
@@ -1187,6 +1464,8 @@ pub mod tests {
             key,
             value: (),
             status: connected_state(),
+            last_seen: Instant::now(),
+            failures: 0,
         };
 
         // Add a pending node
@@ -1319,8 +1598,12 @@ pub mod tests {
         ) -> bool {
             let filter = SetFilter { set: filter_set };
             let pending_timeout = Duration::from_millis(pending_timeout_millis);
-            let mut kbucket =
-                KBucket::<NodeId, u8>::new(pending_timeout, max_incoming, Some(Box::new(filter)));
+            let mut kbucket = KBucket::<NodeId, u8>::new(
+                pending_timeout,
+                max_incoming,
+                Some(Box::new(filter)),
+                EvictionPolicy::default(),
+            );
 
             for node in initial_nodes {
                 let _ = kbucket.insert(node);
@@ -1342,7 +1625,12 @@ pub mod tests {
     #[test]
     fn table_update_status_connection() {
         let max_incoming = 7;
-        let mut bucket = KBucket::<NodeId, ()>::new(Duration::from_secs(1), max_incoming, None);
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            max_incoming,
+            None,
+            EvictionPolicy::default(),
+        );
 
         let mut incoming_connected = 0;
         let mut keys = Vec::new();
@@ -1363,6 +1651,8 @@ pub mod tests {
                 key: key.clone(),
                 value: (),
                 status,
+                last_seen: Instant::now(),
+                failures: 0,
             };
             assert_eq!(InsertResult::Inserted, bucket.insert(node));
         }
@@ -1399,8 +1689,12 @@ pub mod tests {
     fn bucket_max_incoming_nodes() {
         fn prop(status: Vec<NodeStatus>) -> bool {
             let max_incoming_nodes = 5;
-            let mut bucket =
-                KBucket::<NodeId, ()>::new(Duration::from_secs(1), max_incoming_nodes, None);
+            let mut bucket = KBucket::<NodeId, ()>::new(
+                Duration::from_secs(1),
+                max_incoming_nodes,
+                None,
+                EvictionPolicy::default(),
+            );
 
             // The expected lists of connected and disconnected nodes.
             let mut connected = VecDeque::new();
@@ -1413,6 +1707,8 @@ pub mod tests {
                     key: key.clone(),
                     value: (),
                     status,
+                    last_seen: Instant::now(),
+                    failures: 0,
                 };
                 let full = bucket.num_entries() == MAX_NODES_PER_BUCKET;
                 match bucket.insert(node) {
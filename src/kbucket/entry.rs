@@ -206,6 +206,8 @@ where
             key: self.0.key.clone(),
             value,
             status,
+            last_seen: Instant::now(),
+            failures: 0,
         })
     }
 }
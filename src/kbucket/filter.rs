@@ -1,6 +1,6 @@
 //! Provides a trait that can be implemented to apply a filter to a table or bucket.
 
-use crate::Enr;
+use super::TableEnr;
 
 pub trait Filter<TVal: Eq>: FilterClone<TVal> + Send + Sync {
     fn filter(
@@ -40,11 +40,11 @@ const MAX_NODES_PER_SUBNET_BUCKET: usize = 2;
 #[derive(Clone)]
 pub struct IpTableFilter;
 
-impl Filter<Enr> for IpTableFilter {
+impl Filter<TableEnr> for IpTableFilter {
     fn filter(
         &self,
-        value_to_be_inserted: &Enr,
-        other_vals: &mut dyn Iterator<Item = &Enr>,
+        value_to_be_inserted: &TableEnr,
+        other_vals: &mut dyn Iterator<Item = &TableEnr>,
     ) -> bool {
         ip_filter(value_to_be_inserted, other_vals, MAX_NODES_PER_SUBNET_TABLE)
     }
@@ -53,11 +53,11 @@ impl Filter<Enr> for IpTableFilter {
 #[derive(Clone)]
 pub struct IpBucketFilter;
 
-impl Filter<Enr> for IpBucketFilter {
+impl Filter<TableEnr> for IpBucketFilter {
     fn filter(
         &self,
-        value_to_be_inserted: &Enr,
-        other_vals: &mut dyn Iterator<Item = &Enr>,
+        value_to_be_inserted: &TableEnr,
+        other_vals: &mut dyn Iterator<Item = &TableEnr>,
     ) -> bool {
         ip_filter(
             value_to_be_inserted,
@@ -68,8 +68,8 @@ impl Filter<Enr> for IpBucketFilter {
 }
 
 fn ip_filter(
-    value_to_be_inserted: &Enr,
-    other_vals: &mut dyn Iterator<Item = &Enr>,
+    value_to_be_inserted: &TableEnr,
+    other_vals: &mut dyn Iterator<Item = &TableEnr>,
     limit: usize,
 ) -> bool {
     if let Some(ip) = value_to_be_inserted.ip4() {
@@ -0,0 +1,114 @@
+//! The ENRs held by the routing table, optionally in their compact RLP-encoded form.
+
+use crate::Enr;
+use std::net::Ipv4Addr;
+
+/// An ENR held by the routing table.
+///
+/// Compact ENRs are kept RLP-encoded and decoded on access, trading CPU for memory. Their
+/// sequence number and IPv4 address remain decoded, as the table compares and filters them on
+/// insertions.
+#[derive(Debug, Clone)]
+pub struct TableEnr(Repr);
+
+#[derive(Debug, Clone)]
+enum Repr {
+    Decoded(Enr),
+    Encoded {
+        seq: u64,
+        ip4: Option<Ipv4Addr>,
+        rlp: Box<[u8]>,
+    },
+}
+
+impl TableEnr {
+    /// Wraps an ENR, encoding it if `compact` is set.
+    pub fn new(enr: Enr, compact: bool) -> Self {
+        if compact {
+            TableEnr(Repr::Encoded {
+                seq: enr.seq(),
+                ip4: enr.ip4(),
+                rlp: rlp::encode(&enr).to_vec().into_boxed_slice(),
+            })
+        } else {
+            TableEnr(Repr::Decoded(enr))
+        }
+    }
+
+    /// Returns the ENR, decoding it if it is stored in its compact form.
+    pub fn enr(&self) -> Enr {
+        match &self.0 {
+            Repr::Decoded(enr) => enr.clone(),
+            Repr::Encoded { rlp, .. } => {
+                rlp::decode(rlp).expect("Encoded from a valid ENR, must decode")
+            }
+        }
+    }
+
+    /// The sequence number of the ENR.
+    pub fn seq(&self) -> u64 {
+        match &self.0 {
+            Repr::Decoded(enr) => enr.seq(),
+            Repr::Encoded { seq, .. } => *seq,
+        }
+    }
+
+    /// The IPv4 address of the ENR, if any.
+    pub fn ip4(&self) -> Option<Ipv4Addr> {
+        match &self.0 {
+            Repr::Decoded(enr) => enr.ip4(),
+            Repr::Encoded { ip4, .. } => *ip4,
+        }
+    }
+
+    /// Whether the ENR is stored in its compact form.
+    pub fn is_compact(&self) -> bool {
+        matches!(self.0, Repr::Encoded { .. })
+    }
+}
+
+impl From<Enr> for TableEnr {
+    fn from(enr: Enr) -> Self {
+        TableEnr::new(enr, false)
+    }
+}
+
+impl PartialEq for TableEnr {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Repr::Decoded(enr), Repr::Decoded(other)) => enr == other,
+            (Repr::Encoded { rlp, .. }, Repr::Encoded { rlp: other, .. }) => rlp == other,
+            _ => self.enr() == other.enr(),
+        }
+    }
+}
+
+impl Eq for TableEnr {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::{CombinedKey, EnrBuilder};
+
+    fn test_enr() -> Enr {
+        let key = CombinedKey::generate_secp256k1();
+        EnrBuilder::new("v4")
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(9000)
+            .tcp4(9000)
+            .add_value("eth2", &[1u8; 16])
+            .build(&key)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_compact_round_trip() {
+        let enr = test_enr();
+        let compact = TableEnr::new(enr.clone(), true);
+        assert!(compact.is_compact());
+        assert_eq!(compact.enr(), enr);
+        assert_eq!(compact.seq(), enr.seq());
+        assert_eq!(compact.ip4(), enr.ip4());
+        assert_eq!(compact, TableEnr::from(enr));
+    }
+}
@@ -117,6 +117,7 @@ pub mod permit_ban;
 mod query_pool;
 pub mod rpc;
 pub mod service;
+mod signed_value;
 pub mod socket;
 
 #[macro_use]
@@ -125,13 +126,18 @@ extern crate lazy_static;
 pub type Enr = enr::Enr<enr::CombinedKey>;
 
 pub use crate::discv5::{Discv5, Discv5Event};
-pub use config::{Discv5Config, Discv5ConfigBuilder};
-pub use error::{Discv5Error, QueryError, RequestError, ResponseError};
+pub use config::{
+    Discv5Config, Discv5ConfigBuilder, EnrFilter, HandshakeFilter, PacketFilter, StoreValidator,
+};
+pub use error::{Discv5Error, FindValueError, QueryError, RequestError, ResponseError};
 pub use executor::{Executor, TokioExecutor};
+pub use handler::HandshakeFailureReason;
 pub use ipmode::IpMode;
-pub use kbucket::{ConnectionDirection, ConnectionState, Key};
+pub use kbucket::{ConnectionDirection, ConnectionState, EvictionPolicy, Key};
 pub use permit_ban::PermitBanList;
-pub use service::TalkRequest;
+pub use service::{
+    target_distances, PingResponse, QueryCompletion, QueryStatus, QueryType, TalkRequest,
+};
 pub use socket::{RateLimiter, RateLimiterBuilder};
 // re-export the ENR crate
 pub use enr;
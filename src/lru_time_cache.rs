@@ -61,7 +61,6 @@ impl<K: Clone + Eq + Hash, V> LruTimeCache<K, V> {
 
     /// Returns a reference to the value with the given `key`, if present and not expired, without
     /// updating the timestamp.
-    #[allow(dead_code)]
     pub fn peek(&self, key: &K) -> Option<&V> {
         if let Some((value, time)) = self.map.get(key) {
             return if *time + self.ttl >= Instant::now() {
@@ -86,6 +85,16 @@ impl<K: Clone + Eq + Hash, V> LruTimeCache<K, V> {
         self.map.remove(key).map(|v| v.0)
     }
 
+    /// Returns an iterator over the key-value pairs of the cache, without updating their time.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.map.iter_mut().map(|(key, (value, _))| (key, value))
+    }
+
+    /// Retains only the key-value pairs for which `f` returns `true`.
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &V) -> bool) {
+        self.map.retain(|key, (value, _)| f(key, value));
+    }
+
     /// Removes expired items from the cache.
     fn remove_expired_values(&mut self, now: Instant) {
         let mut expired_keys = vec![];
@@ -187,6 +196,19 @@ mod tests {
         assert_eq!(3, cache.len());
     }
 
+    #[test]
+    fn retain() {
+        let mut cache = LruTimeCache::new(Duration::from_secs(10), None);
+
+        cache.insert(1, 10);
+        cache.insert(2, 20);
+        cache.insert(3, 30);
+        cache.retain(|key, _| key != &2);
+        assert_eq!(2, cache.len());
+        assert_eq!(None, cache.peek(&2));
+        assert_eq!(Some(&30), cache.peek(&3));
+    }
+
     #[test]
     fn remove() {
         let mut cache = LruTimeCache::new(Duration::from_secs(10), None);
@@ -1,13 +1,59 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::{handler::HandshakeFailureReason, packet::PacketKind, service::QueryCompletion};
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
 
 lazy_static! {
     pub static ref METRICS: InternalMetrics = InternalMetrics::default();
 }
 
+/// The upper bounds of the buckets of the query duration histogram. Queries lasting longer than
+/// the last bound are counted in an additional bucket.
+pub const QUERY_DURATION_BUCKETS: [Duration; 6] = [
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+    Duration::from_secs(30),
+];
+
+/// Counters of the queries of a kind and their outcomes.
+#[derive(Default)]
+pub struct QueryCounters {
+    /// The number of queries started.
+    pub started: AtomicUsize,
+    /// The number of queries that found their results.
+    pub completed: AtomicUsize,
+    /// The number of queries that timed out.
+    pub timed_out: AtomicUsize,
+    /// The number of queries that ran out of peers to contact.
+    pub exhausted: AtomicUsize,
+}
+
+impl QueryCounters {
+    /// Records that a query finished with the given outcome.
+    pub fn add_completion(&self, completion: QueryCompletion) {
+        let counter = match completion {
+            QueryCompletion::Finished => &self.completed,
+            QueryCompletion::TimedOut => &self.timed_out,
+            QueryCompletion::Exhausted => &self.exhausted,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 /// A collection of metrics used throughout the server.
 pub struct InternalMetrics {
     /// The number of active UDP sessions that are currently established.
     pub active_sessions: AtomicUsize,
+    /// The number of handshakes in progress, awaiting the response to a WHOAREYOU packet.
+    pub pending_handshakes: AtomicUsize,
+    /// The number of WHOAREYOU challenges dropped without a handshake in response.
+    pub expired_challenges: AtomicUsize,
+    /// The number of ENRs dropped from NODES responses for exceeding the maximum ENR size.
+    pub oversized_enrs: AtomicUsize,
     /// The number of seconds to store received packets to taking a moving average over.
     pub moving_window: u64,
     /// The number of unsolicited requests received per moving window.
@@ -16,6 +62,27 @@ pub struct InternalMetrics {
     pub bytes_sent: AtomicUsize,
     /// The number of bytes received.
     pub bytes_recv: AtomicUsize,
+    /// The number of bytes sent, by packet kind.
+    pub bytes_sent_by_kind: PacketBytesCounters,
+    /// The number of bytes of the packets received that passed the packet filter and were
+    /// decoded, by packet kind.
+    pub bytes_recv_by_kind: PacketBytesCounters,
+    /// The number of requests dropped for exceeding the per node request rate limit.
+    pub rate_limited_requests: AtomicUsize,
+    /// The number of packets dropped for reusing the nonce of a message already received over
+    /// the same session.
+    pub replayed_packets: AtomicUsize,
+    /// The number of responses dropped for not matching the id of a request awaiting a response
+    /// over the session they were received on.
+    pub mismatched_responses: AtomicUsize,
+    /// The `FIND_NODE` queries started and their outcomes.
+    pub find_node_queries: QueryCounters,
+    /// The `FIND_VALUE` queries started and their outcomes.
+    pub find_value_queries: QueryCounters,
+    /// The number of finished queries per duration bucket, see [`QUERY_DURATION_BUCKETS`].
+    pub query_durations: [AtomicUsize; QUERY_DURATION_BUCKETS.len() + 1],
+    /// The number of failed handshakes, by failure reason.
+    pub handshake_failures: HandshakeFailureCounters,
 }
 
 impl Default for InternalMetrics {
@@ -23,9 +90,21 @@ impl Default for InternalMetrics {
         InternalMetrics {
             moving_window: 5,
             active_sessions: AtomicUsize::new(0),
+            pending_handshakes: AtomicUsize::new(0),
+            expired_challenges: AtomicUsize::new(0),
+            oversized_enrs: AtomicUsize::new(0),
             unsolicited_requests_per_window: AtomicUsize::new(0),
             bytes_sent: AtomicUsize::new(0),
             bytes_recv: AtomicUsize::new(0),
+            bytes_sent_by_kind: PacketBytesCounters::default(),
+            bytes_recv_by_kind: PacketBytesCounters::default(),
+            rate_limited_requests: AtomicUsize::new(0),
+            replayed_packets: AtomicUsize::new(0),
+            mismatched_responses: AtomicUsize::new(0),
+            find_node_queries: QueryCounters::default(),
+            find_value_queries: QueryCounters::default(),
+            query_durations: Default::default(),
+            handshake_failures: HandshakeFailureCounters::default(),
         }
     }
 }
@@ -42,6 +121,94 @@ impl InternalMetrics {
         self.bytes_sent
             .store(current_bytes_sent.saturating_add(bytes), Ordering::Relaxed);
     }
+
+    /// Adds the duration of a finished query to the query duration histogram.
+    pub fn add_query_duration(&self, duration: Duration) {
+        let bucket = QUERY_DURATION_BUCKETS
+            .iter()
+            .position(|bound| duration <= *bound)
+            .unwrap_or(QUERY_DURATION_BUCKETS.len());
+        self.query_durations[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters of the bytes of UDP packets of each kind.
+#[derive(Default)]
+pub struct PacketBytesCounters {
+    /// The bytes of ordinary message packets.
+    pub message: AtomicUsize,
+    /// The bytes of WHOAREYOU packets.
+    pub whoareyou: AtomicUsize,
+    /// The bytes of handshake packets.
+    pub handshake: AtomicUsize,
+}
+
+impl PacketBytesCounters {
+    /// Returns the counter of the packets of the given kind.
+    pub fn counter(&self, kind: &PacketKind) -> &AtomicUsize {
+        match kind {
+            PacketKind::Message { .. } => &self.message,
+            PacketKind::WhoAreYou { .. } => &self.whoareyou,
+            PacketKind::Handshake { .. } => &self.handshake,
+        }
+    }
+
+    /// Adds the bytes of a packet of the given kind.
+    pub fn add(&self, kind: &PacketKind, bytes: usize) {
+        self.counter(kind).fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Counters of the failed handshakes for each failure reason.
+#[derive(Default)]
+pub struct HandshakeFailureCounters {
+    /// The handshakes the node didn't complete in time.
+    pub timeout: AtomicUsize,
+    /// The handshakes with an invalid challenge signature.
+    pub invalid_signature: AtomicUsize,
+    /// The handshakes with an invalid ENR.
+    pub invalid_enr: AtomicUsize,
+    /// The handshakes rejected by the incoming handshake filter.
+    pub rejected: AtomicUsize,
+    /// The handshakes with an unexpected or malformed packet.
+    pub invalid_packet: AtomicUsize,
+}
+
+impl HandshakeFailureCounters {
+    /// Returns the counter of the handshakes failed for the given reason.
+    pub fn counter(&self, reason: HandshakeFailureReason) -> &AtomicUsize {
+        match reason {
+            HandshakeFailureReason::Timeout => &self.timeout,
+            HandshakeFailureReason::InvalidSignature => &self.invalid_signature,
+            HandshakeFailureReason::InvalidEnr => &self.invalid_enr,
+            HandshakeFailureReason::Rejected => &self.rejected,
+            HandshakeFailureReason::InvalidPacket => &self.invalid_packet,
+        }
+    }
+
+    /// Records a handshake failed for the given reason.
+    pub fn add(&self, reason: HandshakeFailureReason) {
+        self.counter(reason).fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The contribution of a single handler to a gauge shared by all the handlers of the process.
+#[derive(Default)]
+pub(crate) struct GaugeShare {
+    /// The value this share adds to the gauge.
+    value: usize,
+}
+
+impl GaugeShare {
+    /// Sets this share of the gauge to `value`.
+    pub fn set(&mut self, gauge: &AtomicUsize, value: usize) {
+        if value > self.value {
+            gauge.fetch_add(value - self.value, Ordering::Relaxed);
+        } else {
+            gauge.fetch_sub(self.value - value, Ordering::Relaxed);
+        }
+        self.value = value;
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -49,24 +216,172 @@ impl InternalMetrics {
 pub struct Metrics {
     /// The number of active UDP sessions that are currently established.
     pub active_sessions: usize,
+    /// The number of handshakes in progress, awaiting the response to a WHOAREYOU packet.
+    pub pending_handshakes: usize,
+    /// The number of WHOAREYOU challenges dropped without a handshake in response.
+    pub expired_challenges: usize,
+    /// The number of ENRs dropped from NODES responses for exceeding the maximum ENR size.
+    pub oversized_enrs: usize,
     /// The number of unsolicited requests received per second (averaged over a moving window).
     pub unsolicited_requests_per_second: f64,
     /// The number of bytes sent.
     pub bytes_sent: usize,
     /// The number of bytes received.
     pub bytes_recv: usize,
+    /// The number of bytes sent, by packet kind.
+    pub bytes_sent_by_kind: PacketBytes,
+    /// The number of bytes of the packets received that passed the packet filter and were
+    /// decoded, by packet kind. Packets dropped before being decoded are only counted in
+    /// `bytes_recv`.
+    pub bytes_recv_by_kind: PacketBytes,
+    /// The number of requests dropped for exceeding the per node request rate limit.
+    pub rate_limited_requests: usize,
+    /// The number of packets dropped for reusing the nonce of a message already received over
+    /// the same session.
+    pub replayed_packets: usize,
+    /// The number of responses dropped for not matching the id of a request awaiting a response
+    /// over the session they were received on, e.g. late or spoofed responses.
+    pub mismatched_responses: usize,
+    /// The `FIND_NODE` queries started and their outcomes.
+    pub find_node_queries: QueryMetrics,
+    /// The `FIND_VALUE` queries started and their outcomes.
+    pub find_value_queries: QueryMetrics,
+    /// The number of finished queries per duration bucket. The bucket at index `i` counts the
+    /// queries lasting at most `QUERY_DURATION_BUCKETS[i]`, and longer than the previous bound.
+    /// The last bucket counts the queries lasting longer than all bounds.
+    pub query_durations: [usize; QUERY_DURATION_BUCKETS.len() + 1],
+    /// The number of failed handshakes, by failure reason.
+    pub handshake_failures: HandshakeFailures,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// The number of queries of a kind started and their outcomes.
+pub struct QueryMetrics {
+    /// The number of queries started.
+    pub started: usize,
+    /// The number of queries that found their results.
+    pub completed: usize,
+    /// The number of queries that timed out.
+    pub timed_out: usize,
+    /// The number of queries that ran out of peers to contact.
+    pub exhausted: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// The number of failed handshakes for each failure reason.
+pub struct HandshakeFailures {
+    /// The handshakes the node didn't complete in time.
+    pub timeout: usize,
+    /// The handshakes with an invalid challenge signature.
+    pub invalid_signature: usize,
+    /// The handshakes with an invalid ENR.
+    pub invalid_enr: usize,
+    /// The handshakes rejected by the incoming handshake filter.
+    pub rejected: usize,
+    /// The handshakes with an unexpected or malformed packet.
+    pub invalid_packet: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// The number of bytes of UDP packets of each kind.
+pub struct PacketBytes {
+    /// The bytes of ordinary message packets.
+    pub message: usize,
+    /// The bytes of WHOAREYOU packets.
+    pub whoareyou: usize,
+    /// The bytes of handshake packets.
+    pub handshake: usize,
+}
+
+impl From<&PacketBytesCounters> for PacketBytes {
+    fn from(counters: &PacketBytesCounters) -> Self {
+        PacketBytes {
+            message: counters.message.load(Ordering::Relaxed),
+            whoareyou: counters.whoareyou.load(Ordering::Relaxed),
+            handshake: counters.handshake.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl From<&HandshakeFailureCounters> for HandshakeFailures {
+    fn from(counters: &HandshakeFailureCounters) -> Self {
+        HandshakeFailures {
+            timeout: counters.timeout.load(Ordering::Relaxed),
+            invalid_signature: counters.invalid_signature.load(Ordering::Relaxed),
+            invalid_enr: counters.invalid_enr.load(Ordering::Relaxed),
+            rejected: counters.rejected.load(Ordering::Relaxed),
+            invalid_packet: counters.invalid_packet.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl From<&QueryCounters> for QueryMetrics {
+    fn from(counters: &QueryCounters) -> Self {
+        QueryMetrics {
+            started: counters.started.load(Ordering::Relaxed),
+            completed: counters.completed.load(Ordering::Relaxed),
+            timed_out: counters.timed_out.load(Ordering::Relaxed),
+            exhausted: counters.exhausted.load(Ordering::Relaxed),
+        }
+    }
 }
 
 impl From<&METRICS> for Metrics {
     fn from(internal_metrics: &METRICS) -> Self {
         Metrics {
             active_sessions: internal_metrics.active_sessions.load(Ordering::Relaxed),
+            pending_handshakes: internal_metrics.pending_handshakes.load(Ordering::Relaxed),
+            expired_challenges: internal_metrics.expired_challenges.load(Ordering::Relaxed),
+            oversized_enrs: internal_metrics.oversized_enrs.load(Ordering::Relaxed),
             unsolicited_requests_per_second: internal_metrics
                 .unsolicited_requests_per_window
                 .load(Ordering::Relaxed) as f64
                 / internal_metrics.moving_window as f64,
             bytes_sent: internal_metrics.bytes_sent.load(Ordering::Relaxed),
             bytes_recv: internal_metrics.bytes_recv.load(Ordering::Relaxed),
+            bytes_sent_by_kind: (&internal_metrics.bytes_sent_by_kind).into(),
+            bytes_recv_by_kind: (&internal_metrics.bytes_recv_by_kind).into(),
+            rate_limited_requests: internal_metrics
+                .rate_limited_requests
+                .load(Ordering::Relaxed),
+            replayed_packets: internal_metrics.replayed_packets.load(Ordering::Relaxed),
+            mismatched_responses: internal_metrics
+                .mismatched_responses
+                .load(Ordering::Relaxed),
+            find_node_queries: (&internal_metrics.find_node_queries).into(),
+            find_value_queries: (&internal_metrics.find_value_queries).into(),
+            query_durations: {
+                let mut query_durations = [0; QUERY_DURATION_BUCKETS.len() + 1];
+                for (count, bucket) in query_durations
+                    .iter_mut()
+                    .zip(internal_metrics.query_durations.iter())
+                {
+                    *count = bucket.load(Ordering::Relaxed);
+                }
+                query_durations
+            },
+            handshake_failures: (&internal_metrics.handshake_failures).into(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gauge_shares() {
+        let gauge = AtomicUsize::new(0);
+        let mut share_1 = GaugeShare::default();
+        let mut share_2 = GaugeShare::default();
+
+        share_1.set(&gauge, 2);
+        share_2.set(&gauge, 3);
+        assert_eq!(gauge.load(Ordering::Relaxed), 5);
+
+        share_1.set(&gauge, 1);
+        assert_eq!(gauge.load(Ordering::Relaxed), 4);
+        share_2.set(&gauge, 0);
+        assert_eq!(gauge.load(Ordering::Relaxed), 1);
+    }
+}
@@ -79,6 +79,18 @@ impl NodeContact {
         })
     }
 
+    /// A contact for a node known only by its public key and address, such as a node learned
+    /// out-of-band. Its ENR is learned once a session is established.
+    ///
+    /// The public key is required, a node id alone isn't enough to key the handshake.
+    pub fn from_public_key(public_key: CombinedPublicKey, socket_addr: SocketAddr) -> Self {
+        NodeContact {
+            public_key,
+            socket_addr,
+            enr: None,
+        }
+    }
+
     #[cfg(feature = "libp2p")]
     pub fn try_from_multiaddr(multiaddr: Multiaddr) -> Result<Self, &'static str> {
         // The multiaddr must contain either the ip4 or ip6 protocols, the UDP protocol and the P2P
@@ -123,11 +135,10 @@ impl NodeContact {
                 _ => return Err("The key type is not supported"),
             };
 
-        Ok(NodeContact {
+        Ok(NodeContact::from_public_key(
             public_key,
-            socket_addr: SocketAddr::new(ip_addr, udp_port),
-            enr: None,
-        })
+            SocketAddr::new(ip_addr, udp_port),
+        ))
     }
 }
 
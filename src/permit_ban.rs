@@ -24,4 +24,66 @@ impl PermitBanList {
             .insert(node_address.socket_addr.ip(), time_to_unban);
         self.ban_nodes.insert(node_address.node_id, time_to_unban);
     }
+
+    /// Returns whether the IP is banned. Bans that have expired no longer apply, even before they
+    /// are removed from the list.
+    pub fn is_ip_banned(&self, ip: &IpAddr) -> bool {
+        matches!(self.ban_ips.get(ip), Some(time_to_unban) if is_active(time_to_unban))
+    }
+
+    /// Returns whether the node is banned. Bans that have expired no longer apply, even before
+    /// they are removed from the list.
+    pub fn is_node_banned(&self, node_id: &NodeId) -> bool {
+        matches!(self.ban_nodes.get(node_id), Some(time_to_unban) if is_active(time_to_unban))
+    }
+}
+
+/// Whether a ban lasting until `time_to_unban` still applies.
+fn is_active(time_to_unban: &Option<Instant>) -> bool {
+    match time_to_unban {
+        Some(time) => Instant::now() < *time,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ban_expiry() {
+        let mut list = PermitBanList::default();
+        let node_address = NodeAddress {
+            socket_addr: "192.0.2.1:9000".parse().unwrap(),
+            node_id: NodeId::random(),
+        };
+        let ip = node_address.socket_addr.ip();
+        let node_id = node_address.node_id;
+        assert!(!list.is_ip_banned(&ip));
+        assert!(!list.is_node_banned(&node_id));
+
+        list.ban(
+            node_address,
+            Some(Instant::now() + Duration::from_millis(50)),
+        );
+        assert!(list.is_ip_banned(&ip));
+        assert!(list.is_node_banned(&node_id));
+
+        // the ban lifts once it expires
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!list.is_ip_banned(&ip));
+        assert!(!list.is_node_banned(&node_id));
+    }
+
+    #[test]
+    fn test_permanent_ban() {
+        let mut list = PermitBanList::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        list.ban_ips.insert(ip, None);
+        assert!(list.is_ip_banned(&ip));
+
+        list.ban_ips.remove(&ip);
+        assert!(!list.is_ip_banned(&ip));
+    }
 }
@@ -46,6 +46,8 @@ pub trait TargetKey<TNodeId> {
 pub struct QueryPool<TTarget, TNodeId, TResult> {
     next_id: usize,
     query_timeout: Duration,
+    /// The maximum number of queries that run at once, if limited. Queries beyond it stay queued.
+    max_concurrent: Option<usize>,
     queries: FnvHashMap<QueryId, Query<TTarget, TNodeId, TResult>>,
 }
 
@@ -74,21 +76,40 @@ where
         QueryPool {
             next_id: 0,
             query_timeout,
+            max_concurrent: None,
             queries: Default::default(),
         }
     }
 
+    /// Limits the number of queries that run at once. Queries added beyond the limit are queued
+    /// and started in order as running queries complete.
+    pub fn with_max_concurrent(mut self, max_concurrent: Option<usize>) -> Self {
+        self.max_concurrent = max_concurrent;
+        self
+    }
+
+    /// Returns the number of started queries, i.e. the queries that are not queued.
+    pub fn num_active(&self) -> usize {
+        self.queries
+            .values()
+            .filter(|query| query.started.is_some())
+            .count()
+    }
+
     /// Returns an iterator over the queries in the pool.
     pub fn iter(&self) -> impl Iterator<Item = &Query<TTarget, TNodeId, TResult>> {
         self.queries.values()
     }
 
     /// Adds a query to the pool that iterates towards the closest peers to the target.
+    ///
+    /// If `timeout` is set, it overrides the pool's query timeout for this query.
     pub fn add_findnode_query<I>(
         &mut self,
         config: FindNodeQueryConfig,
         target: TTarget,
         peers: I,
+        timeout: Option<Duration>,
     ) -> QueryId
     where
         I: IntoIterator<Item = Key<TNodeId>>,
@@ -96,15 +117,18 @@ where
         let target_key = target.key();
         let findnode_query = FindNodeQuery::with_config(config, target_key, peers);
         let peer_iter = QueryPeerIter::FindNode(findnode_query);
-        self.add(peer_iter, target)
+        self.add(peer_iter, target, timeout)
     }
 
     /// Adds a query to the pool that iterates towards the closest peers to the target.
+    ///
+    /// If `timeout` is set, it overrides the pool's query timeout for this query.
     pub fn add_findvalue_query<I>(
         &mut self,
         config: FindNodeQueryConfig,
         target: TTarget,
         peers: I,
+        timeout: Option<Duration>,
     ) -> QueryId
         where
             I: IntoIterator<Item = Key<TNodeId>>,
@@ -112,7 +136,7 @@ where
         let target_key = target.key();
         let findnode_query = FindNodeQuery::with_config(config, target_key, peers);
         let peer_iter = QueryPeerIter::FindValue(findnode_query);
-        self.add(peer_iter, target)
+        self.add(peer_iter, target, timeout)
     }
 
     /// Adds a query to the pool that returns peers that satisfy a predicate.
@@ -129,13 +153,19 @@ where
         let target_key = target.key();
         let predicate_query = PredicateQuery::with_config(config, target_key, peers, predicate);
         let peer_iter = QueryPeerIter::Predicate(predicate_query);
-        self.add(peer_iter, target)
+        self.add(peer_iter, target, None)
     }
 
-    fn add(&mut self, peer_iter: QueryPeerIter<TNodeId, TResult>, target: TTarget) -> QueryId {
+    fn add(
+        &mut self,
+        peer_iter: QueryPeerIter<TNodeId, TResult>,
+        target: TTarget,
+        timeout: Option<Duration>,
+    ) -> QueryId {
         let id = QueryId(self.next_id);
         self.next_id = self.next_id.wrapping_add(1);
-        let query = Query::new(id, peer_iter, target);
+        let timeout = timeout.unwrap_or(self.query_timeout);
+        let query = Query::new(id, peer_iter, target, timeout);
         self.queries.insert(id, query);
         id
     }
@@ -145,6 +175,14 @@ where
         self.queries.get_mut(&id)
     }
 
+    /// Returns the instant at which the first of the started queries times out, if any.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.queries
+            .values()
+            .filter_map(|query| query.started.map(|started| started + query.timeout))
+            .min()
+    }
+
     /// Polls the pool to advance the queries.
     pub fn poll(&mut self) -> QueryPoolState<'_, TTarget, TNodeId, TResult> {
         let now = Instant::now();
@@ -152,8 +190,31 @@ where
         let mut waiting = None;
         let mut timeout = None;
 
+        // start the queued queries, oldest first, while there are free slots
+        let free_slots = match self.max_concurrent {
+            Some(max) => max.saturating_sub(self.num_active()),
+            None => usize::MAX,
+        };
+        if free_slots > 0 {
+            let mut queued = self
+                .queries
+                .values()
+                .filter(|query| query.started.is_none())
+                .map(|query| query.id)
+                .collect::<Vec<_>>();
+            queued.sort_unstable_by_key(|id| id.0);
+            for query_id in queued.into_iter().take(free_slots) {
+                if let Some(query) = self.queries.get_mut(&query_id) {
+                    query.started = Some(now);
+                }
+            }
+        }
+
         for (&query_id, query) in self.queries.iter_mut() {
-            query.started = query.started.or(Some(now));
+            if query.started.is_none() {
+                // queued
+                continue;
+            }
             match query.next(now) {
                 QueryState::Finished => {
                     finished = Some(query_id);
@@ -165,7 +226,7 @@ where
                 }
                 QueryState::Waiting(None) | QueryState::WaitingAtCapacity => {
                     let elapsed = now - query.started.unwrap_or(now);
-                    if elapsed >= self.query_timeout {
+                    if elapsed >= query.timeout {
                         timeout = Some(query_id);
                         break;
                     }
@@ -218,6 +279,8 @@ pub struct Query<TTarget, TNodeId, TResult> {
     started: Option<Instant>,
     /// Target we are looking for.
     target: TTarget,
+    /// The duration after which the query times out.
+    timeout: Duration,
 
     found_value: bool,
 }
@@ -236,11 +299,17 @@ where
     TResult: Into<TNodeId> + Clone,
 {
     /// Creates a new query without starting it.
-    fn new(id: QueryId, peer_iter: QueryPeerIter<TNodeId, TResult>, target: TTarget) -> Self {
+    fn new(
+        id: QueryId,
+        peer_iter: QueryPeerIter<TNodeId, TResult>,
+        target: TTarget,
+        timeout: Duration,
+    ) -> Self {
         Query {
             id,
             peer_iter,
             target,
+            timeout,
             started: None,
             found_value: false
         }
@@ -251,6 +320,15 @@ where
         self.id
     }
 
+    /// Contacts the given peers first, if they are among the peers the query started from. This
+    /// has no effect on predicate queries.
+    pub fn prefer_peers(&mut self, peers: &[TNodeId]) {
+        match &mut self.peer_iter {
+            QueryPeerIter::FindNode(iter) | QueryPeerIter::FindValue(iter) => iter.prefer(peers),
+            QueryPeerIter::Predicate(_) => {}
+        }
+    }
+
     /// Informs the query that the attempt to contact `peer` failed.
     pub fn on_failure(&mut self, peer: &TNodeId) {
         match &mut self.peer_iter {
@@ -287,18 +365,44 @@ where
         match &mut self.peer_iter {
             QueryPeerIter::FindNode(iter) => iter.next(now),
             QueryPeerIter::Predicate(iter) => iter.next(now),
-            QueryPeerIter::FindValue(iter) => {
-                if self.found_value {
-                    return QueryState::Finished
-                }
+            QueryPeerIter::FindValue(iter) => iter.next(now),
+        }
+    }
 
-                match iter.next(now) {
-                    QueryState::Finished => {
-                        QueryState::WaitingAtCapacity
-                    },
-                    s => s
-                }
-            }
+    /// Returns the time elapsed since the query started.
+    pub fn elapsed(&self) -> Duration {
+        self.started
+            .map(|started| started.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Returns the duration after which the query times out.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Returns the number of peers the query is looking for.
+    pub fn num_results(&self) -> usize {
+        match &self.peer_iter {
+            QueryPeerIter::FindNode(iter) => iter.num_results(),
+            QueryPeerIter::Predicate(iter) => iter.num_results(),
+            QueryPeerIter::FindValue(iter) => iter.num_results(),
+        }
+    }
+
+    /// Returns the number of peers the query has contacted so far.
+    pub fn num_contacted(&self) -> usize {
+        match &self.peer_iter {
+            QueryPeerIter::FindNode(iter) | QueryPeerIter::FindValue(iter) => iter.num_contacted(),
+            QueryPeerIter::Predicate(iter) => iter.num_contacted(),
+        }
+    }
+
+    /// Returns the number of peers that have successfully responded to the query so far.
+    pub fn num_responses(&self) -> usize {
+        match &self.peer_iter {
+            QueryPeerIter::FindNode(iter) | QueryPeerIter::FindValue(iter) => iter.num_succeeded(),
+            QueryPeerIter::Predicate(iter) => iter.num_succeeded(),
         }
     }
 
@@ -328,6 +432,11 @@ where
     pub fn mark_as_found(&mut self) {
         self.found_value = true
     }
+
+    /// Returns whether the value this query is looking for has been found.
+    pub fn found_value(&self) -> bool {
+        self.found_value
+    }
 }
 
 /// The result of a `Query`.
@@ -337,3 +446,103 @@ pub struct QueryResult<TTarget, TClosest> {
     /// The closest peers to the target found by the query.
     pub closest_peers: TClosest,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::NodeId;
+
+    struct TestTarget(NodeId);
+
+    impl TargetKey<NodeId> for TestTarget {
+        fn key(&self) -> Key<NodeId> {
+            self.0.into()
+        }
+    }
+
+    fn config() -> FindNodeQueryConfig {
+        FindNodeQueryConfig {
+            parallelism: 3,
+            num_results: 16,
+            peer_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Polls the pool until it has no more peers to contact, returning any timed out query.
+    fn poll_timeout(pool: &mut QueryPool<TestTarget, NodeId, NodeId>) -> Option<QueryId> {
+        loop {
+            match pool.poll() {
+                QueryPoolState::Waiting(Some(_)) => {}
+                QueryPoolState::Timeout(query) => return Some(query.id()),
+                _ => return None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_query_timeout_override() {
+        let mut pool = QueryPool::new(Duration::from_secs(60));
+        let default_query = pool.add_findnode_query(
+            config(),
+            TestTarget(NodeId::random()),
+            vec![Key::from(NodeId::random())],
+            None,
+        );
+        let short_query = pool.add_findvalue_query(
+            config(),
+            TestTarget(NodeId::random()),
+            vec![Key::from(NodeId::random())],
+            Some(Duration::from_millis(10)),
+        );
+
+        assert_eq!(poll_timeout(&mut pool), None);
+        let next_timeout = pool.next_timeout().unwrap();
+        assert!(next_timeout <= Instant::now() + Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(20));
+
+        // only the query with the overridden timeout times out
+        assert_eq!(poll_timeout(&mut pool), Some(short_query));
+        assert_eq!(poll_timeout(&mut pool), None);
+        assert!(pool.get_mut(default_query).is_some());
+    }
+
+    #[test]
+    fn test_max_concurrent_queries() {
+        let mut pool: QueryPool<TestTarget, NodeId, crate::Enr> =
+            QueryPool::new(Duration::from_secs(60)).with_max_concurrent(Some(2));
+        let queries = (0..5)
+            .map(|_| {
+                pool.add_findnode_query(
+                    config(),
+                    TestTarget(NodeId::random()),
+                    vec![Key::from(NodeId::random())],
+                    None,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut finished = Vec::new();
+        loop {
+            let state = pool.poll();
+            let request = match state {
+                QueryPoolState::Waiting(Some((query, peer))) => Some((query.id(), peer)),
+                QueryPoolState::Finished(query) => {
+                    finished.push(query.id());
+                    None
+                }
+                QueryPoolState::Idle => break,
+                QueryPoolState::Waiting(None) | QueryPoolState::Timeout(_) => {
+                    panic!("All queries must progress")
+                }
+            };
+            assert!(pool.num_active() <= 2);
+            // answer each request without new peers, completing its query
+            if let Some((query_id, peer)) = request {
+                pool.get_mut(query_id).unwrap().on_success(&peer, &[]);
+            }
+        }
+
+        finished.sort_unstable_by_key(|id| id.0);
+        assert_eq!(finished, queries);
+    }
+}
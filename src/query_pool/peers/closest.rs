@@ -45,6 +45,10 @@ pub struct FindNodeQuery<TNodeId> {
     /// The number of peers for which the query is currently waiting for results.
     num_waiting: usize,
 
+    /// The distances of the preferred peers among the peers the query started from. They are
+    /// contacted first.
+    preferred: Vec<Distance>,
+
     /// The configuration of the query.
     config: FindNodeQueryConfig,
 }
@@ -119,9 +123,24 @@ where
             progress,
             closest_peers,
             num_waiting: 0,
+            preferred: Vec::new(),
         }
     }
 
+    /// Contacts the given peers first, if they are among the peers the query started from.
+    /// Preferred peers are contacted before the others, in order of their distance to the target.
+    pub fn prefer(&mut self, peers: &[TNodeId]) {
+        self.preferred = peers
+            .iter()
+            .map(|peer| {
+                let key: Key<TNodeId> = peer.clone().into();
+                key.distance(&self.target_key)
+            })
+            .filter(|distance| self.closest_peers.contains_key(distance))
+            .collect();
+        self.preferred.sort_unstable();
+    }
+
     /// Callback for delivering the result of a successful request to a peer
     /// that the query is waiting on.
     ///
@@ -256,6 +275,25 @@ where
         // Check if the query is at capacity w.r.t. the allowed parallelism.
         let at_capacity = self.at_capacity();
 
+        // Contact the preferred peers first.
+        if !at_capacity {
+            let closest_peers = &mut self.closest_peers;
+            let preferred = self.preferred.iter().find(|distance| {
+                matches!(
+                    closest_peers.get(distance),
+                    Some(QueryPeer {
+                        state: QueryPeerState::NotContacted,
+                        ..
+                    })
+                )
+            });
+            if let Some(peer) = preferred.and_then(|distance| closest_peers.get_mut(distance)) {
+                peer.state = QueryPeerState::Waiting(now + self.config.peer_timeout);
+                self.num_waiting += 1;
+                return QueryState::Waiting(Some(peer.key.preimage().clone()));
+            }
+        }
+
         for peer in self.closest_peers.values_mut() {
             match peer.state {
                 QueryPeerState::NotContacted => {
@@ -321,6 +359,27 @@ where
         }
     }
 
+    /// Returns the number of results the query is looking for.
+    pub fn num_results(&self) -> usize {
+        self.config.num_results
+    }
+
+    /// Returns the number of peers that have been contacted so far.
+    pub fn num_contacted(&self) -> usize {
+        self.closest_peers
+            .values()
+            .filter(|peer| !matches!(peer.state, QueryPeerState::NotContacted))
+            .count()
+    }
+
+    /// Returns the number of peers that have successfully responded so far.
+    pub fn num_succeeded(&self) -> usize {
+        self.closest_peers
+            .values()
+            .filter(|peer| matches!(peer.state, QueryPeerState::Succeeded))
+            .count()
+    }
+
     /// Consumes the query, returning the target and the closest peers.
     pub fn into_result(self) -> Vec<TNodeId> {
         self.closest_peers
@@ -311,6 +311,27 @@ where
         }
     }
 
+    /// Returns the number of results the query is looking for.
+    pub fn num_results(&self) -> usize {
+        self.config.num_results
+    }
+
+    /// Returns the number of peers that have been contacted so far.
+    pub fn num_contacted(&self) -> usize {
+        self.closest_peers
+            .values()
+            .filter(|peer| !matches!(peer.state, QueryPeerState::NotContacted))
+            .count()
+    }
+
+    /// Returns the number of peers that have successfully responded so far.
+    pub fn num_succeeded(&self) -> usize {
+        self.closest_peers
+            .values()
+            .filter(|peer| matches!(peer.state, QueryPeerState::Succeeded))
+            .count()
+    }
+
     /// Consumes the query, returning the peers who match the predicate.
     pub fn into_result(self) -> Vec<TNodeId> {
         self.closest_peers
@@ -1,7 +1,11 @@
 use std::convert::TryInto;
+use crate::metrics::METRICS;
 use enr::{CombinedKey, Enr, NodeId};
+use rand::Rng;
 use rlp::{DecoderError, RlpStream};
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::Ordering;
 use tracing::{debug, warn};
 
 type TopicHash = [u8; 32];
@@ -25,8 +29,10 @@ impl RequestId {
         Ok(RequestId(data))
     }
 
+    /// Generates a random ID from the thread-local CSPRNG, so that the IDs of outstanding
+    /// requests can't be predicted to spoof responses.
     pub fn random() -> Self {
-        let rand: u64 = rand::random();
+        let rand: u64 = rand::thread_rng().gen();
         RequestId(rand.to_be_bytes().to_vec())
     }
 
@@ -95,6 +101,31 @@ pub enum RequestBody {
     },
     /// A TOPICQUERY request.
     TopicQuery { topic: TopicHash },
+    /// A STORE request.
+    Store {
+        /// The key the value is stored under.
+        key: NodeId,
+        /// The value to store.
+        value: Vec<u8>,
+    },
+    /// A RELAYINIT request, asking the relay to prompt the target node to contact the initiator.
+    RelayInit {
+        /// The ENR of the node initiating the relay.
+        initiator: crate::Enr,
+        /// The node the relay forwards the request to.
+        target: NodeId,
+    },
+    /// A RELAYMSG request, forwarded by a relay, prompting the recipient to contact the
+    /// initiator.
+    RelayMsg {
+        /// The ENR of the node initiating the relay.
+        initiator: crate::Enr,
+    },
+    /// A STOREBATCH request, storing several values in a single request.
+    StoreBatch {
+        /// The keys and the values to store under them.
+        entries: Vec<(NodeId, Vec<u8>)>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -114,6 +145,9 @@ pub enum ResponseBody {
         total: u64,
         /// A list of ENR's returned by the responder.
         nodes: Vec<Enr<CombinedKey>>,
+        /// The signed RLP encodings of `nodes` as received, by node id. Empty for responses
+        /// built locally, which are encoded from `nodes`.
+        encodings: HashMap<NodeId, Vec<u8>>,
     },
     /// The TALK response.
     Talk {
@@ -129,7 +163,35 @@ pub enum ResponseBody {
     },
     Value {
         response: Vec<u8>,
-    }
+    },
+    /// The STORED response.
+    Stored {
+        /// Whether the responder accepted the value.
+        accepted: bool,
+        /// Nodes the responder knows of that are closer to the key than itself, on which the
+        /// value should also be stored.
+        closer: Vec<Enr<CombinedKey>>,
+    },
+    /// The RELAYED response to a RELAYINIT or RELAYMSG request.
+    Relayed {
+        /// Whether the relay forwarded the request to the target, or the target will contact
+        /// the initiator.
+        forwarded: bool,
+    },
+    /// The STOREDBATCH response to a STOREBATCH request.
+    StoredBatch {
+        /// Whether the responder accepted each entry, in the order of the request.
+        accepted: Vec<bool>,
+    },
+    /// A chunk of a value too large to fit in a single VALUE response.
+    ValueChunk {
+        /// The total number of chunks the value is split in.
+        total: u32,
+        /// The index of this chunk.
+        index: u32,
+        /// The data of this chunk.
+        data: Vec<u8>,
+    },
 }
 
 impl Request {
@@ -140,7 +202,11 @@ impl Request {
             RequestBody::Talk { .. } => 5,
             RequestBody::RegisterTopic { .. } => 7,
             RequestBody::TopicQuery { .. } => 10,
-            RequestBody::FindValue { .. } => 11
+            RequestBody::FindValue { .. } => 11,
+            RequestBody::Store { .. } => 13,
+            RequestBody::RelayInit { .. } => 15,
+            RequestBody::RelayMsg { .. } => 16,
+            RequestBody::StoreBatch { .. } => 18,
         }
     }
 
@@ -209,6 +275,45 @@ impl Request {
                 buf.extend_from_slice(&s.out());
                 buf
             }
+            RequestBody::Store { key, value } => {
+                let mut s = RlpStream::new();
+                s.begin_list(3);
+                s.append(&id.as_bytes());
+                s.append(&key.raw().to_vec());
+                s.append(&value);
+                buf.extend_from_slice(&s.out());
+                buf
+            }
+            RequestBody::RelayInit { initiator, target } => {
+                let mut s = RlpStream::new();
+                s.begin_list(3);
+                s.append(&id.as_bytes());
+                s.append(&initiator);
+                s.append(&target.raw().to_vec());
+                buf.extend_from_slice(&s.out());
+                buf
+            }
+            RequestBody::RelayMsg { initiator } => {
+                let mut s = RlpStream::new();
+                s.begin_list(2);
+                s.append(&id.as_bytes());
+                s.append(&initiator);
+                buf.extend_from_slice(&s.out());
+                buf
+            }
+            RequestBody::StoreBatch { entries } => {
+                let mut s = RlpStream::new();
+                s.begin_list(2);
+                s.append(&id.as_bytes());
+                s.begin_list(entries.len());
+                for (key, value) in entries {
+                    s.begin_list(2);
+                    s.append(&key.raw().to_vec());
+                    s.append(&value);
+                }
+                buf.extend_from_slice(&s.out());
+                buf
+            }
         }
     }
 }
@@ -221,7 +326,11 @@ impl Response {
             ResponseBody::Talk { .. } => 6,
             ResponseBody::Ticket { .. } => 8,
             ResponseBody::RegisterConfirmation { .. } => 9,
-            ResponseBody::Value { .. } => 12
+            ResponseBody::Value { .. } => 12,
+            ResponseBody::Stored { .. } => 14,
+            ResponseBody::Relayed { .. } => 17,
+            ResponseBody::StoredBatch { .. } => 19,
+            ResponseBody::ValueChunk { .. } => 20,
         }
     }
 
@@ -240,7 +349,17 @@ impl Response {
             ResponseBody::RegisterConfirmation { .. } => {
                 matches!(req, RequestBody::RegisterTopic { .. })
             }
-            ResponseBody::Value { .. } => matches!(req, RequestBody::FindValue { .. }),
+            ResponseBody::Value { .. } | ResponseBody::ValueChunk { .. } => {
+                matches!(req, RequestBody::FindValue { .. })
+            }
+            ResponseBody::Stored { .. } => matches!(req, RequestBody::Store { .. }),
+            ResponseBody::Relayed { .. } => {
+                matches!(
+                    req,
+                    RequestBody::RelayInit { .. } | RequestBody::RelayMsg { .. }
+                )
+            }
+            ResponseBody::StoredBatch { .. } => matches!(req, RequestBody::StoreBatch { .. }),
         }
     }
 
@@ -264,7 +383,7 @@ impl Response {
                 buf.extend_from_slice(&s.out());
                 buf
             }
-            ResponseBody::Nodes { total, nodes } => {
+            ResponseBody::Nodes { total, nodes, .. } => {
                 let mut s = RlpStream::new();
                 s.begin_list(3);
                 s.append(&id.as_bytes());
@@ -314,6 +433,50 @@ impl Response {
                 buf.extend_from_slice(&s.out());
                 buf
             }
+            ResponseBody::Stored { accepted, closer } => {
+                let mut s = RlpStream::new();
+                // the closer nodes are omitted when there are none
+                s.begin_list(if closer.is_empty() { 2 } else { 3 });
+                s.append(&id.as_bytes());
+                s.append(&accepted);
+                if !closer.is_empty() {
+                    s.begin_list(closer.len());
+                    for enr in closer {
+                        s.append(&enr);
+                    }
+                }
+                buf.extend_from_slice(&s.out());
+                buf
+            }
+            ResponseBody::Relayed { forwarded } => {
+                let mut s = RlpStream::new();
+                s.begin_list(2);
+                s.append(&id.as_bytes());
+                s.append(&forwarded);
+                buf.extend_from_slice(&s.out());
+                buf
+            }
+            ResponseBody::StoredBatch { accepted } => {
+                let mut s = RlpStream::new();
+                s.begin_list(2);
+                s.append(&id.as_bytes());
+                s.begin_list(accepted.len());
+                for accepted in accepted {
+                    s.append(&accepted);
+                }
+                buf.extend_from_slice(&s.out());
+                buf
+            }
+            ResponseBody::ValueChunk { total, index, data } => {
+                let mut s = RlpStream::new();
+                s.begin_list(4);
+                s.append(&id.as_bytes());
+                s.append(&total);
+                s.append(&index);
+                s.append(&data);
+                buf.extend_from_slice(&s.out());
+                buf
+            }
         }
     }
 }
@@ -347,7 +510,7 @@ impl std::fmt::Display for ResponseBody {
                 "PONG: Enr-seq: {}, Ip: {:?},  Port: {}",
                 enr_seq, ip, port
             ),
-            ResponseBody::Nodes { total, nodes } => {
+            ResponseBody::Nodes { total, nodes, .. } => {
                 write!(f, "NODES: total: {}, Nodes: [", total)?;
                 let mut first = true;
                 for id in nodes {
@@ -373,6 +536,25 @@ impl std::fmt::Display for ResponseBody {
             ResponseBody::Value { response } => {
                 write!(f, "Response: Response {}", hex::encode(response))
             }
+            ResponseBody::Stored { accepted, closer } => write!(
+                f,
+                "STORED: accepted: {}, closer nodes: {}",
+                accepted,
+                closer.len()
+            ),
+            ResponseBody::Relayed { forwarded } => {
+                write!(f, "RELAYED: forwarded: {}", forwarded)
+            }
+            ResponseBody::StoredBatch { accepted } => {
+                write!(f, "STOREDBATCH: accepted: {:?}", accepted)
+            }
+            ResponseBody::ValueChunk { total, index, data } => write!(
+                f,
+                "VALUECHUNK: chunk {} of {}, {} bytes",
+                index,
+                total,
+                data.len()
+            ),
         }
     }
 }
@@ -409,6 +591,34 @@ impl std::fmt::Display for RequestBody {
                 "FIND_VALUE: key: {}",
                 hex::encode(key.raw()),
             ),
+            RequestBody::Store { key, value } => write!(
+                f,
+                "STORE: key: {}, value: {}",
+                hex::encode(key.raw()),
+                hex::encode(value)
+            ),
+            RequestBody::RelayInit { initiator, target } => write!(
+                f,
+                "RELAYINIT: initiator: {}, target: {}",
+                initiator.node_id(),
+                target
+            ),
+            RequestBody::RelayMsg { initiator } => {
+                write!(f, "RELAYMSG: initiator: {}", initiator.node_id())
+            }
+            RequestBody::StoreBatch { entries } => {
+                write!(f, "STOREBATCH: keys: [")?;
+                let mut first = true;
+                for (key, _) in entries {
+                    if !first {
+                        write!(f, ", {}", hex::encode(key.raw()))?;
+                    } else {
+                        write!(f, "{}", hex::encode(key.raw()))?;
+                    }
+                    first = false;
+                }
+                write!(f, "]")
+            }
         }
     }
 }
@@ -542,13 +752,13 @@ impl Message {
                     return Err(DecoderError::RlpIncorrectListLen);
                 }
 
-                let nodes = {
+                let (nodes, encodings) = {
                     let enr_list_rlp = rlp.at(2)?;
                     if enr_list_rlp.is_empty() {
                         // no records
-                        vec![]
+                        (vec![], HashMap::new())
                     } else {
-                        enr_list_rlp.as_list::<Enr<CombinedKey>>()?
+                        decode_enrs(&enr_list_rlp)?
                     }
                 };
                 Message::Response(Response {
@@ -556,6 +766,7 @@ impl Message {
                     body: ResponseBody::Nodes {
                         total: rlp.val_at::<u64>(1)?,
                         nodes,
+                        encodings,
                     },
                 })
             }
@@ -648,6 +859,177 @@ impl Message {
                     body: ResponseBody::Value { response },
                 })
             }
+            13 => {
+                // Store Request
+                if list_len != 3 {
+                    debug!(
+                        "Store Request has an invalid RLP list length. Expected 3, found {}",
+                        list_len
+                    );
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                let key = rlp.val_at::<Vec<u8>>(1)?;
+
+                if key.len() != 32 {
+                    warn!("Rejected Store request with invalid key size {}", key.len());
+                    return Err(DecoderError::Custom("STORE request key invalid"));
+                }
+
+                let value = rlp.val_at::<Vec<u8>>(2)?;
+                Message::Request(Request {
+                    id,
+                    body: RequestBody::Store {
+                        key: NodeId::new(key.as_slice().try_into().unwrap()),
+                        value,
+                    },
+                })
+            }
+            14 => {
+                // Stored Response
+                if list_len != 2 && list_len != 3 {
+                    debug!(
+                        "Stored Response has an invalid RLP list length. Expected 2 or 3, found {}",
+                        list_len
+                    );
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                let accepted = rlp.val_at::<bool>(1)?;
+                let closer = if list_len == 3 {
+                    rlp.at(2)?.as_list::<Enr<CombinedKey>>()?
+                } else {
+                    vec![]
+                };
+                Message::Response(Response {
+                    id,
+                    body: ResponseBody::Stored { accepted, closer },
+                })
+            }
+            15 => {
+                // RelayInit Request
+                if list_len != 3 {
+                    debug!(
+                        "RelayInit Request has an invalid RLP list length. Expected 3, found {}",
+                        list_len
+                    );
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                let initiator = rlp.val_at::<Enr<CombinedKey>>(1)?;
+                let target = rlp.val_at::<Vec<u8>>(2)?;
+
+                if target.len() != 32 {
+                    warn!(
+                        "Rejected RelayInit request with invalid target size {}",
+                        target.len()
+                    );
+                    return Err(DecoderError::Custom("RELAYINIT request target invalid"));
+                }
+
+                Message::Request(Request {
+                    id,
+                    body: RequestBody::RelayInit {
+                        initiator,
+                        target: NodeId::new(target.as_slice().try_into().unwrap()),
+                    },
+                })
+            }
+            16 => {
+                // RelayMsg Request
+                if list_len != 2 {
+                    debug!(
+                        "RelayMsg Request has an invalid RLP list length. Expected 2, found {}",
+                        list_len
+                    );
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                let initiator = rlp.val_at::<Enr<CombinedKey>>(1)?;
+                Message::Request(Request {
+                    id,
+                    body: RequestBody::RelayMsg { initiator },
+                })
+            }
+            17 => {
+                // Relayed Response
+                if list_len != 2 {
+                    debug!(
+                        "Relayed Response has an invalid RLP list length. Expected 2, found {}",
+                        list_len
+                    );
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                let forwarded = rlp.val_at::<bool>(1)?;
+                Message::Response(Response {
+                    id,
+                    body: ResponseBody::Relayed { forwarded },
+                })
+            }
+            18 => {
+                // StoreBatch Request
+                if list_len != 2 {
+                    debug!(
+                        "StoreBatch Request has an invalid RLP list length. Expected 2, found {}",
+                        list_len
+                    );
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                let mut entries = Vec::new();
+                for entry in rlp.at(1)?.iter() {
+                    if entry.item_count()? != 2 {
+                        return Err(DecoderError::RlpIncorrectListLen);
+                    }
+                    let key = entry.val_at::<Vec<u8>>(0)?;
+
+                    if key.len() != 32 {
+                        warn!("Rejected StoreBatch request with invalid key size {}", key.len());
+                        return Err(DecoderError::Custom("STOREBATCH request key invalid"));
+                    }
+
+                    let value = entry.val_at::<Vec<u8>>(1)?;
+                    entries.push((NodeId::new(key.as_slice().try_into().unwrap()), value));
+                }
+                Message::Request(Request {
+                    id,
+                    body: RequestBody::StoreBatch { entries },
+                })
+            }
+            19 => {
+                // StoredBatch Response
+                if list_len != 2 {
+                    debug!(
+                        "StoredBatch Response has an invalid RLP list length. Expected 2, found {}",
+                        list_len
+                    );
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                let accepted = rlp.list_at::<bool>(1)?;
+                Message::Response(Response {
+                    id,
+                    body: ResponseBody::StoredBatch { accepted },
+                })
+            }
+            20 => {
+                // ValueChunk Response
+                if list_len != 4 {
+                    debug!(
+                        "ValueChunk Response has an invalid RLP list length. Expected 4, found {}",
+                        list_len
+                    );
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                let total = rlp.val_at::<u32>(1)?;
+                let index = rlp.val_at::<u32>(2)?;
+                if index >= total {
+                    warn!(
+                        "Rejected ValueChunk response with index {} out of {} chunks",
+                        index, total
+                    );
+                    return Err(DecoderError::Custom("VALUECHUNK response index invalid"));
+                }
+                let data = rlp.val_at::<Vec<u8>>(3)?;
+                Message::Response(Response {
+                    id,
+                    body: ResponseBody::ValueChunk { total, index, data },
+                })
+            }
             _ => {
                 return Err(DecoderError::Custom("Unknown RPC message type"));
             } /*
@@ -709,6 +1091,37 @@ impl Message {
     }
 }
 
+/// The maximum size of an RLP-encoded ENR, per the ENR specification.
+const MAX_ENR_SIZE: usize = 300;
+
+/// ENRs decoded from a NODES response, with their signed encodings by node id.
+type DecodedEnrs = (Vec<Enr<CombinedKey>>, HashMap<NodeId, Vec<u8>>);
+
+/// Decodes a list of ENRs, along with the signed encodings they were decoded from by node id. If
+/// a node id appears more than once, the first encoding is kept. ENRs over `MAX_ENR_SIZE` are
+/// dropped rather than failing the whole list.
+fn decode_enrs(rlp: &rlp::Rlp<'_>) -> Result<DecodedEnrs, DecoderError> {
+    let mut enrs = Vec::new();
+    let mut encodings = HashMap::new();
+    for enr_rlp in rlp.iter() {
+        if enr_rlp.as_raw().len() > MAX_ENR_SIZE {
+            debug!(
+                "Dropping an ENR of {} bytes, exceeding the maximum of {}",
+                enr_rlp.as_raw().len(),
+                MAX_ENR_SIZE
+            );
+            METRICS.oversized_enrs.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        let enr: Enr<CombinedKey> = enr_rlp.as_val()?;
+        encodings
+            .entry(enr.node_id())
+            .or_insert_with(|| enr_rlp.as_raw().to_vec());
+        enrs.push(enr);
+    }
+    Ok((enrs, encodings))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -781,6 +1194,7 @@ mod tests {
             body: ResponseBody::Nodes {
                 total,
                 nodes: vec![],
+                encodings: HashMap::new(),
             },
         });
         assert_eq!(message.encode(), expected_output);
@@ -801,6 +1215,7 @@ mod tests {
             body: ResponseBody::Nodes {
                 total,
                 nodes: vec![enr],
+                encodings: HashMap::new(),
             },
         });
         dbg!(hex::encode(message.clone().encode()));
@@ -824,6 +1239,7 @@ mod tests {
             body: ResponseBody::Nodes {
                 total,
                 nodes: vec![enr, enr2],
+                encodings: HashMap::new(),
             },
         });
         dbg!(hex::encode(message.clone().encode()));
@@ -841,7 +1257,7 @@ mod tests {
 
         match decoded {
             Message::Response(response) => match response.body {
-                ResponseBody::Nodes { total, nodes } => {
+                ResponseBody::Nodes { total, nodes, .. } => {
                     assert_eq!(total, 1);
                     assert_eq!(nodes[0], expected_enr1);
                     assert_eq!(nodes[1], expected_enr2);
@@ -852,6 +1268,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn decode_response_nodes_drops_oversized_enrs() {
+        let key = CombinedKey::generate_secp256k1();
+        let enr1 = EnrBuilder::new("v4").build(&key).unwrap();
+        let key = CombinedKey::generate_secp256k1();
+        let enr2 = EnrBuilder::new("v4").build(&key).unwrap();
+
+        // a record padded past the maximum ENR size
+        let mut oversized = RlpStream::new_list(2);
+        oversized.append(&vec![0u8; 64]);
+        oversized.append(&vec![0u8; MAX_ENR_SIZE]);
+
+        let mut s = RlpStream::new_list(3);
+        s.append(&vec![1u8]);
+        s.append(&1u64);
+        s.begin_list(3);
+        s.append(&enr1);
+        s.append_raw(&oversized.out(), 1);
+        s.append(&enr2);
+        let mut input = vec![4];
+        input.extend_from_slice(&s.out());
+
+        let dropped = METRICS.oversized_enrs.load(Ordering::Relaxed);
+        match Message::decode(&input).unwrap() {
+            Message::Response(Response {
+                body: ResponseBody::Nodes { nodes, .. },
+                ..
+            }) => assert_eq!(nodes, vec![enr1, enr2]),
+            _ => panic!("Invalid decoding"),
+        }
+        assert!(METRICS.oversized_enrs.load(Ordering::Relaxed) > dropped);
+    }
+
     #[test]
     fn encode_decode_ping_request() {
         let id = RequestId(vec![1]);
@@ -900,6 +1349,150 @@ mod tests {
         assert_eq!(request, decoded);
     }
 
+    #[test]
+    fn encode_decode_store_request() {
+        let id = RequestId(vec![1]);
+        let request = Message::Request(Request {
+            id,
+            body: RequestBody::Store {
+                key: NodeId::random(),
+                value: vec![1, 2, 3, 4],
+            },
+        });
+
+        let encoded = request.clone().encode();
+        let decoded = Message::decode(&encoded).unwrap();
+
+        assert_eq!(request, decoded);
+    }
+
+    #[test]
+    fn encode_decode_stored_response() {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = EnrBuilder::new("v4")
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(500)
+            .build(&key)
+            .unwrap();
+
+        for (accepted, closer) in [(true, vec![]), (false, vec![]), (true, vec![enr])] {
+            let id = RequestId(vec![1]);
+            let response = Message::Response(Response {
+                id,
+                body: ResponseBody::Stored { accepted, closer },
+            });
+
+            let encoded = response.clone().encode();
+            let decoded = Message::decode(&encoded).unwrap();
+
+            assert_eq!(response, decoded);
+        }
+    }
+
+    #[test]
+    fn encode_decode_store_batch_request() {
+        let id = RequestId(vec![1]);
+        let request = Message::Request(Request {
+            id,
+            body: RequestBody::StoreBatch {
+                entries: vec![(NodeId::random(), vec![1, 2, 3]), (NodeId::random(), vec![])],
+            },
+        });
+
+        let encoded = request.clone().encode();
+        let decoded = Message::decode(&encoded).unwrap();
+
+        assert_eq!(request, decoded);
+    }
+
+    #[test]
+    fn encode_decode_stored_batch_response() {
+        let id = RequestId(vec![1]);
+        let response = Message::Response(Response {
+            id,
+            body: ResponseBody::StoredBatch {
+                accepted: vec![true, false, true],
+            },
+        });
+
+        let encoded = response.clone().encode();
+        let decoded = Message::decode(&encoded).unwrap();
+
+        assert_eq!(response, decoded);
+    }
+
+    #[test]
+    fn encode_decode_value_chunk_response() {
+        let id = RequestId(vec![1]);
+        let response = Message::Response(Response {
+            id: id.clone(),
+            body: ResponseBody::ValueChunk {
+                total: 3,
+                index: 1,
+                data: vec![7; 100],
+            },
+        });
+
+        let encoded = response.clone().encode();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(response, decoded);
+
+        // a chunk index past the total is rejected
+        let response = Message::Response(Response {
+            id,
+            body: ResponseBody::ValueChunk {
+                total: 3,
+                index: 3,
+                data: vec![7; 100],
+            },
+        });
+        assert!(Message::decode(&response.encode()).is_err());
+    }
+
+    #[test]
+    fn encode_decode_relay_requests() {
+        let key = CombinedKey::generate_secp256k1();
+        let initiator = EnrBuilder::new("v4")
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(500)
+            .build(&key)
+            .unwrap();
+
+        for body in [
+            RequestBody::RelayInit {
+                initiator: initiator.clone(),
+                target: NodeId::random(),
+            },
+            RequestBody::RelayMsg { initiator },
+        ] {
+            let request = Message::Request(Request {
+                id: RequestId(vec![1]),
+                body,
+            });
+
+            let encoded = request.clone().encode();
+            let decoded = Message::decode(&encoded).unwrap();
+
+            assert_eq!(request, decoded);
+        }
+    }
+
+    #[test]
+    fn encode_decode_relayed_response() {
+        for forwarded in [true, false] {
+            let id = RequestId(vec![1]);
+            let response = Message::Response(Response {
+                id,
+                body: ResponseBody::Relayed { forwarded },
+            });
+
+            let encoded = response.clone().encode();
+            let decoded = Message::decode(&encoded).unwrap();
+
+            assert_eq!(response, decoded);
+        }
+    }
+
     #[test]
     fn encode_decode_nodes_response() {
         let key = CombinedKey::generate_secp256k1();
@@ -918,6 +1511,10 @@ mod tests {
             .build(&key)
             .unwrap();
 
+        // the records share a node id, the encoding of the first one is kept
+        let encodings = vec![(enr1.node_id(), rlp::encode(&enr1).to_vec())]
+            .into_iter()
+            .collect();
         let enr_list = vec![enr1, enr2, enr3];
         let id = RequestId(vec![1]);
         let request = Message::Response(Response {
@@ -925,6 +1522,7 @@ mod tests {
             body: ResponseBody::Nodes {
                 total: 1,
                 nodes: enr_list,
+                encodings,
             },
         });
 
@@ -15,15 +15,17 @@
 
 use self::{
     ip_vote::IpVote,
-    query_info::{QueryInfo, QueryType},
+    query_info::QueryInfo,
+    value_store::ValueStore,
 };
 use crate::{
-    error::{RequestError, ResponseError},
+    error::{QueryError, RequestError, ResponseError},
     handler::{Handler, HandlerIn, HandlerOut},
     kbucket::{
         self, ConnectionDirection, ConnectionState, FailureReason, InsertResult, KBucketsTable,
-        NodeStatus, UpdateResult,
+        NodeStatus, TableEnr, UpdateResult, MAX_NODES_PER_BUCKET,
     },
+    metrics::METRICS,
     node_info::{NodeAddress, NodeContact, NonContactable},
     packet::MAX_PACKET_SIZE,
     query_pool::{
@@ -31,38 +33,213 @@ use crate::{
     },
     rpc, Discv5Config, Discv5Event, Enr,
 };
-use delay_map::HashSetDelay;
+use delay_map::{HashMapDelay, HashSetDelay};
 use enr::{CombinedKey, NodeId};
 use fnv::FnvHashMap;
 use futures::prelude::*;
 use more_asserts::debug_unreachable;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use rand::Rng;
 use rpc::*;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, task::Poll, time::Instant};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{atomic::Ordering, Arc},
+    task::Poll,
+    time::{Duration, Instant},
+};
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, trace, warn};
 
 mod ip_vote;
 mod query_info;
 mod test;
+mod value_store;
+
+pub use query_info::{target_distances, QueryCompletion, QueryStatus, QueryType};
+pub(crate) use query_info::SharedResponses;
 
 /// The number of distances (buckets) we simultaneously request from each peer.
 /// NOTE: This must not be larger than 127.
 pub(crate) const DISTANCES_TO_REQUEST_PER_PEER: usize = 3;
 
+/// The estimated overhead of a regular message on top of its RPC payload.
+///
+/// Responses assume that a session is established. Thus, on top of the encoded payload the
+/// packet should be a regular message. A regular message has an IV (16 bytes), and a header of 55
+/// bytes. The NODES RPC requires 16 bytes for the ID and the `total` field. Also there is a 16
+/// byte HMAC for encryption and an extra byte for RLP encoding.
+///
+/// We could also be responding via an autheader which can take up to 282 bytes in its header.
+/// As most messages will be normal messages we will try and pack as many ENR's we can in and
+/// drop the response packet if a user requests an auth message of a very packed response.
+const MESSAGE_OVERHEAD: usize = 104;
+
+/// The maximum encoded size of the entries of a STOREBATCH request.
+pub(crate) const MAX_STORE_BATCH_SIZE: usize = MAX_PACKET_SIZE - MESSAGE_OVERHEAD;
+
+/// The maximum size of the data of a VALUECHUNK response. Values larger than this are split into
+/// chunks, leaving room for the `total` and `index` fields of each chunk.
+pub(crate) const MAX_VALUE_CHUNK_SIZE: usize = MAX_PACKET_SIZE - MESSAGE_OVERHEAD - 10;
+
+/// The number of routing table entries pinged on every liveness check.
+const LIVENESS_CHECK_BATCH: usize = 3;
+
+/// The maximum number of closer nodes returned in a STORED response.
+const MAX_CLOSER_NODES: usize = 3;
+
+/// Packs ENRs into as few NODES responses as possible, each fitting in a packet of
+/// `max_packet_size` bytes, capped to `MAX_PACKET_SIZE`.
+///
+/// The ENRs are placed largest first, each in the first response with room left for it. An ENR
+/// too large to fit with others is sent in a response of its own.
+pub(crate) fn pack_nodes(nodes: Vec<Enr>, max_packet_size: usize) -> Vec<Vec<Enr>> {
+    let capacity = max_packet_size
+        .min(MAX_PACKET_SIZE)
+        .saturating_sub(MESSAGE_OVERHEAD);
+    let mut nodes: Vec<(usize, Enr)> = nodes
+        .into_iter()
+        .map(|enr| (rlp::encode(&enr).len(), enr))
+        .collect();
+    nodes.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    let mut packets: Vec<(usize, Vec<Enr>)> = Vec::new();
+    for (size, enr) in nodes {
+        match packets
+            .iter_mut()
+            .find(|(total_size, _)| total_size + size < capacity)
+        {
+            Some((total_size, packet)) => {
+                *total_size += size;
+                trace!("Adding ENR {}, size {}, total size {}", enr, size, total_size);
+                packet.push(enr);
+            }
+            None => packets.push((size, vec![enr])),
+        }
+    }
+    packets.into_iter().map(|(_, packet)| packet).collect()
+}
+
+/// Builds the responses carrying a value to a FIND_VALUE request: a single VALUE response if the
+/// value fits in a packet, VALUECHUNK responses of up to `MAX_VALUE_CHUNK_SIZE` bytes otherwise.
+pub(crate) fn value_responses(id: RequestId, value: Vec<u8>) -> Vec<Response> {
+    if value.len() <= MAX_VALUE_CHUNK_SIZE {
+        return vec![Response {
+            id,
+            body: ResponseBody::Value { response: value },
+        }];
+    }
+    let chunks = value.chunks(MAX_VALUE_CHUNK_SIZE);
+    let total = chunks.len() as u32;
+    chunks
+        .enumerate()
+        .map(|(index, data)| Response {
+            id: id.clone(),
+            body: ResponseBody::ValueChunk {
+                total,
+                index: index as u32,
+                data: data.to_vec(),
+            },
+        })
+        .collect()
+}
+
+/// Orders the ENRs found by a query by their distance to the target, and keeps the closest
+/// `num_results` of them.
+///
+/// The XOR distance orders the ENRs by log2 distance first, its remaining bits breaking ties. It
+/// differs for every node id, so the result only depends on the set of ENRs found and not on the
+/// order they were discovered in.
+pub(crate) fn closest_enrs(
+    target: &kbucket::Key<NodeId>,
+    mut enrs: Vec<Enr>,
+    num_results: usize,
+) -> Vec<Enr> {
+    enrs.sort_by_cached_key(|enr| target.distance(&kbucket::Key::from(enr.node_id())));
+    enrs.dedup_by_key(|enr| enr.node_id());
+    enrs.truncate(num_results);
+    enrs
+}
+
+/// The size of the encoding of a `[key, value]` entry of a STORE or STOREBATCH request. Entries
+/// larger than `MAX_STORE_BATCH_SIZE` do not fit in a single packet.
+pub(crate) fn store_entry_size(key: &NodeId, value: &[u8]) -> usize {
+    let mut s = rlp::RlpStream::new_list(2);
+    s.append(&key.raw().to_vec());
+    s.append(&value);
+    s.out().len()
+}
+
+/// Splits entries into batches, in order, whose encoding fits in a single STOREBATCH request.
+/// Entries are expected to fit on their own (see [`store_entry_size`]), one that does not is
+/// placed in a batch of its own.
+pub(crate) fn store_batches(entries: Vec<(NodeId, Vec<u8>)>) -> Vec<Vec<(NodeId, Vec<u8>)>> {
+    let mut batches: Vec<Vec<(NodeId, Vec<u8>)>> = Vec::new();
+    let mut batch_size = 0;
+    for (key, value) in entries {
+        let entry_size = store_entry_size(&key, &value);
+
+        match batches.last_mut() {
+            Some(batch) if batch_size + entry_size <= MAX_STORE_BATCH_SIZE => {
+                batch_size += entry_size;
+                batch.push((key, value));
+            }
+            _ => {
+                batch_size = entry_size;
+                batches.push(vec![(key, value)]);
+            }
+        }
+    }
+    batches
+}
+
+/// Randomly lengthens or shortens `period` by up to the fraction `jitter` of it.
+pub(crate) fn jittered(period: Duration, jitter: f64) -> Duration {
+    if jitter == 0.0 {
+        return period;
+    }
+    period.mul_f64(1.0 + rand::thread_rng().gen_range(-jitter..=jitter))
+}
+
+/// The response of a peer to a PING request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PingResponse {
+    /// The sequence number of the peer's ENR.
+    pub enr_seq: u64,
+    /// The IP address the peer observed the request from.
+    pub ip: IpAddr,
+    /// The port the peer observed the request from.
+    pub port: u16,
+}
+
+impl PingResponse {
+    /// The socket address the peer observed the request from, which is the local node's
+    /// external address as seen by the peer.
+    pub fn observed_socket(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+}
+
 /// Request type for Protocols using `TalkReq` message.
 ///
-/// Automatically responds with an empty body on drop if
-/// [`TalkRequest::respond`] is not called.
+/// Automatically responds with an empty body on drop, or once the `talk_response_timeout`
+/// elapses, if [`TalkRequest::respond`] is not called.
 #[derive(Debug)]
 pub struct TalkRequest {
     id: RequestId,
     node_address: NodeAddress,
     protocol: Vec<u8>,
     body: Vec<u8>,
-    sender: Option<mpsc::UnboundedSender<HandlerIn>>,
+    /// The channel to the handler, taken by the first response sent.
+    sender: TalkResponseSlot,
+    /// Reports to the service that the request no longer awaits a response.
+    answered: mpsc::UnboundedSender<(NodeAddress, RequestId)>,
 }
 
+/// The channel to the handler of an inbound TALK request, shared by the request and its timeout
+/// in the service. It is taken by the first response sent.
+type TalkResponseSlot = Arc<Mutex<Option<mpsc::UnboundedSender<HandlerIn>>>>;
+
 #[derive(Debug)]
 pub struct FindValueRequest {
     id: RequestId,
@@ -88,24 +265,20 @@ impl FindValueRequest {
     pub fn respond(mut self, response: Option<Vec<u8>>) -> Result<(), ResponseError> {
         match response {
             Some(response) => {
-                let response = Response {
-                    id: self.id.clone(),
-                    body: ResponseBody::Value { response },
-                };
-
-                self.sender
-                    .take()
-                    .unwrap()
-                    .send(HandlerIn::Response(
-                        self.node_address.clone(),
-                        Box::new(response),
-                    ))
-                    .map_err(|_| ResponseError::ChannelClosed)?;
+                let sender = self.sender.take().unwrap();
+                for response in value_responses(self.id.clone(), response) {
+                    sender
+                        .send(HandlerIn::Response(
+                            self.node_address.clone(),
+                            Box::new(response),
+                        ))
+                        .map_err(|_| ResponseError::ChannelClosed)?;
+                }
             }
             None => {
                 let sender = self.sender.take()
                     .unwrap();
-                for resp in self.nodes_responses {
+                for resp in std::mem::take(&mut self.nodes_responses) {
                     sender
                         .send(resp)
                         .map_err(|_| ResponseError::ChannelClosed)?
@@ -117,25 +290,47 @@ impl FindValueRequest {
     }
 }
 
-impl Drop for TalkRequest {
+impl Drop for FindValueRequest {
     fn drop(&mut self) {
         let sender = match self.sender.take() {
             Some(s) => s,
             None => return,
         };
 
-        let response = Response {
-            id: self.id.clone(),
-            body: ResponseBody::Talk { response: vec![] },
-        };
+        debug!("Sending NODES response to FIND_VALUE request from {}", self.node_address);
+        for response in std::mem::take(&mut self.nodes_responses) {
+            if let Err(e) = sender.send(response) {
+                warn!("Failed to send nodes response {}", e)
+            }
+        }
+    }
+}
 
-        debug!("Sending empty TALK response to {}", self.node_address);
-        if let Err(e) = sender.send(HandlerIn::Response(
-            self.node_address.clone(),
-            Box::new(response),
-        )) {
-            warn!("Failed to send empty talk response {}", e)
+impl Drop for TalkRequest {
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.lock().take() {
+            send_empty_talk_response(&sender, self.id.clone(), self.node_address.clone());
         }
+        // the service may already have stopped
+        let _ = self
+            .answered
+            .send((self.node_address.clone(), self.id.clone()));
+    }
+}
+
+/// Answers a TALK request with an empty response.
+fn send_empty_talk_response(
+    sender: &mpsc::UnboundedSender<HandlerIn>,
+    id: RequestId,
+    node_address: NodeAddress,
+) {
+    debug!("Sending empty TALK response to {}", node_address);
+    let response = Response {
+        id,
+        body: ResponseBody::Talk { response: vec![] },
+    };
+    if let Err(e) = sender.send(HandlerIn::Response(node_address, Box::new(response))) {
+        warn!("Failed to send empty talk response {}", e)
     }
 }
 
@@ -156,7 +351,9 @@ impl TalkRequest {
         &self.body
     }
 
-    pub fn respond(mut self, response: Vec<u8>) -> Result<(), ResponseError> {
+    /// Responds to the request, unless it was already answered with an empty response after
+    /// timing out.
+    pub fn respond(self, response: Vec<u8>) -> Result<(), ResponseError> {
         debug!("Sending TALK response to {}", self.node_address);
 
         let response = Response {
@@ -164,9 +361,9 @@ impl TalkRequest {
             body: ResponseBody::Talk { response },
         };
 
-        self.sender
-            .take()
-            .unwrap()
+        let sender = self.sender.lock().take();
+        sender
+            .ok_or(ResponseError::TimedOut)?
             .send(HandlerIn::Response(
                 self.node_address.clone(),
                 Box::new(response),
@@ -183,14 +380,54 @@ pub enum ServiceRequest {
     /// - A FindNode Query - Searches for peers using a random target.
     /// - A Predicate Query - Searches for peers closest to a random target that match a specified
     /// predicate.
-    StartQuery(QueryKind, oneshot::Sender<Vec<Enr>>),
-    /// A request to start a query. There are two types of queries:
-    /// - A FindNode Query - Searches for peers using a random target.
-    /// - A Predicate Query - Searches for peers closest to a random target that match a specified
-    /// predicate.
-    FindValue(NodeId, mpsc::UnboundedSender<Result<Vec<u8>, FindValueError>>),
+    ///
+    /// The found ENRs are returned along with their log2 distance to the target, and how the
+    /// query ended.
+    StartQuery(
+        QueryKind,
+        oneshot::Sender<(Vec<(Enr, u64)>, QueryCompletion)>,
+    ),
+    /// A request to start a `FIND_VALUE` query, optionally overriding the configured query
+    /// timeout and parallelism. Values are only reported once `quorum` peers returned them.
+    FindValue {
+        key: NodeId,
+        timeout: Option<Duration>,
+        parallelism: Option<usize>,
+        quorum: usize,
+        callback: mpsc::UnboundedSender<Result<Vec<u8>, FindValueError>>,
+    },
+    /// A request to start a `FIND_NODE` query, which streams each ENR discovered during the
+    /// query. The stream ends once the query has finished.
+    FindNodeStream(NodeId, mpsc::UnboundedSender<Enr>),
+    /// A request to start a `FIND_NODE` query, which returns the signed encodings of the ENRs
+    /// found as received from the peers.
+    FindNodeRaw(NodeId, oneshot::Sender<Vec<Vec<u8>>>),
+    /// Stores a value under the given key on a single node. Whether the node accepted the value
+    /// is returned along with the nodes it knows of that are closer to the key.
+    Store(NodeContact, NodeId, Vec<u8>, StoreSender),
+    /// Stores several values on a single node in one request. Whether the node accepted each
+    /// value is returned in the order of the entries.
+    StoreBatch(
+        NodeContact,
+        Vec<(NodeId, Vec<u8>)>,
+        oneshot::Sender<Result<Vec<bool>, RequestError>>,
+    ),
+    /// Registers a value originated by the local node to be republished periodically.
+    Publish(NodeId, Vec<u8>),
+    /// Retrieves the keys of the values held in the local value store.
+    StoredKeys(oneshot::Sender<Vec<NodeId>>),
+    /// Retrieves a value held in the local value store.
+    StoredValue(NodeId, oneshot::Sender<Option<Vec<u8>>>),
+    /// Retrieves a snapshot of the progress of the in-flight queries.
+    ActiveQueries(oneshot::Sender<Vec<QueryStatus>>),
     /// Find the ENR of a node given its multiaddr.
     FindEnr(NodeContact, oneshot::Sender<Result<Enr, RequestError>>),
+    /// Sends a PING request to a node.
+    Ping(NodeContact, oneshot::Sender<Result<PingResponse, RequestError>>),
+    /// The local ENR was signed again under the given sequence number. The update is reported to
+    /// the event stream and, if `proactive_enr_updates` is enabled, announced to the connected
+    /// peers.
+    LocalEnrUpdated(u64),
     /// The TALK discv5 RPC function.
     Talk(
         NodeContact,
@@ -198,14 +435,36 @@ pub enum ServiceRequest {
         Vec<u8>,
         oneshot::Sender<Result<Vec<u8>, RequestError>>,
     ),
+    /// Drops the session with a node and marks it as disconnected in the routing table.
+    Disconnect(NodeId),
+    /// Establishes fresh keys for the session with a node.
+    Rekey(NodeId),
+    /// Returns whether an established session exists with a node.
+    IsConnected(NodeId, oneshot::Sender<bool>),
+    /// Asks a relay to prompt the target node to contact the local node.
+    RelayInit(NodeContact, NodeId, oneshot::Sender<Result<bool, RequestError>>),
     /// Sets up an event stream where the discv5 server will return various events such as
     /// discovered nodes as it traverses the DHT.
     RequestEventStream(oneshot::Sender<mpsc::Receiver<Discv5Event>>),
+    /// Stops accepting new requests and shuts the service down once the in-flight queries and
+    /// requests have resolved, or the shutdown timeout has elapsed. The sender is notified once
+    /// the service has shut down.
+    Shutdown(oneshot::Sender<()>),
 }
 
-use crate::discv5::PERMIT_BAN_LIST;
+/// The handlers of the TALK protocols registered by the application, by protocol id.
+pub(crate) type TalkProtocols = Arc<RwLock<HashMap<Vec<u8>, mpsc::Sender<TalkRequest>>>>;
+
+/// The peers queries contact first, set by the application.
+pub(crate) type PreferredPeers = Arc<RwLock<HashSet<NodeId>>>;
+
+/// Returns whether a node accepted a STORE request, along with the nodes it knows of that are
+/// closer to the key.
+pub(crate) type StoreSender = oneshot::Sender<Result<(bool, Vec<Enr>), RequestError>>;
+
+use crate::discv5::{random_node_id_at_distance, PERMIT_BAN_LIST};
 use crate::error::FindValueError;
-use crate::service::query_info::{QueryCallback};
+use crate::service::query_info::{QueryCallback, ValueSender};
 
 pub struct Service {
     /// Configuration parameters.
@@ -218,7 +477,7 @@ pub struct Service {
     enr_key: Arc<RwLock<CombinedKey>>,
 
     /// Storage of the ENR record for each node.
-    kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+    kbuckets: Arc<RwLock<KBucketsTable<NodeId, TableEnr>>>,
 
     /// All the iterative queries we are currently performing.
     queries: QueryPool<QueryInfo, NodeId, Enr>,
@@ -227,12 +486,19 @@ pub struct Service {
     /// query.
     active_requests: FnvHashMap<RequestId, ActiveRequest>,
 
-    /// Keeps track of the number of responses received from a NODES response.
-    active_nodes_responses: HashMap<NodeId, NodesResponse>,
+    /// Keeps track of the number of responses received from a NODES response, by request.
+    active_nodes_responses: HashMap<RequestId, NodesResponse>,
+
+    /// The chunks received so far of the values split over several VALUECHUNK responses.
+    active_value_chunks: HashMap<RequestId, ValueChunks>,
 
     /// A map of votes nodes have made about our external IP address. We accept the majority.
     ip_votes: Option<IpVote>,
 
+    /// The external IPv4 and IPv6 addresses predicted from the votes, shared with the discv5
+    /// wrapper.
+    external_address_prediction: Arc<RwLock<(Option<SocketAddrV4>, Option<SocketAddrV6>)>>,
+
     /// The channel to send messages to the handler.
     handler_send: mpsc::UnboundedSender<HandlerIn>,
 
@@ -248,11 +514,57 @@ pub struct Service {
     /// The exit channel for the service.
     exit: oneshot::Receiver<()>,
 
+    /// The deadline of a graceful shutdown in progress, along with the channel notified once the
+    /// service has shut down.
+    shutdown: Option<(tokio::time::Instant, oneshot::Sender<()>)>,
+
+    /// The time of the next attempt to contact the boot nodes and the backoff before the attempt
+    /// after it, until a session is established.
+    bootstrap: Option<(tokio::time::Instant, Duration)>,
+
     /// A queue of peers that require regular ping to check connectivity.
     peers_to_ping: HashSetDelay<NodeId>,
 
+    /// The time of the next routing table refresh, if enabled.
+    table_refresh: Option<tokio::time::Instant>,
+
+    /// The interval at which the liveness of the routing table entries is checked, if enabled.
+    liveness_check: Option<tokio::time::Interval>,
+
+    /// The number of consecutive PINGs each node of the routing table failed to respond to.
+    ping_failures: HashMap<NodeId, u32>,
+
+    /// The peers outside the routing table that PINGed us and are being pinged back, with their
+    /// ENR to insert once they answer.
+    incoming_ping_probes: HashMap<NodeId, Enr>,
+
     /// A channel that the service emits events on.
     event_stream: Option<mpsc::Sender<Discv5Event>>,
+
+    /// The handlers of the registered TALK protocols, shared with the discv5 wrapper.
+    talk_protocols: TalkProtocols,
+
+    /// The peers queries contact first, shared with the discv5 wrapper.
+    preferred_peers: PreferredPeers,
+
+    /// The values stored on this node by other nodes.
+    value_store: ValueStore,
+
+    /// The values originated by this node, along with the time they stop being republished.
+    published_values: HashMap<NodeId, (Vec<u8>, tokio::time::Instant)>,
+
+    /// A queue of originated values that are due to be republished.
+    values_to_republish: HashSetDelay<NodeId>,
+
+    /// The inbound TALK requests awaiting a response. They are answered with an empty response
+    /// once the `talk_response_timeout` elapses.
+    talk_timeouts: HashMapDelay<(NodeAddress, RequestId), TalkResponseSlot>,
+
+    /// Passed to the inbound TALK requests, which report once they no longer await a response.
+    talk_answered_send: mpsc::UnboundedSender<(NodeAddress, RequestId)>,
+
+    /// The inbound TALK requests that were answered or dropped, whose timeouts are removed.
+    talk_answered_recv: mpsc::UnboundedReceiver<(NodeAddress, RequestId)>,
 }
 
 /// Active RPC request awaiting a response from the handler.
@@ -271,10 +583,18 @@ struct ActiveRequest {
 pub enum CallbackResponse {
     /// A response to a requested ENR.
     Enr(oneshot::Sender<Result<Enr, RequestError>>),
+    /// A response from a PING request.
+    Pong(oneshot::Sender<Result<PingResponse, RequestError>>),
     /// A response from a TALK request
     Talk(oneshot::Sender<Result<Vec<u8>, RequestError>>),
-    /// A response from a TALK request
-    Value(mpsc::UnboundedSender<Result<Vec<u8>, FindValueError>>),
+    /// A response from a FIND_VALUE request.
+    Value(ValueSender),
+    /// A response from a STORE request.
+    Store(StoreSender),
+    /// A response from a STOREBATCH request.
+    StoreBatch(oneshot::Sender<Result<Vec<bool>, RequestError>>),
+    /// A response from a RELAYINIT request.
+    Relay(oneshot::Sender<Result<bool, RequestError>>),
 }
 
 /// For multiple responses to a FindNodes request, this keeps track of the request count
@@ -286,6 +606,54 @@ struct NodesResponse {
     received_nodes: Vec<Enr>,
 }
 
+/// The chunks of a value received in VALUECHUNK responses to a FIND_VALUE request.
+struct ValueChunks {
+    /// The number of chunks the value is split in.
+    total: u32,
+    /// The number of chunks received, including invalid ones.
+    received: u32,
+    /// The data of the valid chunks received, by index.
+    chunks: BTreeMap<u32, Vec<u8>>,
+}
+
+impl ValueChunks {
+    fn new(total: u32) -> Self {
+        ValueChunks {
+            total,
+            received: 0,
+            chunks: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a chunk, ignoring it if it disagrees on the number of chunks.
+    fn add(&mut self, total: u32, index: u32, data: Vec<u8>) {
+        self.received += 1;
+        if total == self.total {
+            self.chunks.insert(index, data);
+        }
+    }
+
+    /// The size of the data received so far.
+    fn size(&self) -> usize {
+        self.chunks.values().map(Vec::len).sum()
+    }
+
+    /// Whether as many chunks as expected were received.
+    fn is_complete(&self) -> bool {
+        self.received >= self.total
+    }
+
+    /// Whether every chunk of the value was received.
+    fn is_whole(&self) -> bool {
+        self.chunks.len() == self.total as usize
+    }
+
+    /// Reassembles the data of the chunks received, in order.
+    fn into_data(self) -> Vec<u8> {
+        self.chunks.into_values().flatten().collect()
+    }
+}
+
 impl Default for NodesResponse {
     fn default() -> Self {
         NodesResponse {
@@ -301,10 +669,14 @@ impl Service {
     /// `local_enr` is the `ENR` representing the local node. This contains node identifying information, such
     /// as IP addresses and ports which we wish to broadcast to other nodes via this discovery
     /// mechanism.
+    #[allow(clippy::too_many_arguments)]
     pub async fn spawn(
         local_enr: Arc<RwLock<Enr>>,
         enr_key: Arc<RwLock<CombinedKey>>,
-        kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+        kbuckets: Arc<RwLock<KBucketsTable<NodeId, TableEnr>>>,
+        external_address_prediction: Arc<RwLock<(Option<SocketAddrV4>, Option<SocketAddrV6>)>>,
+        talk_protocols: TalkProtocols,
+        preferred_peers: PreferredPeers,
         config: Discv5Config,
         listen_socket: SocketAddr,
     ) -> Result<(oneshot::Sender<()>, mpsc::Sender<ServiceRequest>), std::io::Error> {
@@ -330,6 +702,7 @@ impl Service {
         // create the required channels
         let (discv5_send, discv5_recv) = mpsc::channel(30);
         let (exit_send, exit) = oneshot::channel();
+        let (talk_answered_send, talk_answered_recv) = mpsc::unbounded_channel();
 
         config
             .executor
@@ -340,17 +713,43 @@ impl Service {
                     local_enr,
                     enr_key,
                     kbuckets,
-                    queries: QueryPool::new(config.query_timeout),
+                    queries: QueryPool::new(config.query_timeout)
+                        .with_max_concurrent(config.max_concurrent_queries),
                     active_requests: Default::default(),
                     active_nodes_responses: HashMap::new(),
+                    active_value_chunks: HashMap::new(),
                     ip_votes,
+                    external_address_prediction,
                     handler_send,
                     handler_recv,
                     handler_exit: Some(handler_exit),
                     peers_to_ping: HashSetDelay::new(config.ping_interval),
+                    table_refresh: config.table_refresh_interval.map(|period| {
+                        tokio::time::Instant::now() + jittered(period, config.timer_jitter)
+                    }),
+                    liveness_check: config.liveness_check_interval.map(|period| {
+                        tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+                    }),
+                    ping_failures: HashMap::new(),
+                    incoming_ping_probes: HashMap::new(),
                     discv5_recv,
                     event_stream: None,
+                    talk_protocols,
+                    preferred_peers,
+                    value_store: ValueStore::new(
+                        config.max_value_size,
+                        config.value_store_capacity,
+                        config.stored_value_ttl,
+                    ),
+                    published_values: HashMap::new(),
+                    values_to_republish: HashSetDelay::new(config.value_republish_interval),
+                    talk_timeouts: HashMapDelay::new(config.talk_response_timeout),
+                    talk_answered_send,
+                    talk_answered_recv,
                     exit,
+                    shutdown: None,
+                    bootstrap: (!config.boot_nodes.is_empty())
+                        .then(|| (tokio::time::Instant::now(), config.bootstrap_backoff)),
                     config: config.clone(),
                 };
 
@@ -365,6 +764,26 @@ impl Service {
     async fn start(&mut self) {
         tracing::info!("{:?}", self.config.ip_mode);
         loop {
+            // complete a graceful shutdown once nothing is in flight anymore
+            if let Some((deadline, _)) = &self.shutdown {
+                let in_flight =
+                    self.queries.iter().next().is_some() || !self.active_requests.is_empty();
+                if !in_flight || tokio::time::Instant::now() >= *deadline {
+                    if let Some(exit) = self.handler_exit.take() {
+                        let _ = exit.send(());
+                        info!("Discv5 Service shutdown");
+                    }
+                    if let Some((_, callback)) = self.shutdown.take() {
+                        let _ = callback.send(());
+                    }
+                    return;
+                }
+            }
+            let shutdown_deadline = self.shutdown.as_ref().map(|(deadline, _)| *deadline);
+
+            let query_timeout = self.queries.next_timeout();
+            let bootstrap_deadline = self.bootstrap.map(|(deadline, _)| deadline);
+            let table_refresh_deadline = self.table_refresh;
             tokio::select! {
                 _ = &mut self.exit => {
                     if let Some(exit) = self.handler_exit.take() {
@@ -373,27 +792,105 @@ impl Service {
                     }
                     return;
                 }
+                _ = tokio::time::sleep_until(shutdown_deadline.unwrap_or_else(tokio::time::Instant::now)), if shutdown_deadline.is_some() => {}
+                _ = tokio::time::sleep_until(bootstrap_deadline.unwrap_or_else(tokio::time::Instant::now)), if bootstrap_deadline.is_some() => {
+                    self.contact_boot_nodes();
+                }
                 Some(service_request) = self.discv5_recv.recv() => {
+                    if self.shutdown.is_some() {
+                        debug!("Service is shutting down, dropping request");
+                        continue;
+                    }
                     match service_request {
                         ServiceRequest::StartQuery(query, callback) => {
                             match query {
-                                QueryKind::FindNode { target_node } => {
-                                    self.start_findnode_query(target_node, callback);
+                                QueryKind::FindNode { target_node, timeout, num_results } => {
+                                    self.start_findnode_query(QueryType::FindNode(target_node), timeout, num_results, QueryCallback::FindNode(callback));
+                                }
+                                QueryKind::FindNodeShared { target_node, shared_responses } => {
+                                    self.start_shared_findnode_query(target_node, shared_responses, callback);
+                                }
+                                QueryKind::FindNodeAt { target_node, distances } => {
+                                    self.start_findnode_query(QueryType::FindNodeAt { target: target_node, distances }, None, None, QueryCallback::FindNode(callback));
                                 }
                                 QueryKind::Predicate { target_node, target_peer_no, predicate } => {
                                     self.start_predicate_query(target_node, target_peer_no, predicate, callback);
                                 }
                             }
                         }
-                        ServiceRequest::FindValue(target_value, callback) => {
-                            self.start_findvalue_query(target_value, callback);
+                        ServiceRequest::FindValue { key, timeout, parallelism, quorum, callback } => {
+                            self.start_findvalue_query(key, timeout, parallelism, quorum, callback);
+                        }
+                        ServiceRequest::FindNodeStream(target_node, sender) => {
+                            let callback = QueryCallback::FindNodeStream { sender, seen: HashSet::new() };
+                            self.start_findnode_query(QueryType::FindNode(target_node), None, None, callback);
+                        }
+                        ServiceRequest::FindNodeRaw(target_node, sender) => {
+                            let callback = QueryCallback::FindNodeRaw { sender, encodings: HashMap::new() };
+                            self.start_findnode_query(QueryType::FindNode(target_node), None, None, callback);
+                        }
+                        ServiceRequest::Store(node_contact, key, value, callback) => {
+                            self.store_request(node_contact, key, value, Some(callback));
+                        }
+                        ServiceRequest::StoreBatch(node_contact, entries, callback) => {
+                            self.store_batch_request(node_contact, entries, callback);
+                        }
+                        ServiceRequest::Publish(key, value) => {
+                            self.publish_value(key, value);
+                        }
+                        ServiceRequest::StoredKeys(callback) => {
+                            if callback.send(self.value_store.keys()).is_err() {
+                                error!("Failed to return the stored keys");
+                            }
+                        }
+                        ServiceRequest::StoredValue(key, callback) => {
+                            if callback.send(self.value_store.peek(&key).cloned()).is_err() {
+                                error!("Failed to return the stored value");
+                            }
+                        }
+                        ServiceRequest::ActiveQueries(callback) => {
+                            if callback.send(self.active_queries()).is_err() {
+                                error!("Failed to return the active queries");
+                            }
                         }
                         ServiceRequest::FindEnr(node_contact, callback) => {
                             self.request_enr(node_contact, Some(callback));
                         }
+                        ServiceRequest::Ping(node_contact, callback) => {
+                            self.ping_request(node_contact, Some(callback));
+                        }
+                        ServiceRequest::LocalEnrUpdated(seq) => {
+                            self.send_event(Discv5Event::LocalEnrUpdated { seq });
+                            if self.config.proactive_enr_updates {
+                                self.ping_connected_peers();
+                            }
+                        }
                         ServiceRequest::Talk(node_contact, protocol, request, callback) => {
                             self.talk_request(node_contact, protocol, request, callback);
                         }
+                        ServiceRequest::Disconnect(node_id) => {
+                            if let Err(e) = self.handler_send.send(HandlerIn::Disconnect(node_id)) {
+                                warn!("Failed to send disconnect to the handler {}", e)
+                            }
+                            self.connection_updated(node_id, ConnectionStatus::Disconnected);
+                        }
+                        ServiceRequest::IsConnected(node_id, callback) => {
+                            if let Err(e) = self.handler_send.send(HandlerIn::IsConnected(node_id, callback)) {
+                                warn!("Failed to send the connection status request to the handler {}", e)
+                            }
+                        }
+                        ServiceRequest::Rekey(node_id) => {
+                            if let Err(e) = self.handler_send.send(HandlerIn::Rekey(node_id)) {
+                                warn!("Failed to send rekey to the handler {}", e)
+                            }
+                            // the next request performs the handshake
+                            if let Some(enr) = self.find_enr(&node_id) {
+                                self.send_ping(enr);
+                            }
+                        }
+                        ServiceRequest::RelayInit(relay_contact, target, callback) => {
+                            self.relay_init_request(relay_contact, target, callback);
+                        }
                         ServiceRequest::RequestEventStream(callback) => {
                             // the channel size needs to be large to handle many discovered peers
                             // if we are reporting them on the event stream.
@@ -404,12 +901,21 @@ impl Service {
                                 error!("Failed to return the event stream channel");
                             }
                         }
+                        ServiceRequest::Shutdown(callback) => {
+                            debug!("Shutting down once the in-flight requests have resolved");
+                            let deadline = tokio::time::Instant::now() + self.config.shutdown_timeout;
+                            self.shutdown = Some((deadline, callback));
+                        }
                     }
                 }
                 Some(event) = self.handler_recv.recv() => {
                     match event {
                         HandlerOut::Established(enr, socket_addr, direction) => {
                             self.send_event(Discv5Event::SessionEstablished(enr.clone(), socket_addr));
+                            if self.bootstrap.take().is_some() {
+                                info!("Bootstrapped, session established with {}", enr.node_id());
+                                self.send_event(Discv5Event::Bootstrapped);
+                            }
                             self.inject_session_established(enr, direction);
                         }
                         HandlerOut::Request(node_address, request) => {
@@ -440,21 +946,46 @@ impl Service {
                             }
                             self.rpc_failure(request_id, error);
                         }
+                        HandlerOut::HandshakeFailed(node_id, reason) => {
+                            self.send_event(Discv5Event::HandshakeFailed { node_id, reason });
+                        }
                     }
                 }
                 event = Service::bucket_maintenance_poll(&self.kbuckets) => {
                     self.send_event(event);
                 }
+                _ = Service::query_timeout_wait(query_timeout) => {
+                    // A query has timed out, the query pool reports it on the next iteration.
+                }
                 query_event = Service::query_event_poll(&mut self.queries) => {
+                    let timed_out = matches!(query_event, QueryEvent::TimedOut(_));
                     match query_event {
-                        QueryEvent::Waiting(query_id, node_id, request_body) => {
+                        QueryEvent::Waiting(query_id, node_id, Ok(request_body)) => {
                             self.send_rpc_query(query_id, node_id, request_body);
                         }
-                        // Note: Currently the distinction between a timed-out query and a finished
-                        // query is superfluous, however it may be useful in future versions.
+                        QueryEvent::Waiting(query_id, node_id, Err(e)) => {
+                            // The request cannot be built, treat the peer as failed
+                            warn!("Query id: {:?} could not build a request: {}", query_id, e);
+                            if let Some(query) = self.queries.get_mut(query_id) {
+                                query.on_failure(&node_id);
+                            }
+                        }
+                        // A known target which didn't respond is looked up iteratively instead
+                        QueryEvent::Finished(query) if query.target().known_target && query.num_responses() == 0 => {
+                            METRICS.find_node_queries.add_completion(QueryCompletion::Exhausted);
+                            let timeout = query.timeout().saturating_sub(query.elapsed());
+                            let target = query.into_result().target;
+                            debug!("Known target {:?} did not respond, looking it up iteratively", target.query_type);
+                            let num_results = FindNodeQueryConfig::new_from_config(&self.config).num_results;
+                            self.start_findnode_query(target.query_type, Some(timeout), Some(num_results), target.callback);
+                        }
                         QueryEvent::Finished(query) | QueryEvent::TimedOut(query) => {
                             let id = query.id();
+                            let found_value = query.found_value();
+                            let num_results = query.num_results();
+                            let elapsed = query.elapsed();
                             let mut result = query.into_result();
+                            let target_key = result.target.key();
                             // obtain the ENR's for the resulting nodes
                             let mut found_enrs = Vec::new();
                             for node_id in result.closest_peers {
@@ -469,30 +1000,120 @@ impl Service {
                                     warn!("ENR not present in queries results");
                                 }
                             }
+                            let found_enrs = closest_enrs(&target_key, found_enrs, num_results);
+
+                            let completion = if found_value {
+                                QueryCompletion::Finished
+                            } else if timed_out {
+                                QueryCompletion::TimedOut
+                            } else if matches!(result.target.query_type, QueryType::FindValue(_)) || found_enrs.len() < num_results {
+                                QueryCompletion::Exhausted
+                            } else {
+                                QueryCompletion::Finished
+                            };
+                            let query_counters = match result.target.query_type {
+                                QueryType::FindValue(_) => &METRICS.find_value_queries,
+                                _ => &METRICS.find_node_queries,
+                            };
+                            query_counters.add_completion(completion);
+                            METRICS.add_query_duration(elapsed);
 
                             match result.target.callback {
                                 QueryCallback::FindNode(callback) => {
-                                    if callback.send(found_enrs).is_err() {
+                                    let found_enrs = found_enrs
+                                        .into_iter()
+                                        .map(|enr| {
+                                            // the target itself has no log2 distance
+                                            let distance = target_key
+                                                .log2_distance(&enr.node_id().into())
+                                                .unwrap_or(0);
+                                            (enr, distance)
+                                        })
+                                        .collect::<Vec<_>>();
+                                    if callback.send((found_enrs, completion)).is_err() {
+                                        warn!("Callback dropped for query {}. Results dropped", *id);
+                                    }
+                                },
+                                // Dropping the sender ends the stream.
+                                QueryCallback::FindNodeStream { .. } => {}
+                                // ENRs only known from the routing table were not received
+                                QueryCallback::FindNodeRaw { sender, mut encodings } => {
+                                    let found_encodings = found_enrs
+                                        .iter()
+                                        .filter_map(|enr| encodings.remove(&enr.node_id()))
+                                        .collect();
+                                    if sender.send(found_encodings).is_err() {
+                                        warn!("Callback dropped for query {}. Results dropped", *id);
+                                    }
+                                },
+                                // The value has already been sent to the callback, dropping it
+                                // ends the stream once outstanding requests have resolved.
+                                QueryCallback::FindValue(_) if found_value => {}
+                                QueryCallback::FindValue(callback) if !timed_out => {
+                                    let error = if callback.quorum() > 1 {
+                                        FindValueError::QuorumFailed
+                                    } else {
+                                        FindValueError::NotFound
+                                    };
+                                    if callback.send(error).is_err() {
                                         warn!("Callback dropped for query {}. Results dropped", *id);
                                     }
                                 },
                                 QueryCallback::FindValue(callback) => {
-                                    if callback.send(Err(FindValueError::RequestErrorWithEnrs((RequestError::Timeout, found_enrs)))).is_err() {
+                                    if callback.send(FindValueError::RequestErrorWithEnrs((RequestError::Timeout, found_enrs))).is_err() {
                                         warn!("Callback dropped for query {}. Results dropped", *id);
                                     }
                                 },
+                                // The peers found were inserted as they responded.
+                                QueryCallback::TableRefresh => {}
+                                QueryCallback::Republish(value) => {
+                                    let key = *target_key.preimage();
+                                    let contacts: Vec<NodeContact> = found_enrs
+                                        .into_iter()
+                                        .filter_map(|enr| match NodeContact::try_from_enr(enr, self.config.ip_mode) {
+                                            Ok(contact) => Some(contact),
+                                            Err(e) => {
+                                                debug!("Not republishing value on non-contactable peer {}", e.enr);
+                                                None
+                                            }
+                                        })
+                                        .take(self.config.value_replication_factor)
+                                        .collect();
+                                    for contact in contacts {
+                                        self.store_request(contact, key, value.clone(), None);
+                                    }
+                                },
                             }
                         }
                     }
                 }
-                Some(Ok(node_id)) = self.peers_to_ping.next() => {
+                _ = tokio::time::sleep_until(table_refresh_deadline.unwrap_or_else(tokio::time::Instant::now)), if table_refresh_deadline.is_some() && self.shutdown.is_none() => {
+                    self.refresh_table();
+                }
+                _ = Service::interval_wait(&mut self.liveness_check), if self.shutdown.is_none() => {
+                    self.check_liveness();
+                }
+                Some(Ok(key)) = self.values_to_republish.next(), if self.shutdown.is_none() => {
+                    self.republish_value(key);
+                }
+                Some(Ok(((node_address, id), sender))) = self.talk_timeouts.next() => {
+                    // a request left unanswered is answered with an empty response
+                    let sender = sender.lock().take();
+                    if let Some(sender) = sender {
+                        send_empty_talk_response(&sender, id, node_address);
+                    }
+                }
+                Some(request) = self.talk_answered_recv.recv() => {
+                    self.talk_timeouts.remove(&request);
+                }
+                Some(Ok(node_id)) = self.peers_to_ping.next(), if self.shutdown.is_none() => {
                     // If the node is in the routing table, Ping it and re-queue the node.
                     let key = kbucket::Key::from(node_id);
                     let enr =  {
                         if let kbucket::Entry::Present(entry, _) = self.kbuckets.write().entry(&key) {
                         // The peer is in the routing table, ping it and re-queue the ping
                         self.peers_to_ping.insert(node_id);
-                        Some(entry.value().clone())
+                        Some(entry.value().enr())
                         } else { None }
                     };
 
@@ -504,48 +1125,145 @@ impl Service {
         }
     }
 
-    /// Internal function that starts a query.
-    fn start_findnode_query(&mut self, target_node: NodeId, callback: oneshot::Sender<Vec<Enr>>) {
+    /// Returns a snapshot of the progress of the in-flight queries.
+    fn active_queries(&self) -> Vec<QueryStatus> {
+        self.queries
+            .iter()
+            .map(|query| QueryStatus {
+                target: *query.target().key().preimage(),
+                query_type: query.target().query_type.clone(),
+                elapsed: query.elapsed(),
+                peers_contacted: query.num_contacted(),
+                responses: query.num_responses(),
+            })
+            .collect()
+    }
+
+    /// Internal function that starts a query. Returns the id of the query, unless no peers are
+    /// known to start it from.
+    fn start_findnode_query(
+        &mut self,
+        query_type: QueryType,
+        timeout: Option<Duration>,
+        num_results: Option<usize>,
+        callback: QueryCallback,
+    ) -> Option<QueryId> {
         let mut target = QueryInfo {
-            query_type: QueryType::FindNode(target_node),
+            query_type,
             untrusted_enrs: Default::default(),
             distances_to_request: DISTANCES_TO_REQUEST_PER_PEER,
-            callback: QueryCallback::FindNode(callback),
+            callback,
+            shared_responses: None,
+            requested_distances: Default::default(),
+            known_target: false,
         };
 
         let target_key: kbucket::Key<NodeId> = target.key();
         let mut known_closest_peers = Vec::new();
+        let mut num_results = num_results;
         {
             let mut kbuckets = self.kbuckets.write();
-            for closest in kbuckets.closest_values(&target_key) {
-                // Add the known ENR's to the untrusted list
-                target.untrusted_enrs.push(closest.value);
-                // Add the key to the list for the query
-                known_closest_peers.push(closest.key);
+            // A plain lookup of a known node's exact id only requests the node's own ENR
+            // (distance 0) rather than iterating towards it.
+            let known_target = match (&target.query_type, &target.callback, num_results) {
+                (QueryType::FindNode(_), QueryCallback::FindNode(_), None) => {
+                    match kbuckets.entry(&target_key) {
+                        kbucket::Entry::Present(entry, _) => Some(entry.value().enr()),
+                        kbucket::Entry::Pending(mut entry, _) => Some(entry.value().enr()),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            if let Some(enr) = known_target {
+                target.add_untrusted_enr(enr, self.config.max_untrusted_enrs);
+                target.known_target = true;
+                known_closest_peers.push(target_key.clone());
+                num_results = Some(1);
+            } else {
+                for closest in kbuckets.closest_values(&target_key) {
+                    // Add the known ENR's to the untrusted list
+                    target.add_untrusted_enr(closest.value.enr(), self.config.max_untrusted_enrs);
+                    // Add the key to the list for the query
+                    known_closest_peers.push(closest.key);
+                }
             }
         }
 
         if known_closest_peers.is_empty() {
             warn!("No known_closest_peers found. Return empty result without sending query.");
-            if let QueryCallback::FindNode(callback) = target.callback {
-                if callback.send(vec![]).is_err() {
-                    warn!("Failed to callback");
-                }
+            match target.callback {
+                QueryCallback::FindNode(callback) => callback
+                    .send((vec![], QueryCompletion::Exhausted))
+                    .unwrap_or_else(|_| warn!("Failed to callback")),
+                QueryCallback::FindNodeRaw { sender, .. } => sender
+                    .send(vec![])
+                    .unwrap_or_else(|_| warn!("Failed to callback")),
+                _ => {}
             }
+            None
         } else {
-            let query_config = FindNodeQueryConfig::new_from_config(&self.config);
-            self.queries
-                .add_findnode_query(query_config, target, known_closest_peers);
+            let mut query_config = FindNodeQueryConfig::new_from_config(&self.config);
+            if let Some(num_results) = num_results {
+                query_config.num_results = num_results;
+            }
+            METRICS.find_node_queries.started.fetch_add(1, Ordering::Relaxed);
+            let query_id =
+                self.queries
+                    .add_findnode_query(query_config, target, known_closest_peers, timeout);
+            self.prefer_peers(query_id);
+            Some(query_id)
+        }
+    }
+
+    /// Makes a query contact the preferred peers first, among the peers it starts from.
+    fn prefer_peers(&mut self, query_id: QueryId) {
+        let preferred: Vec<NodeId> = self.preferred_peers.read().iter().copied().collect();
+        if preferred.is_empty() {
+            return;
+        }
+        if let Some(query) = self.queries.get_mut(query_id) {
+            query.prefer_peers(&preferred);
+        }
+    }
+
+    /// Starts a `FIND_NODE` query of a multi-target lookup, which shares its NODES responses with
+    /// the other queries of the lookup.
+    fn start_shared_findnode_query(
+        &mut self,
+        target_node: NodeId,
+        shared_responses: SharedResponses,
+        callback: oneshot::Sender<(Vec<(Enr, u64)>, QueryCompletion)>,
+    ) -> Option<QueryId> {
+        let query_id = self.start_findnode_query(
+            QueryType::FindNode(target_node),
+            None,
+            None,
+            QueryCallback::FindNode(callback),
+        )?;
+        if let Some(query) = self.queries.get_mut(query_id) {
+            query.target_mut().shared_responses = Some(shared_responses);
         }
+        Some(query_id)
     }
 
     /// Internal function that starts a query.
-    fn start_findvalue_query(&mut self, target_value: NodeId, callback: mpsc::UnboundedSender<Result<Vec<u8>, FindValueError>>) {
+    fn start_findvalue_query(
+        &mut self,
+        target_value: NodeId,
+        timeout: Option<Duration>,
+        parallelism: Option<usize>,
+        quorum: usize,
+        callback: mpsc::UnboundedSender<Result<Vec<u8>, FindValueError>>,
+    ) {
         let mut target = QueryInfo {
             query_type: QueryType::FindValue(target_value),
             untrusted_enrs: Default::default(),
             distances_to_request: DISTANCES_TO_REQUEST_PER_PEER,
-            callback: QueryCallback::FindValue(callback),
+            callback: QueryCallback::FindValue(ValueSender::with_quorum(callback, quorum)),
+            shared_responses: None,
+            requested_distances: Default::default(),
+            known_target: false,
         };
 
         let target_key: kbucket::Key<NodeId> = target.key();
@@ -554,7 +1272,7 @@ impl Service {
             let mut kbuckets = self.kbuckets.write();
             for closest in kbuckets.closest_values(&target_key) {
                 // Add the known ENR's to the untrusted list
-                target.untrusted_enrs.push(closest.value);
+                target.add_untrusted_enr(closest.value.enr(), self.config.max_untrusted_enrs);
                 // Add the key to the list for the query
                 known_closest_peers.push(closest.key);
             }
@@ -563,14 +1281,20 @@ impl Service {
         if known_closest_peers.is_empty() {
             warn!("No known_closest_peers found. Return empty result without sending query.");
             if let QueryCallback::FindValue(callback) = target.callback {
-                if callback.send(Err(FindValueError::RequestErrorWithEnrs((RequestError::Timeout, vec![])))).is_err() {
+                if callback.send(FindValueError::RequestErrorWithEnrs((RequestError::Timeout, vec![]))).is_err() {
                     warn!("Failed to callback");
                 }
             }
         } else {
-            let query_config = FindNodeQueryConfig::new_from_config(&self.config);
-            self.queries
-                .add_findvalue_query(query_config, target, known_closest_peers);
+            let mut query_config = FindNodeQueryConfig::new_from_config(&self.config);
+            if let Some(parallelism) = parallelism {
+                query_config.parallelism = parallelism;
+            }
+            METRICS.find_value_queries.started.fetch_add(1, Ordering::Relaxed);
+            let query_id =
+                self.queries
+                    .add_findvalue_query(query_config, target, known_closest_peers, timeout);
+            self.prefer_peers(query_id);
         }
     }
 
@@ -580,26 +1304,29 @@ impl Service {
         target_node: NodeId,
         num_nodes: usize,
         predicate: Box<dyn Fn(&Enr) -> bool + Send>,
-        callback: oneshot::Sender<Vec<Enr>>,
+        callback: oneshot::Sender<(Vec<(Enr, u64)>, QueryCompletion)>,
     ) {
         let mut target = QueryInfo {
             query_type: QueryType::FindNode(target_node),
             untrusted_enrs: Default::default(),
             distances_to_request: DISTANCES_TO_REQUEST_PER_PEER,
             callback: QueryCallback::FindNode(callback),
+            shared_responses: None,
+            requested_distances: Default::default(),
+            known_target: false,
         };
 
         let target_key: kbucket::Key<NodeId> = target.key();
 
         // Map the TableEntry to an ENR.
-        let kbucket_predicate = |e: &Enr| predicate(e);
+        let kbucket_predicate = |e: &TableEnr| predicate(&e.enr());
 
         let mut known_closest_peers = Vec::<kbucket::PredicateKey<_>>::new();
         {
             let mut kbuckets = self.kbuckets.write();
             for closest in kbuckets.closest_values_predicate(&target_key, &kbucket_predicate) {
                 // Add the known ENR's to the untrusted list
-                target.untrusted_enrs.push(closest.value.clone());
+                target.add_untrusted_enr(closest.value.enr(), self.config.max_untrusted_enrs);
                 // Add the key to the list for the query
                 known_closest_peers.push(closest.into());
             }
@@ -608,13 +1335,14 @@ impl Service {
         if known_closest_peers.is_empty() {
             warn!("No known_closest_peers found. Return empty result without sending query.");
             if let QueryCallback::FindNode(callback) = target.callback {
-                if callback.send(vec![]).is_err() {
+                if callback.send((vec![], QueryCompletion::Exhausted)).is_err() {
                     warn!("Failed to callback");
                 }
             }
         } else {
             let mut query_config = PredicateQueryConfig::new_from_config(&self.config);
             query_config.num_results = num_nodes;
+            METRICS.find_node_queries.started.fetch_add(1, Ordering::Relaxed);
             self.queries
                 .add_predicate_query(query_config, target, known_closest_peers, predicate);
         }
@@ -625,7 +1353,7 @@ impl Service {
         // check if we know this node id in our routing table
         let key = kbucket::Key::from(*node_id);
         if let kbucket::Entry::Present(entry, _) = self.kbuckets.write().entry(&key) {
-            return Some(entry.value().clone());
+            return Some(entry.value().enr());
         }
         // check the untrusted addresses for ongoing queries
         for query in self.queries.iter() {
@@ -641,6 +1369,41 @@ impl Service {
         None
     }
 
+    /// Whether the inbound TALK requests of a protocol are dispatched.
+    fn allows_talk_protocol(&self, protocol: &[u8]) -> bool {
+        let allowed = match &self.config.talk_protocols_allowed {
+            Some(allowed) => allowed.iter().any(|allowed| allowed.as_slice() == protocol),
+            None => true,
+        };
+        allowed
+            && !self
+                .config
+                .talk_protocols_denied
+                .iter()
+                .any(|denied| denied.as_slice() == protocol)
+    }
+
+    /// Reports a PING from a node outside the routing table, pinging it back to consider adding
+    /// it if probing is enabled and its ENR is known.
+    fn inject_incoming_ping(&mut self, node_address: NodeAddress) {
+        let node_id = node_address.node_id;
+        let enr = self.find_enr(&node_id);
+        self.send_event(Discv5Event::IncomingPing {
+            enr: enr.clone(),
+            from: node_address.socket_addr,
+        });
+
+        if !self.config.probe_incoming_pings || self.incoming_ping_probes.contains_key(&node_id) {
+            return;
+        }
+        if let Some(enr) = enr {
+            if let Ok(contact) = NodeContact::try_from_enr(enr.clone(), self.config.ip_mode) {
+                self.incoming_ping_probes.insert(node_id, enr);
+                self.ping_request(contact, None);
+            }
+        }
+    }
+
     /// Processes an RPC request from a peer. Requests respond to the received socket address,
     /// rather than the IP of the known ENR.
     fn handle_rpc_request(&mut self, node_address: NodeAddress, req: Request) {
@@ -650,6 +1413,20 @@ impl Service {
                 self.send_nodes_response(node_address, id, distances);
             }
             RequestBody::FindValue { key, distances } => {
+                // respond directly if the value is held locally
+                if let Some(value) = self.value_store.get(&key) {
+                    debug!("Sending VALUE response to {}", node_address);
+                    for response in value_responses(id, value.clone()) {
+                        if let Err(e) = self
+                            .handler_send
+                            .send(HandlerIn::Response(node_address.clone(), Box::new(response)))
+                        {
+                            warn!("Failed to send response {}", e)
+                        }
+                    }
+                    return;
+                }
+
                 let nodes_responses = self.prepare_nodes_response(node_address.clone(), id.clone(), distances.clone());
                 let req = FindValueRequest {
                     id,
@@ -664,21 +1441,25 @@ impl Service {
             RequestBody::Ping { enr_seq } => {
                 // check if we need to update the known ENR
                 let mut to_request_enr = None;
+                let mut in_table = true;
                 match self.kbuckets.write().entry(&node_address.node_id.into()) {
                     kbucket::Entry::Present(ref mut entry, _) => {
                         if entry.value().seq() < enr_seq {
-                            let enr = entry.value().clone();
+                            let enr = entry.value().enr();
                             to_request_enr = Some(enr);
                         }
                     }
                     kbucket::Entry::Pending(ref mut entry, _) => {
                         if entry.value().seq() < enr_seq {
-                            let enr = entry.value().clone();
+                            let enr = entry.value().enr();
                             to_request_enr = Some(enr);
                         }
                     }
-                    // don't know of the ENR, request the update
-                    _ => {}
+                    // the node is not in the routing table
+                    _ => in_table = false,
+                }
+                if !in_table {
+                    self.inject_incoming_ping(node_address.clone());
                 }
                 if let Some(enr) = to_request_enr {
                     match NodeContact::try_from_enr(enr, self.config.ip_mode) {
@@ -714,15 +1495,40 @@ impl Service {
                 }
             }
             RequestBody::Talk { protocol, request } => {
+                if !self.allows_talk_protocol(&protocol) {
+                    debug!(
+                        "Not dispatching the TALK request of a disallowed protocol from {}",
+                        node_address
+                    );
+                    if !self.config.drop_disallowed_talk_requests {
+                        send_empty_talk_response(&self.handler_send, id, node_address);
+                    }
+                    return;
+                }
+                let sender = Arc::new(Mutex::new(Some(self.handler_send.clone())));
+                // a request left unanswered is answered with an empty response once timed out
+                self.talk_timeouts
+                    .insert((node_address.clone(), id.clone()), sender.clone());
                 let req = TalkRequest {
                     id,
                     node_address,
                     protocol,
                     body: request,
-                    sender: Some(self.handler_send.clone()),
+                    sender,
+                    answered: self.talk_answered_send.clone(),
                 };
 
-                self.send_event(Discv5Event::TalkRequest(req));
+                // requests of registered protocols are passed to their handler
+                let handler = self.talk_protocols.read().get(req.protocol()).cloned();
+                match handler {
+                    Some(handler) => {
+                        // a request the handler can't accept is answered with an empty response
+                        if let Err(e) = handler.try_send(req) {
+                            warn!("Failed to pass the TALK request to its protocol handler: {}", e);
+                        }
+                    }
+                    None => self.send_event(Discv5Event::TalkRequest(req)),
+                }
             }
             RequestBody::RegisterTopic { .. } => {
                 debug!("Received RegisterTopic request which is unimplemented");
@@ -730,6 +1536,137 @@ impl Service {
             RequestBody::TopicQuery { .. } => {
                 debug!("Received TopicQuery request which is unimplemented");
             }
+            RequestBody::Store { key, value } => {
+                let accepted = match self.store_value(key, value) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        debug!("Rejected STORE request from {}: {}", node_address, e);
+                        false
+                    }
+                };
+                let closer = if accepted {
+                    self.closer_nodes(&key, &node_address.node_id)
+                } else {
+                    Vec::new()
+                };
+
+                let response = Response {
+                    id,
+                    body: ResponseBody::Stored { accepted, closer },
+                };
+                debug!("Sending STORED response to {}", node_address);
+                if let Err(e) = self
+                    .handler_send
+                    .send(HandlerIn::Response(node_address, Box::new(response)))
+                {
+                    warn!("Failed to send response {}", e)
+                }
+            }
+            RequestBody::StoreBatch { entries } => {
+                let accepted = entries
+                    .into_iter()
+                    .map(|(key, value)| match self.store_value(key, value) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            debug!("Rejected STOREBATCH entry from {}: {}", node_address, e);
+                            false
+                        }
+                    })
+                    .collect();
+
+                let response = Response {
+                    id,
+                    body: ResponseBody::StoredBatch { accepted },
+                };
+                debug!("Sending STOREDBATCH response to {}", node_address);
+                if let Err(e) = self
+                    .handler_send
+                    .send(HandlerIn::Response(node_address, Box::new(response)))
+                {
+                    warn!("Failed to send response {}", e)
+                }
+            }
+            RequestBody::RelayInit { initiator, target } => {
+                // only relay on behalf of the node sending the request, and only to nodes we are
+                // connected to
+                let forwarded = if initiator.node_id() != node_address.node_id {
+                    debug!(
+                        "Rejected RELAYINIT request from {} for initiator {}",
+                        node_address,
+                        initiator.node_id()
+                    );
+                    false
+                } else {
+                    match self.connected_contact(&target) {
+                        Some(contact) => {
+                            self.send_rpc_request(ActiveRequest {
+                                contact,
+                                request_body: RequestBody::RelayMsg { initiator },
+                                query_id: None,
+                                callback: None,
+                            });
+                            true
+                        }
+                        None => {
+                            debug!(
+                                "Rejected RELAYINIT request from {} for unknown target {}",
+                                node_address, target
+                            );
+                            false
+                        }
+                    }
+                };
+
+                self.send_relayed_response(node_address, id, forwarded);
+            }
+            RequestBody::RelayMsg { initiator } => {
+                // only contact the initiator when prompted by a relay we are connected to
+                let forwarded = if self.connected_contact(&node_address.node_id).is_none() {
+                    debug!("Rejected RELAYMSG request from unknown relay {}", node_address);
+                    false
+                } else {
+                    match NodeContact::try_from_enr(initiator, self.config.ip_mode) {
+                        Ok(contact) => {
+                            debug!("Contacting relay initiator {}", contact);
+                            self.ping_request(contact, None);
+                            true
+                        }
+                        Err(NonContactable { enr }) => {
+                            debug!("Relay initiator is not contactable {}", enr);
+                            false
+                        }
+                    }
+                };
+
+                self.send_relayed_response(node_address, id, forwarded);
+            }
+        }
+    }
+
+    /// Returns the contact of a node of the routing table we are connected to.
+    fn connected_contact(&self, node_id: &NodeId) -> Option<NodeContact> {
+        let key: kbucket::Key<NodeId> = (*node_id).into();
+        let enr = match self.kbuckets.write().entry(&key) {
+            kbucket::Entry::Present(entry, status) if status.is_connected() => {
+                entry.value().enr()
+            }
+            _ => return None,
+        };
+        NodeContact::try_from_enr(enr, self.config.ip_mode).ok()
+    }
+
+    /// Sends a RELAYED response to a relay request.
+    fn send_relayed_response(&mut self, node_address: NodeAddress, id: RequestId, forwarded: bool) {
+        let response = Response {
+            id,
+            body: ResponseBody::Relayed { forwarded },
+        };
+        debug!("Sending RELAYED response to {}", node_address);
+        if let Err(e) = self
+            .handler_send
+            .send(HandlerIn::Response(node_address, Box::new(response)))
+        {
+            warn!("Failed to send response {}", e)
         }
     }
 
@@ -762,8 +1699,43 @@ impl Service {
 
             let node_id = node_address.node_id;
 
-            match response.body {
-                ResponseBody::Nodes { total, mut nodes } => {
+            // values split over VALUECHUNK responses are handled as a VALUE response once every
+            // chunk arrived
+            let body = match response.body {
+                ResponseBody::ValueChunk { total, index, data } => {
+                    let chunks = self
+                        .active_value_chunks
+                        .entry(id.clone())
+                        .or_insert_with(|| ValueChunks::new(total));
+                    chunks.add(total, index, data);
+                    // stop collecting oversized values early, they are rejected below
+                    let oversized = chunks.size() > self.config.max_value_size;
+                    if !chunks.is_complete() && !oversized {
+                        self.active_requests.insert(id, active_request);
+                        return;
+                    }
+                    let chunks = match self.active_value_chunks.remove(&id) {
+                        Some(chunks) => chunks,
+                        None => return,
+                    };
+                    if !chunks.is_whole() && !oversized {
+                        warn!("Peer {} returned an incomplete value", node_id);
+                        if let Some(query_id) = active_request.query_id {
+                            if let Some(query) = self.queries.get_mut(query_id) {
+                                query.on_failure(&node_id);
+                            }
+                        }
+                        return;
+                    }
+                    ResponseBody::Value {
+                        response: chunks.into_data(),
+                    }
+                }
+                body => body,
+            };
+
+            match body {
+                ResponseBody::Nodes { total, mut nodes, encodings } => {
                     if let Some(CallbackResponse::Value(ref callback)) = active_request.callback {
                         if callback.is_closed() {
                             return;
@@ -803,9 +1775,26 @@ impl Service {
                                 active_request.contact
                             );
                         }
-                        let response = nodes
-                            .pop()
-                            .ok_or(RequestError::InvalidEnr("Peer did not return an ENR"));
+                        let known_enr = active_request.contact.enr();
+                        let response = match nodes.pop() {
+                            None => Err(RequestError::InvalidEnr("Peer did not return an ENR")),
+                            Some(enr) if enr.node_id() != node_id => {
+                                Err(RequestError::InvalidRemoteEnr)
+                            }
+                            // keep the most recent ENR if the peer returned an outdated one
+                            Some(enr) => match known_enr {
+                                Some(known_enr) if known_enr.seq() > enr.seq() => {
+                                    debug!(
+                                        "Peer returned an outdated ENR, seq {} < {}. {}",
+                                        enr.seq(),
+                                        known_enr.seq(),
+                                        active_request.contact
+                                    );
+                                    Ok(known_enr)
+                                }
+                                _ => Ok(enr),
+                            },
+                        };
                         if let Err(e) = callback.send(response) {
                             warn!("Failed to send response in callback {:?}", e)
                         }
@@ -850,11 +1839,22 @@ impl Service {
                         }
                     }
 
+                    // keep the encodings received for a query returning them
+                    let max_encodings = self.config.max_untrusted_enrs;
+                    if let Some(query) = active_request
+                        .query_id
+                        .and_then(|query_id| self.queries.get_mut(query_id))
+                    {
+                        query
+                            .target_mut()
+                            .add_encodings(&nodes, &encodings, max_encodings);
+                    }
+
                     // handle the case that there is more than one response
                     if total > 1 {
                         let mut current_response = self
                             .active_nodes_responses
-                            .remove(&node_id)
+                            .remove(&id)
                             .unwrap_or_default();
 
                         debug!(
@@ -865,14 +1865,17 @@ impl Service {
                         // another response
                         // We allow for implementations to send at a minimum 3 nodes per response.
                         // We allow for the number of nodes to be returned as the maximum we emit.
+                        // Stop awaiting further responses once enough nodes were received.
                         if current_response.count < self.config.max_nodes_response / 3 + 1
                             && (current_response.count as u64) < total
+                            && current_response.received_nodes.len() + nodes.len()
+                                < self.config.max_nodes_response_total
                         {
                             current_response.count += 1;
 
                             current_response.received_nodes.append(&mut nodes);
                             self.active_nodes_responses
-                                .insert(node_id, current_response);
+                                .insert(id.clone(), current_response);
                             self.active_requests.insert(id, active_request);
                             return;
                         }
@@ -884,6 +1887,14 @@ impl Service {
                         nodes = current_response.received_nodes;
                     }
 
+                    if nodes.len() > self.config.max_nodes_response_total {
+                        debug!(
+                            "Nodes response from {} exceeds {} nodes, truncating",
+                            active_request.contact, self.config.max_nodes_response_total
+                        );
+                        nodes.truncate(self.config.max_nodes_response_total);
+                    }
+
                     debug!(
                         "Received a nodes response of len: {}, total: {}, from: {}",
                         nodes.len(),
@@ -894,27 +1905,101 @@ impl Service {
                     // in a later response sends a response with a total of 1, all previous nodes
                     // will be ignored.
                     // ensure any mapping is removed in this rare case
-                    self.active_nodes_responses.remove(&node_id);
+                    self.active_nodes_responses.remove(&id);
 
+                    if let (Some(query_id), RequestBody::FindNode { distances }) =
+                        (active_request.query_id, &active_request.request_body)
+                    {
+                        if let Some(shared_responses) = self
+                            .queries
+                            .get_mut(query_id)
+                            .and_then(|query| query.target().shared_responses.as_ref())
+                        {
+                            shared_responses
+                                .lock()
+                                .insert((node_id, distances.clone()), nodes.clone());
+                        }
+                    }
                     self.discovered(&node_id, nodes, active_request.query_id);
                 }
                 ResponseBody::Value { response } => {
                     // Send the response to the user
                     match active_request.callback {
-                        Some(CallbackResponse::Value(callback)) => {
-                            if let Err(e) = callback.send(Ok(response)) {
+                        Some(CallbackResponse::Value(callback))
+                            if response.len() > self.config.max_value_size =>
+                        {
+                            // Reject the value and treat the peer as failed
+                            warn!("Peer {} returned an oversized value", node_id);
+                            let error = FindValueError::ValueTooLarge {
+                                size: response.len(),
+                                max: self.config.max_value_size,
+                            };
+                            if let Err(e) = callback.send(error) {
                                 warn!("Failed to send callback response {:?}", e)
                             };
 
-                            if let Some(q) = self.queries.get_mut(active_request.query_id.unwrap()) {
-                                q.mark_as_found();
+                            if let Some(query_id) = active_request.query_id {
+                                if let Some(query) = self.queries.get_mut(query_id) {
+                                    query.on_failure(&node_id);
+                                }
+                            }
+                        }
+                        Some(CallbackResponse::Value(callback)) => {
+                            let cached = if self.config.cache_found_values {
+                                Some(response.clone())
+                            } else {
+                                None
+                            };
+                            // identical values returned by several peers are only sent once, when
+                            // the quorum is reached
+                            let sent = match callback.send_value(node_id, response) {
+                                Ok(sent) => sent,
+                                Err(e) => {
+                                    warn!("Failed to send callback response {:?}", e);
+                                    false
+                                }
+                            };
+
+                            if let (true, Some(value), RequestBody::FindValue { key, .. }) =
+                                (sent, cached, &active_request.request_body)
+                            {
+                                if let Err(e) = self.value_store.insert(*key, value) {
+                                    debug!("Failed to cache the value found for {}: {}", key, e);
+                                }
+                            }
+
+                            let query_id = active_request.query_id.unwrap();
+                            if let Some(q) = self.queries.get_mut(query_id) {
+                                if sent {
+                                    q.mark_as_found();
+                                }
                                 self.discovered(&node_id, vec![], active_request.query_id);
                             }
+                            if sent {
+                                // the lookup is over, the requests still in flight are not needed
+                                self.cancel_query_requests(query_id);
+                            }
                         }
                         _ => error!("Invalid callback for response"),
                     }
                 }
                 ResponseBody::Pong { enr_seq, ip, port } => {
+                    self.ping_failures.remove(&node_id);
+                    // the node answered the probe of its PING, consider adding it
+                    if let Some(enr) = self.incoming_ping_probes.remove(&node_id) {
+                        self.connection_updated(
+                            node_id,
+                            ConnectionStatus::Connected(enr, ConnectionDirection::Outgoing),
+                        );
+                    }
+                    // Send the response to the user
+                    if let Some(CallbackResponse::Pong(callback)) = active_request.callback.take() {
+                        let response = PingResponse { enr_seq, ip, port };
+                        if let Err(e) = callback.send(Ok(response)) {
+                            warn!("Failed to send callback response {:?}", e)
+                        };
+                    }
+
                     let socket = SocketAddr::new(ip, port);
                     // perform ENR majority-based update if required.
 
@@ -939,6 +2024,10 @@ impl Service {
                         if let Some(ref mut ip_votes) = self.ip_votes {
                             ip_votes.insert(node_id, socket);
                             let (maybe_ip4_majority, maybe_ip6_majority) = ip_votes.majority();
+                            self.update_external_address_prediction(
+                                maybe_ip4_majority,
+                                maybe_ip6_majority,
+                            );
 
                             let new_ip4 = maybe_ip4_majority.and_then(|majority| {
                                 if Some(majority) != local_ip4_socket {
@@ -970,6 +2059,8 @@ impl Service {
                                             updated = true;
                                             info!("Local UDP ip6 socket updated to: {}", new_ip6);
                                             self.send_event(Discv5Event::SocketUpdated(new_ip6));
+                                            let seq = self.local_enr.read().seq();
+                                            self.send_event(Discv5Event::LocalEnrUpdated { seq });
                                         }
                                         Err(e) => {
                                             warn!("Failed to update local UDP ip6 socket. ip6: {}, error: {:?}", new_ip6, e);
@@ -987,6 +2078,8 @@ impl Service {
                                             updated = true;
                                             info!("Local UDP socket updated to: {}", new_ip4);
                                             self.send_event(Discv5Event::SocketUpdated(new_ip4));
+                                            let seq = self.local_enr.read().seq();
+                                            self.send_event(Discv5Event::LocalEnrUpdated { seq });
                                         }
                                         Err(e) => {
                                             warn!("Failed to update local UDP socket. ip: {}, error: {:?}", new_ip4, e);
@@ -1028,12 +2121,56 @@ impl Service {
                         _ => error!("Invalid callback for response"),
                     }
                 }
+                ResponseBody::Stored { accepted, closer } => {
+                    // Send the response to the user
+                    match active_request.callback {
+                        Some(CallbackResponse::Store(callback)) => {
+                            if let Err(e) = callback.send(Ok((accepted, closer))) {
+                                warn!("Failed to send callback response {:?}", e)
+                            };
+                        }
+                        // Republished values have no callback
+                        None => {
+                            debug!("Republished value accepted: {}", accepted);
+                        }
+                        _ => error!("Invalid callback for response"),
+                    }
+                }
+                ResponseBody::StoredBatch { accepted } => {
+                    // Send the response to the user
+                    match active_request.callback {
+                        Some(CallbackResponse::StoreBatch(callback)) => {
+                            if let Err(e) = callback.send(Ok(accepted)) {
+                                warn!("Failed to send callback response {:?}", e)
+                            };
+                        }
+                        _ => error!("Invalid callback for response"),
+                    }
+                }
+                ResponseBody::Relayed { forwarded } => {
+                    // Send the response to the user
+                    match active_request.callback {
+                        Some(CallbackResponse::Relay(callback)) => {
+                            if let Err(e) = callback.send(Ok(forwarded)) {
+                                warn!("Failed to send callback response {:?}", e)
+                            };
+                        }
+                        // Requests forwarded by a relay have no callback
+                        None => {
+                            debug!("Relayed request forwarded: {}", forwarded);
+                        }
+                        _ => error!("Invalid callback for response"),
+                    }
+                }
                 ResponseBody::Ticket { .. } => {
                     error!("Received a TICKET response. This is unimplemented and should be unreachable.");
                 }
                 ResponseBody::RegisterConfirmation { .. } => {
                     error!("Received a RegisterConfirmation response. This is unimplemented and should be unreachable.");
                 }
+                ResponseBody::ValueChunk { .. } => {
+                    unreachable!("Value chunks are reassembled into a VALUE response")
+                }
             }
         } else {
             warn!(
@@ -1045,25 +2182,82 @@ impl Service {
 
     // Send RPC Requests //
 
+    /// Records the external addresses predicted from the IP votes, reporting each family's
+    /// prediction if it changed.
+    fn update_external_address_prediction(
+        &mut self,
+        ip4: Option<SocketAddrV4>,
+        ip6: Option<SocketAddrV6>,
+    ) {
+        let (previous_ip4, previous_ip6) =
+            std::mem::replace(&mut *self.external_address_prediction.write(), (ip4, ip6));
+        if let Some(socket) = ip4.filter(|_| ip4 != previous_ip4) {
+            self.send_event(Discv5Event::ExternalAddrUpdated(socket.into()));
+        }
+        if let Some(socket) = ip6.filter(|_| ip6 != previous_ip6) {
+            self.send_event(Discv5Event::ExternalAddrUpdated(socket.into()));
+        }
+    }
+
+    /// Returns the NODES response of a peer to the request of a query, if another query of the same
+    /// multi-target lookup already received it.
+    fn shared_response(
+        &mut self,
+        query_id: QueryId,
+        peer: NodeId,
+        request_body: &RequestBody,
+    ) -> Option<Vec<Enr>> {
+        let distances = match request_body {
+            RequestBody::FindNode { distances } => distances,
+            _ => return None,
+        };
+        let query = self.queries.get_mut(query_id)?;
+        let shared_responses = query.target().shared_responses.as_ref()?;
+        shared_responses
+            .lock()
+            .get(&(peer, distances.clone()))
+            .cloned()
+    }
+
     /// Sends a PING request to a node.
     fn send_ping(&mut self, enr: Enr) {
         match NodeContact::try_from_enr(enr, self.config.ip_mode) {
-            Ok(contact) => {
-                let request_body = RequestBody::Ping {
-                    enr_seq: self.local_enr.read().seq(),
-                };
-                let active_request = ActiveRequest {
-                    contact,
-                    request_body,
-                    query_id: None,
-                    callback: None,
-                };
-                self.send_rpc_request(active_request);
-            }
+            Ok(contact) => self.ping_request(contact, None),
             Err(NonContactable { enr }) => error!("Trying to ping a non-contactable peer {}", enr),
         }
     }
 
+    /// Pings the boot nodes to establish a session with any of them, and schedules the next
+    /// attempt with an exponentially increasing backoff.
+    fn contact_boot_nodes(&mut self) {
+        if let Some((_, backoff)) = self.bootstrap {
+            debug!("Contacting {} boot nodes", self.config.boot_nodes.len());
+            for enr in self.config.boot_nodes.clone() {
+                self.send_ping(enr);
+            }
+            let next_backoff = std::cmp::min(backoff * 2, self.config.bootstrap_max_backoff);
+            self.bootstrap = Some((tokio::time::Instant::now() + backoff, next_backoff));
+        }
+    }
+
+    /// Sends a PING request to a node contact, optionally reporting the PONG response.
+    fn ping_request(
+        &mut self,
+        contact: NodeContact,
+        callback: Option<oneshot::Sender<Result<PingResponse, RequestError>>>,
+    ) {
+        let request_body = RequestBody::Ping {
+            enr_seq: self.local_enr.read().seq(),
+        };
+        let active_request = ActiveRequest {
+            contact,
+            request_body,
+            query_id: None,
+            callback: callback.map(CallbackResponse::Pong),
+        };
+        self.send_rpc_request(active_request);
+    }
+
     /// Ping all peers that are connected in the routing table.
     fn ping_connected_peers(&mut self) {
         // maintain the ping interval
@@ -1073,7 +2267,7 @@ impl Service {
                 .iter()
                 .filter_map(|entry| {
                     if entry.status.is_connected() {
-                        Some(entry.node.value.clone())
+                        Some(entry.node.value.enr())
                     } else {
                         None
                     }
@@ -1121,6 +2315,200 @@ impl Service {
         self.send_rpc_request(active_request);
     }
 
+    /// Sends a STORE request to the peer.
+    fn store_request(
+        &mut self,
+        contact: NodeContact,
+        key: NodeId,
+        value: Vec<u8>,
+        callback: Option<StoreSender>,
+    ) {
+        let request_body = RequestBody::Store { key, value };
+
+        let active_request = ActiveRequest {
+            contact,
+            request_body,
+            query_id: None,
+            callback: callback.map(CallbackResponse::Store),
+        };
+        self.send_rpc_request(active_request);
+    }
+
+    /// Stores a value received from a STORE or STOREBATCH request, unless the configured
+    /// validator rejects it.
+    fn store_value(&mut self, key: NodeId, value: Vec<u8>) -> Result<(), FindValueError> {
+        if let Some(validator) = &self.config.store_validator {
+            if !validator(&key, &value) {
+                return Err(FindValueError::ValueRejected);
+            }
+        }
+        self.value_store.insert(key, value)
+    }
+
+    /// Returns the nodes of the routing table closer to `key` than the local node, other than the
+    /// `requester`, on which a value stored under `key` should also be stored.
+    fn closer_nodes(&mut self, key: &NodeId, requester: &NodeId) -> Vec<Enr> {
+        let target = kbucket::Key::from(*key);
+        let local_distance =
+            target.distance(&kbucket::Key::from(self.local_enr.read().node_id()));
+        self.kbuckets
+            .write()
+            .closest_values(&target)
+            .filter(|closest| {
+                closest.key.preimage() != requester && target.distance(&closest.key) < local_distance
+            })
+            .take(MAX_CLOSER_NODES)
+            .map(|closest| closest.value.enr())
+            .collect()
+    }
+
+    /// Sends a STOREBATCH request to the peer.
+    fn store_batch_request(
+        &mut self,
+        contact: NodeContact,
+        entries: Vec<(NodeId, Vec<u8>)>,
+        callback: oneshot::Sender<Result<Vec<bool>, RequestError>>,
+    ) {
+        let request_body = RequestBody::StoreBatch { entries };
+
+        let active_request = ActiveRequest {
+            contact,
+            request_body,
+            query_id: None,
+            callback: Some(CallbackResponse::StoreBatch(callback)),
+        };
+        self.send_rpc_request(active_request);
+    }
+
+    /// Sends a RELAYINIT request to the relay, asking it to prompt the target to contact us.
+    fn relay_init_request(
+        &mut self,
+        relay_contact: NodeContact,
+        target: NodeId,
+        callback: oneshot::Sender<Result<bool, RequestError>>,
+    ) {
+        let request_body = RequestBody::RelayInit {
+            initiator: self.local_enr.read().clone(),
+            target,
+        };
+
+        let active_request = ActiveRequest {
+            contact: relay_contact,
+            request_body,
+            query_id: None,
+            callback: Some(CallbackResponse::Relay(callback)),
+        };
+        self.send_rpc_request(active_request);
+    }
+
+    /// Registers a value originated by this node, to be republished until the configured
+    /// `value_ttl` elapses. Publishing an existing key replaces its value and restarts its ttl.
+    fn publish_value(&mut self, key: NodeId, value: Vec<u8>) {
+        let expires = tokio::time::Instant::now() + self.config.value_ttl;
+        self.published_values.insert(key, (value, expires));
+        self.schedule_republish(key);
+    }
+
+    /// Queues an originated value to be republished after the (jittered) republish interval.
+    fn schedule_republish(&mut self, key: NodeId) {
+        let interval = jittered(self.config.value_republish_interval, self.config.timer_jitter);
+        self.values_to_republish.insert_at(key, interval);
+    }
+
+    /// Stores an originated value again on the closest peers to its key, by running a
+    /// `FIND_NODE` query and sending a STORE request to the peers found. Values whose ttl has
+    /// elapsed are no longer originated and are dropped instead.
+    fn republish_value(&mut self, key: NodeId) {
+        let value = match self.published_values.get(&key) {
+            Some((_, expires)) if *expires <= tokio::time::Instant::now() => {
+                debug!("Value for key {} expired, no longer republishing", key);
+                self.published_values.remove(&key);
+                return;
+            }
+            Some((value, _)) => value.clone(),
+            None => return,
+        };
+
+        debug!("Republishing value for key {}", key);
+        self.schedule_republish(key);
+        let num_results = self.config.value_replication_factor.max(MAX_NODES_PER_BUCKET);
+        self.start_findnode_query(
+            QueryType::FindNode(key),
+            None,
+            Some(num_results),
+            QueryCallback::Republish(value),
+        );
+    }
+
+    /// Pings the entries of the routing table that were contacted least recently, to find out
+    /// whether they are still alive.
+    fn check_liveness(&mut self) {
+        let mut entries: Vec<(Instant, Enr)> = self
+            .kbuckets
+            .read()
+            .buckets_iter()
+            .flat_map(|bucket| bucket.iter())
+            .map(|node| (node.last_seen, node.value.enr()))
+            .collect();
+        entries.sort_unstable_by_key(|(last_seen, _)| *last_seen);
+        for (_, enr) in entries.into_iter().take(LIVENESS_CHECK_BATCH) {
+            self.send_ping(enr);
+        }
+    }
+
+    /// Runs a `FIND_NODE` query for a random target in the sparsest bucket of the routing table,
+    /// unless the previous refresh query is still in progress, and schedules the next refresh.
+    fn refresh_table(&mut self) {
+        self.table_refresh = self.config.table_refresh_interval.map(|period| {
+            tokio::time::Instant::now() + jittered(period, self.config.timer_jitter)
+        });
+        if self
+            .queries
+            .iter()
+            .any(|query| matches!(query.target().callback, QueryCallback::TableRefresh))
+        {
+            debug!("Previous table refresh still in progress, skipping the refresh");
+            return;
+        }
+        let target = match self.table_refresh_target() {
+            Some(target) => target,
+            None => {
+                debug!("Routing table empty, skipping the refresh");
+                return;
+            }
+        };
+        debug!("Refreshing the routing table, target: {}", target);
+        self.start_findnode_query(
+            QueryType::FindNode(target),
+            None,
+            None,
+            QueryCallback::TableRefresh,
+        );
+    }
+
+    /// Picks a random target in the bucket with the fewest entries, among the buckets from the
+    /// closest non-empty bucket to the farthest one. Closer buckets can't be populated as there
+    /// are hardly any nodes that close. Returns `None` if the routing table is empty.
+    fn table_refresh_target(&self) -> Option<NodeId> {
+        let entries: Vec<usize> = self
+            .kbuckets
+            .read()
+            .buckets_iter()
+            .map(|bucket| bucket.num_entries())
+            .collect();
+        let closest = entries.iter().position(|count| *count > 0)?;
+        let fewest = entries[closest..].iter().min()?;
+        let sparsest: Vec<usize> = (closest..entries.len())
+            .filter(|index| entries[*index] == *fewest)
+            .collect();
+        let index = sparsest[rand::random::<usize>() % sparsest.len()];
+        // the bucket at index `i` holds the nodes at log2 distance `i + 1`
+        Some(random_node_id_at_distance(
+            &self.local_enr.read().node_id(),
+            index as u64 + 1,
+        ))
+    }
+
     /// Sends a NODES response, given a list of found ENR's. This function splits the nodes up
     /// into multiple responses to ensure the response stays below the maximum packet size.
     fn send_nodes_response(
@@ -1150,7 +2538,7 @@ impl Service {
                 .into_iter()
                 .filter_map(|entry| {
                     if entry.node.key.preimage() != &node_address.node_id {
-                        Some(entry.node.value.clone())
+                        Some(entry.node.value.enr())
                     } else {
                         None
                     }
@@ -1160,6 +2548,10 @@ impl Service {
             }
         }
 
+        if let Some(max) = self.config.max_enrs_per_response {
+            nodes_to_send.truncate(max);
+        }
+
         // if there are no nodes, send an empty response
         if nodes_to_send.is_empty() {
             let response = Response {
@@ -1167,6 +2559,7 @@ impl Service {
                 body: ResponseBody::Nodes {
                     total: 1u64,
                     nodes: Vec::new(),
+                    encodings: HashMap::new(),
                 },
             };
             trace!(
@@ -1181,48 +2574,17 @@ impl Service {
             }
         } else {
             // build the NODES response
-            let mut to_send_nodes: Vec<Vec<Enr>> = Vec::new();
-            let mut total_size = 0;
-            let mut rpc_index = 0;
-            to_send_nodes.push(Vec::new());
-            for enr in nodes_to_send.into_iter() {
-                let entry_size = rlp::encode(&enr).len();
-                // Responses assume that a session is established. Thus, on top of the encoded
-                // ENR's the packet should be a regular message. A regular message has an IV (16
-                // bytes), and a header of 55 bytes. The find-nodes RPC requires 16 bytes for the ID and the
-                // `total` field. Also there is a 16 byte HMAC for encryption and an extra byte for
-                // RLP encoding.
-                //
-                // We could also be responding via an autheader which can take up to 282 bytes in its
-                // header.
-                // As most messages will be normal messages we will try and pack as many ENR's we
-                // can in and drop the response packet if a user requests an auth message of a very
-                // packed response.
-                //
-                // The estimated total overhead for a regular message is therefore 104 bytes.
-                if entry_size + total_size < MAX_PACKET_SIZE - 104 {
-                    total_size += entry_size;
-                    trace!(
-                        "Adding ENR {}, size {}, total size {}",
-                        enr,
-                        entry_size,
-                        total_size
-                    );
-                    to_send_nodes[rpc_index].push(enr);
-                } else {
-                    total_size = entry_size;
-                    to_send_nodes.push(vec![enr]);
-                    rpc_index += 1;
-                }
-            }
+            let to_send_nodes = pack_nodes(nodes_to_send, self.config.max_packet_size);
+            let total = to_send_nodes.len() as u64;
 
             let responses: Vec<Response> = to_send_nodes
                 .into_iter()
                 .map(|nodes| Response {
                     id: rpc_id.clone(),
                     body: ResponseBody::Nodes {
-                        total: (rpc_index + 1) as u64,
+                        total,
                         nodes,
+                        encodings: HashMap::new(),
                     },
                 })
                 .collect();
@@ -1272,7 +2634,7 @@ impl Service {
                 .into_iter()
                 .filter_map(|entry| {
                     if entry.node.key.preimage() != &node_address.node_id {
-                        Some(entry.node.value.clone())
+                        Some(entry.node.value.enr())
                     } else {
                         None
                     }
@@ -1282,6 +2644,10 @@ impl Service {
             }
         }
 
+        if let Some(max) = self.config.max_enrs_per_response {
+            nodes_to_send.truncate(max);
+        }
+
         // if there are no nodes, send an empty response
         if nodes_to_send.is_empty() {
             let response = Response {
@@ -1289,6 +2655,7 @@ impl Service {
                 body: ResponseBody::Nodes {
                     total: 1u64,
                     nodes: Vec::new(),
+                    encodings: HashMap::new(),
                 },
             };
             trace!(
@@ -1298,48 +2665,17 @@ impl Service {
             return vec![HandlerIn::Response(node_address, Box::new(response))]
         } else {
             // build the NODES response
-            let mut to_send_nodes: Vec<Vec<Enr>> = Vec::new();
-            let mut total_size = 0;
-            let mut rpc_index = 0;
-            to_send_nodes.push(Vec::new());
-            for enr in nodes_to_send.into_iter() {
-                let entry_size = rlp::encode(&enr).len();
-                // Responses assume that a session is established. Thus, on top of the encoded
-                // ENR's the packet should be a regular message. A regular message has an IV (16
-                // bytes), and a header of 55 bytes. The find-nodes RPC requires 16 bytes for the ID and the
-                // `total` field. Also there is a 16 byte HMAC for encryption and an extra byte for
-                // RLP encoding.
-                //
-                // We could also be responding via an autheader which can take up to 282 bytes in its
-                // header.
-                // As most messages will be normal messages we will try and pack as many ENR's we
-                // can in and drop the response packet if a user requests an auth message of a very
-                // packed response.
-                //
-                // The estimated total overhead for a regular message is therefore 104 bytes.
-                if entry_size + total_size < MAX_PACKET_SIZE - 104 {
-                    total_size += entry_size;
-                    trace!(
-                        "Adding ENR {}, size {}, total size {}",
-                        enr,
-                        entry_size,
-                        total_size
-                    );
-                    to_send_nodes[rpc_index].push(enr);
-                } else {
-                    total_size = entry_size;
-                    to_send_nodes.push(vec![enr]);
-                    rpc_index += 1;
-                }
-            }
+            let to_send_nodes = pack_nodes(nodes_to_send, self.config.max_packet_size);
+            let total = to_send_nodes.len() as u64;
 
             let responses: Vec<Response> = to_send_nodes
                 .into_iter()
                 .map(|nodes| Response {
                     id: rpc_id.clone(),
                     body: ResponseBody::Nodes {
-                        total: (rpc_index + 1) as u64,
+                        total,
                         nodes,
+                        encodings: HashMap::new(),
                     },
                 })
                 .collect();
@@ -1358,6 +2694,17 @@ impl Service {
         return_peer: NodeId,
         request_body: RequestBody,
     ) {
+        // reuse the response of the peer received by another query of a multi-target lookup
+        if let Some(nodes) = self.shared_response(query_id, return_peer, &request_body) {
+            trace!(
+                "Query {} reuses the NODES response of {}",
+                *query_id,
+                return_peer
+            );
+            self.discovered(&return_peer, nodes, Some(query_id));
+            return;
+        }
+
         // find the ENR associated with the query
         if let Some(enr) = self.find_enr(&return_peer) {
             let mut callback = None;
@@ -1400,8 +2747,12 @@ impl Service {
 
     /// Sends generic RPC requests. Each request gets added to known outputs, awaiting a response.
     fn send_rpc_request(&mut self, active_request: ActiveRequest) {
-        // Generate a random rpc_id which is matched per node id
-        let id = RequestId::random();
+        // Generate a random rpc_id which is matched per node id, distinct from the ids of the
+        // requests awaiting a response
+        let mut id = RequestId::random();
+        while self.active_requests.contains_key(&id) {
+            id = RequestId::random();
+        }
         let request: Request = Request {
             id: id.clone(),
             body: active_request.request_body.clone(),
@@ -1448,16 +2799,23 @@ impl Service {
                 // If the ENR exists in the routing table and the discovered ENR has a greater
                 // sequence number, perform some filter checks before updating the enr.
 
-                let must_update_enr = match self.kbuckets.write().entry(&key) {
-                    kbucket::Entry::Present(entry, _) => entry.value().seq() < enr.seq(),
-                    kbucket::Entry::Pending(mut entry, _) => entry.value().seq() < enr.seq(),
-                    _ => false,
-                };
+                // nodes failing the ENR filter remain in the query but not in the table
+                let must_update_enr = self.passes_enr_filter(enr)
+                    && match self.kbuckets.write().entry(&key) {
+                        kbucket::Entry::Present(entry, _) => entry.value().seq() < enr.seq(),
+                        kbucket::Entry::Pending(mut entry, _) => entry.value().seq() < enr.seq(),
+                        _ => false,
+                    };
 
                 if must_update_enr {
-                    if let UpdateResult::Failed(reason) =
-                        self.kbuckets.write().update_node(&key, enr.clone(), None)
-                    {
+                    let was_present = self.is_in_table(&key);
+                    let update = self.kbuckets.write().update_node(
+                        &key,
+                        TableEnr::new(enr.clone(), self.config.compact_enr_storage),
+                        None,
+                    );
+                    self.report_node_update(&key, was_present, &update);
+                    if let UpdateResult::Failed(reason) = update {
                         self.peers_to_ping.remove(&enr.node_id());
                         debug!(
                             "Failed to update discovered ENR. Node: {}, Reason: {:?}",
@@ -1483,17 +2841,21 @@ impl Service {
             if let Some(query) = self.queries.get_mut(query_id) {
                 let mut peer_count = 0;
                 for enr_ref in enrs.iter() {
-                    if !query
+                    query
                         .target_mut()
-                        .untrusted_enrs
-                        .iter()
-                        .any(|e| e.node_id() == enr_ref.node_id())
-                    {
-                        query.target_mut().untrusted_enrs.push(enr_ref.clone());
-                    }
+                        .add_untrusted_enr(enr_ref.clone(), self.config.max_untrusted_enrs);
                     peer_count += 1;
                 }
                 debug!("{} peers found for query id {:?}", peer_count, query_id);
+                if let QueryCallback::FindNodeStream { sender, seen } =
+                    &mut query.target_mut().callback
+                {
+                    for enr in enrs.iter() {
+                        if seen.insert(enr.node_id()) && sender.send(enr.clone()).is_err() {
+                            debug!("Stream dropped for query {:?}", query_id);
+                        }
+                    }
+                }
                 query.on_success(source, &enrs)
             } else {
                 debug!("Response returned for ended query {:?}", query_id)
@@ -1501,6 +2863,15 @@ impl Service {
         }
     }
 
+    /// Whether the ENR passes the configured ENR filter, if any, and may be inserted into the
+    /// routing table.
+    fn passes_enr_filter(&self, enr: &Enr) -> bool {
+        match &self.config.enr_filter {
+            Some(filter) => filter(enr),
+            None => true,
+        }
+    }
+
     /// Update the connection status of a node in the routing table.
     /// This tracks whether or not we should be pinging peers. Disconnected peers are removed from
     /// the queue and newly added peers to the routing table are added to the queue.
@@ -1510,14 +2881,23 @@ impl Service {
         let mut event_to_send = None;
 
         let key = kbucket::Key::from(node_id);
+        let was_present = self.is_in_table(&key);
         match new_status {
+            ConnectionStatus::Connected(enr, _) if !self.passes_enr_filter(&enr) => {
+                debug!("Connected node rejected by the ENR filter: {}", node_id);
+            }
             ConnectionStatus::Connected(enr, direction) => {
                 // attempt to update or insert the new ENR.
                 let status = NodeStatus {
                     state: ConnectionState::Connected,
                     direction,
                 };
-                match self.kbuckets.write().insert_or_update(&key, enr, status) {
+                let insert_result = self.kbuckets.write().insert_or_update(
+                    &key,
+                    TableEnr::new(enr, self.config.compact_enr_storage),
+                    status,
+                );
+                match insert_result {
                     InsertResult::Inserted => {
                         // We added this peer to the table
                         debug!("New connected node added to routing table: {}", node_id);
@@ -1542,20 +2922,29 @@ impl Service {
                             debug!("Node promoted to connected: {}", node_id);
                             self.peers_to_ping.insert(node_id);
                         }
+                        event_to_send = Some(Discv5Event::NodeUpdated { node_id });
                     }
-                    InsertResult::ValueUpdated | InsertResult::UpdatedPending => {}
+                    InsertResult::ValueUpdated => {
+                        event_to_send = Some(Discv5Event::NodeUpdated { node_id });
+                    }
+                    InsertResult::UpdatedPending | InsertResult::NotModified => {}
                     InsertResult::Failed(reason) => {
                         self.peers_to_ping.remove(&node_id);
                         trace!("Could not insert node: {}, reason: {:?}", node_id, reason);
+                        if was_present && !self.is_in_table(&key) {
+                            event_to_send = Some(Discv5Event::NodeEvicted { node_id });
+                        }
                     }
                 }
             }
             ConnectionStatus::PongReceived(enr) => {
-                match self
-                    .kbuckets
-                    .write()
-                    .update_node(&key, enr, Some(ConnectionState::Connected))
-                {
+                let update = self.kbuckets.write().update_node(
+                    &key,
+                    TableEnr::new(enr, self.config.compact_enr_storage),
+                    Some(ConnectionState::Connected),
+                );
+                self.report_node_update(&key, was_present, &update);
+                match update {
                     UpdateResult::Failed(reason) => {
                         self.peers_to_ping.remove(&node_id);
                         debug!(
@@ -1570,11 +2959,13 @@ impl Service {
             }
             ConnectionStatus::Disconnected => {
                 // If the node has disconnected, remove any ping timer for the node.
-                match self.kbuckets.write().update_node_status(
+                let update = self.kbuckets.write().update_node_status(
                     &key,
                     ConnectionState::Disconnected,
                     None,
-                ) {
+                );
+                self.report_node_update(&key, was_present, &update);
+                match update {
                     UpdateResult::Failed(reason) => match reason {
                         FailureReason::KeyNonExistant => {}
                         others => {
@@ -1604,7 +2995,7 @@ impl Service {
                     self.kbuckets.write().entry(&node_key)
                 {
                     // NOTE: We don't check the status of this peer. We try and ping outdated peers.
-                    Some(entry.value().clone())
+                    Some(entry.value().enr())
                 } else {
                     None
                 }
@@ -1615,6 +3006,36 @@ impl Service {
         }
     }
 
+    /// Returns whether a node is held in the routing table.
+    fn is_in_table(&self, key: &kbucket::Key<NodeId>) -> bool {
+        matches!(
+            self.kbuckets.write().entry(key),
+            kbucket::Entry::Present(_, _)
+        )
+    }
+
+    /// Reports the update of a node's entry in the routing table on the event stream, unless the
+    /// update left the table unchanged. `was_present` is whether the node was held in the table
+    /// before the update.
+    fn report_node_update(
+        &mut self,
+        key: &kbucket::Key<NodeId>,
+        was_present: bool,
+        update: &UpdateResult,
+    ) {
+        let node_id = *key.preimage();
+        let event = match update {
+            UpdateResult::Updated | UpdateResult::UpdatedAndPromoted if was_present => {
+                Discv5Event::NodeUpdated { node_id }
+            }
+            UpdateResult::Failed(_) if was_present && !self.is_in_table(key) => {
+                Discv5Event::NodeEvicted { node_id }
+            }
+            _ => return,
+        };
+        self.send_event(event);
+    }
+
     /// The equivalent of libp2p `inject_connected()` for a udp session. We have no stream, but a
     /// session key-pair has been negotiated.
     fn inject_session_established(&mut self, enr: Enr, direction: ConnectionDirection) {
@@ -1631,10 +3052,30 @@ impl Service {
         self.connection_updated(node_id, ConnectionStatus::Connected(enr, direction));
     }
 
+    /// Drops the requests of a query still awaiting a response, releasing their callbacks.
+    /// Responses arriving for them later are ignored.
+    fn cancel_query_requests(&mut self, query_id: QueryId) {
+        let mut cancelled = Vec::new();
+        self.active_requests.retain(|id, request| {
+            if request.query_id == Some(query_id) {
+                cancelled.push((id.clone(), request.contact.node_id()));
+                false
+            } else {
+                true
+            }
+        });
+        for (id, node_id) in cancelled {
+            trace!("Cancelling request of query {} to {}", *query_id, node_id);
+            self.active_nodes_responses.remove(&id);
+            self.active_value_chunks.remove(&id);
+        }
+    }
+
     /// A session could not be established or an RPC request timed-out (after a few retries, if
     /// specified).
     fn rpc_failure(&mut self, id: RequestId, error: RequestError) {
         trace!("RPC Error removing request. Reason: {:?}, id {}", error, id);
+        self.active_value_chunks.remove(&id);
         if let Some(active_request) = self.active_requests.remove(&id) {
             // If this is initiated by the user, return an error on the callback. All callbacks
             // support a request error.
@@ -1645,6 +3086,12 @@ impl Service {
                         .unwrap_or_else(|_| debug!("Couldn't send TALK error response to user"));
                     return;
                 }
+                Some(CallbackResponse::Pong(callback)) => {
+                    // return the error, the node is informed of the failure below
+                    callback
+                        .send(Err(error.clone()))
+                        .unwrap_or_else(|_| debug!("Couldn't send PING error response to user"));
+                }
                 Some(CallbackResponse::Talk(callback)) => {
                     // return the error
                     callback
@@ -1653,10 +3100,30 @@ impl Service {
                     return;
                 }
                 Some(CallbackResponse::Value(callback)) => {
-                    // return the error
+                    // return the error, the query is informed of the failure below
                     callback
-                        .send(Err(FindValueError::RequestError(error)))
+                        .send(FindValueError::RequestError(error.clone()))
                         .unwrap_or_else(|_| debug!("Couldn't send VALUE error response to user"));
+                }
+                Some(CallbackResponse::Store(callback)) => {
+                    // return the error
+                    callback
+                        .send(Err(error))
+                        .unwrap_or_else(|_| debug!("Couldn't send STORE error response to user"));
+                    return;
+                }
+                Some(CallbackResponse::StoreBatch(callback)) => {
+                    // return the error
+                    callback.send(Err(error)).unwrap_or_else(|_| {
+                        debug!("Couldn't send STOREBATCH error response to user")
+                    });
+                    return;
+                }
+                Some(CallbackResponse::Relay(callback)) => {
+                    // return the error
+                    callback
+                        .send(Err(error))
+                        .unwrap_or_else(|_| debug!("Couldn't send RELAYED error response to user"));
                     return;
                 }
                 None => {
@@ -1669,7 +3136,7 @@ impl Service {
                 // if a failed FindNodes request, ensure we haven't partially received packets. If
                 // so, process the partially found nodes
                 RequestBody::FindNode { .. } => {
-                    if let Some(nodes_response) = self.active_nodes_responses.remove(&node_id) {
+                    if let Some(nodes_response) = self.active_nodes_responses.remove(&id) {
                         if !nodes_response.received_nodes.is_empty() {
                             warn!(
                                 "NODES Response failed, but was partially processed from: {}",
@@ -1717,14 +3184,48 @@ impl Service {
                 }
             }
 
+            self.kbuckets.write().record_failure(&node_id.into());
+            if let RequestBody::Ping { .. } = active_request.request_body {
+                self.incoming_ping_probes.remove(&node_id);
+                if self.ping_failed(node_id) {
+                    return;
+                }
+            }
             self.connection_updated(node_id, ConnectionStatus::Disconnected);
         }
     }
 
+    /// Records a failed PING to a node, evicting it from the routing table once it failed
+    /// `max_ping_failures` consecutive PINGs, if liveness checks are enabled. Pinned nodes are
+    /// kept, flagged as disconnected. Returns whether the node was evicted.
+    fn ping_failed(&mut self, node_id: NodeId) -> bool {
+        if self.liveness_check.is_none() {
+            return false;
+        }
+        let failures = self.ping_failures.entry(node_id).or_default();
+        *failures += 1;
+        if *failures < self.config.max_ping_failures {
+            return false;
+        }
+        self.ping_failures.remove(&node_id);
+        let key = kbucket::Key::from(node_id);
+        if self.kbuckets.read().is_pinned(&key) {
+            debug!("Keeping the pinned node failing to respond to PINGs: {}", node_id);
+            return false;
+        }
+        self.peers_to_ping.remove(&node_id);
+        if !self.kbuckets.write().remove(&key) {
+            return false;
+        }
+        debug!("Node evicted after failing to respond to PINGs: {}", node_id);
+        self.send_event(Discv5Event::NodeEvicted { node_id });
+        true
+    }
+
     /// A future that maintains the routing table and inserts nodes when required. This returns the
     /// `Discv5Event::NodeInserted` variant if a new node has been inserted into the routing table.
     async fn bucket_maintenance_poll(
-        kbuckets: &Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+        kbuckets: &Arc<RwLock<KBucketsTable<NodeId, TableEnr>>>,
     ) -> Discv5Event {
         future::poll_fn(move |_cx| {
             // Drain applied pending entries from the routing table.
@@ -1740,6 +3241,26 @@ impl Service {
         .await
     }
 
+    /// A future that resolves once the query timing out first has reached its timeout, waking
+    /// the service to report it.
+    async fn query_timeout_wait(deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => future::pending().await,
+        }
+    }
+
+    /// A future that resolves on the next tick of an optional interval, or never if it is
+    /// disabled.
+    async fn interval_wait(interval: &mut Option<tokio::time::Interval>) {
+        match interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+
     /// A future the maintains active queries. This returns completed and timed out queries, as
     /// well as queries which need to be driven further with extra requests.
     async fn query_event_poll(queries: &mut QueryPool<QueryInfo, NodeId, Enr>) -> QueryEvent {
@@ -1747,7 +3268,7 @@ impl Service {
             QueryPoolState::Finished(query) => Poll::Ready(QueryEvent::Finished(Box::new(query))),
             QueryPoolState::Waiting(Some((query, return_peer))) => {
                 let node_id = return_peer;
-                let request_body = query.target().rpc_request(return_peer);
+                let request_body = query.target_mut().rpc_request(return_peer);
                 Poll::Ready(QueryEvent::Waiting(query.id(), node_id, request_body))
             }
             QueryPoolState::Timeout(query) => {
@@ -1763,8 +3284,9 @@ impl Service {
 /// The result of the `query_event_poll` indicating an action is required to further progress an
 /// active query.
 enum QueryEvent {
-    /// The query is waiting for a peer to be contacted.
-    Waiting(QueryId, NodeId, RequestBody),
+    /// The query is waiting for a peer to be contacted. Contains an error if the request for
+    /// the peer could not be built.
+    Waiting(QueryId, NodeId, Result<RequestBody, QueryError>),
     /// The query has timed out, possible returning peers.
     TimedOut(Box<crate::query_pool::Query<QueryInfo, NodeId, Enr>>),
     /// The query has completed successfully.
@@ -1773,8 +3295,26 @@ enum QueryEvent {
 
 /// The types of queries that can be made.
 pub enum QueryKind {
-    /// A FindNode query. Searches for peers that are closest to a particular target.
-    FindNode { target_node: NodeId },
+    /// A FindNode query. Searches for peers that are closest to a particular target. The
+    /// `timeout` overrides the configured query timeout and `num_results` the number of closest
+    /// peers returned, if set.
+    FindNode {
+        target_node: NodeId,
+        timeout: Option<Duration>,
+        num_results: Option<usize>,
+    },
+    /// A FindNode query of a multi-target lookup, which reuses the NODES responses received by
+    /// the other queries of the lookup instead of requesting them again.
+    FindNodeShared {
+        target_node: NodeId,
+        shared_responses: SharedResponses,
+    },
+    /// A FindNode query which requests an explicit set of distances from each peer, rather than
+    /// the distances surrounding the target.
+    FindNodeAt {
+        target_node: NodeId,
+        distances: Vec<u64>,
+    },
     /// A predicate query. Searches for peers that are close to a target but filtered by a specific
     /// predicate and limited by a target peer count.
     Predicate {
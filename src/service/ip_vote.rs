@@ -7,9 +7,14 @@ use std::{
 };
 
 /// A collection of IP:Ports for our node reported from external peers.
+///
+/// IPv4 and IPv6 votes are tallied independently, such that a dual-stack peer can vote for
+/// both of our addresses.
 pub(crate) struct IpVote {
-    /// The current collection of IP:Port votes.
-    votes: HashMap<NodeId, (SocketAddr, Instant)>,
+    /// The current collection of IPv4 IP:Port votes.
+    ip4_votes: HashMap<NodeId, (SocketAddrV4, Instant)>,
+    /// The current collection of IPv6 IP:Port votes.
+    ip6_votes: HashMap<NodeId, (SocketAddrV6, Instant)>,
     /// The minimum number of votes required before an IP/PORT is accepted.
     minimum_threshold: usize,
     /// The time votes remain valid.
@@ -23,33 +28,43 @@ impl IpVote {
             panic!("Setting enr_peer_update_min to a value less than 2 will cause issues with discovery with peers behind NAT");
         }
         IpVote {
-            votes: HashMap::new(),
+            ip4_votes: HashMap::new(),
+            ip6_votes: HashMap::new(),
             minimum_threshold,
             vote_duration,
         }
     }
 
     pub fn insert(&mut self, key: NodeId, socket: impl std::convert::Into<SocketAddr>) {
-        self.votes
-            .insert(key, (socket.into(), Instant::now() + self.vote_duration));
+        let expires = Instant::now() + self.vote_duration;
+        match socket.into() {
+            SocketAddr::V4(socket) => {
+                self.ip4_votes.insert(key, (socket, expires));
+            }
+            SocketAddr::V6(socket) => {
+                self.ip6_votes.insert(key, (socket, expires));
+            }
+        }
     }
 
-    /// Returns the majority `SocketAddr` if it exists. If there are not enough votes to meet the threshold this returns None.
+    /// Returns the majority `SocketAddr` of each IP family if it exists. If there are not enough
+    /// votes of a family to meet the threshold, None is returned for that family.
     pub fn majority(&mut self) -> (Option<SocketAddrV4>, Option<SocketAddrV6>) {
         // remove any expired votes
         let instant = Instant::now();
-        self.votes.retain(|_, v| v.1 > instant);
+        self.ip4_votes.retain(|_, v| v.1 > instant);
+        self.ip6_votes.retain(|_, v| v.1 > instant);
 
         // count votes, take majority
+        // NOTE: here we depend on addresses being already cleaned up. No mapped or compat
+        // addresses should be present. This is done in the codec.
         let mut ip4_count: FnvHashMap<SocketAddrV4, usize> = FnvHashMap::default();
+        for (socket, _) in self.ip4_votes.values() {
+            *ip4_count.entry(*socket).or_insert_with(|| 0) += 1;
+        }
         let mut ip6_count: FnvHashMap<SocketAddrV6, usize> = FnvHashMap::default();
-        for (socket, _) in self.votes.values() {
-            // NOTE: here we depend on addresses being already cleaned up. No mapped or compat
-            // addresses should be present. This is done in the codec.
-            match socket {
-                SocketAddr::V4(socket) => *ip4_count.entry(*socket).or_insert_with(|| 0) += 1,
-                SocketAddr::V6(socket) => *ip6_count.entry(*socket).or_insert_with(|| 0) += 1,
-            }
+        for (socket, _) in self.ip6_votes.values() {
+            *ip6_count.entry(*socket).or_insert_with(|| 0) += 1;
         }
 
         // find the maximum socket addr
@@ -67,7 +82,7 @@ fn majority<K>(iter: impl Iterator<Item = (K, usize)>, threshold: &usize) -> Opt
 
 #[cfg(test)]
 mod tests {
-    use super::{Duration, IpVote, NodeId, SocketAddrV4};
+    use super::{Duration, IpVote, NodeId, SocketAddrV4, SocketAddrV6};
 
     #[test]
     fn test_three_way_vote_draw() {
@@ -120,4 +135,22 @@ mod tests {
 
         assert_eq!(votes.majority(), (None, None));
     }
+
+    #[test]
+    fn test_dual_stack_votes() {
+        let mut votes = IpVote::new(2, Duration::from_secs(10));
+        let socket_4 = SocketAddrV4::new("127.0.0.1".parse().unwrap(), 1);
+        let socket_6 = SocketAddrV6::new("::1".parse().unwrap(), 1, 0, 0);
+        let node_1 = NodeId::random();
+        let node_2 = NodeId::random();
+
+        // the same peers vote for both families
+        votes.insert(node_1, socket_4);
+        votes.insert(node_1, socket_6);
+        votes.insert(node_2, socket_4);
+        assert_eq!(votes.majority(), (Some(socket_4), None));
+
+        votes.insert(node_2, socket_6);
+        assert_eq!(votes.majority(), (Some(socket_4), Some(socket_6)));
+    }
 }
@@ -1,8 +1,20 @@
-use crate::{kbucket::Key, rpc::RequestBody, Enr, RequestError};
-use enr::{k256::sha2::digest::generic_array::GenericArray, NodeId};
-use smallvec::SmallVec;
-use tokio::sync::{mpsc, oneshot};
 use crate::error::FindValueError;
+use crate::{kbucket::Key, query_pool::TargetKey, rpc::RequestBody, Enr, QueryError};
+use enr::{
+    k256::sha2::{digest::generic_array::GenericArray, Digest, Sha256},
+    NodeId,
+};
+use parking_lot::Mutex;
+use smallvec::SmallVec;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{
+    mpsc::{self, error::SendError},
+    oneshot,
+};
 
 /// Information about a query.
 #[derive(Debug)]
@@ -19,43 +31,264 @@ pub struct QueryInfo {
     /// The number of distances we request for each peer.
     /// NOTE: This must not be larger than 127.
     pub distances_to_request: usize,
+
+    /// The NODES responses shared with the other queries of a multi-target lookup, if any.
+    pub shared_responses: Option<SharedResponses>,
+
+    /// The distances already requested from each peer, which are not requested from it again.
+    pub requested_distances: HashMap<NodeId, HashSet<u64>>,
+
+    /// Whether the query only requests the ENR of its target, a node of the routing table. If
+    /// the target doesn't respond, it is looked up iteratively instead.
+    pub known_target: bool,
 }
 
+/// The NODES responses received by the queries of a multi-target lookup, by peer and requested
+/// distances. Queries for nearby targets request the same distances from the peers they have in
+/// common, which are then only requested once.
+pub type SharedResponses = Arc<Mutex<HashMap<(NodeId, Vec<u64>), Vec<Enr>>>>;
+
 /// Additional information about the query.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum QueryType {
     /// The user requested a `FIND_NODE` query to be performed. It should be reported when finished.
     FindNode(NodeId),
     /// The user requested a `FIND_NODE` query to be performed. It should be reported when finished.
     FindValue(NodeId),
+    /// The user requested a `FIND_NODE` query for an explicit set of distances to be performed.
+    /// It should be reported when finished.
+    FindNodeAt { target: NodeId, distances: Vec<u64> },
+}
+
+/// How a `FIND_NODE` query ended, which tells whether its results are complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryCompletion {
+    /// The query found the requested number of peers.
+    Finished,
+    /// The query timed out, the peers found are partial results.
+    TimedOut,
+    /// The query ran out of peers to contact before finding the requested number of peers.
+    Exhausted,
+}
+
+/// A snapshot of the progress of an in-flight query, for diagnostics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryStatus {
+    /// The target of the query.
+    pub target: NodeId,
+    /// What the query is looking for.
+    pub query_type: QueryType,
+    /// The time elapsed since the query started. This is zero for queries still queued behind the
+    /// concurrent queries limit.
+    pub elapsed: Duration,
+    /// The number of peers the query has contacted.
+    pub peers_contacted: usize,
+    /// The number of peers that have responded to the query.
+    pub responses: usize,
 }
 
 /// Additional information about the query.
 #[derive(Debug)]
 pub enum QueryCallback {
-    FindNode(oneshot::Sender<Vec<Enr>>),
-    FindValue(mpsc::UnboundedSender<Result<Vec<u8>, FindValueError>>),
+    /// Returns the ENRs found along with their log2 distance to the query target, and how the
+    /// query ended.
+    FindNode(oneshot::Sender<(Vec<(Enr, u64)>, QueryCompletion)>),
+    FindValue(ValueSender),
+    /// Streams each ENR discovered while the query progresses. ENRs are only sent once, which
+    /// the `seen` node ids track.
+    FindNodeStream {
+        sender: mpsc::UnboundedSender<Enr>,
+        seen: HashSet<NodeId>,
+    },
+    /// Returns the signed encodings of the ENRs found, as received from the peers. The
+    /// `encodings` received during the query are kept by node id.
+    FindNodeRaw {
+        sender: oneshot::Sender<Vec<Vec<u8>>>,
+        encodings: HashMap<NodeId, Vec<u8>>,
+    },
+    /// Stores the value on the closest peers found.
+    Republish(Vec<u8>),
+    /// Refreshes the routing table. The peers found are inserted as they respond, there is no
+    /// result to return.
+    TableRefresh,
+}
+
+/// The result channel of a `FIND_VALUE` query. It is shared by all requests of the query and
+/// only yields each distinct value once, however many peers return it.
+///
+/// A value is only yielded once `quorum` distinct peers have returned it.
+#[derive(Debug, Clone)]
+pub struct ValueSender {
+    sender: mpsc::UnboundedSender<Result<Vec<u8>, FindValueError>>,
+    /// The number of peers that must return identical values before the value is yielded.
+    quorum: usize,
+    /// The peers that returned each value, indexed by the value's hash.
+    holders: Arc<Mutex<HashMap<[u8; 32], HashSet<NodeId>>>>,
+}
+
+impl ValueSender {
+    pub fn new(sender: mpsc::UnboundedSender<Result<Vec<u8>, FindValueError>>) -> Self {
+        Self::with_quorum(sender, 1)
+    }
+
+    pub fn with_quorum(
+        sender: mpsc::UnboundedSender<Result<Vec<u8>, FindValueError>>,
+        quorum: usize,
+    ) -> Self {
+        ValueSender {
+            sender,
+            quorum,
+            holders: Default::default(),
+        }
+    }
+
+    /// The number of peers that must return identical values before the value is yielded.
+    pub fn quorum(&self) -> usize {
+        self.quorum
+    }
+
+    /// Records a value returned by `holder` and sends it once exactly `quorum` distinct peers
+    /// returned it. A peer returning the same value again is only counted once. Returns whether
+    /// the value was sent.
+    pub fn send_value(
+        &self,
+        holder: NodeId,
+        value: Vec<u8>,
+    ) -> Result<bool, SendError<Result<Vec<u8>, FindValueError>>> {
+        let hash: [u8; 32] = Sha256::digest(&value).into();
+        let holders = {
+            let mut holders = self.holders.lock();
+            let value_holders = holders.entry(hash).or_default();
+            if !value_holders.insert(holder) {
+                return Ok(false);
+            }
+            value_holders.len()
+        };
+        if holders != self.quorum {
+            return Ok(false);
+        }
+        self.sender.send(Ok(value)).map(|_| true)
+    }
+
+    /// Sends an error to the query's result stream.
+    pub fn send(
+        &self,
+        error: FindValueError,
+    ) -> Result<(), SendError<Result<Vec<u8>, FindValueError>>> {
+        self.sender.send(Err(error))
+    }
+
+    /// Returns whether the result stream has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
 }
 
 impl QueryInfo {
-    /// Builds an RPC Request, given the QueryInfo
-    pub(crate) fn rpc_request(&self, peer: NodeId) -> RequestBody {
-        match self.query_type {
+    /// Adds an ENR to the untrusted list if it is not already present. If the list then holds
+    /// more than `max_untrusted_enrs` entries, the ENR farthest from the target is evicted.
+    pub(crate) fn add_untrusted_enr(&mut self, enr: Enr, max_untrusted_enrs: usize) {
+        if self
+            .untrusted_enrs
+            .iter()
+            .any(|e| e.node_id() == enr.node_id())
+        {
+            return;
+        }
+        self.untrusted_enrs.push(enr);
+
+        if self.untrusted_enrs.len() > max_untrusted_enrs {
+            let target_key = self.key();
+            if let Some(position) = self
+                .untrusted_enrs
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, e)| target_key.distance(&Key::from(e.node_id())))
+                .map(|(position, _)| position)
+            {
+                self.untrusted_enrs.swap_remove(position);
+            }
+        }
+    }
+
+    /// Keeps the signed encodings received for `nodes` if the query returns them. If more than
+    /// `max_encodings` are then kept, the encodings farthest from the target are evicted.
+    pub(crate) fn add_encodings(
+        &mut self,
+        nodes: &[Enr],
+        received: &HashMap<NodeId, Vec<u8>>,
+        max_encodings: usize,
+    ) {
+        let target_key = self.key();
+        if let QueryCallback::FindNodeRaw { encodings, .. } = &mut self.callback {
+            for enr in nodes {
+                if let Some(encoding) = received.get(&enr.node_id()) {
+                    encodings
+                        .entry(enr.node_id())
+                        .or_insert_with(|| encoding.clone());
+                }
+            }
+            while encodings.len() > max_encodings {
+                let farthest = encodings
+                    .keys()
+                    .max_by_key(|node_id| target_key.distance(&Key::from(**node_id)))
+                    .copied();
+                match farthest {
+                    Some(node_id) => encodings.remove(&node_id),
+                    None => break,
+                };
+            }
+        }
+    }
+
+    /// Builds an RPC Request, given the QueryInfo. Fails if more distances are to be requested
+    /// than a peer can be asked for, or if all distances were already requested from the peer.
+    pub(crate) fn rpc_request(&mut self, peer: NodeId) -> Result<RequestBody, QueryError> {
+        let request = match self.query_type {
             QueryType::FindNode(node_id) => {
-                let distances = findnode_log2distance(node_id, peer, self.distances_to_request)
-                    .unwrap_or_else(|| vec![0]);
+                let distances = self.unrequested_distances(node_id, peer)?;
                 RequestBody::FindNode { distances }
             }
             QueryType::FindValue(key) => {
-                let distances = findnode_log2distance(key, peer, self.distances_to_request)
-                    .unwrap_or_else(|| vec![0]);
+                let distances = self.unrequested_distances(key, peer)?;
                 RequestBody::FindValue { key, distances }
             }
+            QueryType::FindNodeAt { ref distances, .. } => RequestBody::FindNode {
+                distances: distances.clone(),
+            },
+        };
+        Ok(request)
+    }
+
+    /// Returns the distances to request from a peer for `target`, skipping the distances already
+    /// requested from it in favour of the next adjacent ones, and records them as requested.
+    fn unrequested_distances(
+        &mut self,
+        target: NodeId,
+        peer: NodeId,
+    ) -> Result<Vec<u64>, QueryError> {
+        let count = self.distances_to_request;
+        let mut distances = target_distances(target, peer, count)?;
+        let requested = self.requested_distances.entry(peer).or_default();
+        if !requested.is_empty() {
+            distances = target_distances(target, peer, (count + requested.len()).min(127))?
+                .into_iter()
+                .filter(|distance| !requested.contains(distance))
+                .take(count)
+                .collect();
+            if distances.is_empty() {
+                return Err(QueryError::InvalidDistances(
+                    "All distances were already requested from the peer".into(),
+                ));
+            }
         }
+        requested.extend(distances.iter().copied());
+        Ok(distances)
     }
 }
 
-impl crate::query_pool::TargetKey<NodeId> for QueryInfo {
+impl TargetKey<NodeId> for QueryInfo {
     fn key(&self) -> Key<NodeId> {
         match self.query_type {
             QueryType::FindNode(ref node_id) => {
@@ -64,39 +297,58 @@ impl crate::query_pool::TargetKey<NodeId> for QueryInfo {
             QueryType::FindValue(ref key) => {
                 Key::new_raw(*key, *GenericArray::from_slice(&key.raw()))
             }
+            QueryType::FindNodeAt { ref target, .. } => {
+                Key::new_raw(*target, *GenericArray::from_slice(&target.raw()))
+            }
         }
     }
 }
 
-/// Calculates the log2 distance for a destination peer given a target and the size (number of
-/// distances to request).
+/// Calculates the log2 distances a query requests from a destination peer given a target and
+/// the number of distances to request.
 ///
 /// As the iteration increases, FINDNODE requests adjacent distances from the exact peer distance.
 ///
 /// As an example, if the target has a distance of 12 from the remote peer, the sequence of distances that are sent for increasing iterations would be [12, 13, 11, 14, 10, .. ].
-fn findnode_log2distance(target: NodeId, peer: NodeId, size: usize) -> Option<Vec<u64>> {
-    if size > 127 {
-        // invoke and endless loop - coding error
-        panic!("Iterations cannot be greater than 127");
+///
+/// Distance 0 is only requested when the peer is the target itself, as a peer can only respond
+/// to it with its own ENR. Otherwise the distances above the exact distance are used instead.
+///
+/// Returns `QueryError::InvalidDistances` if more than 127 distances are requested.
+pub fn target_distances(
+    target: NodeId,
+    peer: NodeId,
+    count: usize,
+) -> Result<Vec<u64>, QueryError> {
+    if count > 127 {
+        return Err(QueryError::InvalidDistances(format!(
+            "Cannot request {} distances, the maximum is 127",
+            count
+        )));
     }
 
     let dst_key: Key<NodeId> = peer.into();
-    let distance = dst_key.log2_distance(&target.into())?;
+    let distance = match dst_key.log2_distance(&target.into()) {
+        Some(distance) => distance,
+        None => return Ok(vec![0]),
+    };
 
     let mut result_list = vec![distance];
     let mut difference = 1;
-    while result_list.len() < size {
+    while result_list.len() < count {
         if distance + difference <= 256 {
             result_list.push(distance + difference);
         }
-        if result_list.len() < size {
+        if result_list.len() < count {
             if let Some(d) = distance.checked_sub(difference) {
-                result_list.push(d);
+                if d > 0 {
+                    result_list.push(d);
+                }
             }
         }
         difference += 1;
     }
-    Some(result_list[..size].to_vec())
+    Ok(result_list[..count].to_vec())
 }
 
 #[cfg(test)]
@@ -113,7 +365,7 @@ mod tests {
         let expected_distances = vec![169, 170, 168, 171, 167, 172, 166, 173, 165];
 
         assert_eq!(
-            findnode_log2distance(target, destination, expected_distances.len()).unwrap(),
+            target_distances(target, destination, expected_distances.len()).unwrap(),
             expected_distances
         );
     }
@@ -125,14 +377,228 @@ mod tests {
         destination[31] = 8; // gives a log2 distance of 5
         let destination = NodeId::new(&destination);
 
-        let expected_distances = vec![4, 5, 3, 6, 2, 7, 1, 8, 0, 9, 10];
+        let expected_distances = vec![4, 5, 3, 6, 2, 7, 1, 8, 9, 10, 11];
+
+        assert_eq!(
+            target_distances(target, destination, expected_distances.len()).unwrap(),
+            expected_distances
+        );
+    }
+
+    #[test]
+    fn test_log2distance_lowest() {
+        let target = NodeId::new(&[0u8; 32]);
+        let mut destination = [0u8; 32];
+        destination[31] = 1; // gives a log2 distance of 1
+        let destination = NodeId::new(&destination);
+
+        let expected_distances = vec![1, 2, 3, 4, 5];
 
         assert_eq!(
-            findnode_log2distance(target, destination, expected_distances.len()).unwrap(),
+            target_distances(target, destination, expected_distances.len()).unwrap(),
             expected_distances
         );
     }
 
+    #[test]
+    fn test_log2distance_excludes_zero() {
+        let target = NodeId::new(&[0u8; 32]);
+        let mut destination = [0u8; 32];
+        destination[31] = 2; // gives a log2 distance of 2
+        let destination = NodeId::new(&destination);
+
+        let distances = target_distances(target, destination, 127).unwrap();
+        assert_eq!(distances[..5], [2, 3, 1, 4, 5]);
+        assert_eq!(distances.len(), 127);
+        assert!(!distances.contains(&0));
+    }
+
+    #[test]
+    fn test_log2distance_count_too_large() {
+        let target = NodeId::new(&[0u8; 32]);
+        let mut destination = [0u8; 32];
+        destination[10] = 1; // gives a log2 distance of 169
+        let destination = NodeId::new(&destination);
+
+        assert_eq!(
+            target_distances(target, destination, 127).unwrap().len(),
+            127
+        );
+        assert!(matches!(
+            target_distances(target, destination, 128),
+            Err(QueryError::InvalidDistances(_))
+        ));
+    }
+
+    #[test]
+    fn test_rpc_request_too_many_distances() {
+        let (callback, _) = oneshot::channel();
+        let mut query_info = QueryInfo {
+            query_type: QueryType::FindNode(NodeId::random()),
+            untrusted_enrs: Default::default(),
+            callback: QueryCallback::FindNode(callback),
+            distances_to_request: 128,
+            shared_responses: None,
+            requested_distances: Default::default(),
+            known_target: false,
+        };
+
+        assert!(matches!(
+            query_info.rpc_request(NodeId::random()),
+            Err(QueryError::InvalidDistances(_))
+        ));
+    }
+
+    #[test]
+    fn test_rpc_request_skips_requested_distances() {
+        let (callback, _) = oneshot::channel();
+        let target = NodeId::new(&[0u8; 32]);
+        let mut peer = [0u8; 32];
+        peer[10] = 1; // gives a log2 distance of 169
+        let peer = NodeId::new(&peer);
+        let mut query_info = QueryInfo {
+            query_type: QueryType::FindNode(target),
+            untrusted_enrs: Default::default(),
+            callback: QueryCallback::FindNode(callback),
+            distances_to_request: 3,
+            shared_responses: None,
+            requested_distances: Default::default(),
+            known_target: false,
+        };
+
+        // the second round asks the peer for the next adjacent distances only
+        assert_eq!(
+            query_info.rpc_request(peer),
+            Ok(RequestBody::FindNode {
+                distances: vec![169, 170, 168]
+            })
+        );
+        assert_eq!(
+            query_info.rpc_request(peer),
+            Ok(RequestBody::FindNode {
+                distances: vec![171, 167, 172]
+            })
+        );
+
+        // other peers are asked for their own distances
+        assert_eq!(
+            query_info.rpc_request(target),
+            Ok(RequestBody::FindNode { distances: vec![0] })
+        );
+        assert!(matches!(
+            query_info.rpc_request(target),
+            Err(QueryError::InvalidDistances(_))
+        ));
+    }
+
+    #[test]
+    fn test_log2distance_target_itself() {
+        let target = NodeId::random();
+        assert_eq!(target_distances(target, target, 3).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn test_find_node_at_distances_unchanged() {
+        let (callback, _) = oneshot::channel();
+        let distances = vec![256, 3, 17, 255];
+        let mut query_info = QueryInfo {
+            query_type: QueryType::FindNodeAt {
+                target: NodeId::random(),
+                distances: distances.clone(),
+            },
+            untrusted_enrs: Default::default(),
+            callback: QueryCallback::FindNode(callback),
+            distances_to_request: 3,
+            shared_responses: None,
+            requested_distances: Default::default(),
+            known_target: false,
+        };
+
+        assert_eq!(
+            query_info.rpc_request(NodeId::random()),
+            Ok(RequestBody::FindNode { distances })
+        );
+    }
+
+    #[test]
+    fn test_untrusted_enrs_bounded() {
+        let (callback, _) = oneshot::channel();
+        let target = NodeId::random();
+        let mut query_info = QueryInfo {
+            query_type: QueryType::FindNode(target),
+            untrusted_enrs: Default::default(),
+            callback: QueryCallback::FindNode(callback),
+            distances_to_request: 3,
+            shared_responses: None,
+            requested_distances: Default::default(),
+            known_target: false,
+        };
+
+        let max_untrusted_enrs = 16;
+        let mut enrs: Vec<Enr> = (0..100)
+            .map(|_| {
+                let key = enr::CombinedKey::generate_secp256k1();
+                enr::EnrBuilder::new("v4").build(&key).unwrap()
+            })
+            .collect();
+        for enr in enrs.iter() {
+            query_info.add_untrusted_enr(enr.clone(), max_untrusted_enrs);
+            assert!(query_info.untrusted_enrs.len() <= max_untrusted_enrs);
+        }
+
+        // only the closest ENRs to the target are kept
+        let target_key: Key<NodeId> = target.into();
+        enrs.sort_by_key(|enr| target_key.distance(&Key::from(enr.node_id())));
+        let mut kept: Vec<NodeId> = query_info
+            .untrusted_enrs
+            .iter()
+            .map(|enr| enr.node_id())
+            .collect();
+        kept.sort_by_key(|node_id| node_id.raw());
+        let mut expected: Vec<NodeId> = enrs
+            .iter()
+            .take(max_untrusted_enrs)
+            .map(|enr| enr.node_id())
+            .collect();
+        expected.sort_by_key(|node_id| node_id.raw());
+        assert_eq!(kept, expected);
+    }
+
+    #[test]
+    fn test_value_sender_deduplicates() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let sender = ValueSender::new(sender);
+        let cloned_sender = sender.clone();
+
+        assert!(sender.send_value(NodeId::random(), vec![1]).unwrap());
+        assert!(!cloned_sender.send_value(NodeId::random(), vec![1]).unwrap());
+        assert!(sender.send_value(NodeId::random(), vec![2]).unwrap());
+        assert!(!cloned_sender.send_value(NodeId::random(), vec![1]).unwrap());
+
+        assert_eq!(receiver.try_recv().unwrap(), Ok(vec![1]));
+        assert_eq!(receiver.try_recv().unwrap(), Ok(vec![2]));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_value_sender_quorum() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let sender = ValueSender::with_quorum(sender, 2);
+
+        let holder = NodeId::random();
+        assert!(!sender.send_value(holder, vec![1]).unwrap());
+        assert!(!sender.send_value(NodeId::random(), vec![2]).unwrap());
+        // a peer returning the value again doesn't count towards the quorum
+        assert!(!sender.send_value(holder, vec![1]).unwrap());
+        assert!(receiver.try_recv().is_err());
+
+        // the value is sent once the quorum is reached
+        assert!(sender.send_value(NodeId::random(), vec![1]).unwrap());
+        assert!(!sender.send_value(NodeId::random(), vec![1]).unwrap());
+        assert_eq!(receiver.try_recv().unwrap(), Ok(vec![1]));
+        assert!(receiver.try_recv().is_err());
+    }
+
     #[test]
     fn test_log2distance_upper() {
         let target = NodeId::new(&[0u8; 32]);
@@ -143,7 +609,7 @@ mod tests {
         let expected_distances = vec![252, 253, 251, 254, 250, 255, 249, 256, 248, 247, 246];
 
         assert_eq!(
-            findnode_log2distance(target, destination, expected_distances.len()).unwrap(),
+            target_distances(target, destination, expected_distances.len()).unwrap(),
             expected_distances
         );
     }
@@ -10,12 +10,17 @@ use crate::{
     query_pool::{QueryId, QueryPool},
     rpc,
     rpc::RequestId,
-    service::{ActiveRequest, Service},
+    service::{ActiveRequest, Service, SharedResponses},
     Discv5ConfigBuilder, Enr,
 };
 use enr::{CombinedKey, EnrBuilder};
 use parking_lot::RwLock;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::sync::{mpsc, oneshot};
 
 fn _connected_state() -> NodeStatus {
@@ -59,8 +64,8 @@ async fn build_service(
 
     let (table_filter, bucket_filter) = if filters {
         (
-            Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<Enr>>),
-            Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<Enr>>),
+            Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<TableEnr>>),
+            Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<TableEnr>>),
         )
     } else {
         (None, None)
@@ -72,11 +77,13 @@ async fn build_service(
         config.incoming_bucket_limit,
         table_filter,
         bucket_filter,
+        config.eviction_policy.clone(),
     )));
 
     // create the required channels
     let (_discv5_send, discv5_recv) = mpsc::channel(30);
     let (_exit_send, exit) = oneshot::channel();
+    let (talk_answered_send, talk_answered_recv) = mpsc::unbounded_channel();
 
     Service {
         local_enr,
@@ -85,14 +92,34 @@ async fn build_service(
         queries: QueryPool::new(config.query_timeout),
         active_requests: Default::default(),
         active_nodes_responses: HashMap::new(),
+        active_value_chunks: HashMap::new(),
         ip_votes: None,
+        external_address_prediction: Arc::new(RwLock::new((None, None))),
         handler_send,
         handler_recv,
         handler_exit: Some(_handler_exit),
         peers_to_ping: HashSetDelay::new(config.ping_interval),
+        table_refresh: None,
+        liveness_check: None,
+        ping_failures: HashMap::new(),
+        incoming_ping_probes: HashMap::new(),
         discv5_recv,
         event_stream: None,
+        talk_protocols: Default::default(),
+        preferred_peers: Default::default(),
+        value_store: ValueStore::new(
+            config.max_value_size,
+            config.value_store_capacity,
+            config.stored_value_ttl,
+        ),
+        published_values: HashMap::new(),
+        values_to_republish: HashSetDelay::new(config.value_republish_interval),
+        talk_timeouts: HashMapDelay::new(config.talk_response_timeout),
+        talk_answered_send,
+        talk_answered_recv,
         exit,
+        shutdown: None,
+        bootstrap: None,
         config,
     }
 }
@@ -127,7 +154,7 @@ async fn test_updating_connection_on_ping() {
     // Set up service with one disconnected node
     let key = kbucket::Key::from(enr2.node_id());
     if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
-        match entry.insert(enr2.clone(), disconnected_state()) {
+        match entry.insert(enr2.clone().into(), disconnected_state()) {
             BucketInsertResult::Inserted => {}
             BucketInsertResult::Full => {
                 panic!("Can't be full");
@@ -166,3 +193,1422 @@ async fn test_updating_connection_on_ping() {
     let node = buckets.iter_ref().next().unwrap();
     assert!(node.status.is_connected())
 }
+
+#[tokio::test]
+async fn test_findvalue_query_parallelism() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10003)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    // Populate the routing table with more peers than the largest alpha tested
+    let mut inserted = 0;
+    while inserted < 10 {
+        let key = CombinedKey::generate_secp256k1();
+        let peer = EnrBuilder::new("v4")
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(10004)
+            .build(&key)
+            .unwrap();
+        let key = kbucket::Key::from(peer.node_id());
+        if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+            if let BucketInsertResult::Inserted = entry.insert(peer.into(), disconnected_state()) {
+                inserted += 1;
+            }
+        }
+    }
+
+    for &alpha in &[1, 3, 5] {
+        let (callback, _callback_recv) = mpsc::unbounded_channel();
+        service.start_findvalue_query(NodeId::random(), None, Some(alpha), 1, callback);
+
+        // Count the requests the query issues before it waits on their responses
+        let mut in_flight = 0;
+        while let QueryPoolState::Waiting(Some(_)) = service.queries.poll() {
+            in_flight += 1;
+        }
+        assert_eq!(in_flight, alpha);
+
+        service.queries = QueryPool::new(service.config.query_timeout);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_republish_value() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10005)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    let interval = Duration::from_secs(10);
+    service.config.value_ttl = Duration::from_secs(35);
+    service.config.value_republish_interval = interval;
+
+    // A peer to republish the value to
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10006)
+        .build(&peer_key)
+        .unwrap();
+    let key = kbucket::Key::from(peer.node_id());
+    if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+        assert!(matches!(
+            entry.insert(peer.into(), disconnected_state()),
+            BucketInsertResult::Inserted
+        ));
+    }
+
+    let value_key = NodeId::random();
+    let start = tokio::time::Instant::now();
+    service.publish_value(value_key, vec![1, 2, 3]);
+
+    let republish_queries = |service: &Service| {
+        service
+            .queries
+            .iter()
+            .filter(|query| matches!(query.target().callback, QueryCallback::Republish(_)))
+            .count()
+    };
+
+    // The value is republished once per interval until its ttl elapses
+    for republished in 1..=3 {
+        let key = service.values_to_republish.next().await.unwrap().unwrap();
+        assert_eq!(key, value_key);
+        assert_eq!(
+            start.elapsed().as_secs(),
+            interval.as_secs() * republished as u64
+        );
+        service.republish_value(key);
+        assert_eq!(republish_queries(&service), republished);
+    }
+
+    let key = service.values_to_republish.next().await.unwrap().unwrap();
+    service.republish_value(key);
+    assert_eq!(republish_queries(&service), 3);
+    assert!(service.published_values.is_empty());
+    assert!(service.values_to_republish.is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_table_refresh() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10033)
+        .build(&enr_key)
+        .unwrap();
+    let local_id = enr.node_id();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    let interval = Duration::from_secs(10);
+    let start = tokio::time::Instant::now();
+    service.config.table_refresh_interval = Some(interval);
+    service.table_refresh = Some(start + interval);
+
+    // two peers in the bucket at distance 256 and one in the sparser bucket at distance 255
+    for (port, distance) in [(10034, 256), (10035, 256), (10036, 255)] {
+        let peer = loop {
+            let peer_key = CombinedKey::generate_secp256k1();
+            let peer = EnrBuilder::new("v4")
+                .ip4("127.0.0.1".parse().unwrap())
+                .udp4(port)
+                .build(&peer_key)
+                .unwrap();
+            let peer_key: kbucket::Key<NodeId> = peer.node_id().into();
+            if peer_key.log2_distance(&local_id.into()) == Some(distance) {
+                break peer;
+            }
+        };
+        let key = kbucket::Key::from(peer.node_id());
+        if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+            assert!(matches!(
+                entry.insert(peer.into(), disconnected_state()),
+                BucketInsertResult::Inserted
+            ));
+        }
+    }
+
+    // targets are picked in the sparsest bucket
+    let local_key: kbucket::Key<NodeId> = local_id.into();
+    for _ in 0..10 {
+        let target = service.table_refresh_target().unwrap();
+        assert_eq!(local_key.log2_distance(&target.into()), Some(255));
+    }
+
+    let refresh_queries = |service: &Service| {
+        service
+            .queries
+            .iter()
+            .filter(|query| matches!(query.target().callback, QueryCallback::TableRefresh))
+            .count()
+    };
+
+    // the table is refreshed once per interval, without piling up refresh queries
+    for refresh in 1..=3 {
+        tokio::time::sleep_until(service.table_refresh.unwrap()).await;
+        assert_eq!(start.elapsed().as_secs(), interval.as_secs() * refresh);
+        service.refresh_table();
+        assert_eq!(refresh_queries(&service), 1);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_timer_jitter() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10042)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    let interval = Duration::from_secs(100);
+    service.config.table_refresh_interval = Some(interval);
+    service.config.timer_jitter = 0.2;
+
+    // consecutive refresh intervals vary, within the jitter of the configured interval
+    let mut intervals = Vec::new();
+    for _ in 0..20 {
+        service.refresh_table();
+        let next_refresh = service.table_refresh.unwrap();
+        intervals.push(next_refresh - tokio::time::Instant::now());
+        tokio::time::sleep_until(next_refresh).await;
+    }
+    assert!(intervals
+        .iter()
+        .all(|i| *i >= interval.mul_f64(0.8) && *i <= interval.mul_f64(1.2)));
+    assert!(intervals.windows(2).any(|pair| pair[0] != pair[1]));
+
+    // without jitter, the interval is exact
+    service.config.timer_jitter = 0.0;
+    service.refresh_table();
+    assert_eq!(
+        service.table_refresh.unwrap() - tokio::time::Instant::now(),
+        interval
+    );
+}
+
+#[tokio::test]
+async fn test_max_enrs_per_response() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10043)
+        .build(&enr_key)
+        .unwrap();
+    let local_key: kbucket::Key<NodeId> = enr.node_id().into();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    // four peers at distance 256
+    let mut inserted = 0;
+    while inserted < 4 {
+        let peer_key = CombinedKey::generate_secp256k1();
+        let peer = EnrBuilder::new("v4")
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(10044 + inserted)
+            .build(&peer_key)
+            .unwrap();
+        let key = kbucket::Key::from(peer.node_id());
+        if local_key.log2_distance(&key) != Some(256) {
+            continue;
+        }
+        if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+            assert!(matches!(
+                entry.insert(peer.into(), disconnected_state()),
+                BucketInsertResult::Inserted
+            ));
+        }
+        inserted += 1;
+    }
+
+    let requester_key = CombinedKey::generate_secp256k1();
+    let requester = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10048)
+        .build(&requester_key)
+        .unwrap();
+    let node_address = NodeContact::from(requester).node_address();
+    let sent_enrs = |service: &mut Service| -> usize {
+        service
+            .prepare_nodes_response(node_address.clone(), RequestId(vec![1]), vec![0, 256])
+            .into_iter()
+            .map(|handler_in| match handler_in {
+                HandlerIn::Response(_, response) => match response.body {
+                    rpc::ResponseBody::Nodes { nodes, .. } => nodes.len(),
+                    _ => panic!("The response must be a NODES response"),
+                },
+                _ => panic!("The service must send a response"),
+            })
+            .sum()
+    };
+
+    // our own ENR and the four peers
+    assert_eq!(sent_enrs(&mut service), 5);
+
+    // the response is capped, our own ENR included
+    service.config.max_enrs_per_response = Some(2);
+    assert_eq!(sent_enrs(&mut service), 2);
+}
+
+#[tokio::test]
+async fn test_oversized_store_request_rejected() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10007)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10008)
+        .build(&peer_key)
+        .unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    let node_contact: NodeContact = peer.into();
+    let node_address = node_contact.node_address();
+    let max_value_size = service.config.max_value_size;
+    let small_key = NodeId::random();
+    let large_key = NodeId::random();
+
+    for (id, key, size) in [
+        (1, small_key, max_value_size),
+        (2, large_key, max_value_size + 1),
+    ] {
+        let request = rpc::Request {
+            id: RequestId(vec![id]),
+            body: rpc::RequestBody::Store {
+                key,
+                value: vec![0; size],
+            },
+        };
+        service.handle_rpc_request(node_address.clone(), request);
+    }
+
+    assert_eq!(
+        service.value_store.get(&small_key),
+        Some(&vec![0; max_value_size])
+    );
+    assert_eq!(service.value_store.get(&large_key), None);
+}
+
+#[tokio::test]
+async fn test_oversized_value_response_rejected() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10009)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10010)
+        .build(&peer_key)
+        .unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    let node_contact: NodeContact = peer.into();
+    let node_address = node_contact.node_address();
+    let max_value_size = service.config.max_value_size;
+    let (callback, mut callback_recv) = mpsc::unbounded_channel();
+
+    service.active_requests.insert(
+        RequestId(vec![1]),
+        ActiveRequest {
+            contact: node_contact,
+            request_body: rpc::RequestBody::FindValue {
+                key: NodeId::random(),
+                distances: vec![255],
+            },
+            query_id: None,
+            callback: Some(CallbackResponse::Value(ValueSender::new(callback))),
+        },
+    );
+
+    let response = rpc::Response {
+        id: RequestId(vec![1]),
+        body: rpc::ResponseBody::Value {
+            response: vec![0; max_value_size + 1],
+        },
+    };
+    service.handle_rpc_response(node_address, response);
+
+    assert_eq!(
+        callback_recv.try_recv().unwrap(),
+        Err(FindValueError::ValueTooLarge {
+            size: max_value_size + 1,
+            max: max_value_size,
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_findnode_stream_query() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10011)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    let peers: Vec<Enr> = (0..4)
+        .map(|i| {
+            let key = CombinedKey::generate_secp256k1();
+            EnrBuilder::new("v4")
+                .ip4("127.0.0.1".parse().unwrap())
+                .udp4(10012 + i)
+                .build(&key)
+                .unwrap()
+        })
+        .collect();
+    let key = kbucket::Key::from(peers[0].node_id());
+    if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+        assert!(matches!(
+            entry.insert(peers[0].clone().into(), disconnected_state()),
+            BucketInsertResult::Inserted
+        ));
+    }
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let callback = QueryCallback::FindNodeStream {
+        sender,
+        seen: HashSet::new(),
+    };
+    service.start_findnode_query(QueryType::FindNode(NodeId::random()), None, None, callback);
+    let query_id = match service.queries.poll() {
+        QueryPoolState::Waiting(Some((query, _))) => query.id(),
+        _ => panic!("The query must request the known peer"),
+    };
+
+    // the first response is streamed while the query is ongoing
+    service.discovered(
+        &peers[0].node_id(),
+        vec![peers[1].clone(), peers[2].clone()],
+        Some(query_id),
+    );
+    assert_eq!(receiver.try_recv().unwrap(), peers[1]);
+    assert_eq!(receiver.try_recv().unwrap(), peers[2]);
+    assert!(receiver.try_recv().is_err());
+    assert!(service.queries.get_mut(query_id).is_some());
+
+    // nodes already streamed are not streamed again
+    service.discovered(
+        &peers[1].node_id(),
+        vec![peers[2].clone(), peers[3].clone()],
+        Some(query_id),
+    );
+    assert_eq!(receiver.try_recv().unwrap(), peers[3]);
+    assert!(receiver.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_nodes_response_total_truncated() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10016)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+    service.config.max_nodes_response_total = 5;
+
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10017)
+        .build(&peer_key)
+        .unwrap();
+    let key = kbucket::Key::from(peer.node_id());
+    if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+        assert!(matches!(
+            entry.insert(peer.clone().into(), disconnected_state()),
+            BucketInsertResult::Inserted
+        ));
+    }
+
+    // Nodes at the distances requested from the peer
+    let distances = vec![254, 255, 256];
+    let mut nodes = Vec::new();
+    while nodes.len() < 12 {
+        let key = CombinedKey::generate_secp256k1();
+        let node = EnrBuilder::new("v4")
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(10018)
+            .build(&key)
+            .unwrap();
+        let distance = kbucket::Key::from(peer.node_id()).log2_distance(&node.node_id().into());
+        if distance.is_some_and(|d| distances.contains(&d)) {
+            nodes.push(node);
+        }
+    }
+
+    // Observe the nodes reported to a query
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let callback = QueryCallback::FindNodeStream {
+        sender,
+        seen: HashSet::new(),
+    };
+    service.start_findnode_query(QueryType::FindNode(NodeId::random()), None, None, callback);
+    let query_id = match service.queries.poll() {
+        QueryPoolState::Waiting(Some((query, _))) => query.id(),
+        _ => panic!("The query must request the known peer"),
+    };
+
+    let node_contact: NodeContact = peer.into();
+    let node_address = node_contact.node_address();
+    service.active_requests.insert(
+        RequestId(vec![1]),
+        ActiveRequest {
+            contact: node_contact,
+            request_body: rpc::RequestBody::FindNode { distances },
+            query_id: Some(query_id),
+            callback: None,
+        },
+    );
+
+    // The peer claims three packets of four nodes each
+    for chunk in nodes.chunks(4).take(2) {
+        let response = rpc::Response {
+            id: RequestId(vec![1]),
+            body: rpc::ResponseBody::Nodes {
+                total: 3,
+                nodes: chunk.to_vec(),
+                encodings: HashMap::new(),
+            },
+        };
+        service.handle_rpc_response(node_address.clone(), response);
+    }
+
+    // The second packet exceeds the cap, the response is truncated and no further packets are
+    // awaited
+    for node in nodes.iter().take(5) {
+        assert_eq!(&receiver.try_recv().unwrap(), node);
+    }
+    assert!(receiver.try_recv().is_err());
+    assert!(!service.active_requests.contains_key(&RequestId(vec![1])));
+    assert!(service.active_nodes_responses.is_empty());
+}
+
+/// The packets of multi-packet NODES responses to concurrent requests to the same peer are
+/// collected per request.
+#[tokio::test]
+async fn test_concurrent_nodes_responses() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10049)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10050)
+        .build(&peer_key)
+        .unwrap();
+    let peer_key = kbucket::Key::from(peer.node_id());
+    let node_contact: NodeContact = peer.into();
+    let node_address = node_contact.node_address();
+
+    // two FINDNODE requests in flight to the peer, each answered with two packets
+    let ids = [RequestId(vec![1]), RequestId(vec![2])];
+    for id in ids.iter() {
+        service.active_requests.insert(
+            id.clone(),
+            ActiveRequest {
+                contact: node_contact.clone(),
+                request_body: rpc::RequestBody::FindNode {
+                    distances: vec![256],
+                },
+                query_id: None,
+                callback: None,
+            },
+        );
+    }
+    let packet = |id: &RequestId| {
+        let node = loop {
+            let key = CombinedKey::generate_secp256k1();
+            let node = EnrBuilder::new("v4")
+                .ip4("127.0.0.1".parse().unwrap())
+                .udp4(10051)
+                .build(&key)
+                .unwrap();
+            if peer_key.log2_distance(&node.node_id().into()) == Some(256) {
+                break node;
+            }
+        };
+        rpc::Response {
+            id: id.clone(),
+            body: rpc::ResponseBody::Nodes {
+                total: 2,
+                nodes: vec![node],
+                encodings: HashMap::new(),
+            },
+        }
+    };
+
+    // the first packets of both responses interleave
+    service.handle_rpc_response(node_address.clone(), packet(&ids[0]));
+    service.handle_rpc_response(node_address.clone(), packet(&ids[1]));
+    assert!(service.active_requests.contains_key(&ids[0]));
+    assert!(service.active_requests.contains_key(&ids[1]));
+
+    // the second packet completes only its own request
+    service.handle_rpc_response(node_address.clone(), packet(&ids[0]));
+    assert!(!service.active_requests.contains_key(&ids[0]));
+    assert!(service.active_requests.contains_key(&ids[1]));
+    assert_eq!(
+        service
+            .active_nodes_responses
+            .get(&ids[1])
+            .map(|response| response.received_nodes.len()),
+        Some(1)
+    );
+
+    service.handle_rpc_response(node_address, packet(&ids[1]));
+    assert!(service.active_requests.is_empty());
+    assert!(service.active_nodes_responses.is_empty());
+}
+
+/// A query returning raw ENRs keeps the encodings as received, even when they are not the
+/// encodings the decoded ENRs re-encode to.
+#[tokio::test]
+async fn test_findnode_raw_keeps_received_encodings() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10052)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10053)
+        .build(&peer_key)
+        .unwrap();
+    let key = kbucket::Key::from(peer.node_id());
+    if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+        assert!(matches!(
+            entry.insert(peer.clone().into(), disconnected_state()),
+            BucketInsertResult::Inserted
+        ));
+    }
+
+    let (sender, _receiver) = oneshot::channel();
+    let callback = QueryCallback::FindNodeRaw {
+        sender,
+        encodings: HashMap::new(),
+    };
+    let query_id = service
+        .start_findnode_query(QueryType::FindNode(NodeId::random()), None, None, callback)
+        .unwrap();
+    let request_body = match service.queries.poll() {
+        QueryPoolState::Waiting(Some((query, return_peer))) => {
+            query.target_mut().rpc_request(return_peer).unwrap()
+        }
+        _ => panic!("The query must request the known peer"),
+    };
+    let distances = match &request_body {
+        rpc::RequestBody::FindNode { distances } => distances.clone(),
+        _ => panic!("The query must send a FINDNODE request"),
+    };
+    let found = loop {
+        let key = CombinedKey::generate_secp256k1();
+        let node = EnrBuilder::new("v4")
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(10054)
+            .build(&key)
+            .unwrap();
+        let distance = kbucket::Key::from(peer.node_id()).log2_distance(&node.node_id().into());
+        if distance.is_some_and(|d| distances.contains(&d)) {
+            break node;
+        }
+    };
+
+    // the record with its sequence number padded to two bytes, which its signature still covers
+    let canonical = rlp::encode(&found);
+    let mut received = rlp::RlpStream::new_list(rlp::Rlp::new(&canonical).item_count().unwrap());
+    for (index, item) in rlp::Rlp::new(&canonical).iter().enumerate() {
+        if index == 1 {
+            received.append(&vec![0u8, found.seq() as u8]);
+        } else {
+            received.append_raw(item.as_raw(), 1);
+        }
+    }
+    let received = received.out().to_vec();
+    assert_ne!(received, canonical.to_vec());
+
+    let mut nodes = rlp::RlpStream::new_list(3);
+    nodes.append(&vec![1u8]);
+    nodes.append(&1u64);
+    nodes.begin_list(1);
+    nodes.append_raw(&received, 1);
+    let mut packet = vec![4];
+    packet.extend_from_slice(&nodes.out());
+    let response = match rpc::Message::decode(&packet).unwrap() {
+        rpc::Message::Response(response) => response,
+        _ => panic!("Invalid decoding"),
+    };
+
+    let node_contact: NodeContact = peer.into();
+    let node_address = node_contact.node_address();
+    service.active_requests.insert(
+        RequestId(vec![1]),
+        ActiveRequest {
+            contact: node_contact,
+            request_body,
+            query_id: Some(query_id),
+            callback: None,
+        },
+    );
+    service.handle_rpc_response(node_address, response);
+
+    match &service.queries.get_mut(query_id).unwrap().target().callback {
+        QueryCallback::FindNodeRaw { encodings, .. } => {
+            assert_eq!(encodings.get(&found.node_id()), Some(&received));
+            let decoded: Enr = rlp::decode(&received).unwrap();
+            assert_eq!(decoded, found);
+        }
+        _ => panic!("The query must return raw ENRs"),
+    }
+}
+
+/// Only plain lookups of a known node's exact id request just the node's own ENR, lookups for an
+/// explicit number of results iterate.
+#[tokio::test]
+async fn test_known_target_lookup() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10055)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10056)
+        .build(&peer_key)
+        .unwrap();
+    let key = kbucket::Key::from(peer.node_id());
+    if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+        assert!(matches!(
+            entry.insert(peer.clone().into(), disconnected_state()),
+            BucketInsertResult::Inserted
+        ));
+    }
+
+    for (num_results, known_target) in [(None, true), (Some(16), false)] {
+        service.queries = QueryPool::new(service.config.query_timeout);
+        let (callback, _callback_recv) = oneshot::channel();
+        service.start_findnode_query(
+            QueryType::FindNode(peer.node_id()),
+            None,
+            num_results,
+            QueryCallback::FindNode(callback),
+        );
+        match service.queries.poll() {
+            QueryPoolState::Waiting(Some((query, _))) => {
+                assert_eq!(query.target().known_target, known_target);
+                assert_eq!(query.num_results(), num_results.unwrap_or(1));
+            }
+            _ => panic!("The query must request the known peer"),
+        }
+    }
+}
+
+/// An inbound TALK request awaits its response timeout until it is answered.
+#[tokio::test]
+async fn test_talk_request_timeout_removed() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10057)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+    let (handler, mut requests) = mpsc::channel(1);
+    service
+        .talk_protocols
+        .write()
+        .insert(b"proto".to_vec(), handler);
+
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10058)
+        .build(&peer_key)
+        .unwrap();
+    let node_address = NodeContact::from(peer).node_address();
+    let id = RequestId(vec![1]);
+    service.handle_rpc_request(
+        node_address.clone(),
+        rpc::Request {
+            id: id.clone(),
+            body: rpc::RequestBody::Talk {
+                protocol: b"proto".to_vec(),
+                request: b"ping".to_vec(),
+            },
+        },
+    );
+    let key = (node_address, id);
+    assert!(service.talk_timeouts.contains_key(&key));
+
+    // answering the request reports it to the service, which drops its timeout
+    requests
+        .try_recv()
+        .unwrap()
+        .respond(b"pong".to_vec())
+        .unwrap();
+    assert_eq!(service.talk_answered_recv.try_recv().unwrap(), key);
+    assert!(service.talk_answered_recv.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_preferred_peer_contacted_first() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10040)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    let mut peers = Vec::new();
+    while peers.len() < 10 {
+        let key = CombinedKey::generate_secp256k1();
+        let peer = EnrBuilder::new("v4")
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(10041)
+            .build(&key)
+            .unwrap();
+        let key = kbucket::Key::from(peer.node_id());
+        if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+            if let BucketInsertResult::Inserted =
+                entry.insert(peer.clone().into(), disconnected_state())
+            {
+                peers.push(peer.node_id());
+            }
+        }
+    }
+
+    // the peer farthest from the target is preferred
+    let target = NodeId::random();
+    let target_key = kbucket::Key::from(target);
+    let farthest = *peers
+        .iter()
+        .max_by_key(|peer| target_key.distance(&kbucket::Key::from(**peer)))
+        .unwrap();
+    service.preferred_peers.write().insert(farthest);
+
+    let (callback, _callback_recv) = oneshot::channel();
+    service.start_findnode_query(
+        QueryType::FindNode(target),
+        None,
+        None,
+        QueryCallback::FindNode(callback),
+    );
+    match service.queries.poll() {
+        QueryPoolState::Waiting(Some((_, return_peer))) => assert_eq!(return_peer, farthest),
+        _ => panic!("The query must request a peer"),
+    }
+}
+
+#[tokio::test]
+async fn test_shared_findnode_responses() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10037)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+
+    let peer_key = CombinedKey::generate_secp256k1();
+    let peer = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(10038)
+        .build(&peer_key)
+        .unwrap();
+    let key = kbucket::Key::from(peer.node_id());
+    if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+        assert!(matches!(
+            entry.insert(peer.clone().into(), disconnected_state()),
+            BucketInsertResult::Inserted
+        ));
+    }
+
+    // two nearby targets, at the same distance from the peer
+    let first_target = NodeId::random();
+    let mut raw = first_target.raw();
+    raw[31] ^= 1;
+    let second_target = NodeId::new(&raw);
+    let shared_responses = SharedResponses::default();
+
+    // the first query requests the peer, which responds with a node
+    let (callback, _callback_recv) = oneshot::channel();
+    let first_query = service
+        .start_shared_findnode_query(first_target, shared_responses.clone(), callback)
+        .unwrap();
+    let request_body = match service.queries.poll() {
+        QueryPoolState::Waiting(Some((query, return_peer))) => {
+            assert_eq!(return_peer, peer.node_id());
+            query.target_mut().rpc_request(return_peer).unwrap()
+        }
+        _ => panic!("The query must request the known peer"),
+    };
+    let distances = match &request_body {
+        rpc::RequestBody::FindNode { distances } => distances.clone(),
+        _ => panic!("The query must send a FINDNODE request"),
+    };
+    let found = loop {
+        let key = CombinedKey::generate_secp256k1();
+        let node = EnrBuilder::new("v4")
+            .ip4("127.0.0.1".parse().unwrap())
+            .udp4(10039)
+            .build(&key)
+            .unwrap();
+        let distance = kbucket::Key::from(peer.node_id()).log2_distance(&node.node_id().into());
+        if distance.is_some_and(|d| distances.contains(&d)) {
+            break node;
+        }
+    };
+    let node_contact: NodeContact = peer.clone().into();
+    let node_address = node_contact.node_address();
+    service.active_requests.insert(
+        RequestId(vec![1]),
+        ActiveRequest {
+            contact: node_contact,
+            request_body,
+            query_id: Some(first_query),
+            callback: None,
+        },
+    );
+    let response = rpc::Response {
+        id: RequestId(vec![1]),
+        body: rpc::ResponseBody::Nodes {
+            total: 1,
+            nodes: vec![found.clone()],
+            encodings: HashMap::new(),
+        },
+    };
+    service.handle_rpc_response(node_address, response);
+    assert_eq!(shared_responses.lock().len(), 1);
+    service.queries = QueryPool::new(service.config.query_timeout);
+
+    // the second query reuses the response instead of requesting the peer again
+    let (callback, _callback_recv) = oneshot::channel();
+    let second_query = service
+        .start_shared_findnode_query(second_target, shared_responses.clone(), callback)
+        .unwrap();
+    let request_body = match service.queries.poll() {
+        QueryPoolState::Waiting(Some((query, return_peer))) => {
+            assert_eq!(return_peer, peer.node_id());
+            query.target_mut().rpc_request(return_peer).unwrap()
+        }
+        _ => panic!("The query must request the known peer"),
+    };
+    service.send_rpc_query(second_query, peer.node_id(), request_body);
+    assert!(service.active_requests.is_empty());
+
+    // the query carries on with the node the peer returned
+    match service.queries.poll() {
+        QueryPoolState::Waiting(Some((_, return_peer))) => {
+            assert_eq!(return_peer, found.node_id())
+        }
+        _ => panic!("The query must request the node found"),
+    }
+}
+
+/// Inserts `peer` as a connected peer of `service` and delivers its PONG reporting `socket` as
+/// our observed address.
+fn receive_pong_vote(service: &mut Service, peer: &Enr, id: u8, socket: SocketAddr) {
+    // Only votes of peers we contacted are counted
+    let key = kbucket::Key::from(peer.node_id());
+    if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+        assert!(matches!(
+            entry.insert(peer.clone().into(), _connected_state()),
+            BucketInsertResult::Inserted
+        ));
+    }
+
+    let node_contact: NodeContact = peer.clone().into();
+    let node_address = node_contact.node_address();
+    let id = RequestId(vec![id]);
+    service.active_requests.insert(
+        id.clone(),
+        ActiveRequest {
+            contact: node_contact,
+            request_body: rpc::RequestBody::Ping { enr_seq: 1 },
+            query_id: None,
+            callback: None,
+        },
+    );
+    let response = rpc::Response {
+        id,
+        body: rpc::ResponseBody::Pong {
+            enr_seq: peer.seq(),
+            ip: socket.ip(),
+            port: socket.port(),
+        },
+    };
+    service.handle_rpc_response(node_address, response);
+}
+
+async fn build_voting_service(
+    port: u16,
+    enr_peer_update_min: usize,
+) -> (Service, mpsc::Receiver<Discv5Event>) {
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(port)
+        .build(&enr_key)
+        .unwrap();
+    let socket_addr = enr.udp4_socket().unwrap();
+
+    let mut service = build_service(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        socket_addr.into(),
+        false,
+    )
+    .await;
+    service.ip_votes = Some(IpVote::new(enr_peer_update_min, Duration::from_secs(10)));
+    let (event_send, event_recv) = mpsc::channel(30);
+    service.event_stream = Some(event_send);
+    (service, event_recv)
+}
+
+fn random_peer(port: u16) -> Enr {
+    EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(port)
+        .build(&CombinedKey::generate_secp256k1())
+        .unwrap()
+}
+
+fn external_addr_updates(event_recv: &mut mpsc::Receiver<Discv5Event>) -> Vec<SocketAddr> {
+    let mut updates = Vec::new();
+    while let Ok(event) = event_recv.try_recv() {
+        if let Discv5Event::ExternalAddrUpdated(socket) = event {
+            updates.push(socket);
+        }
+    }
+    updates
+}
+
+#[tokio::test]
+async fn test_external_address_prediction() {
+    init();
+    let (mut service, mut event_recv) = build_voting_service(10019, 2).await;
+
+    let external_address: SocketAddrV4 = "192.0.2.1:9000".parse().unwrap();
+    let mut predictions = Vec::new();
+    for i in 0..3u16 {
+        let peer = random_peer(10020 + i);
+        receive_pong_vote(&mut service, &peer, i as u8, external_address.into());
+        predictions.push(*service.external_address_prediction.read());
+    }
+
+    // The prediction is made once enough peers agree
+    assert_eq!(
+        predictions,
+        vec![
+            (None, None),
+            (Some(external_address), None),
+            (Some(external_address), None)
+        ]
+    );
+
+    // The change of prediction is reported once
+    assert_eq!(
+        external_addr_updates(&mut event_recv),
+        vec![SocketAddr::from(external_address)]
+    );
+}
+
+#[tokio::test]
+async fn test_external_address_prediction_dual_stack() {
+    init();
+    let (mut service, mut event_recv) = build_voting_service(10024, 2).await;
+
+    let external_ip4: SocketAddrV4 = "192.0.2.1:9000".parse().unwrap();
+    let external_ip6: SocketAddrV6 = "[2001:db8::1]:9000".parse().unwrap();
+    let peer_1 = random_peer(10025);
+    let peer_2 = random_peer(10026);
+
+    // Each peer votes for both families, the votes of one family must not replace the other
+    receive_pong_vote(&mut service, &peer_1, 1, external_ip4.into());
+    receive_pong_vote(&mut service, &peer_1, 2, external_ip6.into());
+    assert_eq!(*service.external_address_prediction.read(), (None, None));
+
+    receive_pong_vote(&mut service, &peer_2, 3, external_ip4.into());
+    assert_eq!(
+        *service.external_address_prediction.read(),
+        (Some(external_ip4), None)
+    );
+
+    receive_pong_vote(&mut service, &peer_2, 4, external_ip6.into());
+    assert_eq!(
+        *service.external_address_prediction.read(),
+        (Some(external_ip4), Some(external_ip6))
+    );
+
+    // Both families are advertised in the local ENR
+    assert_eq!(service.local_enr.read().udp4_socket(), Some(external_ip4));
+    assert_eq!(service.local_enr.read().udp6_socket(), Some(external_ip6));
+
+    assert_eq!(
+        external_addr_updates(&mut event_recv),
+        vec![external_ip4.into(), external_ip6.into()]
+    );
+}
+
+#[tokio::test]
+async fn test_enr_update_vote_threshold() {
+    init();
+    let (mut service, _event_recv) = build_voting_service(10027, 3).await;
+    let local_socket = service.local_enr.read().udp4_socket();
+
+    let external_address: SocketAddrV4 = "192.0.2.1:9000".parse().unwrap();
+    for i in 0..2u16 {
+        let peer = random_peer(10028 + i);
+        receive_pong_vote(&mut service, &peer, i as u8, external_address.into());
+    }
+    // just below the threshold the advertised address is kept
+    assert_eq!(service.local_enr.read().udp4_socket(), local_socket);
+
+    let peer = random_peer(10030);
+    receive_pong_vote(&mut service, &peer, 2, external_address.into());
+    // at the threshold the advertised address is updated
+    assert_eq!(
+        service.local_enr.read().udp4_socket(),
+        Some(external_address)
+    );
+}
+
+fn table_events(event_recv: &mut mpsc::Receiver<Discv5Event>) -> Vec<Discv5Event> {
+    let mut events = Vec::new();
+    while let Ok(event) = event_recv.try_recv() {
+        if let Discv5Event::NodeInserted { .. }
+        | Discv5Event::NodeUpdated { .. }
+        | Discv5Event::NodeEvicted { .. } = event
+        {
+            events.push(event);
+        }
+    }
+    events
+}
+
+#[tokio::test]
+async fn test_table_change_events() {
+    init();
+    let (mut service, mut event_recv) = build_voting_service(10031, 10).await;
+    // pending nodes replace disconnected nodes right away
+    let local_key = kbucket::Key::from(service.local_enr.read().node_id());
+    service.kbuckets = Arc::new(RwLock::new(KBucketsTable::new(
+        local_key.clone(),
+        Duration::from_millis(10),
+        service.config.incoming_bucket_limit,
+        None,
+        None,
+        kbucket::EvictionPolicy::default(),
+    )));
+
+    // enough peers to fill the furthest bucket, and one more
+    let mut peers = Vec::new();
+    while peers.len() < MAX_NODES_PER_BUCKET + 1 {
+        let peer = random_peer(10032);
+        if local_key.log2_distance(&kbucket::Key::from(peer.node_id())) == Some(256) {
+            peers.push(peer);
+        }
+    }
+    let pending = peers.pop().unwrap();
+    let first = peers[0].node_id();
+
+    for peer in peers.iter() {
+        let status = ConnectionStatus::Connected(peer.clone(), ConnectionDirection::Outgoing);
+        service.connection_updated(peer.node_id(), status);
+    }
+    // updates that don't change the table are not reported
+    let status = ConnectionStatus::Connected(peers[0].clone(), ConnectionDirection::Outgoing);
+    service.connection_updated(first, status);
+    service.connection_updated(first, ConnectionStatus::Disconnected);
+    service.connection_updated(first, ConnectionStatus::Disconnected);
+    // the bucket is full, the new peer waits to replace the disconnected one
+    let status = ConnectionStatus::Connected(pending.clone(), ConnectionDirection::Outgoing);
+    service.connection_updated(pending.node_id(), status);
+
+    let events = table_events(&mut event_recv);
+    assert_eq!(events.len(), MAX_NODES_PER_BUCKET + 1);
+    for (event, peer) in events.iter().zip(peers.iter()) {
+        assert!(
+            matches!(event, Discv5Event::NodeInserted { node_id, replaced: None } if *node_id == peer.node_id())
+        );
+    }
+    assert!(
+        matches!(events.last(), Some(Discv5Event::NodeUpdated { node_id }) if *node_id == first)
+    );
+
+    // the pending node is applied once its timeout has elapsed
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(!service.is_in_table(&kbucket::Key::from(first)));
+    let event = Service::bucket_maintenance_poll(&service.kbuckets).await;
+    assert!(matches!(
+        event,
+        Discv5Event::NodeInserted { node_id, replaced: Some(replaced) }
+            if node_id == pending.node_id() && replaced == first
+    ));
+}
+
+#[test]
+fn test_store_batches() {
+    let entries: Vec<(NodeId, Vec<u8>)> = (0..5)
+        .map(|i| (NodeId::random(), vec![i; 500]))
+        .chain(std::iter::once((NodeId::random(), vec![5; 1500])))
+        .chain(std::iter::once((NodeId::random(), vec![6; 10])))
+        .collect();
+
+    let batches = store_batches(entries.clone());
+    // two 500 byte values fit in a batch, the oversized value is sent on its own
+    let sizes: Vec<usize> = batches.iter().map(|batch| batch.len()).collect();
+    assert_eq!(sizes, vec![2, 2, 1, 1, 1]);
+    assert_eq!(batches.into_iter().flatten().collect::<Vec<_>>(), entries);
+
+    for batch in store_batches(entries[..5].to_vec()) {
+        let request = rpc::Request {
+            id: RequestId(vec![1; 8]),
+            body: rpc::RequestBody::StoreBatch { entries: batch },
+        };
+        assert!(request.encode().len() <= MAX_STORE_BATCH_SIZE);
+    }
+}
+
+#[test]
+fn test_closest_enrs_deterministic() {
+    let target: kbucket::Key<NodeId> = NodeId::random().into();
+    let enrs: Vec<Enr> = (0..10)
+        .map(|_| {
+            let key = CombinedKey::generate_secp256k1();
+            EnrBuilder::new("v4").build(&key).unwrap()
+        })
+        .collect();
+
+    // the ENRs found in any order, possibly more than once, give the same result
+    let closest = closest_enrs(&target, enrs.clone(), 5);
+    let mut rediscovered: Vec<Enr> = enrs.iter().rev().cloned().collect();
+    rediscovered.extend(enrs[..3].iter().cloned());
+    assert_eq!(closest_enrs(&target, rediscovered, 5), closest);
+
+    // the result holds the closest ENRs, by increasing log2 distance
+    let mut expected = enrs;
+    expected.sort_by_key(|enr| target.distance(&kbucket::Key::from(enr.node_id())));
+    expected.truncate(5);
+    assert_eq!(closest, expected);
+    let log2_distances: Vec<_> = closest
+        .iter()
+        .map(|enr| target.log2_distance(&enr.node_id().into()))
+        .collect();
+    assert!(log2_distances.windows(2).all(|pair| pair[0] <= pair[1]));
+}
+
+/// Builds an ENR whose RLP encoding is `size` bytes long.
+fn enr_of_size(size: usize) -> Enr {
+    let key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4").build(&key).unwrap();
+    let base_size = rlp::encode(&enr).len() + 6;
+    let enr = EnrBuilder::new("v4")
+        .add_value("pad", vec![0u8; size - base_size].as_slice())
+        .build(&key)
+        .unwrap();
+    assert!(rlp::encode(&enr).len().abs_diff(size) <= 3);
+    enr
+}
+
+#[test]
+fn test_pack_nodes() {
+    // packed in order, these would take three packets of 704 bytes
+    let nodes: Vec<Enr> = [190, 285, 240, 280, 130]
+        .iter()
+        .map(|size| enr_of_size(*size))
+        .collect();
+    let max_packet_size = 704;
+
+    let packets = pack_nodes(nodes.clone(), max_packet_size);
+    assert_eq!(packets.len(), 2);
+    let mut packed: Vec<Enr> = packets.iter().flatten().cloned().collect();
+    packed.sort_by_key(|enr| enr.node_id().raw());
+    let mut expected = nodes.clone();
+    expected.sort_by_key(|enr| enr.node_id().raw());
+    assert_eq!(packed, expected);
+
+    for packet in packets {
+        let response = rpc::Response {
+            id: RequestId(vec![1; 8]),
+            body: rpc::ResponseBody::Nodes {
+                total: 2,
+                nodes: packet,
+                encodings: HashMap::new(),
+            },
+        };
+        assert!(response.encode().len() <= max_packet_size - 104);
+    }
+
+    // a smaller packet size only fits a single ENR per packet
+    assert_eq!(pack_nodes(nodes.clone(), 404).len(), 5);
+    // the packet size is capped to the protocol limit
+    assert_eq!(
+        pack_nodes(nodes.clone(), 4000),
+        pack_nodes(nodes, MAX_PACKET_SIZE)
+    );
+}
@@ -0,0 +1,190 @@
+use crate::error::FindValueError;
+use enr::NodeId;
+use hashlink::LinkedHashMap;
+use std::time::{Duration, Instant};
+
+/// The values this node stores on behalf of other nodes via STORE requests.
+///
+/// Values expire once they have been held for the configured ttl. When the store is full, the
+/// least recently used value is evicted to make room for a new one.
+pub(crate) struct ValueStore {
+    /// The stored values along with their insertion time, indexed by their key and ordered from
+    /// least to most recently used.
+    values: LinkedHashMap<NodeId, (Vec<u8>, Instant)>,
+    /// The maximum size in bytes of a single value.
+    max_value_size: usize,
+    /// The maximum number of values held.
+    capacity: usize,
+    /// The time values are held for.
+    ttl: Duration,
+}
+
+impl ValueStore {
+    pub fn new(max_value_size: usize, capacity: usize, ttl: Duration) -> Self {
+        ValueStore {
+            values: LinkedHashMap::new(),
+            max_value_size,
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Stores a value under `key`, replacing any previous value. Values exceeding the maximum
+    /// value size are rejected.
+    pub fn insert(&mut self, key: NodeId, value: Vec<u8>) -> Result<(), FindValueError> {
+        if value.len() > self.max_value_size {
+            return Err(FindValueError::ValueTooLarge {
+                size: value.len(),
+                max: self.max_value_size,
+            });
+        }
+        let now = Instant::now();
+        self.remove_expired_values(now);
+
+        self.values.insert(key, (value, now));
+        while self.values.len() > self.capacity {
+            self.values.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Returns the value stored under `key`, if any and not expired. Marks the value as
+    /// recently used.
+    pub fn get(&mut self, key: &NodeId) -> Option<&Vec<u8>> {
+        self.remove_expired_values(Instant::now());
+
+        match self.values.raw_entry_mut().from_key(key) {
+            hashlink::linked_hash_map::RawEntryMut::Occupied(mut occupied) => {
+                occupied.to_back();
+                Some(&occupied.into_mut().0)
+            }
+            hashlink::linked_hash_map::RawEntryMut::Vacant(_) => None,
+        }
+    }
+
+    /// Returns the value stored under `key`, if any and not expired, without marking it as
+    /// recently used.
+    pub fn peek(&self, key: &NodeId) -> Option<&Vec<u8>> {
+        let now = Instant::now();
+        self.values
+            .get(key)
+            .filter(|(_, inserted)| now.saturating_duration_since(*inserted) < self.ttl)
+            .map(|(value, _)| value)
+    }
+
+    /// Returns the keys of all non-expired values held.
+    pub fn keys(&mut self) -> Vec<NodeId> {
+        self.remove_expired_values(Instant::now());
+        self.values.keys().copied().collect()
+    }
+
+    /// Returns the number of non-expired values held.
+    #[cfg(test)]
+    pub fn len(&mut self) -> usize {
+        self.remove_expired_values(Instant::now());
+        self.values.len()
+    }
+
+    /// Removes the values that have been held for longer than the ttl.
+    fn remove_expired_values(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.values
+            .retain(|_, (_, inserted)| now.saturating_duration_since(*inserted) < ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_insert_and_get() {
+        let mut store = ValueStore::new(10, 100, TTL);
+        let key = NodeId::random();
+
+        assert!(store.insert(key, vec![1, 2, 3]).is_ok());
+        assert_eq!(store.get(&key), Some(&vec![1, 2, 3]));
+
+        // values are replaced
+        assert!(store.insert(key, vec![4]).is_ok());
+        assert_eq!(store.get(&key), Some(&vec![4]));
+        assert_eq!(store.get(&NodeId::random()), None);
+    }
+
+    #[test]
+    fn test_value_size_limit() {
+        let mut store = ValueStore::new(10, 100, TTL);
+        let key = NodeId::random();
+
+        assert!(store.insert(key, vec![0; 10]).is_ok());
+        assert_eq!(
+            store.insert(key, vec![0; 11]),
+            Err(FindValueError::ValueTooLarge { size: 11, max: 10 })
+        );
+        // the previous value is kept
+        assert_eq!(store.get(&key), Some(&vec![0; 10]));
+    }
+
+    #[test]
+    fn test_peek_does_not_update_usage() {
+        let mut store = ValueStore::new(10, 2, TTL);
+        let keys: Vec<NodeId> = (0..3).map(|_| NodeId::random()).collect();
+
+        store.insert(keys[0], vec![0]).unwrap();
+        store.insert(keys[1], vec![1]).unwrap();
+        assert_eq!(store.peek(&keys[0]), Some(&vec![0]));
+
+        store.insert(keys[2], vec![2]).unwrap();
+        // `keys[0]` is evicted as `peek()` does not mark it as recently used
+        assert_eq!(store.peek(&keys[0]), None);
+        let mut stored_keys = store.keys();
+        stored_keys.sort_by_key(|key| key.raw());
+        let mut expected = vec![keys[1], keys[2]];
+        expected.sort_by_key(|key| key.raw());
+        assert_eq!(stored_keys, expected);
+    }
+
+    #[test]
+    fn test_capacity_eviction() {
+        let mut store = ValueStore::new(10, 2, TTL);
+        let keys: Vec<NodeId> = (0..3).map(|_| NodeId::random()).collect();
+
+        store.insert(keys[0], vec![0]).unwrap();
+        store.insert(keys[1], vec![1]).unwrap();
+        // `keys[0]` is now the most recently used
+        assert_eq!(store.get(&keys[0]), Some(&vec![0]));
+
+        store.insert(keys[2], vec![2]).unwrap();
+        assert_eq!(store.len(), 2);
+        // the least recently used value is evicted
+        assert_eq!(store.get(&keys[1]), None);
+        assert_eq!(store.get(&keys[0]), Some(&vec![0]));
+        assert_eq!(store.get(&keys[2]), Some(&vec![2]));
+    }
+
+    #[test]
+    fn test_ttl_expiry() {
+        let ttl = Duration::from_millis(100);
+        let mut store = ValueStore::new(10, 100, ttl);
+        let first = NodeId::random();
+        let second = NodeId::random();
+
+        store.insert(first, vec![1]).unwrap();
+        sleep(ttl / 2);
+        store.insert(second, vec![2]).unwrap();
+        // reading a value does not extend its ttl
+        assert_eq!(store.get(&first), Some(&vec![1]));
+        sleep(ttl / 2);
+
+        assert_eq!(store.get(&first), None);
+        assert_eq!(store.get(&second), Some(&vec![2]));
+        assert_eq!(store.len(), 1);
+
+        sleep(ttl / 2);
+        assert_eq!(store.get(&second), None);
+        assert_eq!(store.len(), 0);
+    }
+}
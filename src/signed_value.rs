@@ -0,0 +1,132 @@
+//! Values signed by the node that originated them.
+//!
+//! A signed value is stored in the DHT as an RLP-encoded list of the value, the signature of the
+//! value's key and the value, and the originator's public key. This allows nodes retrieving the
+//! value via FIND_VALUE to detect values tampered with by the nodes holding them.
+
+use enr::{ed25519_dalek, k256, CombinedKey, CombinedPublicKey, EnrKey, EnrPublicKey, NodeId};
+use rlp::{DecoderError, Rlp, RlpStream};
+
+/// A value along with the signature of its originator.
+#[derive(Debug, Clone)]
+pub(crate) struct SignedValue {
+    /// The signed value.
+    pub value: Vec<u8>,
+    /// The signature of the key and the value.
+    signature: Vec<u8>,
+    /// The public key of the originator.
+    public_key: CombinedPublicKey,
+}
+
+impl SignedValue {
+    /// Signs `value`, stored under `key`, with the originator's key.
+    pub fn new(key: &NodeId, value: Vec<u8>, enr_key: &CombinedKey) -> Result<Self, String> {
+        let signature = enr_key
+            .sign_v4(&signing_message(key, &value))
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(SignedValue {
+            value,
+            signature,
+            public_key: enr_key.public(),
+        })
+    }
+
+    /// Returns the id of the node that signed the value.
+    pub fn signer(&self) -> NodeId {
+        self.public_key.clone().into()
+    }
+
+    /// Verifies that the value stored under `key` was signed by the node `originator`.
+    pub fn verify(&self, key: &NodeId, originator: &NodeId) -> bool {
+        &self.signer() == originator
+            && self
+                .public_key
+                .verify_v4(&signing_message(key, &self.value), &self.signature)
+    }
+
+    /// Encodes the signed value to the bytes stored in the DHT.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut s = RlpStream::new();
+        s.begin_list(3);
+        s.append(&self.value);
+        s.append(&self.signature);
+        s.append(&self.public_key.encode());
+        s.out().to_vec()
+    }
+
+    /// Decodes a signed value from the bytes stored in the DHT.
+    pub fn decode(data: &[u8]) -> Result<Self, DecoderError> {
+        let rlp = Rlp::new(data);
+        if rlp.item_count()? != 3 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        let value = rlp.val_at::<Vec<u8>>(0)?;
+        let signature = rlp.val_at::<Vec<u8>>(1)?;
+        let public_key_bytes = rlp.val_at::<Vec<u8>>(2)?;
+
+        // compressed secp256k1 keys are 33 bytes, ed25519 keys 32 bytes
+        let public_key = match public_key_bytes.len() {
+            33 => k256::ecdsa::VerifyingKey::from_sec1_bytes(&public_key_bytes)
+                .map(CombinedPublicKey::from)
+                .map_err(|_| DecoderError::Custom("Invalid secp256k1 public key"))?,
+            32 => ed25519_dalek::PublicKey::from_bytes(&public_key_bytes)
+                .map(CombinedPublicKey::from)
+                .map_err(|_| DecoderError::Custom("Invalid ed25519 public key"))?,
+            _ => return Err(DecoderError::Custom("Unknown public key type")),
+        };
+
+        Ok(SignedValue {
+            value,
+            signature,
+            public_key,
+        })
+    }
+}
+
+/// The message signed by the originator of a value.
+fn signing_message(key: &NodeId, value: &[u8]) -> Vec<u8> {
+    let mut message = key.raw().to_vec();
+    message.extend_from_slice(value);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        for enr_key in [
+            CombinedKey::generate_secp256k1(),
+            CombinedKey::generate_ed25519(),
+        ] {
+            let originator = NodeId::from(enr_key.public());
+            let key = NodeId::random();
+            let signed = SignedValue::new(&key, vec![1, 2, 3], &enr_key).unwrap();
+
+            let decoded = SignedValue::decode(&signed.encode()).unwrap();
+            assert_eq!(decoded.value, vec![1, 2, 3]);
+            assert_eq!(decoded.signer(), originator);
+            assert!(decoded.verify(&key, &originator));
+
+            // the signature only covers the key it was stored under
+            assert!(!decoded.verify(&NodeId::random(), &originator));
+            // the value must be signed by the expected originator
+            assert!(!decoded.verify(&key, &NodeId::random()));
+        }
+    }
+
+    #[test]
+    fn test_tampered_value() {
+        let enr_key = CombinedKey::generate_secp256k1();
+        let originator = NodeId::from(enr_key.public());
+        let key = NodeId::random();
+
+        let mut signed = SignedValue::new(&key, vec![1, 2, 3], &enr_key).unwrap();
+        signed.value = vec![1, 2, 4];
+        let decoded = SignedValue::decode(&signed.encode()).unwrap();
+        assert!(!decoded.verify(&key, &originator));
+
+        assert!(SignedValue::decode(&[1, 2, 3]).is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use super::rate_limiter::RateLimiter;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FilterConfig {
     /// Whether the packet filter is enabled or not.
     pub enabled: bool,
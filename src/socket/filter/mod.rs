@@ -82,7 +82,7 @@ impl Filter {
             return true;
         }
 
-        if PERMIT_BAN_LIST.read().ban_ips.get(&src.ip()).is_some() {
+        if PERMIT_BAN_LIST.read().is_ip_banned(&src.ip()) {
             debug!("Dropped unsolicited packet from banned src: {:?}", src);
             return false;
         }
@@ -133,12 +133,7 @@ impl Filter {
             return true;
         }
 
-        if PERMIT_BAN_LIST
-            .read()
-            .ban_nodes
-            .get(&node_address.node_id)
-            .is_some()
-        {
+        if PERMIT_BAN_LIST.read().is_node_banned(&node_address.node_id) {
             debug!(
                 "Dropped unsolicited packet from banned node_id: {}",
                 node_address
@@ -221,3 +216,64 @@ impl Filter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> Filter {
+        let config = FilterConfig {
+            enabled: false,
+            rate_limiter: None,
+            max_nodes_per_ip: None,
+            max_bans_per_ip: None,
+        };
+        Filter::new(config, None)
+    }
+
+    #[test]
+    fn test_banned_ip_dropped() {
+        let mut filter = filter();
+        // an address no other test uses, as the ban list is shared
+        let src: SocketAddr = "192.0.2.10:9000".parse().unwrap();
+        assert!(filter.initial_pass(&src));
+
+        let time_to_unban = Instant::now() + Duration::from_millis(50);
+        PERMIT_BAN_LIST
+            .write()
+            .ban_ips
+            .insert(src.ip(), Some(time_to_unban));
+        assert!(!filter.initial_pass(&src));
+
+        // timed bans lift once they expire
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(filter.initial_pass(&src));
+        PERMIT_BAN_LIST.write().ban_ips.remove(&src.ip());
+    }
+
+    #[test]
+    fn test_banned_node_dropped() {
+        let mut filter = filter();
+        let node_address = NodeAddress {
+            socket_addr: "192.0.2.11:9000".parse().unwrap(),
+            node_id: NodeId::random(),
+        };
+        let packet = Packet::new_random(&NodeId::random()).unwrap();
+        assert!(filter.final_pass(&node_address, &packet));
+
+        let time_to_unban = Instant::now() + Duration::from_millis(50);
+        PERMIT_BAN_LIST
+            .write()
+            .ban_nodes
+            .insert(node_address.node_id, Some(time_to_unban));
+        assert!(!filter.final_pass(&node_address, &packet));
+
+        // timed bans lift once they expire
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(filter.final_pass(&node_address, &packet));
+        PERMIT_BAN_LIST
+            .write()
+            .ban_nodes
+            .remove(&node_address.node_id);
+    }
+}
@@ -53,6 +53,16 @@ pub struct Quota {
     max_tokens: u64,
 }
 
+impl Quota {
+    /// A quota of `max_tokens` tokens every `replenish_all_every`.
+    pub(crate) fn n_every(max_tokens: u64, replenish_all_every: Duration) -> Self {
+        Quota {
+            replenish_all_every,
+            max_tokens,
+        }
+    }
+}
+
 /// Manages rate limiting of requests per peer, with differentiated rates per protocol.
 #[derive(Debug, Clone)]
 pub struct RateLimiter {
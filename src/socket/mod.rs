@@ -1,4 +1,4 @@
-use crate::{Executor, IpMode};
+use crate::{config::PacketFilter, Executor, IpMode};
 use parking_lot::RwLock;
 use recv::*;
 use send::*;
@@ -6,16 +6,21 @@ use socket2::{Domain, Protocol, Socket as Socket2, Type};
 use std::{
     collections::HashMap,
     io::{Error, ErrorKind},
-    net::SocketAddr,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
     sync::Arc,
     time::Duration,
 };
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+};
+use tracing::warn;
 
 mod filter;
 mod recv;
 mod send;
 
+pub(crate) use filter::rate_limiter::{Limiter, Quota};
 pub use filter::{
     rate_limiter::{RateLimiter, RateLimiterBuilder},
     FilterConfig,
@@ -32,18 +37,60 @@ pub struct SocketConfig {
     pub filter_config: FilterConfig,
     /// If the filter is enabled this sets the default timeout for bans enacted by the filter.
     pub ban_duration: Option<Duration>,
+    /// A user-supplied filter run on every inbound datagram before it is decoded.
+    pub packet_filter: Option<PacketFilter>,
     /// The expected responses reference.
     pub expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
     /// The local node id used to decrypt messages.
     pub local_node_id: enr::NodeId,
 }
 
+/// The sizes of the OS buffers of the bound sockets. The OS default is kept for the sizes not
+/// set.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct BufferSizes {
+    /// The size of the receive buffer, if set.
+    pub recv: Option<usize>,
+    /// The size of the send buffer, if set.
+    pub send: Option<usize>,
+}
+
+/// The bound UDP sockets, for each IP family.
+pub(crate) struct BoundSockets {
+    /// The socket IPv4 packets are sent and received on.
+    ip4: Option<Arc<UdpSocket>>,
+    /// The socket IPv6 packets are sent and received on. For a dual-stack socket this is the same
+    /// socket as `ip4`.
+    ip6: Option<Arc<UdpSocket>>,
+}
+
+impl BoundSockets {
+    /// The distinct sockets packets are received on.
+    fn recv_sockets(&self) -> Vec<Arc<UdpSocket>> {
+        let mut sockets: Vec<Arc<UdpSocket>> = self.ip4.iter().cloned().collect();
+        if let Some(ip6) = &self.ip6 {
+            if !sockets.iter().any(|socket| Arc::ptr_eq(socket, ip6)) {
+                sockets.push(ip6.clone());
+            }
+        }
+        sockets
+    }
+
+    /// The local addresses of the bound sockets.
+    pub(crate) fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.recv_sockets()
+            .iter()
+            .filter_map(|socket| socket.local_addr().ok())
+            .collect()
+    }
+}
+
 /// Creates the UDP socket and handles the exit futures for the send/recv UDP handlers.
 pub struct Socket {
     pub send: mpsc::Sender<OutboundPacket>,
     pub recv: mpsc::Receiver<InboundPacket>,
     sender_exit: Option<oneshot::Sender<()>>,
-    recv_exit: Option<oneshot::Sender<()>>,
+    recv_exits: Vec<oneshot::Sender<()>>,
 }
 
 impl Socket {
@@ -52,6 +99,16 @@ impl Socket {
     pub(crate) async fn new_socket(
         socket_addr: &SocketAddr,
         ip_mode: IpMode,
+        buffer_sizes: BufferSizes,
+    ) -> Result<tokio::net::UdpSocket, Error> {
+        let socket = Self::bind_socket(socket_addr, ip_mode).await?;
+        Self::set_buffer_sizes(&socket, buffer_sizes)?;
+        Ok(socket)
+    }
+
+    async fn bind_socket(
+        socket_addr: &SocketAddr,
+        ip_mode: IpMode,
     ) -> Result<tokio::net::UdpSocket, Error> {
         match ip_mode {
             IpMode::Ip4 => match socket_addr {
@@ -81,33 +138,124 @@ impl Socket {
         }
     }
 
-    /// Creates a UDP socket, spawns a send/recv task and returns the channels.
+    /// Applies the configured sizes to the OS buffers of a socket, warning if the OS clamps them.
+    fn set_buffer_sizes(
+        socket: &tokio::net::UdpSocket,
+        buffer_sizes: BufferSizes,
+    ) -> Result<(), Error> {
+        let socket = socket2::SockRef::from(socket);
+        if let Some(size) = buffer_sizes.recv {
+            socket.set_recv_buffer_size(size)?;
+            let applied = socket.recv_buffer_size()?;
+            if applied < size {
+                warn!(
+                    "The OS clamped the socket receive buffer to {} bytes, below the {} requested",
+                    applied, size
+                );
+            }
+        }
+        if let Some(size) = buffer_sizes.send {
+            socket.set_send_buffer_size(size)?;
+            let applied = socket.send_buffer_size()?;
+            if applied < size {
+                warn!(
+                    "The OS clamped the socket send buffer to {} bytes, below the {} requested",
+                    applied, size
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Binds the sockets to listen on. If `listen_addresses` holds an address of either family,
+    /// a separate socket is bound for each of them. Otherwise a single socket is bound on
+    /// `socket_addr`, following the `ip_mode`.
+    pub(crate) async fn bind_sockets(
+        socket_addr: &SocketAddr,
+        listen_addresses: (Option<SocketAddrV4>, Option<SocketAddrV6>),
+        ip_mode: IpMode,
+        buffer_sizes: BufferSizes,
+    ) -> Result<BoundSockets, Error> {
+        match listen_addresses {
+            (None, None) => {
+                let socket = Arc::new(Self::new_socket(socket_addr, ip_mode, buffer_sizes).await?);
+                Ok(match (socket_addr, ip_mode) {
+                    (SocketAddr::V4(_), _) => BoundSockets {
+                        ip4: Some(socket),
+                        ip6: None,
+                    },
+                    (
+                        SocketAddr::V6(_),
+                        IpMode::Ip6 {
+                            enable_mapped_addresses: true,
+                        },
+                    ) => BoundSockets {
+                        ip4: Some(socket.clone()),
+                        ip6: Some(socket),
+                    },
+                    (SocketAddr::V6(_), _) => BoundSockets {
+                        ip4: None,
+                        ip6: Some(socket),
+                    },
+                })
+            }
+            (ip4, ip6) => {
+                let ip4 = match ip4 {
+                    Some(addr) => Some(Arc::new(
+                        Self::new_socket(&addr.into(), IpMode::Ip4, buffer_sizes).await?,
+                    )),
+                    None => None,
+                };
+                // the IPv4 packets are received on their own socket
+                let ip6_mode = IpMode::Ip6 {
+                    enable_mapped_addresses: false,
+                };
+                let ip6 = match ip6 {
+                    Some(addr) => Some(Arc::new(
+                        Self::new_socket(&addr.into(), ip6_mode, buffer_sizes).await?,
+                    )),
+                    None => None,
+                };
+                Ok(BoundSockets { ip4, ip6 })
+            }
+        }
+    }
+
+    /// Spawns a recv task for each bound socket and a send task routing packets to the socket
+    /// of their destination's IP family, and returns the channels.
     /// If this struct is dropped, the send/recv tasks will shutdown.
     /// This needs to be run inside of a tokio executor.
-    pub(crate) fn new(socket: tokio::net::UdpSocket, config: SocketConfig) -> Result<Self, Error> {
-        // Arc the udp socket for the send/recv tasks.
-        let recv_udp = Arc::new(socket);
-        let send_udp = recv_udp.clone();
-
-        // spawn the recv handler
-        let recv_config = RecvHandlerConfig {
-            filter_config: config.filter_config,
-            executor: config.executor.clone(),
-            recv: recv_udp,
-            local_node_id: config.local_node_id,
-            expected_responses: config.expected_responses,
-            ban_duration: config.ban_duration,
-        };
+    pub(crate) fn new(sockets: BoundSockets, config: SocketConfig) -> Result<Self, Error> {
+        // create the channel to send decoded packets to the handler
+        let (handler, recv) = mpsc::channel(30);
+
+        // spawn the recv handlers
+        let recv_exits = sockets
+            .recv_sockets()
+            .into_iter()
+            .map(|recv_udp| {
+                RecvHandler::spawn(RecvHandlerConfig {
+                    filter_config: config.filter_config.clone(),
+                    executor: config.executor.clone(),
+                    recv: recv_udp,
+                    handler: handler.clone(),
+                    local_node_id: config.local_node_id,
+                    expected_responses: config.expected_responses.clone(),
+                    ban_duration: config.ban_duration,
+                    packet_filter: config.packet_filter.clone(),
+                })
+            })
+            .collect();
 
-        let (recv, recv_exit) = RecvHandler::spawn(recv_config);
         // spawn the sender handler
-        let (send, sender_exit) = SendHandler::spawn(config.executor.clone(), send_udp);
+        let (send, sender_exit) =
+            SendHandler::spawn(config.executor.clone(), sockets.ip4, sockets.ip6);
 
         Ok(Socket {
             send,
             recv,
             sender_exit: Some(sender_exit),
-            recv_exit: Some(recv_exit),
+            recv_exits,
         })
     }
 }
@@ -120,6 +268,29 @@ impl std::ops::Drop for Socket {
             .take()
             .expect("Exit always exists")
             .send(());
-        let _ = self.recv_exit.take().expect("Exit always exists").send(());
+        for recv_exit in self.recv_exits.drain(..) {
+            let _ = recv_exit.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_buffer_sizes_applied() {
+        let size = 1 << 16;
+        let buffer_sizes = BufferSizes {
+            recv: Some(size),
+            send: Some(size),
+        };
+        let socket = Socket::new_socket(&"127.0.0.1:0".parse().unwrap(), IpMode::Ip4, buffer_sizes)
+            .await
+            .unwrap();
+        let socket = socket2::SockRef::from(&socket);
+        // the OS may round the sizes up, 64 KiB is within the default OS limits
+        assert!(socket.recv_buffer_size().unwrap() >= size);
+        assert!(socket.send_buffer_size().unwrap() >= size);
     }
 }
@@ -4,7 +4,8 @@
 
 use super::filter::{Filter, FilterConfig};
 use crate::{
-    ipmode::to_ipv4_mapped, metrics::METRICS, node_info::NodeAddress, packet::*, Executor,
+    config::PacketFilter, ipmode::to_ipv4_mapped, metrics::METRICS, node_info::NodeAddress,
+    packet::*, Executor,
 };
 use parking_lot::RwLock;
 use std::{
@@ -37,8 +38,12 @@ pub struct RecvHandlerConfig {
     pub filter_config: FilterConfig,
     /// If the filter is enabled this sets the default timeout for bans enacted by the filter.
     pub ban_duration: Option<Duration>,
+    /// A user-supplied filter run on every datagram before it is decoded.
+    pub packet_filter: Option<PacketFilter>,
     pub executor: Box<dyn Executor>,
     pub recv: Arc<UdpSocket>,
+    /// The channel to send decoded packets to the handler.
+    pub handler: mpsc::Sender<InboundPacket>,
     pub local_node_id: enr::NodeId,
     pub expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
 }
@@ -52,6 +57,8 @@ pub(crate) struct RecvHandler {
     expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
     /// The packet filter which decides whether to accept or reject inbound packets.
     filter: Filter,
+    /// A user-supplied filter run on every datagram before it is decoded.
+    packet_filter: Option<PacketFilter>,
     /// The buffer to accept inbound datagrams.
     recv_buffer: [u8; MAX_PACKET_SIZE],
     /// The local node id used to decrypt headers of messages.
@@ -63,24 +70,21 @@ pub(crate) struct RecvHandler {
 }
 
 impl RecvHandler {
-    /// Spawns the `RecvHandler` on a provided executor.
-    pub(crate) fn spawn(
-        config: RecvHandlerConfig,
-    ) -> (mpsc::Receiver<InboundPacket>, oneshot::Sender<()>) {
+    /// Spawns the `RecvHandler` on a provided executor. Decoded packets are sent to the handler
+    /// channel given in the config, which may be shared by the handlers of several sockets.
+    pub(crate) fn spawn(config: RecvHandlerConfig) -> oneshot::Sender<()> {
         let (exit_sender, exit) = oneshot::channel();
 
         let filter_enabled = config.filter_config.enabled;
 
-        // create the channel to send decoded packets to the handler
-        let (handler, handler_recv) = mpsc::channel(30);
-
         let mut recv_handler = RecvHandler {
             recv: config.recv,
             filter: Filter::new(config.filter_config, config.ban_duration),
+            packet_filter: config.packet_filter,
             recv_buffer: [0; MAX_PACKET_SIZE],
             node_id: config.local_node_id,
             expected_responses: config.expected_responses,
-            handler,
+            handler: config.handler,
             exit,
         };
 
@@ -89,7 +93,7 @@ impl RecvHandler {
             debug!("Recv handler starting");
             recv_handler.start(filter_enabled).await;
         }));
-        (handler_recv, exit_sender)
+        exit_sender
     }
 
     /// The main future driving the recv handler. This will shutdown when the exit future is fired.
@@ -129,6 +133,14 @@ impl RecvHandler {
                 src_address.set_ip(ip4.into())
             }
         }
+        // Run the user-supplied filter on the raw datagram.
+        if let Some(packet_filter) = &self.packet_filter {
+            if !packet_filter(&src_address, &self.recv_buffer[..length]) {
+                trace!("Packet rejected by the packet filter from: {}", src_address);
+                return;
+            }
+        }
+
         // Permit all expected responses
         let permitted = self.expected_responses.read().get(&src_address).is_some();
 
@@ -163,6 +175,8 @@ impl RecvHandler {
             }
         }
 
+        METRICS.bytes_recv_by_kind.add(&packet.header.kind, length);
+
         let inbound = InboundPacket {
             src_address,
             header: packet.header,
@@ -1,6 +1,9 @@
 //! This is a standalone task that encodes and sends Discv5 UDP packets
 use crate::{metrics::METRICS, node_info::NodeAddress, packet::*, Executor};
-use std::sync::Arc;
+use std::{
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+};
 use tokio::{
     net::UdpSocket,
     sync::{mpsc, oneshot},
@@ -16,8 +19,10 @@ pub struct OutboundPacket {
 
 /// The main task that handles outbound UDP packets.
 pub(crate) struct SendHandler {
-    /// The UDP send socket.
-    send: Arc<UdpSocket>,
+    /// The UDP socket packets to IPv4 destinations are sent from.
+    send_ip4: Option<Arc<UdpSocket>>,
+    /// The UDP socket packets to IPv6 destinations are sent from.
+    send_ip6: Option<Arc<UdpSocket>>,
     /// The channel to respond to send requests.
     handler_recv: mpsc::Receiver<OutboundPacket>,
     /// Exit channel to shutdown the handler.
@@ -26,17 +31,21 @@ pub(crate) struct SendHandler {
 
 impl SendHandler {
     /// Spawns the `SendHandler` on a provided executor.
+    /// Packets are sent from the socket of the destination's IP family, a dual-stack socket can
+    /// be given for both families.
     /// This returns the sending channel to process `OutboundPacket`'s and an exit channel to
     /// shutdown the handler.
     pub(crate) fn spawn(
         executor: Box<dyn Executor>,
-        send: Arc<UdpSocket>,
+        send_ip4: Option<Arc<UdpSocket>>,
+        send_ip6: Option<Arc<UdpSocket>>,
     ) -> (mpsc::Sender<OutboundPacket>, oneshot::Sender<()>) {
         let (exit_send, exit) = oneshot::channel();
         let (handler_send, handler_recv) = mpsc::channel(30);
 
         let mut send_handler = SendHandler {
-            send,
+            send_ip4,
+            send_ip6,
             handler_recv,
             exit,
         };
@@ -54,15 +63,7 @@ impl SendHandler {
         loop {
             tokio::select! {
                 Some(packet) = self.handler_recv.recv() => {
-                    let encoded_packet = packet.packet.encode(&packet.node_address.node_id);
-                    if encoded_packet.len() > MAX_PACKET_SIZE {
-                        warn!("Sending packet larger than max size: {} max: {}", encoded_packet.len(), MAX_PACKET_SIZE);
-                    }
-                    if let Err(e) = self.send.send_to(&encoded_packet, &packet.node_address.socket_addr).await {
-                        trace!("Could not send packet. Error: {:?}", e);
-                    } else {
-                        METRICS.add_sent_bytes(encoded_packet.len());
-                    }
+                    self.send(packet).await;
                 }
                 _ = &mut self.exit => {
                     debug!("Send handler shutdown");
@@ -71,4 +72,123 @@ impl SendHandler {
             }
         }
     }
+
+    /// Encodes and sends a packet from the socket of the destination's IP family.
+    async fn send(&self, packet: OutboundPacket) {
+        let socket_addr = packet.node_address.socket_addr;
+        let socket = match socket_addr {
+            SocketAddr::V4(_) => &self.send_ip4,
+            SocketAddr::V6(_) => &self.send_ip6,
+        };
+        let socket = match socket {
+            Some(socket) => socket,
+            None => {
+                trace!("No socket to send packets to {}", socket_addr);
+                return;
+            }
+        };
+
+        let kind_bytes_sent = METRICS
+            .bytes_sent_by_kind
+            .counter(&packet.packet.header.kind);
+        let encoded_packet = packet.packet.encode(&packet.node_address.node_id);
+        if encoded_packet.len() > MAX_PACKET_SIZE {
+            warn!(
+                "Sending packet larger than max size: {} max: {}",
+                encoded_packet.len(),
+                MAX_PACKET_SIZE
+            );
+        }
+        if let Err(e) = socket.send_to(&encoded_packet, &socket_addr).await {
+            trace!("Could not send packet. Error: {:?}", e);
+        } else {
+            METRICS.add_sent_bytes(encoded_packet.len());
+            kind_bytes_sent.fetch_add(encoded_packet.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::TokioExecutor;
+    use enr::NodeId;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_sent_bytes_metrics() {
+        let send = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let recv = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (handler_send, _exit) = SendHandler::spawn(Box::new(TokioExecutor), Some(send), None);
+
+        let node_address = NodeAddress {
+            socket_addr: recv.local_addr().unwrap(),
+            node_id: NodeId::random(),
+        };
+        let packet = Packet::new_whoareyou(rand::random(), rand::random(), 0);
+        let expected_bytes = packet.clone().encode(&node_address.node_id).len();
+
+        // the metrics are shared by all the sockets of the process, others may increase them too
+        let sent_before = METRICS.bytes_sent.load(Ordering::Relaxed);
+        let whoareyou_before = METRICS.bytes_sent_by_kind.whoareyou.load(Ordering::Relaxed);
+        handler_send
+            .send(OutboundPacket {
+                node_address,
+                packet,
+            })
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let (length, _) = recv.recv_from(&mut buf).await.unwrap();
+        assert_eq!(length, expected_bytes);
+
+        // the metrics are updated once the packet has been sent
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while METRICS.bytes_sent_by_kind.whoareyou.load(Ordering::Relaxed)
+                < whoareyou_before + expected_bytes
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("WHOAREYOU bytes not counted");
+        assert!(METRICS.bytes_sent.load(Ordering::Relaxed) >= sent_before + expected_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_send_by_destination_family() {
+        let send_ip4 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let send_ip6 = Arc::new(UdpSocket::bind("[::1]:0").await.unwrap());
+        let recv_ip4 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let recv_ip6 = UdpSocket::bind("[::1]:0").await.unwrap();
+        let (handler_send, _exit) = SendHandler::spawn(
+            Box::new(TokioExecutor),
+            Some(send_ip4.clone()),
+            Some(send_ip6.clone()),
+        );
+
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        for (recv, send) in [(recv_ip4, send_ip4), (recv_ip6, send_ip6)] {
+            let node_address = NodeAddress {
+                socket_addr: recv.local_addr().unwrap(),
+                node_id: NodeId::random(),
+            };
+            let packet = Packet::new_whoareyou(rand::random(), rand::random(), 0);
+            handler_send
+                .send(OutboundPacket {
+                    node_address,
+                    packet,
+                })
+                .await
+                .unwrap();
+
+            // the packet is sent from the socket of the destination's family
+            let (_, src) = tokio::time::timeout(Duration::from_secs(1), recv.recv_from(&mut buf))
+                .await
+                .expect("Packet not received")
+                .unwrap();
+            assert_eq!(src, send.local_addr().unwrap());
+        }
+    }
 }
@@ -0,0 +1,69 @@
+//! Measures the heap memory held by the ENRs of the routing table. The allocations are counted by
+//! a global allocator, which is why this runs as its own test binary.
+
+use discv5::{
+    enr::{CombinedKey, EnrBuilder},
+    kbucket::TableEnr,
+    Enr,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+/// Counts the bytes allocated and not yet freed by each thread.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ =
+            ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size() as isize));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ =
+            ALLOCATED.try_with(|allocated| allocated.set(allocated.get() - layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// The heap bytes held by a table ENR built from `enr`.
+fn held_bytes(enr: &Enr, compact: bool) -> isize {
+    let before = ALLOCATED.with(|allocated| allocated.get());
+    let table_enr = TableEnr::new(enr.clone(), compact);
+    let held = ALLOCATED.with(|allocated| allocated.get()) - before;
+    drop(table_enr);
+    held
+}
+
+#[test]
+fn test_compact_memory() {
+    let key = CombinedKey::generate_secp256k1();
+    let enr = EnrBuilder::new("v4")
+        .ip4("127.0.0.1".parse().unwrap())
+        .udp4(9000)
+        .tcp4(9000)
+        .add_value("eth2", &[1u8; 16])
+        .build(&key)
+        .unwrap();
+
+    let decoded = held_bytes(&enr, false);
+    let compact = held_bytes(&enr, true);
+    // the compact form only holds the RLP encoding on the heap
+    assert_eq!(compact, rlp::encode(&enr).len() as isize);
+    // which is less than half the memory of a decoded ENR
+    assert!(
+        compact * 2 < decoded,
+        "{} bytes compact, {} decoded",
+        compact,
+        decoded
+    );
+}